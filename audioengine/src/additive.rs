@@ -0,0 +1,282 @@
+use envelope::Adsr;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use tuning::Tuning;
+use types::Signal;
+
+pub const MAX_PARTIALS: usize = 512;
+/// Samples between corrections of the rounding errors that make the recursive oscillators
+/// drift in amplitude.
+const RENORMALIZE_INTERVAL: usize = 1024;
+
+/// Sine partial computed by rotating a phasor, which costs a few multiplications per sample
+/// instead of a call to `sin`.
+struct Partial {
+    ratio: f64,
+    amplitude: Signal,
+    // Amplitude after tilt and brightness.
+    gain: Signal,
+    envelope: Adsr,
+    real: Signal,
+    imaginary: Signal,
+    cos: Signal,
+    sin: Signal,
+    audible: bool,
+}
+
+impl Partial {
+    fn new(ratio: f64, amplitude: Signal, sample_rate: f64) -> Self {
+        let mut envelope = Adsr::new(sample_rate);
+        envelope.set_sustain(1.0);
+        Self {
+            ratio,
+            amplitude,
+            gain: amplitude,
+            envelope,
+            real: 1.0,
+            imaginary: 0.0,
+            cos: 1.0,
+            sin: 0.0,
+            audible: false,
+        }
+    }
+}
+
+/// Bank of sine partials at arbitrary ratios of the note frequency, each with its own
+/// amplitude and envelope. Partials at or above Nyquist are skipped.
+pub struct AdditiveBank {
+    sample_rate: f64,
+    frequency: f64,
+    partials: Vec<Partial>,
+    partial_count: usize,
+    tilt: f64,
+    brightness: f64,
+    normalization: Signal,
+    samples_since_renormalize: usize,
+    tuning: Arc<Tuning>,
+    current_key: Option<i32>,
+}
+
+impl AdditiveBank {
+    /// `sample_rate` should be `EngineController::sample_rate`, which decides what is
+    /// culled. Starts out as a 32 partial saw.
+    pub fn new(sample_rate: f64) -> Self {
+        let mut bank = Self {
+            sample_rate,
+            frequency: 0.0,
+            partials: (0..MAX_PARTIALS)
+                .map(|index| Partial::new((index + 1) as f64, 0.0, sample_rate))
+                .collect(),
+            partial_count: 0,
+            tilt: 0.0,
+            brightness: 1.0,
+            normalization: 1.0,
+            samples_since_renormalize: 0,
+            tuning: Arc::new(Tuning::default()),
+            current_key: None,
+        };
+        let saw: Vec<Signal> = (1..=32).map(|n| 1.0 / f64::from(n)).collect();
+        bank.set_harmonics(&saw);
+        bank
+    }
+
+    /// Number of partials in use, up to `MAX_PARTIALS`.
+    pub fn set_partial_count(&mut self, count: usize) {
+        self.partial_count = count.min(MAX_PARTIALS);
+        self.update_gains();
+        self.update_frequencies();
+    }
+
+    pub fn partial_count(&self) -> usize {
+        self.partial_count
+    }
+
+    /// Sets the frequency ratio and amplitude of one partial.
+    pub fn set_partial(&mut self, index: usize, ratio: f64, amplitude: Signal) {
+        let partial = &mut self.partials[index];
+        partial.ratio = ratio.max(0.0);
+        partial.amplitude = amplitude;
+        self.update_gains();
+        self.update_frequencies();
+    }
+
+    /// Uses the harmonic series, with `amplitudes[n]` for the partial at ratio `n + 1`.
+    pub fn set_harmonics(&mut self, amplitudes: &[Signal]) {
+        self.partial_count = amplitudes.len().min(MAX_PARTIALS);
+        for (index, (partial, &amplitude)) in
+            self.partials.iter_mut().zip(amplitudes.iter()).enumerate()
+        {
+            partial.ratio = (index + 1) as f64;
+            partial.amplitude = amplitude;
+        }
+        self.update_gains();
+        self.update_frequencies();
+    }
+
+    /// The envelope of one partial. Its velocity sensitivity sets how much velocity scales
+    /// the partial.
+    pub fn envelope_mut(&mut self, index: usize) -> &mut Adsr {
+        &mut self.partials[index].envelope
+    }
+
+    /// Spectral slope in dB per octave above the fundamental. Negative values darken.
+    pub fn set_tilt(&mut self, db_per_octave: f64) {
+        self.tilt = db_per_octave;
+        self.update_gains();
+    }
+
+    /// In [0.0, 1.0]. Below 1.0 higher partials are rolled off exponentially, at 0.0 little
+    /// more than the fundamental is left.
+    pub fn set_brightness(&mut self, brightness: f64) {
+        self.brightness = brightness.clamp(0.0, 1.0);
+        self.update_gains();
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency.max(0.0);
+        self.update_frequencies();
+    }
+
+    /// Starts every partial's envelope and restarts the partials in phase. `velocity` is in
+    /// [0.0, 1.0].
+    pub fn note_on(&mut self, velocity: Signal) {
+        for partial in self.partials.iter_mut().take(self.partial_count) {
+            partial.real = 1.0;
+            partial.imaginary = 0.0;
+            partial.envelope.note_on(velocity);
+        }
+    }
+
+    /// Tuning `gate` plays keys in. Keys it leaves unmapped release the note.
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+    }
+
+    pub fn note_off(&mut self) {
+        for partial in self.partials.iter_mut().take(self.partial_count) {
+            partial.envelope.note_off();
+        }
+    }
+
+    /// Drives the bank from the key handed to a processor function. A different key counts
+    /// as a new note.
+    pub fn gate(&mut self, key: Option<i32>) {
+        if key == self.current_key {
+            return;
+        }
+        if let Some(frequency) = key.and_then(|key| self.tuning.frequency(f64::from(key))) {
+            self.set_frequency(frequency);
+            self.note_on(1.0);
+        } else {
+            self.note_off();
+        }
+        self.current_key = key;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.partials
+            .iter()
+            .take(self.partial_count)
+            .any(|partial| partial.envelope.is_active())
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        self.samples_since_renormalize += 1;
+        let renormalize = self.samples_since_renormalize >= RENORMALIZE_INTERVAL;
+        if renormalize {
+            self.samples_since_renormalize = 0;
+        }
+
+        let mut output = 0.0;
+        for partial in self.partials.iter_mut().take(self.partial_count) {
+            // Culled partials keep their envelopes running, so `is_active` stays right.
+            let level = partial.envelope.next_sample();
+            if !partial.audible {
+                continue;
+            }
+
+            output += partial.imaginary * partial.gain * level;
+
+            let real = partial.real * partial.cos - partial.imaginary * partial.sin;
+            let imaginary = partial.real * partial.sin + partial.imaginary * partial.cos;
+            partial.real = real;
+            partial.imaginary = imaginary;
+
+            if renormalize {
+                // First order approximation of 1 / |z|, plenty for the tiny drift.
+                let correction = (3.0 - (real * real + imaginary * imaginary)) * 0.5;
+                partial.real *= correction;
+                partial.imaginary *= correction;
+            }
+        }
+
+        output * self.normalization
+    }
+
+    fn update_gains(&mut self) {
+        let slope = self.tilt / (20.0 * 2f64.log10());
+        let rolloff = 1.0 - self.brightness;
+        let mut total = 0.0;
+
+        for partial in self.partials.iter_mut().take(self.partial_count) {
+            let ratio = partial.ratio.max(1e-3);
+            partial.gain = partial.amplitude
+                * ratio.powf(slope)
+                * (-(ratio - 1.0).max(0.0) * rolloff * 0.5).exp();
+            total += partial.gain.abs();
+        }
+
+        // Culled partials still count, so the level does not jump between notes.
+        self.normalization = if total > 1.0 { 1.0 / total } else { 1.0 };
+    }
+
+    fn update_frequencies(&mut self) {
+        let nyquist = self.sample_rate * 0.5;
+        for partial in self.partials.iter_mut().take(self.partial_count) {
+            let frequency = self.frequency * partial.ratio;
+            partial.audible = frequency > 0.0 && frequency < nyquist;
+            let omega = 2.0 * PI * frequency / self.sample_rate;
+            partial.cos = omega.cos();
+            partial.sin = omega.sin();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    fn sine(frequency: f64, index: usize) -> Signal {
+        (2.0 * PI * frequency * index as f64 / SAMPLE_RATE).sin()
+    }
+
+    fn bank(amplitudes: &[Signal], frequency: f64) -> AdditiveBank {
+        let mut bank = AdditiveBank::new(SAMPLE_RATE);
+        bank.set_harmonics(amplitudes);
+        for index in 0..amplitudes.len() {
+            bank.envelope_mut(index).set_attack(0.0);
+        }
+        bank.set_frequency(frequency);
+        bank.note_on(1.0);
+        bank
+    }
+
+    #[test]
+    fn single_partial_is_a_sine() {
+        let mut bank = bank(&[1.0], 1000.0);
+        for i in 0..48_000 {
+            assert!((bank.next_sample() - sine(1000.0, i)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn partials_above_nyquist_are_culled() {
+        // The second harmonic lands above Nyquist, leaving only the fundamental at half level.
+        let mut bank = bank(&[1.0, 1.0], 15_000.0);
+        for i in 0..1000 {
+            assert!((bank.next_sample() - 0.5 * sine(15_000.0, i)).abs() < 1e-9);
+        }
+    }
+}
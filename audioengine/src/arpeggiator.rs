@@ -0,0 +1,367 @@
+use clock::{NoteDivision, StepGrid};
+use random::Random;
+use transport::ProcessContext;
+
+pub const MAX_OCTAVES: u32 = 4;
+/// Keys played at once. Keys held beyond these are left out, so that the arpeggiator never
+/// allocates on the audio thread.
+pub const MAX_KEYS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// Up and back down, without repeating the top and bottom notes.
+    UpDown,
+    Random,
+    /// In the order the keys were pressed.
+    AsPlayed,
+}
+
+/// Changes sent to the arpeggiator on the audio thread through
+/// `EngineController::arpeggiator_command`.
+#[derive(Clone, Copy, Debug)]
+pub enum ArpeggiatorCommand {
+    SetEnabled(bool),
+    SetMode(ArpMode),
+    SetOctaves(u32),
+    SetDivision(NoteDivision),
+    SetGate(f64),
+    SetLatch(bool),
+}
+
+/// Plays the held keys one at a time, stepping on a grid along the transport position, or
+/// along the engine clock while the transport is stopped.
+pub struct Arpeggiator {
+    grid: StepGrid,
+    enabled: bool,
+    mode: ArpMode,
+    octaves: u32,
+    division: NoteDivision,
+    gate: f64,
+    latch: bool,
+    random: Random,
+
+    // Keys in the order they were pressed.
+    held: Vec<i32>,
+    // With latch, the keys of the last chord, kept after they are released.
+    notes: Vec<i32>,
+    sequence: Vec<i32>,
+    index: usize,
+    release_beat: f64,
+    key: Option<i32>,
+}
+
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Arpeggiator {
+    /// Starts out disabled, playing sixteenth notes upwards over one octave.
+    pub fn new() -> Self {
+        Self {
+            grid: StepGrid::new(),
+            enabled: false,
+            mode: ArpMode::Up,
+            octaves: 1,
+            division: NoteDivision::Straight(16),
+            gate: 0.5,
+            latch: false,
+            random: Random::default(),
+            held: Vec::with_capacity(MAX_KEYS),
+            notes: Vec::with_capacity(MAX_KEYS),
+            // Up and down over every octave is almost twice the keys.
+            sequence: Vec::with_capacity(MAX_KEYS * MAX_OCTAVES as usize * 2),
+            index: 0,
+            release_beat: 0.0,
+            key: None,
+        }
+    }
+
+    /// While disabled the input key is passed through unchanged.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn set_mode(&mut self, mode: ArpMode) {
+        self.mode = mode;
+        self.update_sequence();
+    }
+
+    /// Number of octaves the keys are repeated over, from 1 to `MAX_OCTAVES`.
+    pub fn set_octaves(&mut self, octaves: u32) {
+        self.octaves = octaves.clamp(1, MAX_OCTAVES);
+        self.update_sequence();
+    }
+
+    /// Time between notes. Takes effect from the next note.
+    pub fn set_division(&mut self, division: NoteDivision) {
+        self.division = division;
+    }
+
+    /// Share of the time between notes that each note is held, in [0.05, 1.0].
+    pub fn set_gate(&mut self, gate: f64) {
+        self.gate = gate.clamp(0.05, 1.0);
+    }
+
+    /// Keeps playing the last chord after its keys are released, until a new chord is
+    /// pressed.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.notes.clone_from(&self.held);
+            self.update_sequence();
+        }
+    }
+
+    pub fn set_seed(&mut self, seed: u32) {
+        self.random = Random::new(seed);
+    }
+
+    pub fn apply(&mut self, command: ArpeggiatorCommand) {
+        match command {
+            ArpeggiatorCommand::SetEnabled(enabled) => self.set_enabled(enabled),
+            ArpeggiatorCommand::SetMode(mode) => self.set_mode(mode),
+            ArpeggiatorCommand::SetOctaves(octaves) => self.set_octaves(octaves),
+            ArpeggiatorCommand::SetDivision(division) => self.set_division(division),
+            ArpeggiatorCommand::SetGate(gate) => self.set_gate(gate),
+            ArpeggiatorCommand::SetLatch(latch) => self.set_latch(latch),
+        }
+    }
+
+    /// Updates the keys to play from `KeysState::held_keys`, most recently pressed first. Only
+    /// the last `MAX_KEYS` pressed are played.
+    pub fn set_held_keys<'a, I: Iterator<Item = &'a i32>>(&mut self, keys: I) {
+        let was_empty = self.held.is_empty();
+        self.held.clear();
+        self.held.extend(keys.take(MAX_KEYS));
+        self.held.reverse();
+
+        if self.held.is_empty() && self.latch {
+            return;
+        }
+        if was_empty {
+            // A new chord restarts the pattern, with the first note on the next step.
+            self.notes.clear();
+            self.index = 0;
+        }
+        if self.latch {
+            // Keys added to a latched chord join it.
+            for &key in self.held.iter() {
+                if !self.notes.contains(&key) && self.notes.len() < MAX_KEYS {
+                    self.notes.push(key);
+                }
+            }
+        } else {
+            self.notes.clone_from(&self.held);
+        }
+        self.update_sequence();
+    }
+
+    /// Advances to the sample of `context` and returns the key that should sound, given
+    /// `input`, the key that would sound without the arpeggiator.
+    pub fn next_sample(&mut self, input: Option<i32>, context: &ProcessContext) -> Option<i32> {
+        if !self.enabled {
+            return input;
+        }
+        if self.sequence.is_empty() {
+            self.key = None;
+            return None;
+        }
+
+        let beat = if context.playing {
+            context.position
+        } else {
+            context.clock
+        };
+        let step_beats = self.division.beats();
+        self.grid.follow(beat, step_beats, 0.0);
+        while let Some(step_start) = self.grid.next_step(step_beats, 0.0) {
+            let index = match self.mode {
+                ArpMode::Random => {
+                    let random = self.random.next_unipolar() * self.sequence.len() as f64;
+                    random as usize
+                }
+                _ => self.index % self.sequence.len(),
+            };
+            self.key = Some(self.sequence[index]);
+            self.index = index + 1;
+            self.release_beat = step_start + step_beats * self.gate;
+        }
+        if beat >= self.release_beat {
+            self.key = None;
+        }
+        self.key
+    }
+
+    fn update_sequence(&mut self) {
+        self.sequence.clear();
+        self.sequence.extend(self.notes.iter());
+        if self.mode != ArpMode::AsPlayed {
+            self.sequence.sort_unstable();
+        }
+        let chord = self.sequence.len();
+        for octave in 1..self.octaves as i32 {
+            for index in 0..chord {
+                let key = self.sequence[index] + octave * 12;
+                self.sequence.push(key);
+            }
+        }
+
+        match self.mode {
+            ArpMode::Down => self.sequence.reverse(),
+            ArpMode::UpDown if self.sequence.len() > 2 => {
+                let top = self.sequence.len() - 1;
+                for index in (1..top).rev() {
+                    let key = self.sequence[index];
+                    self.sequence.push(key);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::Transport;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    // Sixteenth notes at 120 BPM.
+    const STEP_SAMPLES: usize = 6000;
+
+    fn arpeggiator(mode: ArpMode, octaves: u32) -> Arpeggiator {
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.set_enabled(true);
+        arpeggiator.set_mode(mode);
+        arpeggiator.set_octaves(octaves);
+        arpeggiator
+    }
+
+    fn keys(
+        arpeggiator: &mut Arpeggiator,
+        transport: &mut Transport,
+        samples: usize,
+    ) -> Vec<Option<i32>> {
+        (0..samples)
+            .map(|_| arpeggiator.next_sample(None, &transport.next_sample().0))
+            .collect()
+    }
+
+    fn notes(
+        arpeggiator: &mut Arpeggiator,
+        transport: &mut Transport,
+        count: usize,
+    ) -> Vec<Option<i32>> {
+        keys(arpeggiator, transport, count * STEP_SAMPLES)
+            .into_iter()
+            .step_by(STEP_SAMPLES)
+            .collect()
+    }
+
+    #[test]
+    fn modes_order_the_keys() {
+        // Held keys come most recent first, so 7 was pressed first and 0 last.
+        let held = [0, 4, 7];
+        let expected: [(ArpMode, u32, &[i32]); 4] = [
+            (ArpMode::Up, 2, &[0, 4, 7, 12, 16, 19, 0]),
+            (ArpMode::Down, 1, &[7, 4, 0, 7]),
+            (ArpMode::UpDown, 1, &[0, 4, 7, 4, 0, 4]),
+            (ArpMode::AsPlayed, 1, &[7, 4, 0, 7]),
+        ];
+        for &(mode, octaves, keys) in expected.iter() {
+            let mut arpeggiator = arpeggiator(mode, octaves);
+            arpeggiator.set_held_keys(held.iter());
+            let played: Vec<_> = keys.iter().map(|&key| Some(key)).collect();
+            let notes = notes(
+                &mut arpeggiator,
+                &mut Transport::new(SAMPLE_RATE),
+                keys.len(),
+            );
+            assert_eq!(notes, played, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn gate_releases_each_note() {
+        let mut arpeggiator = arpeggiator(ArpMode::Up, 1);
+        arpeggiator.set_gate(0.25);
+        arpeggiator.set_held_keys([3].iter());
+        let keys = keys(
+            &mut arpeggiator,
+            &mut Transport::new(SAMPLE_RATE),
+            STEP_SAMPLES,
+        );
+        assert_eq!(keys[STEP_SAMPLES / 4 - 1], Some(3));
+        assert_eq!(keys[STEP_SAMPLES / 4], None);
+    }
+
+    #[test]
+    fn latch_keeps_playing_released_chord() {
+        let mut arpeggiator = arpeggiator(ArpMode::Up, 1);
+        arpeggiator.set_latch(true);
+        arpeggiator.set_held_keys([2, 1].iter());
+        arpeggiator.set_held_keys([].iter());
+        let mut transport = Transport::new(SAMPLE_RATE);
+        let notes_played = notes(&mut arpeggiator, &mut transport, 3);
+        assert_eq!(notes_played, vec![Some(1), Some(2), Some(1)]);
+
+        // A new chord replaces the latched one.
+        arpeggiator.set_held_keys([5].iter());
+        let notes_played = notes(&mut arpeggiator, &mut transport, 2);
+        assert_eq!(notes_played, vec![Some(5), Some(5)]);
+    }
+
+    #[test]
+    fn keys_never_outgrow_the_buffers() {
+        let mut arpeggiator = arpeggiator(ArpMode::UpDown, MAX_OCTAVES);
+        arpeggiator.set_latch(true);
+        let capacities = |arpeggiator: &Arpeggiator| {
+            (
+                arpeggiator.held.capacity(),
+                arpeggiator.notes.capacity(),
+                arpeggiator.sequence.capacity(),
+            )
+        };
+        let before = capacities(&arpeggiator);
+
+        let keys: Vec<i32> = (0..2 * MAX_KEYS as i32).collect();
+        arpeggiator.set_held_keys(keys.iter());
+        // Latched keys join the chord up to the limit.
+        arpeggiator.set_held_keys(keys.iter().rev());
+        assert_eq!(arpeggiator.notes.len(), MAX_KEYS);
+        assert_eq!(capacities(&arpeggiator), before);
+    }
+
+    #[test]
+    fn disabled_passes_input_through() {
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.set_held_keys([2, 1].iter());
+        let context = ProcessContext::default();
+        assert_eq!(arpeggiator.next_sample(Some(2), &context), Some(2));
+    }
+
+    #[test]
+    fn chords_start_on_the_grid() {
+        let mut arpeggiator = arpeggiator(ArpMode::Up, 1);
+        let mut transport = Transport::new(SAMPLE_RATE);
+        keys(&mut arpeggiator, &mut transport, STEP_SAMPLES / 2);
+
+        // Pressed half way through a step, the chord waits for the next one.
+        arpeggiator.set_held_keys([4].iter());
+        let keys_played = keys(&mut arpeggiator, &mut transport, STEP_SAMPLES);
+        assert_eq!(keys_played[STEP_SAMPLES / 2 - 1], None);
+        assert_eq!(keys_played[STEP_SAMPLES / 2], Some(4));
+
+        // The transport position takes over when it plays.
+        transport.play();
+        assert_eq!(keys(&mut arpeggiator, &mut transport, 1)[0], Some(4));
+        keys(&mut arpeggiator, &mut transport, STEP_SAMPLES / 2);
+        assert!(arpeggiator
+            .next_sample(None, &transport.next_sample().0)
+            .is_none());
+    }
+}
@@ -1,122 +1,774 @@
+use arpeggiator::{Arpeggiator, ArpeggiatorCommand};
 use cpal;
+use drums::{DrumCommand, DrumMachine};
 
-use keys_state::KeysState;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use types::{KeyAction, SignalProcessorFunction};
+use keys_state::{KeysState, NotePriority};
+use master::{MasterBus, MasterMeter};
+use output::{ClipMode, Dither, OutputSample, OutputStage};
+use portamento::{Glide, GlideMode};
+use sequencer::{Playhead, Sequencer, SequencerCommand};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use transport::{ProcessContext, TapTempo, Transport, TransportCommand};
+use types::{
+    ContextPostProcessorFunction, ContextProcessorFunction, KeyAction, PostProcessorFunction,
+    Signal, SignalProcessorFunction, StereoSignalProcessorFunction,
+};
+
+/// Time it takes to fade the output to silence when the engine is stopped.
+const FADE_OUT_TIME: f64 = 0.02;
+/// How long to wait for the audio thread to hand back the processors when stopping. The
+/// device may have stopped calling back, in which case `stop` reports them lost.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Glide of the engine until one is set, which jumps straight to each note.
+const NO_GLIDE: GlideMode = GlideMode::ConstantTime(0.0);
+/// Whether `cpal::EventLoop::run` calls back on the thread running it, so that the audio
+/// thread can unwind out of the loop and be joined once stopped. CoreAudio and Emscripten
+/// call back from the driver, where unwinding would cross FFI, so there the thread stays
+/// parked in a loop without streams.
+const EVENT_LOOP_EXITS: bool = cfg!(not(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "emscripten"
+)));
+
+/// Settings used when opening the output device. Fields left as `None` fall back to the
+/// device defaults.
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    pub device_name: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub clip_mode: ClipMode,
+    pub dither: Dither,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            device_name: None,
+            sample_rate: None,
+            channels: None,
+            clip_mode: ClipMode::Hard,
+            dither: Dither::Tpdf,
+        }
+    }
+}
+
+/// Why the output could not be opened or the engine did not stop cleanly.
+#[derive(Debug)]
+pub enum EngineError {
+    NoOutputDevice,
+    DeviceNotFound(String),
+    UnsupportedFormat {
+        channels: u16,
+        sample_rate: u32,
+    },
+    DefaultFormat(cpal::DefaultFormatError),
+    SupportedFormats(cpal::FormatsEnumerationError),
+    Stream(cpal::CreationError),
+    /// The device stopped calling back before the processors were handed back.
+    ShutdownTimeout,
+    /// A processor panicked, taking the audio thread and itself down.
+    AudioThreadPanicked,
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::NoOutputDevice => write!(f, "No default output device"),
+            EngineError::DeviceNotFound(name) => write!(f, "Output device {:?} not found", name),
+            EngineError::UnsupportedFormat {
+                channels,
+                sample_rate,
+            } => write!(
+                f,
+                "Output format with {} channels at {} Hz is not supported",
+                channels, sample_rate
+            ),
+            EngineError::DefaultFormat(error) => write!(f, "Default output format: {}", error),
+            EngineError::SupportedFormats(error) => write!(f, "Output formats: {}", error),
+            EngineError::Stream(error) => write!(f, "Failed to open output stream: {}", error),
+            EngineError::ShutdownTimeout => {
+                write!(f, "The audio thread did not stop, its processors are lost")
+            }
+            EngineError::AudioThreadPanicked => write!(f, "The audio thread panicked"),
+        }
+    }
+}
+
+impl Error for EngineError {}
 
 pub struct EngineController {
     key_action_sender: Sender<KeyAction>,
-    signal_processor_change_sender: Sender<SignalProcessorFunction>,
-    pub sample_rate: f64
+    signal_processor_change_sender: Sender<ContextProcessorFunction>,
+    post_processor_change_sender: Sender<ContextPostProcessorFunction>,
+    master_volume_sender: Sender<Signal>,
+    note_priority_sender: Sender<NotePriority>,
+    glide_sender: Sender<(GlideMode, bool)>,
+    sequencer_command_sender: Sender<SequencerCommand>,
+    sequencer_return_receiver: Receiver<SequencerCommand>,
+    arpeggiator_command_sender: Sender<ArpeggiatorCommand>,
+    transport_command_sender: Sender<TransportCommand>,
+    drum_machine_sender: Sender<DrumMachine>,
+    drum_machine_return_receiver: Receiver<DrumMachine>,
+    drum_command_sender: Sender<DrumCommand>,
+    master_meter_receiver: Receiver<MasterMeter>,
+    master_meter: MasterMeter,
+    // Last values sent, carried over by `restart`.
+    master_volume: Signal,
+    note_priority: NotePriority,
+    glide: (GlideMode, bool),
+    playhead_receiver: Receiver<Playhead>,
+    playhead: Playhead,
+    transport_receiver: Receiver<ProcessContext>,
+    transport: ProcessContext,
+    tap_tempo: TapTempo,
+    shutdown_sender: Sender<()>,
+    processors_receiver: Receiver<Processors>,
+    /// Processors handed back by the last stop, carried over by `restart`.
+    stopped_processors: Option<Processors>,
+    audio_thread: Option<JoinHandle<()>>,
+    running: bool,
+    pub sample_rate: f64,
 }
 
 impl EngineController {
-    pub fn start() -> Self {
+    pub fn start() -> Result<Self, EngineError> {
+        Self::start_with_config(EngineConfig::default())
+    }
+
+    pub fn start_with_config(config: EngineConfig) -> Result<Self, EngineError> {
         let (key_action_sender, key_action_receiver) = channel::<KeyAction>();
         let (signal_processor_change_sender, signal_processor_change_receiver) =
-            channel::<SignalProcessorFunction>();
+            channel::<ContextProcessorFunction>();
+        let (post_processor_change_sender, post_processor_change_receiver) =
+            channel::<ContextPostProcessorFunction>();
+        let (master_volume_sender, master_volume_receiver) = channel::<Signal>();
+        let (note_priority_sender, note_priority_receiver) = channel::<NotePriority>();
+        let (glide_sender, glide_receiver) = channel::<(GlideMode, bool)>();
+        let (sequencer_command_sender, sequencer_command_receiver) = channel::<SequencerCommand>();
+        let (sequencer_return_sender, sequencer_return_receiver) = channel::<SequencerCommand>();
+        let (arpeggiator_command_sender, arpeggiator_command_receiver) =
+            channel::<ArpeggiatorCommand>();
+        let (transport_command_sender, transport_command_receiver) = channel::<TransportCommand>();
+        let (drum_machine_sender, drum_machine_receiver) = channel::<DrumMachine>();
+        let (drum_machine_return_sender, drum_machine_return_receiver) = channel::<DrumMachine>();
+        let (drum_command_sender, drum_command_receiver) = channel::<DrumCommand>();
+        // Only the latest levels matter, so the audio thread keeps merging them until the UI
+        // has taken the last ones.
+        let (master_meter_sender, master_meter_receiver) = sync_channel::<MasterMeter>(1);
+        // Like the levels, only the latest playhead and transport state matter.
+        let (playhead_sender, playhead_receiver) = sync_channel::<Playhead>(1);
+        let (transport_sender, transport_receiver) = sync_channel::<ProcessContext>(1);
+        let (shutdown_sender, shutdown_receiver) = channel::<()>();
+        let (processors_sender, processors_receiver) = channel::<Processors>();
 
-        let sample_rate = start_audio_thread(key_action_receiver, signal_processor_change_receiver);
+        let (sample_rate, audio_thread) = start_audio_thread(
+            &config,
+            AudioThreadChannels {
+                key_action_receiver,
+                signal_processor_change_receiver,
+                post_processor_change_receiver,
+                master_volume_receiver,
+                note_priority_receiver,
+                glide_receiver,
+                sequencer_command_receiver,
+                sequencer_return_sender,
+                arpeggiator_command_receiver,
+                transport_command_receiver,
+                drum_machine_receiver,
+                drum_machine_return_sender,
+                drum_command_receiver,
+                master_meter_sender,
+                playhead_sender,
+                transport_sender,
+                shutdown_receiver,
+                processors_sender,
+            },
+        )?;
 
-        Self {
+        Ok(Self {
             key_action_sender,
             signal_processor_change_sender,
-            sample_rate
+            post_processor_change_sender,
+            master_volume_sender,
+            note_priority_sender,
+            glide_sender,
+            sequencer_command_sender,
+            sequencer_return_receiver,
+            arpeggiator_command_sender,
+            transport_command_sender,
+            drum_machine_sender,
+            drum_machine_return_receiver,
+            drum_command_sender,
+            master_meter_receiver,
+            master_meter: MasterMeter::default(),
+            master_volume: 1.0,
+            note_priority: NotePriority::Last,
+            glide: (NO_GLIDE, false),
+            playhead_receiver,
+            playhead: Playhead::default(),
+            transport_receiver,
+            transport: ProcessContext::default(),
+            tap_tempo: TapTempo::new(),
+            shutdown_sender,
+            processors_receiver,
+            stopped_processors: None,
+            audio_thread: Some(audio_thread),
+            running: true,
+            sample_rate,
+        })
+    }
+
+    /// Sets a mono processor, played on both channels.
+    pub fn set_processor_function(&self, mut new_func: SignalProcessorFunction) {
+        self.set_stereo_processor_function(Box::new(move |key| {
+            let signal = new_func(key);
+            (signal, signal)
+        }));
+    }
+
+    pub fn set_stereo_processor_function(&self, mut new_func: StereoSignalProcessorFunction) {
+        self.set_context_processor_function(Box::new(move |key, _| new_func(key)));
+    }
+
+    /// Sets a processor that follows the transport, through the context of each sample.
+    pub fn set_context_processor_function(&self, new_func: ContextProcessorFunction) {
+        if self.is_running() {
+            self.signal_processor_change_sender.send(new_func).unwrap();
         }
     }
 
-    pub fn set_processor_function(
-        &self,
-        new_func: Box<FnMut(Option<i32>) -> f64 + Send>,
-    ) {
-        self.signal_processor_change_sender.send(new_func).unwrap();
+    /// Replaces the effects run on the processor output.
+    pub fn set_post_processor_function(&self, mut new_func: PostProcessorFunction) {
+        self.set_context_post_processor_function(Box::new(move |signal, _| new_func(signal)));
+    }
+
+    /// Replaces the effects run on the processor output with ones that follow the transport.
+    pub fn set_context_post_processor_function(&self, new_func: ContextPostProcessorFunction) {
+        if self.is_running() {
+            self.post_processor_change_sender.send(new_func).unwrap();
+        }
     }
 
     pub fn key_action(&mut self, action: KeyAction) {
-        self.key_action_sender.send(action).unwrap();
+        if self.is_running() {
+            self.key_action_sender.send(action).unwrap();
+        }
+    }
+
+    /// Sets the gain applied to the processor output before the limiter. The change is
+    /// smoothed on the audio thread.
+    pub fn set_master_volume(&mut self, volume: Signal) {
+        self.master_volume = volume;
+        if self.is_running() {
+            self.master_volume_sender.send(volume).unwrap();
+        }
+    }
+
+    /// Chooses which held key is handed to the processor function when several are down.
+    pub fn set_note_priority(&mut self, priority: NotePriority) {
+        self.note_priority = priority;
+        if self.is_running() {
+            self.note_priority_sender.send(priority).unwrap();
+        }
+    }
+
+    /// Glides the pitch handed to context processors in `ProcessContext::pitch` between
+    /// notes, only between overlapping ones when `legato_only` is set. A constant time of
+    /// zero turns it off.
+    pub fn set_glide(&mut self, mode: GlideMode, legato_only: bool) {
+        self.glide = (mode, legato_only);
+        if self.is_running() {
+            self.glide_sender.send(self.glide).unwrap();
+        }
+    }
+
+    /// Controls the step sequencer on the audio thread. It plays while the transport does,
+    /// and its notes take the place of the held keys, which still sound during rests.
+    pub fn sequencer_command(&self, command: SequencerCommand) {
+        // Patterns replaced by earlier commands come back to be dropped here.
+        self.sequencer_return_receiver.try_iter().for_each(drop);
+        if self.is_running() {
+            self.sequencer_command_sender.send(command).unwrap();
+        }
+    }
+
+    /// Controls the transport, the clock the sequencer and arpeggiator step along. Play starts
+    /// the sequencer from the start of its chain.
+    pub fn transport_command(&self, command: TransportCommand) {
+        if self.is_running() {
+            self.transport_command_sender.send(command).unwrap();
+        }
+    }
+
+    /// Sets the tempo from the time between calls, once tapped twice. Returns the new tempo.
+    pub fn tap_tempo(&mut self) -> Option<f64> {
+        let bpm = self.tap_tempo.tap(Instant::now());
+        if let Some(bpm) = bpm {
+            self.transport_command(TransportCommand::SetTempo(bpm));
+        }
+        bpm
+    }
+
+    /// Returns the transport state at the end of the last played buffer.
+    pub fn transport(&mut self) -> ProcessContext {
+        if let Some(context) = self.transport_receiver.try_iter().last() {
+            self.transport = context;
+        }
+        self.transport
+    }
+
+    /// Controls the arpeggiator, which plays the held keys one at a time when enabled.
+    pub fn arpeggiator_command(&self, command: ArpeggiatorCommand) {
+        if self.is_running() {
+            self.arpeggiator_command_sender.send(command).unwrap();
+        }
+    }
+
+    /// Plays `machine` along with the transport, mixed into the processor output. A machine
+    /// set before is replaced.
+    pub fn set_drum_machine(&self, machine: DrumMachine) {
+        // Machines replaced earlier come back to be dropped here.
+        self.drum_machine_return_receiver.try_iter().for_each(drop);
+        if self.is_running() {
+            self.drum_machine_sender.send(machine).unwrap();
+        }
+    }
+
+    /// Changes the steps or lanes of the drum machine set with `set_drum_machine`.
+    pub fn drum_command(&self, command: DrumCommand) {
+        if self.is_running() {
+            self.drum_command_sender.send(command).unwrap();
+        }
+    }
+
+    /// Returns where the sequencer was at the end of the last played buffer.
+    pub fn sequencer_playhead(&mut self) -> Playhead {
+        if let Some(playhead) = self.playhead_receiver.try_iter().last() {
+            self.playhead = playhead;
+        }
+        self.playhead
+    }
+
+    /// Returns the master levels measured since the last call. If no new buffers have been
+    /// played, the previous measurement is returned.
+    pub fn master_meter(&mut self) -> MasterMeter {
+        if let Ok(meter) = self.master_meter_receiver.try_recv() {
+            self.master_meter = meter;
+        }
+        self.master_meter
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Fades the output to silence, destroys the output stream and joins the audio thread,
+    /// waiting at most `SHUTDOWN_TIMEOUT` for it to hand back the processors. Does nothing if
+    /// the engine is already stopped.
+    pub fn stop(&mut self) -> Result<(), EngineError> {
+        if !self.running {
+            return Ok(());
+        }
+        self.running = false;
+
+        // The audio thread may already be gone if the processor panicked.
+        let _ = self.shutdown_sender.send(());
+
+        match self.processors_receiver.recv_timeout(SHUTDOWN_TIMEOUT) {
+            Ok(processors) => {
+                self.stopped_processors = Some(processors);
+                self.join_audio_thread();
+                Ok(())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                self.join_audio_thread();
+                Err(EngineError::AudioThreadPanicked)
+            }
+            // The thread is stuck waiting for the device and cannot be joined, so it is left.
+            Err(RecvTimeoutError::Timeout) => Err(EngineError::ShutdownTimeout),
+        }
+    }
+
+    /// Joins the audio thread, which leaves the event loop right after handing back the
+    /// processors. Where the loop cannot be left, the thread is detached.
+    fn join_audio_thread(&mut self) {
+        if let Some(audio_thread) = self.audio_thread.take() {
+            if EVENT_LOOP_EXITS {
+                // A panic has already been reported by the thread.
+                let _ = audio_thread.join();
+            }
+        }
+    }
+
+    /// Stops the engine and starts it again with `config`. The current processor and post
+    /// processor functions, drum machine, master volume and note priority are carried over
+    /// to the new audio thread. Processors and drums that captured the old `sample_rate`
+    /// should be replaced if the rate changed. The transport, sequencer and arpeggiator
+    /// start afresh. If the old engine does not stop cleanly its error is returned, and a
+    /// further restart goes ahead without the lost processors. If the output cannot be opened
+    /// the engine stays stopped, and the processors are kept for the next restart.
+    pub fn restart(&mut self, config: EngineConfig) -> Result<(), EngineError> {
+        self.stop()?;
+        let mut engine = Self::start_with_config(config)?;
+        engine.set_master_volume(self.master_volume);
+        engine.set_note_priority(self.note_priority);
+        engine.set_glide(self.glide.0, self.glide.1);
+
+        if let Some(processors) = self.stopped_processors.take() {
+            engine.set_context_processor_function(processors.processor);
+            engine.set_context_post_processor_function(processors.post_processor);
+            if let Some(machine) = processors.drum_machine {
+                engine.set_drum_machine(machine);
+            }
+        }
+
+        *self = engine;
+        Ok(())
     }
 }
 
-fn start_audio_thread(
+impl Drop for EngineController {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// The functions and drum machine owned by the audio thread, handed back when it stops.
+struct Processors {
+    processor: ContextProcessorFunction,
+    post_processor: ContextPostProcessorFunction,
+    drum_machine: Option<DrumMachine>,
+}
+
+/// The audio thread's ends of the channels to the `EngineController`.
+struct AudioThreadChannels {
     key_action_receiver: Receiver<KeyAction>,
-    signal_processor_change_receiver: Receiver<SignalProcessorFunction>,
-) -> f64 {
-    let device = cpal::default_output_device().expect("Failed to get default output device");
-    let format = device
+    signal_processor_change_receiver: Receiver<ContextProcessorFunction>,
+    post_processor_change_receiver: Receiver<ContextPostProcessorFunction>,
+    master_volume_receiver: Receiver<Signal>,
+    note_priority_receiver: Receiver<NotePriority>,
+    glide_receiver: Receiver<(GlideMode, bool)>,
+    sequencer_command_receiver: Receiver<SequencerCommand>,
+    sequencer_return_sender: Sender<SequencerCommand>,
+    arpeggiator_command_receiver: Receiver<ArpeggiatorCommand>,
+    transport_command_receiver: Receiver<TransportCommand>,
+    drum_machine_receiver: Receiver<DrumMachine>,
+    drum_machine_return_sender: Sender<DrumMachine>,
+    drum_command_receiver: Receiver<DrumCommand>,
+    master_meter_sender: SyncSender<MasterMeter>,
+    playhead_sender: SyncSender<Playhead>,
+    transport_sender: SyncSender<ProcessContext>,
+    shutdown_receiver: Receiver<()>,
+    processors_sender: Sender<Processors>,
+}
+
+enum StreamState {
+    Running,
+    FadingOut,
+    Silent,
+    /// The stream is destroyed and the processors handed back.
+    Stopped,
+}
+
+/// Unwinds the audio thread out of `cpal::EventLoop::run`, which never returns, once stopped.
+struct ExitEventLoop;
+
+/// Fills the buffer with silence, for the callbacks after the output has faded out.
+fn write_silence(data: cpal::StreamData) {
+    fn fill<T: OutputSample>(buffer: &mut [T]) {
+        for sample in buffer.iter_mut() {
+            *sample = T::from_signal(0.0, 0.0);
+        }
+    }
+    match data {
+        cpal::StreamData::Output {
+            buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer),
+        } => fill(&mut buffer),
+        cpal::StreamData::Output {
+            buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer),
+        } => fill(&mut buffer),
+        cpal::StreamData::Output {
+            buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer),
+        } => fill(&mut buffer),
+        _ => (),
+    }
+}
+
+fn find_output_format(
+    device: &cpal::Device,
+    config: &EngineConfig,
+) -> Result<cpal::Format, EngineError> {
+    let mut format = device
         .default_output_format()
-        .expect("Failed to get default output format");
+        .map_err(EngineError::DefaultFormat)?;
+
+    if config.sample_rate.is_none() && config.channels.is_none() {
+        return Ok(format);
+    }
+
+    let sample_rate = config.sample_rate.unwrap_or(format.sample_rate.0);
+    let channels = config.channels.unwrap_or(format.channels);
+
+    let supported = device
+        .supported_output_formats()
+        .map_err(EngineError::SupportedFormats)?
+        .find(|f| {
+            f.channels == channels
+                && f.min_sample_rate.0 <= sample_rate
+                && sample_rate <= f.max_sample_rate.0
+        })
+        .ok_or(EngineError::UnsupportedFormat {
+            channels,
+            sample_rate,
+        })?;
+
+    format.channels = supported.channels;
+    format.sample_rate = cpal::SampleRate(sample_rate);
+    format.data_type = supported.data_type;
+
+    Ok(format)
+}
+
+fn find_output_device(config: &EngineConfig) -> Result<cpal::Device, EngineError> {
+    match config.device_name {
+        Some(ref name) => cpal::output_devices()
+            .find(|d| d.name() == *name)
+            .ok_or_else(|| EngineError::DeviceNotFound(name.clone())),
+        None => cpal::default_output_device().ok_or(EngineError::NoOutputDevice),
+    }
+}
+
+/// Opens the output and starts playing it on a new thread, returning the sample rate and the
+/// thread.
+///
+/// `cpal::EventLoop::run` never returns. Once stopped, the callback destroys the stream,
+/// hands the processors back and, where `EVENT_LOOP_EXITS`, unwinds out of the loop so that
+/// the thread ends and drops the event loop.
+fn start_audio_thread(
+    config: &EngineConfig,
+    channels: AudioThreadChannels,
+) -> Result<(f64, JoinHandle<()>), EngineError> {
+    let device = find_output_device(config)?;
+    let format = find_output_format(&device, config)?;
+
+    let event_loop = cpal::EventLoop::new();
+    let stream_id = event_loop
+        .build_output_stream(&device, &format)
+        .map_err(EngineError::Stream)?;
 
     let sample_rate = f64::from(format.sample_rate.0);
+    let mut output_stage = OutputStage::new(config.clip_mode, config.dither);
+
+    let audio_thread = thread::spawn(move || {
+        let AudioThreadChannels {
+            key_action_receiver,
+            signal_processor_change_receiver,
+            post_processor_change_receiver,
+            master_volume_receiver,
+            note_priority_receiver,
+            glide_receiver,
+            sequencer_command_receiver,
+            sequencer_return_sender,
+            arpeggiator_command_receiver,
+            transport_command_receiver,
+            drum_machine_receiver,
+            drum_machine_return_sender,
+            drum_command_receiver,
+            master_meter_sender,
+            playhead_sender,
+            transport_sender,
+            shutdown_receiver,
+            processors_sender,
+        } = channels;
 
-    std::thread::spawn(move || {
         let mut key_action = None;
         let mut keys_state = KeysState::new();
-        let mut audio_processor_function: Box<FnMut(Option<i32>) -> f64 + Send> =
-            Box::new(|_| 0.0);
-
-        let event_loop = cpal::EventLoop::new();
-        let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
+        let mut glide = Glide::new(sample_rate);
+        glide.set_mode(NO_GLIDE);
+        let mut audio_processor_function: ContextProcessorFunction = Box::new(|_, _| (0.0, 0.0));
+        let mut post_processor_function: ContextPostProcessorFunction =
+            Box::new(|signal, _| signal);
 
-        let sample_rate = f64::from(format.sample_rate.0);
-        let sample_time = 1.0 / sample_rate;
-        let mut duration = 0.0;
+        let mut master_bus = MasterBus::new(sample_rate);
+        let mut master_meter = MasterMeter::default();
+        let mut sequencer = Sequencer::new();
+        let mut arpeggiator = Arpeggiator::new();
+        let mut transport = Transport::new(sample_rate);
+        let mut drum_machine: Option<DrumMachine> = None;
+        let mut context = ProcessContext::default();
+        let fade_step = 1.0 / (FADE_OUT_TIME * sample_rate);
+        let mut gain = 1.0;
+        let mut state = StreamState::Running;
 
         event_loop.play_stream(stream_id.clone());
 
-        event_loop.run(move |_, data| {
-            for _new_processor in signal_processor_change_receiver.try_iter() {
-                audio_processor_function = _new_processor;
-            }
+        let event_loop = &event_loop;
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            event_loop.run(move |_, data| {
+                match state {
+                    StreamState::Stopped => {
+                        write_silence(data);
+                        return;
+                    }
+                    StreamState::Silent => {
+                        write_silence(data);
+                        event_loop.destroy_stream(stream_id.clone());
+                        let processors = Processors {
+                            processor: mem::replace(
+                                &mut audio_processor_function,
+                                Box::new(|_, _| (0.0, 0.0)),
+                            ),
+                            post_processor: mem::replace(
+                                &mut post_processor_function,
+                                Box::new(|signal, _| signal),
+                            ),
+                            drum_machine: drum_machine.take(),
+                        };
+                        let _ = processors_sender.send(processors);
+                        state = StreamState::Stopped;
+                        if EVENT_LOOP_EXITS {
+                            panic::resume_unwind(Box::new(ExitEventLoop));
+                        }
+                        return;
+                    }
+                    _ => (),
+                }
 
-            for _key in key_action_receiver.try_iter() {
-                key_action = keys_state.key_down(_key);
-            }
+                if shutdown_receiver.try_recv().is_ok() {
+                    state = StreamState::FadingOut;
+                }
 
-            match data {
-                cpal::StreamData::Output {
-                    buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer),
-                } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let value = ((audio_processor_function(key_action)
-                            * 0.5
-                            + 0.5)
-                            * f64::from(std::u16::MAX)) as u16;
-                        for out in sample.iter_mut() {
-                            *out = value;
-                        }
-                        duration += sample_time;
+                for _new_processor in signal_processor_change_receiver.try_iter() {
+                    audio_processor_function = _new_processor;
+                }
+
+                for new_post_processor in post_processor_change_receiver.try_iter() {
+                    post_processor_function = new_post_processor;
+                }
+
+                for (mode, legato_only) in glide_receiver.try_iter() {
+                    glide.set_mode(mode);
+                    glide.set_legato_only(legato_only);
+                }
+
+                for priority in note_priority_receiver.try_iter() {
+                    keys_state.set_priority(priority);
+                    key_action = keys_state.current_key();
+                }
+
+                for _key in key_action_receiver.try_iter() {
+                    key_action = keys_state.key_down(_key);
+                    arpeggiator.set_held_keys(keys_state.held_keys());
+                }
+
+                for volume in master_volume_receiver.try_iter() {
+                    master_bus.set_volume(volume);
+                }
+
+                for command in sequencer_command_receiver.try_iter() {
+                    if let Some(replaced) = sequencer.apply(command) {
+                        let _ = sequencer_return_sender.send(replaced);
                     }
                 }
-                cpal::StreamData::Output {
-                    buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer),
-                } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let value = ((audio_processor_function(key_action)
-                            * 0.5
-                            + 0.5)
-                            * f64::from(std::i16::MAX)) as i16;
-                        for out in sample.iter_mut() {
-                            *out = value;
-                        }
-                        duration += sample_time;
+
+                for command in arpeggiator_command_receiver.try_iter() {
+                    arpeggiator.apply(command);
+                }
+
+                for command in transport_command_receiver.try_iter() {
+                    transport.apply(command);
+                }
+
+                for machine in drum_machine_receiver.try_iter() {
+                    if let Some(replaced) = drum_machine.replace(machine) {
+                        let _ = drum_machine_return_sender.send(replaced);
+                    }
+                }
+
+                for command in drum_command_receiver.try_iter() {
+                    if let Some(machine) = drum_machine.as_mut() {
+                        machine.apply(command);
                     }
                 }
-                cpal::StreamData::Output {
-                    buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer),
-                } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let value =
-                            audio_processor_function(key_action) as f32;
-                        for out in sample.iter_mut() {
-                            *out = value;
+
+                let mut next_value = || {
+                    if let StreamState::FadingOut = state {
+                        gain -= fade_step;
+                        if gain <= 0.0 {
+                            gain = 0.0;
+                            state = StreamState::Silent;
                         }
-                        duration += sample_time;
                     }
+                    let (sample_context, click) = transport.next_sample();
+                    context = sample_context;
+                    let played = arpeggiator.next_sample(key_action, &context);
+                    let sequenced = sequencer.next_sample(&context);
+                    if sequenced.is_some() {
+                        context.velocity = sequencer.velocity();
+                    }
+                    let key = sequenced.or(played);
+                    context.pitch = glide.next_pitch(key);
+                    let (left, right) = audio_processor_function(key, &context);
+                    let drums = drum_machine
+                        .as_mut()
+                        .map_or(0.0, |machine| machine.next_sample(&context));
+                    let (left, right) =
+                        post_processor_function((left + drums, right + drums), &context);
+                    let (left, right) = master_bus.process((left + click, right + click));
+                    (left * gain, right * gain)
+                };
+
+                match data {
+                    cpal::StreamData::Output {
+                        buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer),
+                    } => {
+                        for frame in buffer.chunks_mut(format.channels as usize) {
+                            output_stage.write_stereo_frame(frame, next_value());
+                        }
+                    }
+                    cpal::StreamData::Output {
+                        buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer),
+                    } => {
+                        for frame in buffer.chunks_mut(format.channels as usize) {
+                            output_stage.write_stereo_frame(frame, next_value());
+                        }
+                    }
+                    cpal::StreamData::Output {
+                        buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer),
+                    } => {
+                        for frame in buffer.chunks_mut(format.channels as usize) {
+                            output_stage.write_stereo_frame(frame, next_value());
+                        }
+                    }
+                    _ => (),
                 }
-                _ => (),
+
+                // Levels the UI has not taken yet are merged into the next ones. The UI may have
+                // gone away, in which case nobody is interested in them.
+                master_meter = master_meter.merge(master_bus.take_meter());
+                if master_meter_sender.try_send(master_meter).is_ok() {
+                    master_meter = MasterMeter::default();
+                }
+                let _ = playhead_sender.try_send(sequencer.playhead());
+                let _ = transport_sender.try_send(context);
+            })
+        }));
+
+        // Any other unwind is a panic of the processors, passed on to the join.
+        if let Err(payload) = run {
+            if !payload.is::<ExitEventLoop>() {
+                panic::resume_unwind(payload);
             }
-        });
+        }
     });
 
-    sample_rate
+    Ok((sample_rate, audio_thread))
 }
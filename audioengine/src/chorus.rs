@@ -0,0 +1,322 @@
+use delay::{DelayLine, ModulatedDelay};
+use lfo::LfoRate;
+use transport::ProcessContext;
+use types::{Signal, StereoSignal};
+
+pub const MAX_CHORUS_VOICES: usize = 8;
+const CHORUS_MAX_TIME: f64 = 0.05;
+const FLANGER_MAX_TIME: f64 = 0.02;
+/// Keeps the flanger from ringing forever at extreme feedback settings.
+const MAX_FEEDBACK: Signal = 0.95;
+
+/// Multi-voice chorus. The input is summed to mono and each voice is a copy delayed by its
+/// own phase of the sweep, spread across the stereo field.
+pub struct Chorus {
+    voices: Vec<ModulatedDelay>,
+    voice_count: usize,
+    spread: Signal,
+    mix: Signal,
+}
+
+impl Chorus {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut chorus = Self {
+            voices: (0..MAX_CHORUS_VOICES)
+                .map(|_| ModulatedDelay::new(CHORUS_MAX_TIME, sample_rate))
+                .collect(),
+            voice_count: 0,
+            spread: 1.0,
+            mix: 0.5,
+        };
+        chorus.set_voices(3);
+        chorus.set_rate(LfoRate::Hertz(0.8));
+        chorus.set_delay(0.015);
+        chorus.set_depth(0.003);
+        chorus
+    }
+
+    /// Number of voices, from 1 to `MAX_CHORUS_VOICES`. Restarts the sweeps so the voices are
+    /// evenly spaced in phase.
+    pub fn set_voices(&mut self, count: usize) {
+        self.voice_count = count.clamp(1, MAX_CHORUS_VOICES);
+        let count = self.voice_count as f64;
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            let lfo = voice.lfo_mut();
+            lfo.set_start_phase(index as f64 / count);
+            lfo.retrigger();
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: LfoRate) {
+        for voice in self.voices.iter_mut() {
+            voice.lfo_mut().set_rate(rate);
+        }
+    }
+
+    /// Tempo in beats per minute, used by `LfoRate::Beats`.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        for voice in self.voices.iter_mut() {
+            voice.lfo_mut().set_tempo(bpm);
+        }
+    }
+
+    /// Keeps the tempo at that of the transport, for use in a context post processor.
+    pub fn follow_tempo(&mut self, context: &ProcessContext) {
+        for voice in self.voices.iter_mut() {
+            voice.lfo_mut().follow_tempo(context);
+        }
+    }
+
+    /// Centre delay of the voices, in seconds.
+    pub fn set_delay(&mut self, seconds: f64) {
+        for voice in self.voices.iter_mut() {
+            voice.set_delay(seconds);
+        }
+    }
+
+    /// Sweep depth in seconds to either side of the centre delay.
+    pub fn set_depth(&mut self, seconds: f64) {
+        for voice in self.voices.iter_mut() {
+            voice.set_depth(seconds);
+        }
+    }
+
+    /// Stereo spread of the voices in [0.0, 1.0], 0.0 placing them all in the centre.
+    pub fn set_spread(&mut self, spread: Signal) {
+        self.spread = spread.clamp(0.0, 1.0);
+    }
+
+    /// Balance between the dry input at 0.0 and only the voices at 1.0.
+    pub fn set_mix(&mut self, mix: Signal) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+
+    pub fn clear(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.clear();
+        }
+    }
+
+    pub fn process(&mut self, (left, right): StereoSignal) -> StereoSignal {
+        let input = (left + right) * 0.5;
+        let count = self.voice_count;
+        let (mut wet_left, mut wet_right) = (0.0, 0.0);
+
+        for (index, voice) in self.voices.iter_mut().enumerate().take(count) {
+            let voice = voice.process(input);
+            let pan = if count > 1 {
+                (2.0 * index as Signal / (count - 1) as Signal - 1.0) * self.spread
+            } else {
+                0.0
+            };
+            wet_left += voice * (1.0 - pan);
+            wet_right += voice * (1.0 + pan);
+        }
+
+        let wet = self.mix / count as Signal;
+        let dry = 1.0 - self.mix;
+        (left * dry + wet_left * wet, right * dry + wet_right * wet)
+    }
+}
+
+/// Flanger with feedback. In through-zero mode the dry path is delayed by the centre delay
+/// too, so at full depth the swept copy passes through and past the dry signal.
+pub struct Flanger {
+    sample_rate: f64,
+    delays: [ModulatedDelay; 2],
+    references: [DelayLine; 2],
+    delay: f64,
+    depth: f64,
+    feedback: Signal,
+    through_zero: bool,
+    mix: Signal,
+}
+
+impl Flanger {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut flanger = Self {
+            sample_rate,
+            delays: [
+                ModulatedDelay::new(FLANGER_MAX_TIME, sample_rate),
+                ModulatedDelay::new(FLANGER_MAX_TIME, sample_rate),
+            ],
+            references: [
+                DelayLine::new((FLANGER_MAX_TIME * sample_rate).ceil() as usize),
+                DelayLine::new((FLANGER_MAX_TIME * sample_rate).ceil() as usize),
+            ],
+            delay: 0.003,
+            depth: 0.9,
+            feedback: 0.5,
+            through_zero: false,
+            mix: 0.5,
+        };
+        flanger.set_rate(LfoRate::Hertz(0.2));
+        flanger.update_sweep();
+        flanger.set_stereo_phase(0.25);
+        flanger
+    }
+
+    pub fn set_rate(&mut self, rate: LfoRate) {
+        for delay in self.delays.iter_mut() {
+            delay.lfo_mut().set_rate(rate);
+        }
+    }
+
+    /// Tempo in beats per minute, used by `LfoRate::Beats`.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        for delay in self.delays.iter_mut() {
+            delay.lfo_mut().set_tempo(bpm);
+        }
+    }
+
+    /// Keeps the tempo at that of the transport, for use in a context post processor.
+    pub fn follow_tempo(&mut self, context: &ProcessContext) {
+        for delay in self.delays.iter_mut() {
+            delay.lfo_mut().follow_tempo(context);
+        }
+    }
+
+    /// Centre of the sweep in seconds, at most half of 20 ms.
+    pub fn set_delay(&mut self, seconds: f64) {
+        self.delay = seconds.clamp(0.0, FLANGER_MAX_TIME * 0.5);
+        self.update_sweep();
+    }
+
+    /// Share of the centre delay swept to either side, in [0.0, 1.0].
+    pub fn set_depth(&mut self, depth: f64) {
+        self.depth = depth.clamp(0.0, 1.0);
+        self.update_sweep();
+    }
+
+    /// Share of the flanged signal fed back, in [-0.95, 0.95]. Negative feedback gives a
+    /// hollower sound.
+    pub fn set_feedback(&mut self, feedback: Signal) {
+        self.feedback = feedback.clamp(-MAX_FEEDBACK, MAX_FEEDBACK);
+    }
+
+    pub fn set_through_zero(&mut self, through_zero: bool) {
+        self.through_zero = through_zero;
+    }
+
+    /// Offset, in cycles, of the right channel's sweep from the left. Restarts both sweeps.
+    pub fn set_stereo_phase(&mut self, phase: f64) {
+        for (index, delay) in self.delays.iter_mut().enumerate() {
+            let lfo = delay.lfo_mut();
+            lfo.set_start_phase(phase * index as f64);
+            lfo.retrigger();
+        }
+    }
+
+    /// Balance between the dry input at 0.0 and only the swept copy at 1.0. The notches are
+    /// deepest at 0.5.
+    pub fn set_mix(&mut self, mix: Signal) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+
+    pub fn clear(&mut self) {
+        for delay in self.delays.iter_mut() {
+            delay.clear();
+        }
+        for reference in self.references.iter_mut() {
+            reference.clear();
+        }
+    }
+
+    pub fn process(&mut self, (left, right): StereoSignal) -> StereoSignal {
+        (
+            self.process_channel(0, left),
+            self.process_channel(1, right),
+        )
+    }
+
+    fn process_channel(&mut self, channel: usize, input: Signal) -> Signal {
+        let wet = self.delays[channel].read();
+        self.delays[channel].write(input + wet * self.feedback);
+
+        let dry = if self.through_zero {
+            let reference = &mut self.references[channel];
+            let delayed = reference.read(self.delay * self.sample_rate);
+            reference.write(input);
+            delayed
+        } else {
+            input
+        };
+
+        dry * (1.0 - self.mix) + wet * self.mix
+    }
+
+    fn update_sweep(&mut self) {
+        for delay in self.delays.iter_mut() {
+            delay.set_delay(self.delay);
+            delay.set_depth(self.delay * self.depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn through_zero_aligns_dry_with_the_centre_delay() {
+        let mut flanger = Flanger::new(SAMPLE_RATE);
+        flanger.set_delay(0.001);
+        flanger.set_depth(0.0);
+        flanger.set_feedback(0.0);
+        flanger.set_mix(0.5);
+        flanger.set_through_zero(true);
+        let output: Vec<Signal> = (0..100)
+            .map(|n| {
+                flanger
+                    .process(if n == 0 { (1.0, 1.0) } else { (0.0, 0.0) })
+                    .0
+            })
+            .collect();
+        // Both paths delayed by 48 samples, read before the input is written.
+        for (n, &value) in output.iter().enumerate() {
+            let expected = if n == 48 { 1.0 } else { 0.0 };
+            assert!((value - expected).abs() < 1e-9, "{} at {}", value, n);
+        }
+
+        flanger.set_through_zero(false);
+        flanger.clear();
+        let output: Vec<Signal> = (0..100)
+            .map(|n| {
+                flanger
+                    .process(if n == 0 { (1.0, 1.0) } else { (0.0, 0.0) })
+                    .0
+            })
+            .collect();
+        assert!((output[0] - 0.5).abs() < 1e-9);
+        assert!((output[48] - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spread_pans_the_voices() {
+        let mut centred = Chorus::new(SAMPLE_RATE);
+        let mut spread = Chorus::new(SAMPLE_RATE);
+        for chorus in [&mut centred, &mut spread].iter_mut() {
+            chorus.set_voices(2);
+            chorus.set_mix(1.0);
+        }
+        centred.set_spread(0.0);
+        spread.set_spread(1.0);
+
+        let mut random = Random::new(11);
+        let mut difference: Signal = 0.0;
+        for _ in 0..48_000 {
+            let input = random.next_bipolar();
+            let (left, right) = centred.process((input, input));
+            assert!((left - right).abs() < 1e-12);
+
+            let (spread_left, spread_right) = spread.process((input, input));
+            // Panning moves the voices between the channels without changing their sum.
+            assert!((spread_left + spread_right - left - right).abs() < 1e-9);
+            difference = difference.max((spread_left - spread_right).abs());
+        }
+        assert!(difference > 0.1);
+    }
+}
@@ -0,0 +1,182 @@
+/// Note length relative to a whole note, e.g. `Straight(8)` for an eighth note.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteDivision {
+    Straight(u32),
+    /// One and a half times the straight length.
+    Dotted(u32),
+    /// Two thirds of the straight length, three fitting in the space of two.
+    Triplet(u32),
+}
+
+impl NoteDivision {
+    /// Length in beats, where a beat is a quarter note.
+    pub fn beats(self) -> f64 {
+        let straight = |denominator: u32| 4.0 / f64::from(denominator.max(1));
+        match self {
+            NoteDivision::Straight(denominator) => straight(denominator),
+            NoteDivision::Dotted(denominator) => straight(denominator) * 1.5,
+            NoteDivision::Triplet(denominator) => straight(denominator) * 2.0 / 3.0,
+        }
+    }
+
+    pub fn seconds(self, bpm: f64) -> f64 {
+        beats_to_seconds(self.beats(), bpm)
+    }
+}
+
+pub fn beats_to_seconds(beats: f64, bpm: f64) -> f64 {
+    beats * 60.0 / bpm.max(1.0)
+}
+
+/// Counts beats at the engine's sample rate. The position is worked out from the samples
+/// counted since the last tempo change instead of being accumulated, so it does not drift.
+#[derive(Clone, Debug)]
+pub struct TempoClock {
+    sample_rate: f64,
+    bpm: f64,
+    anchor: f64,
+    samples: u64,
+}
+
+impl TempoClock {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            bpm: 120.0,
+            anchor: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Takes effect from the next sample, continuing from the current position.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.anchor = self.position();
+        self.samples = 0;
+        self.bpm = bpm.max(1.0);
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.bpm
+    }
+
+    /// Moves back to beat 0.
+    pub fn reset(&mut self) {
+        self.anchor = 0.0;
+        self.samples = 0;
+    }
+
+    /// Beats since the start, at the next sample.
+    pub fn position(&self) -> f64 {
+        self.anchor + self.samples as f64 * self.bpm / (60.0 * self.sample_rate)
+    }
+
+    /// Returns the position of the current sample and moves on to the next.
+    pub fn next_sample(&mut self) -> f64 {
+        let position = self.position();
+        self.samples += 1;
+        position
+    }
+}
+
+/// Start times of steps along a beat position, with every second step pushed late by a swing.
+/// Steps fall on multiples of the step length counted from beat 0, so parts sharing a
+/// position stay in time with each other.
+#[derive(Clone, Debug, Default)]
+pub struct StepGrid {
+    // Start of the next step without swing, and its number counting from beat 0.
+    grid_beat: f64,
+    index: u64,
+    next_step_beat: f64,
+    last_beat: f64,
+    synced: bool,
+}
+
+impl StepGrid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lines up with the position again on the next call to `follow`.
+    pub fn reset(&mut self) {
+        self.synced = false;
+    }
+
+    /// Number of the next step, counting from beat 0.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Moves to `beat`. After a reset, or when the position went back or skipped over a
+    /// step, the grid starts again from the first step at or after `beat` and true is
+    /// returned.
+    pub fn follow(&mut self, beat: f64, step_beats: f64, swing: f64) -> bool {
+        let jumped = beat < self.last_beat || beat >= self.grid_beat + step_beats;
+        self.last_beat = beat;
+        if self.synced && !jumped {
+            return false;
+        }
+
+        self.synced = true;
+        // Positions a hair after a step count as on it.
+        self.index = (beat / step_beats - 1e-9).ceil().max(0.0) as u64;
+        self.grid_beat = self.index as f64 * step_beats;
+        self.next_step_beat = self.swung(step_beats, swing);
+        true
+    }
+
+    /// Returns the start of a step that is due at the position given to `follow`, moving on
+    /// to the next one. The length and swing take effect from the step after it.
+    pub fn next_step(&mut self, step_beats: f64, swing: f64) -> Option<f64> {
+        if self.last_beat < self.next_step_beat {
+            return None;
+        }
+        let start = self.next_step_beat;
+        self.index += 1;
+        self.grid_beat += step_beats;
+        self.next_step_beat = self.swung(step_beats, swing);
+        Some(start)
+    }
+
+    fn swung(&self, step_beats: f64, swing: f64) -> f64 {
+        if self.index % 2 == 1 {
+            self.grid_beat + swing * step_beats
+        } else {
+            self.grid_beat
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(grid: &mut StepGrid, beats: &[f64], swing: f64) -> Vec<f64> {
+        let mut starts = Vec::new();
+        for &beat in beats {
+            grid.follow(beat, 0.25, swing);
+            while let Some(start) = grid.next_step(0.25, swing) {
+                starts.push(start);
+            }
+        }
+        starts
+    }
+
+    #[test]
+    fn steps_fall_on_the_grid_with_swing() {
+        let beats: Vec<f64> = (0..100).map(|n| n as f64 * 0.01).collect();
+        let starts = steps(&mut StepGrid::new(), &beats, 0.2);
+        assert_eq!(starts, vec![0.0, 0.3, 0.5, 0.8]);
+    }
+
+    #[test]
+    fn jumps_start_from_the_next_step() {
+        let mut grid = StepGrid::new();
+        assert_eq!(steps(&mut grid, &[0.0, 0.1], 0.0), vec![0.0]);
+        // Skipping ahead lines up with the next step instead of catching up.
+        assert_eq!(steps(&mut grid, &[2.1, 2.25], 0.0), vec![2.25]);
+        assert_eq!(grid.index(), 10);
+        // Going back starts over.
+        assert!(grid.follow(0.0, 0.25, 0.0));
+        assert_eq!(grid.next_step(0.25, 0.0), Some(0.0));
+    }
+}
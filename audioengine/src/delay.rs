@@ -0,0 +1,375 @@
+use clock::beats_to_seconds;
+use filter::{FilterMode, StateVariableFilter};
+use lfo::{Lfo, LfoShape};
+use smoothing::SmoothedValue;
+use transport::ProcessContext;
+use types::{Signal, StereoSignal};
+
+/// Time for the delay to settle on a new delay time. Modulating the time bends the pitch of
+/// the repeats like a tape delay instead of clicking.
+const TIME_SMOOTHING_TIME: f64 = 0.05;
+/// Keeps the loop from building up forever, even with the feedback all the way up.
+const MAX_FEEDBACK: f64 = 0.98;
+
+/// Circular buffer that can be read at fractional delays, using cubic Hermite interpolation
+/// between the four surrounding samples.
+pub struct DelayLine {
+    buffer: Vec<Signal>,
+    write_index: usize,
+}
+
+impl DelayLine {
+    /// Creates a line able to delay by up to `max_delay` samples.
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            // Room for the samples on either side of the longest delay.
+            buffer: vec![0.0; max_delay.max(1) + 3],
+            write_index: 0,
+        }
+    }
+
+    /// Longest delay, in samples, that `read` can reach.
+    pub fn max_delay(&self) -> f64 {
+        (self.buffer.len() - 3) as f64
+    }
+
+    pub fn clear(&mut self) {
+        for sample in self.buffer.iter_mut() {
+            *sample = 0.0;
+        }
+    }
+
+    pub fn write(&mut self, input: Signal) {
+        self.buffer[self.write_index] = input;
+        self.write_index = (self.write_index + 1) % self.buffer.len();
+    }
+
+    /// Returns the signal written `delay` samples ago, where a delay of 1.0 is the last
+    /// written sample. The delay is clamped to [1.0, `max_delay`].
+    pub fn read(&self, delay: f64) -> Signal {
+        let delay = delay.clamp(1.0, self.max_delay());
+        let whole = delay as usize;
+        let fraction = delay - whole as f64;
+
+        let current = self.tap(whole);
+        let older = self.tap(whole + 1);
+        let oldest = self.tap(whole + 2);
+        // Below two samples there is nothing newer written yet, so continue the slope.
+        let newer = if whole > 1 {
+            self.tap(whole - 1)
+        } else {
+            2.0 * current - older
+        };
+
+        let c1 = 0.5 * (older - newer);
+        let c2 = newer - 2.5 * current + 2.0 * older - 0.5 * oldest;
+        let c3 = 0.5 * (oldest - newer) + 1.5 * (current - older);
+        ((c3 * fraction + c2) * fraction + c1) * fraction + current
+    }
+
+    fn tap(&self, delay: usize) -> Signal {
+        let length = self.buffer.len();
+        self.buffer[(self.write_index + length - delay) % length]
+    }
+}
+
+/// Delay line whose delay time is swept by its own LFO, the building block of chorus and
+/// flanger. Reading and writing are separate so the output can be fed back.
+pub struct ModulatedDelay {
+    line: DelayLine,
+    lfo: Lfo,
+    sample_rate: f64,
+    delay: f64,
+    depth: f64,
+}
+
+impl ModulatedDelay {
+    /// `max_time` is the longest delay, in seconds, the sweep may reach.
+    pub fn new(max_time: f64, sample_rate: f64) -> Self {
+        Self {
+            line: DelayLine::new((max_time.max(0.0) * sample_rate).ceil() as usize),
+            lfo: Lfo::new(LfoShape::Sine, sample_rate),
+            sample_rate,
+            delay: 0.0,
+            depth: 0.0,
+        }
+    }
+
+    /// The LFO sweeping the delay. Its rate, shape and phase are set directly.
+    pub fn lfo_mut(&mut self) -> &mut Lfo {
+        &mut self.lfo
+    }
+
+    /// Centre of the sweep, in seconds.
+    pub fn set_delay(&mut self, seconds: f64) {
+        self.delay = seconds.max(0.0);
+    }
+
+    /// How far, in seconds, the delay swings to either side of the centre.
+    pub fn set_depth(&mut self, seconds: f64) {
+        self.depth = seconds.max(0.0);
+    }
+
+    pub fn clear(&mut self) {
+        self.line.clear();
+    }
+
+    /// Advances the LFO and returns the delayed signal. Call once per sample, before `write`.
+    pub fn read(&mut self) -> Signal {
+        let seconds = self.delay + self.depth * self.lfo.next_sample();
+        self.line.read(seconds * self.sample_rate)
+    }
+
+    pub fn write(&mut self, input: Signal) {
+        self.line.write(input);
+    }
+
+    pub fn process(&mut self, input: Signal) -> Signal {
+        let output = self.read();
+        self.write(input);
+        output
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DelayTime {
+    Seconds(f64),
+    /// Locked to the tempo. `NoteDivision::beats` gives the length of common note values.
+    Beats(f64),
+}
+
+/// Feedback delay with filtering in the loop, so every repeat gets darker and thinner. In
+/// ping-pong mode the input is summed to mono and the repeats alternate between left and
+/// right.
+pub struct Delay {
+    sample_rate: f64,
+    time: DelayTime,
+    tempo: f64,
+    delay_samples: SmoothedValue,
+    feedback: Signal,
+    mix: Signal,
+    ping_pong: bool,
+    lines: [DelayLine; 2],
+    low_pass: [StateVariableFilter; 2],
+    high_pass: [StateVariableFilter; 2],
+}
+
+impl Delay {
+    /// `max_time` is the longest delay time, in seconds, that will be needed.
+    pub fn new(max_time: f64, sample_rate: f64) -> Self {
+        let max_delay = (max_time.max(0.0) * sample_rate).ceil() as usize;
+        let filter = |mode, cutoff| {
+            let mut filter = StateVariableFilter::new(mode, sample_rate);
+            filter.set_cutoff(cutoff);
+            filter
+        };
+
+        let mut delay = Self {
+            sample_rate,
+            time: DelayTime::Seconds(0.25),
+            tempo: 120.0,
+            delay_samples: SmoothedValue::new(1.0, TIME_SMOOTHING_TIME, sample_rate),
+            feedback: 0.4,
+            mix: 0.3,
+            ping_pong: false,
+            lines: [DelayLine::new(max_delay), DelayLine::new(max_delay)],
+            low_pass: [
+                filter(FilterMode::LowPass, sample_rate),
+                filter(FilterMode::LowPass, sample_rate),
+            ],
+            high_pass: [
+                filter(FilterMode::HighPass, 0.0),
+                filter(FilterMode::HighPass, 0.0),
+            ],
+        };
+        let samples = delay.target_samples();
+        delay.delay_samples.reset(samples);
+        delay
+    }
+
+    pub fn set_time(&mut self, time: DelayTime) {
+        self.time = time;
+        let samples = self.target_samples();
+        self.delay_samples.set(samples);
+    }
+
+    /// Tempo in beats per minute, used by `DelayTime::Beats`.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.tempo = bpm.max(1.0);
+        let samples = self.target_samples();
+        self.delay_samples.set(samples);
+    }
+
+    /// Keeps the tempo at that of the transport, for delays run by context processors.
+    pub fn follow_tempo(&mut self, context: &ProcessContext) {
+        if context.tempo != self.tempo {
+            self.set_tempo(context.tempo);
+        }
+    }
+
+    /// Share of the delayed signal fed back into the line, in [0.0, 1.0).
+    pub fn set_feedback(&mut self, feedback: Signal) {
+        self.feedback = feedback.clamp(0.0, MAX_FEEDBACK);
+    }
+
+    /// Balance between the dry input at 0.0 and only the repeats at 1.0.
+    pub fn set_mix(&mut self, mix: Signal) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+
+    /// Cutoff of the low-pass in the feedback loop.
+    pub fn set_high_cut(&mut self, cutoff: f64) {
+        for filter in self.low_pass.iter_mut() {
+            filter.set_cutoff(cutoff);
+        }
+    }
+
+    /// Cutoff of the high-pass in the feedback loop.
+    pub fn set_low_cut(&mut self, cutoff: f64) {
+        for filter in self.high_pass.iter_mut() {
+            filter.set_cutoff(cutoff);
+        }
+    }
+
+    pub fn set_ping_pong(&mut self, ping_pong: bool) {
+        self.ping_pong = ping_pong;
+    }
+
+    /// Empties the lines, silencing all pending repeats.
+    pub fn clear(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.clear();
+        }
+        for filter in self.low_pass.iter_mut().chain(self.high_pass.iter_mut()) {
+            filter.reset();
+        }
+    }
+
+    /// Processes a mono signal through the left line only.
+    pub fn process(&mut self, input: Signal) -> Signal {
+        let delay = self.delay_samples.next_value();
+        let delayed = self.lines[0].read(delay);
+        let feedback = self.filter(0, delayed) * self.feedback;
+        self.lines[0].write(input + feedback);
+
+        self.blend(input, delayed)
+    }
+
+    pub fn process_stereo(&mut self, (left, right): StereoSignal) -> StereoSignal {
+        let delay = self.delay_samples.next_value();
+        let delayed_left = self.lines[0].read(delay);
+        let delayed_right = self.lines[1].read(delay);
+        let feedback_left = self.filter(0, delayed_left) * self.feedback;
+        let feedback_right = self.filter(1, delayed_right) * self.feedback;
+
+        if self.ping_pong {
+            self.lines[0].write((left + right) * 0.5 + feedback_right);
+            self.lines[1].write(feedback_left);
+        } else {
+            self.lines[0].write(left + feedback_left);
+            self.lines[1].write(right + feedback_right);
+        }
+
+        (
+            self.blend(left, delayed_left),
+            self.blend(right, delayed_right),
+        )
+    }
+
+    fn filter(&mut self, channel: usize, input: Signal) -> Signal {
+        let filtered = self.low_pass[channel].process(input);
+        self.high_pass[channel].process(filtered)
+    }
+
+    fn blend(&self, dry: Signal, wet: Signal) -> Signal {
+        dry * (1.0 - self.mix) + wet * self.mix
+    }
+
+    fn target_samples(&self) -> f64 {
+        let seconds = match self.time {
+            DelayTime::Seconds(seconds) => seconds,
+            DelayTime::Beats(beats) => beats_to_seconds(beats, self.tempo),
+        };
+        (seconds * self.sample_rate).clamp(1.0, self.lines[0].max_delay())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse_response(line: &mut DelayLine, delay: f64, length: usize) -> Vec<Signal> {
+        (0..length)
+            .map(|i| {
+                line.write(if i == 0 { 1.0 } else { 0.0 });
+                line.read(delay)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn whole_sample_delay_is_exact() {
+        let mut line = DelayLine::new(16);
+        let response = impulse_response(&mut line, 5.0, 10);
+        for (i, &value) in response.iter().enumerate() {
+            assert_eq!(value, if i == 4 { 1.0 } else { 0.0 });
+        }
+    }
+
+    #[test]
+    fn fractional_delay_interpolates_between_samples() {
+        let mut line = DelayLine::new(16);
+        for i in 0..16 {
+            line.write(f64::from(i));
+        }
+        // A straight line is reproduced exactly by cubic interpolation.
+        assert!((line.read(3.25) - 12.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fractional_delay_below_two_samples() {
+        let mut line = DelayLine::new(16);
+        for i in 0..16 {
+            line.write(f64::from(i));
+        }
+        assert!((line.read(1.5) - 14.5).abs() < 1e-12);
+        assert!((line.read(1.25) - 14.75).abs() < 1e-12);
+
+        let mut line = DelayLine::new(16);
+        let frequency = 0.01;
+        for i in 0..100 {
+            line.write((2.0 * std::f64::consts::PI * frequency * f64::from(i)).sin());
+        }
+        let expected = (2.0 * std::f64::consts::PI * frequency * (99.0 - 0.5)).sin();
+        assert!((line.read(1.5) - expected).abs() < 1e-4);
+        // Continuous where the sweep crosses two samples.
+        assert!((line.read(1.999_999) - line.read(2.0)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn ping_pong_alternates_between_channels() {
+        let sample_rate = 1000.0;
+        let mut delay = Delay::new(1.0, sample_rate);
+        delay.set_time(DelayTime::Seconds(0.01));
+        delay.set_feedback(0.5);
+        delay.set_mix(1.0);
+        delay.set_ping_pong(true);
+        // Let the delay time settle.
+        for _ in 0..1000 {
+            delay.process_stereo((0.0, 0.0));
+        }
+
+        let output: Vec<StereoSignal> = (0..40)
+            .map(|i| delay.process_stereo(if i == 0 { (1.0, 1.0) } else { (0.0, 0.0) }))
+            .collect();
+        let energy = |range: std::ops::Range<usize>, right: bool| -> Signal {
+            output[range]
+                .iter()
+                .map(|&(l, r)| if right { r * r } else { l * l })
+                .sum()
+        };
+
+        assert!(energy(5..15, false) > 100.0 * energy(5..15, true));
+        assert!(energy(15..25, true) > 100.0 * energy(15..25, false));
+    }
+}
@@ -0,0 +1,797 @@
+use clock::{NoteDivision, StepGrid};
+use filter::{FilterMode, StateVariableFilter};
+use noise::{Noise, NoiseColor};
+use oscillators::{Oscillator, Waveform};
+use std::f64::consts::PI;
+use transport::ProcessContext;
+use types::{ContextProcessorFunction, Phase, Signal};
+
+pub const DRUM_STEPS: usize = 16;
+/// Below this level a drum counts as silent.
+const SILENCE: Signal = 1e-4;
+/// Frequencies of the square waves making up the hi-hats, those of the TR-808.
+const HAT_FREQUENCIES: [f64; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+/// Start of each burst of the clap, in seconds after the trigger. The last one has a
+/// long tail.
+const CLAP_BURSTS: [f64; 4] = [0.0, 0.01, 0.02, 0.03];
+
+/// A synthesized drum sound.
+pub trait Drum {
+    /// Starts the sound. `velocity` is in [0.0, 1.0].
+    fn trigger(&mut self, velocity: Signal);
+    fn next_sample(&mut self) -> Signal;
+    fn is_active(&self) -> bool;
+    /// Cuts the sound short, like a closed hi-hat stopping an open one.
+    fn choke(&mut self) {}
+}
+
+/// Exponential decay, falling 60 dB over its decay time.
+struct Decay {
+    sample_rate: f64,
+    level: Signal,
+    coefficient: Signal,
+}
+
+impl Decay {
+    fn new(seconds: f64, sample_rate: f64) -> Self {
+        let mut decay = Self {
+            sample_rate,
+            level: 0.0,
+            coefficient: 0.0,
+        };
+        decay.set_time(seconds);
+        decay
+    }
+
+    fn set_time(&mut self, seconds: f64) {
+        self.coefficient = 0.001f64.powf(1.0 / (seconds.max(0.001) * self.sample_rate));
+    }
+
+    fn trigger(&mut self, level: Signal) {
+        self.level = level;
+    }
+
+    fn next_sample(&mut self) -> Signal {
+        let level = self.level;
+        self.level *= self.coefficient;
+        level
+    }
+
+    fn is_active(&self) -> bool {
+        self.level > SILENCE
+    }
+}
+
+/// Sine with a falling pitch, used by the kick and the tom.
+struct PitchSweep {
+    sample_rate: f64,
+    phase: Phase,
+    frequency: f64,
+    sweep: f64,
+    pitch: Decay,
+}
+
+impl PitchSweep {
+    fn new(frequency: f64, sweep: f64, sweep_time: f64, sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            phase: 0.0,
+            frequency,
+            sweep,
+            pitch: Decay::new(sweep_time, sample_rate),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.phase = 0.0;
+        self.pitch.trigger(1.0);
+    }
+
+    fn next_sample(&mut self) -> Signal {
+        let frequency = self.frequency * (1.0 + (self.sweep - 1.0) * self.pitch.next_sample());
+        let output = (2.0 * PI * self.phase).sin();
+        self.phase += frequency / self.sample_rate;
+        self.phase -= self.phase.floor();
+        output
+    }
+}
+
+pub struct Kick {
+    body: PitchSweep,
+    amplitude: Decay,
+}
+
+impl Kick {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            body: PitchSweep::new(50.0, 3.0, 0.04, sample_rate),
+            amplitude: Decay::new(0.5, sample_rate),
+        }
+    }
+
+    /// Frequency the pitch falls to.
+    pub fn set_tune(&mut self, frequency: f64) {
+        self.body.frequency = frequency;
+    }
+
+    /// How many times higher than the tune the pitch starts, giving the punch.
+    pub fn set_sweep(&mut self, ratio: f64) {
+        self.body.sweep = ratio.max(1.0);
+    }
+
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.amplitude.set_time(seconds);
+    }
+}
+
+impl Drum for Kick {
+    fn trigger(&mut self, velocity: Signal) {
+        self.body.trigger();
+        self.amplitude.trigger(velocity);
+    }
+
+    fn next_sample(&mut self) -> Signal {
+        self.body.next_sample() * self.amplitude.next_sample()
+    }
+
+    fn is_active(&self) -> bool {
+        self.amplitude.is_active()
+    }
+}
+
+pub struct Snare {
+    sample_rate: f64,
+    phases: [Phase; 2],
+    tone: Decay,
+    noise: Noise,
+    noise_filter: StateVariableFilter,
+    rattle: Decay,
+    snappy: Signal,
+}
+
+impl Snare {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut noise_filter = StateVariableFilter::new(FilterMode::HighPass, sample_rate);
+        noise_filter.set_cutoff(1500.0);
+        Self {
+            sample_rate,
+            phases: [0.0; 2],
+            tone: Decay::new(0.1, sample_rate),
+            noise: Noise::new(NoiseColor::White),
+            noise_filter,
+            rattle: Decay::new(0.2, sample_rate),
+            snappy: 0.6,
+        }
+    }
+
+    /// Decay of the snare wires.
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.rattle.set_time(seconds);
+    }
+
+    /// Balance between the drum head at 0.0 and the snare wires at 1.0.
+    pub fn set_snappy(&mut self, snappy: Signal) {
+        self.snappy = snappy.clamp(0.0, 1.0);
+    }
+
+    pub fn set_seed(&mut self, seed: u32) {
+        self.noise.set_seed(seed);
+    }
+}
+
+impl Drum for Snare {
+    fn trigger(&mut self, velocity: Signal) {
+        self.phases = [0.0; 2];
+        self.tone.trigger(velocity);
+        self.rattle.trigger(velocity);
+    }
+
+    fn next_sample(&mut self) -> Signal {
+        let mut tone = 0.0;
+        for (phase, &frequency) in self.phases.iter_mut().zip([180.0, 330.0].iter()) {
+            tone += (2.0 * PI * *phase).sin() * 0.5;
+            *phase += frequency / self.sample_rate;
+            *phase -= phase.floor();
+        }
+        let rattle = self.noise_filter.process(self.noise.next_sample());
+
+        tone * self.tone.next_sample() * (1.0 - self.snappy)
+            + rattle * self.rattle.next_sample() * self.snappy
+    }
+
+    fn is_active(&self) -> bool {
+        self.tone.is_active() || self.rattle.is_active()
+    }
+}
+
+/// Metallic noise from six detuned square waves. Closed and open hats differ only in decay.
+pub struct HiHat {
+    oscillators: Vec<Oscillator>,
+    band_pass: StateVariableFilter,
+    high_pass: StateVariableFilter,
+    amplitude: Decay,
+}
+
+impl HiHat {
+    pub fn new(decay: f64, sample_rate: f64) -> Self {
+        let oscillators = HAT_FREQUENCIES
+            .iter()
+            .map(|&frequency| {
+                let mut oscillator = Oscillator::new(Waveform::Square, sample_rate);
+                oscillator.set_frequency(frequency);
+                oscillator
+            })
+            .collect();
+        let mut band_pass = StateVariableFilter::new(FilterMode::BandPass, sample_rate);
+        band_pass.set_cutoff(10_000.0);
+        band_pass.set_resonance(0.3);
+        let mut high_pass = StateVariableFilter::new(FilterMode::HighPass, sample_rate);
+        high_pass.set_cutoff(7000.0);
+
+        Self {
+            oscillators,
+            band_pass,
+            high_pass,
+            amplitude: Decay::new(decay, sample_rate),
+        }
+    }
+
+    pub fn closed(sample_rate: f64) -> Self {
+        Self::new(0.05, sample_rate)
+    }
+
+    pub fn open(sample_rate: f64) -> Self {
+        Self::new(0.4, sample_rate)
+    }
+
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.amplitude.set_time(seconds);
+    }
+}
+
+impl Drum for HiHat {
+    fn trigger(&mut self, velocity: Signal) {
+        self.amplitude.trigger(velocity);
+    }
+
+    fn next_sample(&mut self) -> Signal {
+        let metal: Signal = self.oscillators.iter_mut().map(|o| o.next_sample()).sum();
+        let filtered = self.high_pass.process(
+            self.band_pass
+                .process(metal / HAT_FREQUENCIES.len() as Signal),
+        );
+        filtered * self.amplitude.next_sample()
+    }
+
+    fn is_active(&self) -> bool {
+        self.amplitude.is_active()
+    }
+
+    fn choke(&mut self) {
+        self.amplitude.trigger(0.0);
+    }
+}
+
+/// Band-passed noise in a few quick bursts, like several hands clapping, with a tail.
+pub struct Clap {
+    sample_rate: f64,
+    noise: Noise,
+    filter: StateVariableFilter,
+    bursts: Decay,
+    tail: Decay,
+    velocity: Signal,
+    // Samples since the trigger, `None` once the bursts are over.
+    elapsed: Option<usize>,
+}
+
+impl Clap {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut filter = StateVariableFilter::new(FilterMode::BandPass, sample_rate);
+        filter.set_cutoff(1200.0);
+        filter.set_resonance(0.5);
+        Self {
+            sample_rate,
+            noise: Noise::new(NoiseColor::White),
+            filter,
+            bursts: Decay::new(0.008, sample_rate),
+            tail: Decay::new(0.15, sample_rate),
+            velocity: 0.0,
+            elapsed: None,
+        }
+    }
+
+    /// Decay of the tail after the last burst.
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.tail.set_time(seconds);
+    }
+
+    pub fn set_seed(&mut self, seed: u32) {
+        self.noise.set_seed(seed);
+    }
+}
+
+impl Drum for Clap {
+    fn trigger(&mut self, velocity: Signal) {
+        self.velocity = velocity;
+        self.elapsed = Some(0);
+    }
+
+    fn next_sample(&mut self) -> Signal {
+        if let Some(elapsed) = self.elapsed {
+            let last = CLAP_BURSTS.len() - 1;
+            for (index, &time) in CLAP_BURSTS.iter().enumerate() {
+                if elapsed == (time * self.sample_rate) as usize {
+                    if index == last {
+                        self.tail.trigger(self.velocity);
+                        self.elapsed = None;
+                    } else {
+                        self.bursts.trigger(self.velocity);
+                    }
+                }
+            }
+            if let Some(ref mut elapsed) = self.elapsed {
+                *elapsed += 1;
+            }
+        }
+
+        let level = self.bursts.next_sample().max(self.tail.next_sample());
+        self.filter.process(self.noise.next_sample()) * level
+    }
+
+    fn is_active(&self) -> bool {
+        self.elapsed.is_some() || self.bursts.is_active() || self.tail.is_active()
+    }
+}
+
+pub struct Tom {
+    body: PitchSweep,
+    amplitude: Decay,
+}
+
+impl Tom {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            body: PitchSweep::new(120.0, 1.5, 0.1, sample_rate),
+            amplitude: Decay::new(0.35, sample_rate),
+        }
+    }
+
+    pub fn set_tune(&mut self, frequency: f64) {
+        self.body.frequency = frequency;
+    }
+
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.amplitude.set_time(seconds);
+    }
+}
+
+impl Drum for Tom {
+    fn trigger(&mut self, velocity: Signal) {
+        self.body.trigger();
+        self.amplitude.trigger(velocity);
+    }
+
+    fn next_sample(&mut self) -> Signal {
+        self.body.next_sample() * self.amplitude.next_sample()
+    }
+
+    fn is_active(&self) -> bool {
+        self.amplitude.is_active()
+    }
+}
+
+/// A drum and its row of steps in a `DrumMachine`.
+pub struct DrumLane {
+    pub name: String,
+    pub drum: Box<dyn Drum + Send>,
+    pub steps: [bool; DRUM_STEPS],
+    /// Accented steps play at full velocity.
+    pub accents: [bool; DRUM_STEPS],
+    pub level: Signal,
+    pub mute: bool,
+    pub solo: bool,
+    /// Lane whose drum this lane cuts off when it plays, like a closed hat choking an open
+    /// one.
+    pub chokes: Option<usize>,
+}
+
+impl DrumLane {
+    pub fn new(name: &str, drum: Box<dyn Drum + Send>) -> Self {
+        Self {
+            name: name.to_owned(),
+            drum,
+            steps: [false; DRUM_STEPS],
+            accents: [false; DRUM_STEPS],
+            level: 1.0,
+            mute: false,
+            solo: false,
+            chokes: None,
+        }
+    }
+}
+
+/// Changes sent to the drum machine on the audio thread through
+/// `EngineController::drum_command`. Lanes and steps that do not exist are ignored.
+#[derive(Clone, Copy, Debug)]
+pub enum DrumCommand {
+    /// Turns a step of a lane on or off.
+    SetStep(usize, usize, bool),
+    SetAccent(usize, usize, bool),
+    SetLevel(usize, Signal),
+    SetMute(usize, bool),
+    SetSolo(usize, bool),
+    SetSwing(f64),
+    SetUnaccentedVelocity(Signal),
+}
+
+/// Plays a 16 step pattern over several lanes of drums while the transport plays, with the
+/// first step on every bar of 4/4.
+pub struct DrumMachine {
+    grid: StepGrid,
+    lanes: Vec<DrumLane>,
+    swing: f64,
+    velocity: Signal,
+    step: usize,
+    current_key: Option<i32>,
+}
+
+impl Default for DrumMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DrumMachine {
+    /// A machine without lanes.
+    pub fn new() -> Self {
+        Self {
+            grid: StepGrid::new(),
+            lanes: Vec::new(),
+            swing: 0.0,
+            velocity: 0.7,
+            step: 0,
+            current_key: None,
+        }
+    }
+
+    /// Lanes for kick, snare, closed hat, open hat, clap and tom, in that order. The closed
+    /// hat chokes the open one.
+    pub fn with_kit(sample_rate: f64) -> Self {
+        let mut machine = Self::new();
+        machine.add_lane(DrumLane::new("Kick", Box::new(Kick::new(sample_rate))));
+        machine.add_lane(DrumLane::new("Snare", Box::new(Snare::new(sample_rate))));
+        let mut closed = DrumLane::new("Closed hat", Box::new(HiHat::closed(sample_rate)));
+        closed.chokes = Some(3);
+        machine.add_lane(closed);
+        machine.add_lane(DrumLane::new(
+            "Open hat",
+            Box::new(HiHat::open(sample_rate)),
+        ));
+        machine.add_lane(DrumLane::new("Clap", Box::new(Clap::new(sample_rate))));
+        machine.add_lane(DrumLane::new("Tom", Box::new(Tom::new(sample_rate))));
+        machine
+    }
+
+    pub fn add_lane(&mut self, lane: DrumLane) {
+        self.lanes.push(lane);
+    }
+
+    pub fn lanes(&self) -> &[DrumLane] {
+        &self.lanes
+    }
+
+    pub fn lane_mut(&mut self, index: usize) -> &mut DrumLane {
+        &mut self.lanes[index]
+    }
+
+    /// The step that played last.
+    pub fn step(&self) -> usize {
+        (self.step + DRUM_STEPS - 1) % DRUM_STEPS
+    }
+
+    /// Delay of every second step, as a share of a step in [0.0, 0.5].
+    pub fn set_swing(&mut self, swing: f64) {
+        self.swing = swing.clamp(0.0, 0.5);
+    }
+
+    /// Velocity of steps without an accent, in [0.0, 1.0].
+    pub fn set_unaccented_velocity(&mut self, velocity: Signal) {
+        self.velocity = velocity.clamp(0.0, 1.0);
+    }
+
+    pub fn apply(&mut self, command: DrumCommand) {
+        match command {
+            DrumCommand::SetSwing(swing) => self.set_swing(swing),
+            DrumCommand::SetUnaccentedVelocity(velocity) => self.set_unaccented_velocity(velocity),
+            DrumCommand::SetStep(lane, step, on) => {
+                if let Some(lane) = self.lanes.get_mut(lane) {
+                    if step < DRUM_STEPS {
+                        lane.steps[step] = on;
+                    }
+                }
+            }
+            DrumCommand::SetAccent(lane, step, on) => {
+                if let Some(lane) = self.lanes.get_mut(lane) {
+                    if step < DRUM_STEPS {
+                        lane.accents[step] = on;
+                    }
+                }
+            }
+            DrumCommand::SetLevel(lane, level) => {
+                if let Some(lane) = self.lanes.get_mut(lane) {
+                    lane.level = level;
+                }
+            }
+            DrumCommand::SetMute(lane, mute) => {
+                if let Some(lane) = self.lanes.get_mut(lane) {
+                    lane.mute = mute;
+                }
+            }
+            DrumCommand::SetSolo(lane, solo) => {
+                if let Some(lane) = self.lanes.get_mut(lane) {
+                    lane.solo = solo;
+                }
+            }
+        }
+    }
+
+    /// Plays a lane's drum now.
+    pub fn trigger(&mut self, lane: usize, velocity: Signal) {
+        if let Some(choked) = self.lanes[lane].chokes {
+            if let Some(choked) = self.lanes.get_mut(choked) {
+                choked.drum.choke();
+            }
+        }
+        self.lanes[lane].drum.trigger(velocity);
+    }
+
+    /// Plays the lane numbered by a key, wrapping around, when a new key is pressed.
+    pub fn gate(&mut self, key: Option<i32>) {
+        if key == self.current_key {
+            return;
+        }
+        if let Some(key) = key {
+            if !self.lanes.is_empty() {
+                let lane = key.rem_euclid(self.lanes.len() as i32) as usize;
+                self.trigger(lane, 1.0);
+            }
+        }
+        self.current_key = key;
+    }
+
+    /// Advances to the sample of `context`. While the transport is stopped drums already
+    /// playing ring out.
+    pub fn next_sample(&mut self, context: &ProcessContext) -> Signal {
+        if context.playing {
+            let step_beats = NoteDivision::Straight(16).beats();
+            if self.grid.follow(context.position, step_beats, self.swing) {
+                self.step = (self.grid.index() % DRUM_STEPS as u64) as usize;
+            }
+            while self.grid.next_step(step_beats, self.swing).is_some() {
+                self.play_step();
+            }
+        } else {
+            self.grid.reset();
+        }
+
+        let any_solo = self.lanes.iter().any(|lane| lane.solo);
+        let mut output = 0.0;
+        for lane in self.lanes.iter_mut() {
+            // Silent drums are skipped, the hats alone run six oscillators and two filters.
+            if !lane.drum.is_active() {
+                continue;
+            }
+            let sample = lane.drum.next_sample();
+            let audible = if any_solo { lane.solo } else { !lane.mute };
+            if audible {
+                output += sample * lane.level;
+            }
+        }
+        output
+    }
+
+    /// Wraps the machine in a processor function for
+    /// `EngineController::set_context_processor_function`, with keys playing the lanes.
+    pub fn into_processor_function(mut self) -> ContextProcessorFunction {
+        Box::new(move |key, context| {
+            self.gate(key);
+            let signal = self.next_sample(context);
+            (signal, signal)
+        })
+    }
+
+    fn play_step(&mut self) {
+        let step = self.step;
+        for lane in 0..self.lanes.len() {
+            if self.lanes[lane].steps[step] {
+                let velocity = if self.lanes[lane].accents[step] {
+                    1.0
+                } else {
+                    self.velocity
+                };
+                self.trigger(lane, velocity);
+            }
+        }
+        self.step = (step + 1) % DRUM_STEPS;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use transport::Transport;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    // Sixteenth notes at 120 BPM.
+    const STEP_SAMPLES: usize = 6000;
+
+    #[test]
+    fn drums_fall_silent() {
+        let mut drums: Vec<Box<dyn Drum>> = vec![
+            Box::new(Kick::new(SAMPLE_RATE)),
+            Box::new(Snare::new(SAMPLE_RATE)),
+            Box::new(HiHat::open(SAMPLE_RATE)),
+            Box::new(Clap::new(SAMPLE_RATE)),
+            Box::new(Tom::new(SAMPLE_RATE)),
+        ];
+        for drum in drums.iter_mut() {
+            drum.trigger(1.0);
+            let peak = (0..4800).fold(0.0, |peak: Signal, _| peak.max(drum.next_sample().abs()));
+            assert!(peak > 0.05 && peak < 2.0);
+            for _ in 0..48_000 {
+                drum.next_sample();
+            }
+            assert!(!drum.is_active());
+        }
+    }
+
+    #[test]
+    fn steps_trigger_on_exact_samples() {
+        let mut machine = DrumMachine::new();
+        machine.add_lane(DrumLane::new("Kick", Box::new(Kick::new(SAMPLE_RATE))));
+        machine.lane_mut(0).steps[2] = true;
+        let mut transport = Transport::new(SAMPLE_RATE);
+        transport.play();
+
+        let output: Vec<Signal> = (0..3 * STEP_SAMPLES)
+            .map(|_| machine.next_sample(&transport.next_sample().0))
+            .collect();
+        assert!(output[..2 * STEP_SAMPLES].iter().all(|&s| s == 0.0));
+        // The sine starts at zero, so the kick shows on the sample after its trigger.
+        assert!(output[2 * STEP_SAMPLES + 1] > 0.0);
+    }
+
+    /// Outputs the velocity of a trigger for one sample.
+    struct Probe(Signal);
+
+    impl Drum for Probe {
+        fn trigger(&mut self, velocity: Signal) {
+            self.0 = velocity;
+        }
+
+        fn next_sample(&mut self) -> Signal {
+            mem::replace(&mut self.0, 0.0)
+        }
+
+        fn is_active(&self) -> bool {
+            self.0 != 0.0
+        }
+    }
+
+    /// Runs a machine with one probe lane for a bar, returning the sample and velocity of
+    /// every trigger.
+    fn triggers(steps: &[usize], accents: &[usize], swing: f64) -> Vec<(usize, Signal)> {
+        let mut machine = DrumMachine::new();
+        machine.add_lane(DrumLane::new("Probe", Box::new(Probe(0.0))));
+        for &step in steps {
+            machine.apply(DrumCommand::SetStep(0, step, true));
+        }
+        for &step in accents {
+            machine.apply(DrumCommand::SetAccent(0, step, true));
+        }
+        machine.apply(DrumCommand::SetSwing(swing));
+        machine.apply(DrumCommand::SetUnaccentedVelocity(0.5));
+
+        let mut transport = Transport::new(SAMPLE_RATE);
+        transport.play();
+        (0..DRUM_STEPS * STEP_SAMPLES)
+            .map(|_| machine.next_sample(&transport.next_sample().0))
+            .enumerate()
+            .filter(|&(_, velocity)| velocity != 0.0)
+            .collect()
+    }
+
+    #[test]
+    fn swing_delays_odd_steps() {
+        let swing = 0.25;
+        let late = (swing * STEP_SAMPLES as f64) as usize;
+        let steps = [0, 1, 2, 3];
+        let expected: Vec<_> = steps
+            .iter()
+            .map(|&step| {
+                let start = step * STEP_SAMPLES;
+                (if step % 2 == 1 { start + late } else { start }, 0.5)
+            })
+            .collect();
+        assert_eq!(triggers(&steps, &[], swing), expected);
+    }
+
+    #[test]
+    fn accents_play_at_full_velocity() {
+        let played = triggers(&[0, 4, 8], &[4], 0.0);
+        assert_eq!(
+            played,
+            vec![(0, 0.5), (4 * STEP_SAMPLES, 1.0), (8 * STEP_SAMPLES, 0.5)]
+        );
+    }
+
+    /// Sounds for ten samples after a trigger, counting the samples it is run for.
+    struct Counter {
+        remaining: usize,
+        runs: Arc<AtomicUsize>,
+    }
+
+    impl Drum for Counter {
+        fn trigger(&mut self, _velocity: Signal) {
+            self.remaining = 10;
+        }
+
+        fn next_sample(&mut self) -> Signal {
+            self.runs.fetch_add(1, Ordering::Relaxed);
+            self.remaining = self.remaining.saturating_sub(1);
+            1.0
+        }
+
+        fn is_active(&self) -> bool {
+            self.remaining > 0
+        }
+    }
+
+    #[test]
+    fn silent_drums_are_not_run() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut machine = DrumMachine::new();
+        machine.add_lane(DrumLane::new(
+            "Counter",
+            Box::new(Counter {
+                remaining: 0,
+                runs: runs.clone(),
+            }),
+        ));
+        let context = ProcessContext::default();
+        for _ in 0..100 {
+            machine.next_sample(&context);
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 0);
+        machine.trigger(0, 1.0);
+        for _ in 0..100 {
+            machine.next_sample(&context);
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn mute_and_solo_pick_lanes() {
+        let mut machine = DrumMachine::with_kit(SAMPLE_RATE);
+        let context = ProcessContext::default();
+        let run = |machine: &mut DrumMachine| {
+            machine.trigger(0, 1.0);
+            (0..4800)
+                .map(|_| machine.next_sample(&context).abs())
+                .sum::<Signal>()
+        };
+
+        machine.lane_mut(0).mute = true;
+        assert_eq!(run(&mut machine), 0.0);
+
+        machine.lane_mut(0).solo = true;
+        assert!(run(&mut machine) > 0.0);
+        machine.lane_mut(0).solo = false;
+        machine.lane_mut(1).solo = true;
+        machine.lane_mut(0).mute = false;
+        assert_eq!(run(&mut machine), 0.0);
+    }
+}
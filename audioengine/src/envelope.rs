@@ -0,0 +1,321 @@
+use keys_state::KeysState;
+use types::{KeyAction, Signal};
+
+/// Steepness of exponential segments. Higher values bend the curve more.
+const EXPONENTIAL_STEEPNESS: f64 = 5.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// Fast at the start of a segment and slow towards its end, like an analog envelope.
+    Exponential,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerMode {
+    /// Every new note restarts the envelope from its current level.
+    Retrigger,
+    /// Notes played while another is held continue the running envelope.
+    Legato,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Idle,
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Delay, attack, hold, decay, sustain and release envelope. Times are in seconds, the sustain
+/// level is in [0.0, 1.0].
+pub struct Adsr {
+    sample_rate: f64,
+    delay: f64,
+    attack: f64,
+    hold: f64,
+    decay: f64,
+    sustain: Signal,
+    release: f64,
+    curve: Curve,
+    trigger_mode: TriggerMode,
+    velocity_sensitivity: f64,
+
+    stage: Stage,
+    // Seconds spent in the current stage.
+    stage_time: f64,
+    // Output levels, with the velocity gain applied.
+    stage_start_level: Signal,
+    level: Signal,
+    velocity_gain: Signal,
+    keys_state: KeysState,
+    current_key: Option<i32>,
+}
+
+impl Adsr {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            delay: 0.0,
+            attack: 0.01,
+            hold: 0.0,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.3,
+            curve: Curve::Linear,
+            trigger_mode: TriggerMode::Retrigger,
+            velocity_sensitivity: 0.0,
+            stage: Stage::Idle,
+            stage_time: 0.0,
+            stage_start_level: 0.0,
+            level: 0.0,
+            velocity_gain: 1.0,
+            keys_state: KeysState::new(),
+            current_key: None,
+        }
+    }
+
+    pub fn set_delay(&mut self, seconds: f64) {
+        self.delay = seconds.max(0.0);
+    }
+
+    pub fn set_attack(&mut self, seconds: f64) {
+        self.attack = seconds.max(0.0);
+    }
+
+    pub fn set_hold(&mut self, seconds: f64) {
+        self.hold = seconds.max(0.0);
+    }
+
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.decay = seconds.max(0.0);
+    }
+
+    pub fn set_sustain(&mut self, level: Signal) {
+        self.sustain = level.clamp(0.0, 1.0);
+    }
+
+    pub fn set_release(&mut self, seconds: f64) {
+        self.release = seconds.max(0.0);
+    }
+
+    pub fn set_curve(&mut self, curve: Curve) {
+        self.curve = curve;
+    }
+
+    pub fn set_trigger_mode(&mut self, trigger_mode: TriggerMode) {
+        self.trigger_mode = trigger_mode;
+    }
+
+    /// How much velocity affects the output level. At 0.0 velocity is ignored, at 1.0 the
+    /// level is scaled by the velocity.
+    pub fn set_velocity_sensitivity(&mut self, sensitivity: f64) {
+        self.velocity_sensitivity = sensitivity.clamp(0.0, 1.0);
+    }
+
+    /// Starts the envelope. `velocity` is in [0.0, 1.0]. Retriggering continues from the
+    /// current level, whatever the velocity, and legato notes keep the running velocity.
+    pub fn note_on(&mut self, velocity: Signal) {
+        let gate_open = !matches!(self.stage, Stage::Idle | Stage::Release);
+        if gate_open && self.trigger_mode == TriggerMode::Legato {
+            return;
+        }
+
+        let sensitivity = self.velocity_sensitivity;
+        self.velocity_gain = 1.0 - sensitivity + sensitivity * velocity.clamp(0.0, 1.0);
+        self.enter(Stage::Delay);
+    }
+
+    pub fn note_off(&mut self) {
+        if self.stage != Stage::Idle {
+            self.enter(Stage::Release);
+        }
+    }
+
+    /// Drives the envelope from raw key events, keeping track of which keys are held.
+    pub fn key_action(&mut self, action: KeyAction) {
+        let key = self.keys_state.key_down(action);
+        self.gate(key);
+    }
+
+    /// Drives the envelope from the key handed to a processor function. A different key
+    /// counts as a new note, played at full velocity.
+    pub fn gate(&mut self, key: Option<i32>) {
+        self.gate_with_velocity(key, 1.0);
+    }
+
+    /// Like `gate`, starting new notes at `velocity`.
+    pub fn gate_with_velocity(&mut self, key: Option<i32>, velocity: Signal) {
+        if key == self.current_key {
+            return;
+        }
+        match key {
+            Some(_) => self.note_on(velocity),
+            None => self.note_off(),
+        }
+        self.current_key = key;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    pub fn level(&self) -> Signal {
+        self.level
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        loop {
+            let (duration, target) = match self.stage {
+                Stage::Idle => return 0.0,
+                Stage::Sustain => {
+                    self.level = self.sustain * self.velocity_gain;
+                    break;
+                }
+                Stage::Delay => (self.delay, self.stage_start_level),
+                Stage::Attack => (self.attack, self.velocity_gain),
+                Stage::Hold => (self.hold, self.velocity_gain),
+                Stage::Decay => (self.decay, self.sustain * self.velocity_gain),
+                Stage::Release => (self.release, 0.0),
+            };
+
+            if self.stage_time >= duration {
+                self.level = target;
+                let next = match self.stage {
+                    Stage::Delay => Stage::Attack,
+                    Stage::Attack => Stage::Hold,
+                    Stage::Hold => Stage::Decay,
+                    Stage::Decay => Stage::Sustain,
+                    _ => Stage::Idle,
+                };
+                self.enter(next);
+                continue;
+            }
+
+            let progress = self.stage_time / duration;
+            self.level =
+                self.stage_start_level + (target - self.stage_start_level) * self.shape(progress);
+            self.stage_time += 1.0 / self.sample_rate;
+            break;
+        }
+
+        self.level()
+    }
+
+    fn enter(&mut self, stage: Stage) {
+        self.stage = stage;
+        self.stage_time = 0.0;
+        self.stage_start_level = self.level;
+    }
+
+    fn shape(&self, progress: f64) -> f64 {
+        match self.curve {
+            Curve::Linear => progress,
+            Curve::Exponential => {
+                (1.0 - (-EXPONENTIAL_STEEPNESS * progress).exp())
+                    / (1.0 - (-EXPONENTIAL_STEEPNESS).exp())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One sample per millisecond keeps the stage lengths easy to count.
+    const SAMPLE_RATE: f64 = 1000.0;
+
+    fn envelope() -> Adsr {
+        let mut envelope = Adsr::new(SAMPLE_RATE);
+        envelope.set_delay(0.01);
+        envelope.set_attack(0.02);
+        envelope.set_hold(0.01);
+        envelope.set_decay(0.02);
+        envelope.set_sustain(0.5);
+        envelope.set_release(0.04);
+        envelope
+    }
+
+    fn run(envelope: &mut Adsr, samples: usize) -> Vec<Signal> {
+        (0..samples).map(|_| envelope.next_sample()).collect()
+    }
+
+    #[test]
+    fn stages_take_their_time() {
+        let mut envelope = envelope();
+        envelope.note_on(1.0);
+        let levels = run(&mut envelope, 100);
+
+        assert!(levels[..10].iter().all(|&level| level == 0.0));
+        assert!((levels[20] - 0.5).abs() < 1e-9);
+        assert!((levels[30] - 1.0).abs() < 1e-9);
+        assert!((levels[39] - 1.0).abs() < 1e-9);
+        assert!((levels[50] - 0.75).abs() < 1e-9);
+        assert!(levels[60..].iter().all(|&level| (level - 0.5).abs() < 1e-9));
+
+        envelope.note_off();
+        let release = run(&mut envelope, 50);
+        assert!((release[20] - 0.25).abs() < 1e-9);
+        assert_eq!(release[45], 0.0);
+        assert!(!envelope.is_active());
+    }
+
+    #[test]
+    fn exponential_curve_moves_fast_first() {
+        let mut linear = envelope();
+        let mut exponential = envelope();
+        exponential.set_curve(Curve::Exponential);
+        linear.note_on(1.0);
+        exponential.note_on(1.0);
+
+        let linear = run(&mut linear, 100);
+        let exponential = run(&mut exponential, 100);
+        assert!(exponential[15] > linear[15] + 0.2);
+        assert!((exponential[30] - 1.0).abs() < 1e-9);
+        assert!((exponential[70] - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn retrigger_restarts_and_legato_continues() {
+        let mut retrigger = envelope();
+        retrigger.gate(Some(0));
+        run(&mut retrigger, 100);
+        retrigger.gate(Some(1));
+        let levels = run(&mut retrigger, 20);
+        assert!(levels[..10].iter().all(|&level| (level - 0.5).abs() < 1e-9));
+        assert!(levels[19] > 0.7);
+
+        let mut legato = envelope();
+        legato.set_trigger_mode(TriggerMode::Legato);
+        legato.gate(Some(0));
+        run(&mut legato, 100);
+        legato.gate(Some(1));
+        let levels = run(&mut legato, 20);
+        assert!(levels.iter().all(|&level| (level - 0.5).abs() < 1e-9));
+    }
+
+    #[test]
+    fn velocity_scales_the_level() {
+        let mut envelope = envelope();
+        envelope.set_velocity_sensitivity(1.0);
+        envelope.gate_with_velocity(Some(0), 0.5);
+        let levels = run(&mut envelope, 100);
+        assert!((levels[30] - 0.5).abs() < 1e-9);
+        assert!((levels[99] - 0.25).abs() < 1e-9);
+
+        // A louder retrigger starts where the last note was.
+        envelope.gate_with_velocity(Some(1), 1.0);
+        let levels = run(&mut envelope, 100);
+        assert!((levels[0] - 0.25).abs() < 1e-9);
+        assert!((levels[99] - 0.5).abs() < 1e-9);
+
+        let mut ignored = Adsr::new(SAMPLE_RATE);
+        ignored.gate_with_velocity(Some(0), 0.1);
+        let levels = run(&mut ignored, 1000);
+        assert!((levels[999] - 0.7).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,365 @@
+use std::f64::consts::PI;
+use types::Signal;
+
+/// Cutoffs are kept below this fraction of the sample rate, where the prewarping blows up.
+const MAX_CUTOFF_RATIO: f64 = 0.49;
+const MIN_CUTOFF: f64 = 10.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterMode {
+    LowPass,
+    BandPass,
+    HighPass,
+    Notch,
+    Peak,
+}
+
+/// Makes the cutoff follow the played note. With an `amount` of 1.0 the cutoff moves one
+/// octave per octave played, relative to `reference_key`.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyTracking {
+    pub amount: f64,
+    pub reference_key: i32,
+}
+
+impl KeyTracking {
+    pub fn new(amount: f64, reference_key: i32) -> Self {
+        Self {
+            amount,
+            reference_key,
+        }
+    }
+
+    /// Returns `cutoff` moved by the key tracking for `key`, as produced by `KeysState`.
+    pub fn apply(&self, cutoff: f64, key: Option<i32>) -> f64 {
+        match key {
+            Some(key) => {
+                let semitones = f64::from(key - self.reference_key) * self.amount;
+                cutoff * 2f64.powf(semitones / 12.0)
+            }
+            None => cutoff,
+        }
+    }
+}
+
+impl Default for KeyTracking {
+    fn default() -> Self {
+        Self::new(0.0, 0)
+    }
+}
+
+fn prewarp(cutoff: f64, sample_rate: f64) -> f64 {
+    let cutoff = cutoff.clamp(MIN_CUTOFF, sample_rate * MAX_CUTOFF_RATIO);
+    (PI * cutoff / sample_rate).tan()
+}
+
+/// Zero-delay-feedback state-variable filter (trapezoidal integration), which stays stable
+/// when cutoff and resonance change every sample.
+pub struct StateVariableFilter {
+    mode: FilterMode,
+    sample_rate: f64,
+    g: f64,
+    k: f64,
+    a1: f64,
+    a2: f64,
+    a3: f64,
+    ic1eq: Signal,
+    ic2eq: Signal,
+}
+
+impl StateVariableFilter {
+    pub fn new(mode: FilterMode, sample_rate: f64) -> Self {
+        let mut filter = Self {
+            mode,
+            sample_rate,
+            g: 0.0,
+            k: 2.0,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        filter.set_cutoff(1000.0);
+        filter
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_cutoff(&mut self, cutoff: f64) {
+        self.g = prewarp(cutoff, self.sample_rate);
+        self.update_coefficients();
+    }
+
+    /// Resonance in [0.0, 1.0]. Close to 1.0 the filter rings for a long time, but never
+    /// becomes unstable.
+    pub fn set_resonance(&mut self, resonance: f64) {
+        self.k = 2.0 - 1.98 * resonance.clamp(0.0, 1.0);
+        self.update_coefficients();
+    }
+
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    pub fn process(&mut self, input: Signal) -> Signal {
+        let v3 = input - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let low = v2;
+        let band = v1;
+        let high = input - self.k * v1 - v2;
+
+        match self.mode {
+            FilterMode::LowPass => low,
+            FilterMode::BandPass => band,
+            FilterMode::HighPass => high,
+            FilterMode::Notch => low + high,
+            FilterMode::Peak => low - high,
+        }
+    }
+
+    fn update_coefficients(&mut self) {
+        self.a1 = 1.0 / (1.0 + self.g * (self.g + self.k));
+        self.a2 = self.g * self.a1;
+        self.a3 = self.g * self.a2;
+    }
+}
+
+/// First order all-pass, flat in level but shifting the phase by 90 degrees at the cutoff.
+/// Chains of these make a phaser.
+pub struct FirstOrderAllPass {
+    sample_rate: f64,
+    big_g: f64,
+    state: Signal,
+}
+
+impl FirstOrderAllPass {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut filter = Self {
+            sample_rate,
+            big_g: 0.0,
+            state: 0.0,
+        };
+        filter.set_cutoff(1000.0);
+        filter
+    }
+
+    pub fn set_cutoff(&mut self, cutoff: f64) {
+        self.big_g = Self::coefficient(cutoff, self.sample_rate);
+    }
+
+    /// The coefficient for `cutoff`, for sharing between filters with the same cutoff.
+    pub fn coefficient(cutoff: f64, sample_rate: f64) -> f64 {
+        let g = prewarp(cutoff, sample_rate);
+        g / (1.0 + g)
+    }
+
+    /// Sets a cutoff computed with `coefficient`.
+    pub fn set_coefficient(&mut self, coefficient: f64) {
+        self.big_g = coefficient;
+    }
+
+    pub fn reset(&mut self) {
+        self.state = 0.0;
+    }
+
+    pub fn process(&mut self, input: Signal) -> Signal {
+        let v = (input - self.state) * self.big_g;
+        let low = v + self.state;
+        self.state = low + v;
+        2.0 * low - input
+    }
+}
+
+/// Four pole (24 dB/octave) low-pass ladder with the feedback loop solved instantaneously,
+/// so there is no extra unit delay detuning the resonance at high cutoffs. Input drive is
+/// soft-saturated to tame self-oscillation.
+pub struct LadderFilter {
+    sample_rate: f64,
+    big_g: f64,
+    k: f64,
+    drive: f64,
+    stages: [Signal; 4],
+}
+
+impl LadderFilter {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut filter = Self {
+            sample_rate,
+            big_g: 0.0,
+            k: 0.0,
+            drive: 1.0,
+            stages: [0.0; 4],
+        };
+        filter.set_cutoff(1000.0);
+        filter
+    }
+
+    pub fn set_cutoff(&mut self, cutoff: f64) {
+        let g = prewarp(cutoff, self.sample_rate);
+        self.big_g = g / (1.0 + g);
+    }
+
+    /// Resonance in [0.0, 1.0], self-oscillating near the top of the range.
+    pub fn set_resonance(&mut self, resonance: f64) {
+        self.k = 4.2 * resonance.clamp(0.0, 1.0);
+    }
+
+    /// Gain in front of the saturation, 1.0 being clean for signals in [-1.0, 1.0].
+    pub fn set_drive(&mut self, drive: f64) {
+        self.drive = drive.max(0.0);
+    }
+
+    pub fn reset(&mut self) {
+        self.stages = [0.0; 4];
+    }
+
+    pub fn process(&mut self, input: Signal) -> Signal {
+        let g = self.big_g;
+        // Contribution of the stage states to the last output, as if the input was zero.
+        let one_minus_g = 1.0 - g;
+        let sigma = self
+            .stages
+            .iter()
+            .fold(0.0, |sum, s| sum * g + s * one_minus_g);
+        let g4 = g * g * g * g;
+
+        // Passband gain compensation keeps the level roughly constant as resonance rises.
+        let x = input * self.drive * (1.0 + self.k * 0.5);
+        let feedback = (g4 * x + sigma) / (1.0 + self.k * g4);
+        let mut u = (x - self.k * feedback).tanh();
+
+        for stage in self.stages.iter_mut() {
+            let v = (u - *stage) * g;
+            let y = v + *stage;
+            *stage = y + v;
+            u = y;
+        }
+
+        u / self.drive.max(1e-3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    const MODES: [FilterMode; 5] = [
+        FilterMode::LowPass,
+        FilterMode::BandPass,
+        FilterMode::HighPass,
+        FilterMode::Notch,
+        FilterMode::Peak,
+    ];
+
+    /// Peak level of a unit sine after the filter has settled.
+    fn gain_at<F: FnMut(Signal) -> Signal>(mut process: F, frequency: f64) -> Signal {
+        let sine = |n: usize| (2.0 * PI * frequency * n as f64 / SAMPLE_RATE).sin();
+        for n in 0..24_000 {
+            process(sine(n));
+        }
+        (24_000..48_000).fold(0.0, |peak, n| peak.max(process(sine(n)).abs()))
+    }
+
+    fn assert_bounded<F: FnMut(Signal) -> Signal>(mut process: F, bound: Signal) {
+        let mut random = Random::new(1);
+        for n in 0..96_000 {
+            let input = if n == 0 { 1.0 } else { random.next_bipolar() };
+            let output = process(input);
+            assert!(
+                output.is_finite() && output.abs() < bound,
+                "{} at {}",
+                output,
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn state_variable_filter_is_stable_at_extremes() {
+        for &mode in MODES.iter() {
+            for &cutoff in [SAMPLE_RATE * 0.45, SAMPLE_RATE].iter() {
+                let mut filter = StateVariableFilter::new(mode, SAMPLE_RATE);
+                filter.set_resonance(1.0);
+                filter.set_cutoff(cutoff);
+                assert_bounded(|input| filter.process(input), 1000.0);
+            }
+
+            // Cutoff swept every sample.
+            let mut filter = StateVariableFilter::new(mode, SAMPLE_RATE);
+            filter.set_resonance(1.0);
+            let mut n = 0;
+            assert_bounded(
+                |input| {
+                    n += 1;
+                    filter.set_cutoff(if n % 2 == 0 { 20.0 } else { 23_000.0 });
+                    filter.process(input)
+                },
+                1000.0,
+            );
+        }
+    }
+
+    #[test]
+    fn ladder_filter_is_stable_at_extremes() {
+        for &cutoff in [SAMPLE_RATE * 0.45, SAMPLE_RATE].iter() {
+            for &drive in [1.0, 10.0].iter() {
+                let mut filter = LadderFilter::new(SAMPLE_RATE);
+                filter.set_resonance(1.0);
+                filter.set_cutoff(cutoff);
+                filter.set_drive(drive);
+                assert_bounded(|input| filter.process(input), 10.0);
+            }
+        }
+    }
+
+    #[test]
+    fn state_variable_filter_modes() {
+        let gain = |mode, frequency| {
+            let mut filter = StateVariableFilter::new(mode, SAMPLE_RATE);
+            filter.set_cutoff(1000.0);
+            filter.set_resonance(0.0);
+            gain_at(|input| filter.process(input), frequency)
+        };
+
+        assert!(gain(FilterMode::LowPass, 100.0) > 0.95);
+        assert!(gain(FilterMode::LowPass, 10_000.0) < 0.02);
+        assert!(gain(FilterMode::HighPass, 100.0) < 0.02);
+        assert!(gain(FilterMode::HighPass, 10_000.0) > 0.95);
+        assert!((gain(FilterMode::BandPass, 1000.0) - 0.5).abs() < 0.01);
+        assert!(gain(FilterMode::BandPass, 100.0) < 0.11);
+        assert!(gain(FilterMode::Notch, 1000.0) < 0.01);
+        assert!(gain(FilterMode::Notch, 100.0) > 0.95);
+        assert!(gain(FilterMode::Peak, 100.0) > 0.95);
+        assert!(gain(FilterMode::Peak, 10_000.0) > 0.95);
+    }
+
+    #[test]
+    fn resonance_boosts_the_cutoff() {
+        let mut filter = StateVariableFilter::new(FilterMode::LowPass, SAMPLE_RATE);
+        filter.set_cutoff(1000.0);
+        filter.set_resonance(0.9);
+        assert!(gain_at(|input| filter.process(input), 1000.0) > 4.0);
+    }
+
+    #[test]
+    fn key_tracking_follows_octaves() {
+        let full = KeyTracking::new(1.0, 60);
+        assert!((full.apply(1000.0, Some(72)) - 2000.0).abs() < 1e-9);
+        assert!((full.apply(1000.0, Some(48)) - 500.0).abs() < 1e-9);
+        assert_eq!(full.apply(1000.0, None), 1000.0);
+
+        let half = KeyTracking::new(0.5, 60);
+        assert!((half.apply(1000.0, Some(72)) - 1000.0 * 2f64.sqrt()).abs() < 1e-9);
+        assert_eq!(KeyTracking::default().apply(1000.0, Some(90)), 1000.0);
+    }
+}
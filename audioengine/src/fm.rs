@@ -0,0 +1,344 @@
+use envelope::Adsr;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use tuning::Tuning;
+use types::{ContextProcessorFunction, Phase, Signal};
+
+pub const MAX_OPERATORS: usize = 6;
+/// Phase deviation, in cycles, caused by a modulator at full level.
+const MODULATION_DEPTH: Phase = 1.0;
+/// Phase deviation, in cycles, of an operator fed back at full feedback.
+const FEEDBACK_DEPTH: Phase = 0.25;
+
+/// How the operators are connected. Operators are numbered from 0 and may only modulate
+/// operators with a lower number, so each sample can be computed in one pass from the
+/// highest operator down.
+#[derive(Clone, Debug)]
+pub struct Algorithm {
+    operator_count: usize,
+    /// (modulator, target) pairs.
+    modulations: Vec<(usize, usize)>,
+    carriers: Vec<usize>,
+    feedback_operator: usize,
+}
+
+impl Algorithm {
+    /// Panics if an operator is out of range or a modulation goes upwards.
+    pub fn new(
+        operator_count: usize,
+        modulations: &[(usize, usize)],
+        carriers: &[usize],
+        feedback_operator: usize,
+    ) -> Self {
+        assert!(operator_count > 0 && operator_count <= MAX_OPERATORS);
+        assert!(modulations
+            .iter()
+            .all(|&(modulator, target)| modulator < operator_count && target < modulator));
+        assert!(!carriers.is_empty() && carriers.iter().all(|&c| c < operator_count));
+        assert!(feedback_operator < operator_count);
+
+        Self {
+            operator_count,
+            modulations: modulations.to_vec(),
+            carriers: carriers.to_vec(),
+            feedback_operator,
+        }
+    }
+
+    /// The eight classic four operator algorithms, numbered from 1 like on the hardware.
+    /// Operator 4 (index 3) has the feedback loop.
+    pub fn four_operator(number: usize) -> Self {
+        let (modulations, carriers): (&[(usize, usize)], &[usize]) = match number {
+            1 => (&[(3, 2), (2, 1), (1, 0)], &[0]),
+            2 => (&[(3, 1), (2, 1), (1, 0)], &[0]),
+            3 => (&[(3, 0), (2, 1), (1, 0)], &[0]),
+            4 => (&[(3, 2), (2, 0), (1, 0)], &[0]),
+            5 => (&[(3, 2), (1, 0)], &[0, 2]),
+            6 => (&[(3, 2), (3, 1), (3, 0)], &[0, 1, 2]),
+            7 => (&[(3, 2)], &[0, 1, 2]),
+            _ => (&[], &[0, 1, 2, 3]),
+        };
+        Self::new(4, modulations, carriers, 3)
+    }
+
+    /// A selection of six operator algorithms, numbered like their DX7 counterparts: 1, 2,
+    /// 5, 7, 19, 22, 31 and 32. Other numbers give 32, every operator a carrier.
+    pub fn six_operator(number: usize) -> Self {
+        let stack = [(5, 4), (4, 3), (3, 2), (1, 0)];
+        match number {
+            1 => Self::new(6, &stack, &[0, 2], 5),
+            2 => Self::new(6, &stack, &[0, 2], 1),
+            5 => Self::new(6, &[(5, 4), (3, 2), (1, 0)], &[0, 2, 4], 5),
+            7 => Self::new(6, &[(5, 4), (4, 2), (3, 2), (1, 0)], &[0, 2], 5),
+            19 => Self::new(6, &[(5, 4), (5, 3), (2, 1), (1, 0)], &[0, 3, 4], 5),
+            22 => Self::new(6, &[(5, 4), (5, 3), (5, 2), (1, 0)], &[0, 2, 3, 4], 5),
+            31 => Self::new(6, &[(5, 4)], &[0, 1, 2, 3, 4], 5),
+            _ => Self::new(6, &[], &[0, 1, 2, 3, 4, 5], 5),
+        }
+    }
+
+    pub fn operator_count(&self) -> usize {
+        self.operator_count
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperatorFrequency {
+    /// Multiple of the note frequency.
+    Ratio(f64),
+    /// In Hz, whatever note is played.
+    Fixed(f64),
+}
+
+/// Sine oscillator with its own level envelope, whose phase can be modulated by other
+/// operators.
+pub struct Operator {
+    sample_rate: f64,
+    frequency: OperatorFrequency,
+    detune: f64,
+    level: Signal,
+    envelope: Adsr,
+    phase: Phase,
+    increment: Phase,
+}
+
+impl Operator {
+    fn new(sample_rate: f64) -> Self {
+        let mut envelope = Adsr::new(sample_rate);
+        envelope.set_sustain(1.0);
+        Self {
+            sample_rate,
+            frequency: OperatorFrequency::Ratio(1.0),
+            detune: 0.0,
+            level: 1.0,
+            envelope,
+            phase: 0.0,
+            increment: 0.0,
+        }
+    }
+
+    pub fn set_frequency(&mut self, frequency: OperatorFrequency) {
+        self.frequency = frequency;
+    }
+
+    /// Fine tuning in cents, applied to both ratio and fixed frequencies.
+    pub fn set_detune(&mut self, cents: f64) {
+        self.detune = cents;
+    }
+
+    /// Output level in [0.0, 1.0]. For a modulator this sets the modulation index.
+    pub fn set_level(&mut self, level: Signal) {
+        self.level = level.clamp(0.0, 1.0);
+    }
+
+    /// The level envelope. Its velocity sensitivity sets how much velocity scales the
+    /// operator.
+    pub fn envelope_mut(&mut self) -> &mut Adsr {
+        &mut self.envelope
+    }
+
+    fn note_on(&mut self, note_frequency: f64, velocity: Signal) {
+        let frequency = match self.frequency {
+            OperatorFrequency::Ratio(ratio) => note_frequency * ratio,
+            OperatorFrequency::Fixed(frequency) => frequency,
+        } * 2f64.powf(self.detune / 1200.0);
+        self.increment = frequency.clamp(0.0, self.sample_rate * 0.5) / self.sample_rate;
+        self.envelope.note_on(velocity);
+    }
+
+    fn next_sample(&mut self, modulation: Phase) -> Signal {
+        let output = (2.0 * PI * (self.phase + modulation)).sin();
+        self.phase += self.increment;
+        self.phase -= self.phase.floor();
+        output * self.level * self.envelope.next_sample()
+    }
+}
+
+/// Monophonic phase modulation voice with up to six operators.
+pub struct FmVoice {
+    algorithm: Algorithm,
+    operators: Vec<Operator>,
+    feedback: Signal,
+    feedback_history: [Signal; 2],
+    tuning: Arc<Tuning>,
+    current_key: Option<i32>,
+}
+
+impl FmVoice {
+    pub fn new(algorithm: Algorithm, sample_rate: f64) -> Self {
+        Self {
+            algorithm,
+            operators: (0..MAX_OPERATORS)
+                .map(|_| Operator::new(sample_rate))
+                .collect(),
+            feedback: 0.0,
+            feedback_history: [0.0; 2],
+            tuning: Arc::new(Tuning::default()),
+            current_key: None,
+        }
+    }
+
+    /// Takes effect immediately. Operators keep their settings.
+    pub fn set_algorithm(&mut self, algorithm: Algorithm) {
+        self.algorithm = algorithm;
+    }
+
+    pub fn operator_mut(&mut self, index: usize) -> &mut Operator {
+        &mut self.operators[index]
+    }
+
+    /// Self modulation of the algorithm's feedback operator in [0.0, 1.0], turning its sine
+    /// towards a saw and eventually noise.
+    pub fn set_feedback(&mut self, feedback: Signal) {
+        self.feedback = feedback.clamp(0.0, 1.0);
+    }
+
+    /// Tuning `gate` plays keys in. Keys it leaves unmapped release the note.
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+    }
+
+    /// Starts a note. `velocity` is in [0.0, 1.0].
+    pub fn note_on(&mut self, frequency: f64, velocity: Signal) {
+        for operator in self.operators.iter_mut() {
+            operator.note_on(frequency, velocity);
+        }
+    }
+
+    pub fn note_off(&mut self) {
+        for operator in self.operators.iter_mut() {
+            operator.envelope.note_off();
+        }
+    }
+
+    /// Drives the voice from the key handed to a processor function. A different key counts
+    /// as a new note, played at full velocity.
+    pub fn gate(&mut self, key: Option<i32>) {
+        self.gate_with_velocity(key, 1.0);
+    }
+
+    /// Like `gate`, starting new notes at `velocity`.
+    pub fn gate_with_velocity(&mut self, key: Option<i32>, velocity: Signal) {
+        if key == self.current_key {
+            return;
+        }
+        match key.and_then(|key| self.tuning.frequency(f64::from(key))) {
+            Some(frequency) => self.note_on(frequency, velocity),
+            None => self.note_off(),
+        }
+        self.current_key = key;
+    }
+
+    /// Whether any carrier is still sounding.
+    pub fn is_active(&self) -> bool {
+        self.algorithm
+            .carriers
+            .iter()
+            .any(|&carrier| self.operators[carrier].envelope.is_active())
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        let algorithm = &self.algorithm;
+        let mut modulation = [0.0; MAX_OPERATORS];
+        let mut output = 0.0;
+
+        for index in (0..algorithm.operator_count).rev() {
+            let mut phase_modulation = modulation[index];
+            if index == algorithm.feedback_operator {
+                // Averaging the last two outputs keeps high feedback from oscillating at
+                // Nyquist.
+                let [last, previous] = self.feedback_history;
+                phase_modulation += (last + previous) * 0.5 * self.feedback * FEEDBACK_DEPTH;
+            }
+
+            let sample = self.operators[index].next_sample(phase_modulation);
+
+            if index == algorithm.feedback_operator {
+                self.feedback_history = [sample, self.feedback_history[0]];
+            }
+            for &(_, target) in algorithm.modulations.iter().filter(|m| m.0 == index) {
+                modulation[target] += sample * MODULATION_DEPTH;
+            }
+            if algorithm.carriers.contains(&index) {
+                output += sample;
+            }
+        }
+
+        output / algorithm.carriers.len() as Signal
+    }
+
+    /// Wraps the voice in a processor function for
+    /// `EngineController::set_context_processor_function`, playing notes at the velocity of
+    /// the context, like that of a sequencer step.
+    pub fn into_processor_function(mut self) -> ContextProcessorFunction {
+        Box::new(move |key, context| {
+            self.gate_with_velocity(key, context.velocity);
+            let signal = self.next_sample();
+            (signal, signal)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::ProcessContext;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn carrier_without_modulation_is_a_sine() {
+        let mut voice = FmVoice::new(Algorithm::four_operator(1), SAMPLE_RATE);
+        for index in 1..4 {
+            voice.operator_mut(index).set_level(0.0);
+        }
+        voice.operator_mut(0).envelope_mut().set_attack(0.0);
+        voice.note_on(1000.0, 1.0);
+
+        for i in 0..100 {
+            let expected = (2.0 * PI * 1000.0 * f64::from(i) / SAMPLE_RATE).sin();
+            assert!((voice.next_sample() - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn modulator_changes_the_carrier() {
+        let mut plain = FmVoice::new(Algorithm::four_operator(8), SAMPLE_RATE);
+        let mut modulated = FmVoice::new(Algorithm::four_operator(1), SAMPLE_RATE);
+        plain.note_on(440.0, 1.0);
+        modulated.note_on(440.0, 1.0);
+
+        let difference: Signal = (0..4800)
+            .map(|_| (plain.next_sample() - modulated.next_sample()).abs())
+            .sum();
+        assert!(difference > 1.0);
+    }
+
+    #[test]
+    fn processor_plays_at_the_velocity_of_the_context() {
+        let peak = |velocity: Signal| {
+            let mut voice = FmVoice::new(Algorithm::four_operator(8), SAMPLE_RATE);
+            for index in 0..4 {
+                voice
+                    .operator_mut(index)
+                    .envelope_mut()
+                    .set_velocity_sensitivity(1.0);
+            }
+            let mut processor = voice.into_processor_function();
+            let context = ProcessContext {
+                velocity,
+                ..ProcessContext::default()
+            };
+            (0..4800).fold(0.0, |peak: Signal, _| {
+                peak.max(processor(Some(0), &context).0.abs())
+            })
+        };
+        assert!((peak(0.5) / peak(1.0) - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn upward_modulation_is_rejected() {
+        Algorithm::new(4, &[(0, 1)], &[0], 3);
+    }
+}
@@ -0,0 +1,367 @@
+use delay::DelayLine;
+use random::Random;
+use std::f64::consts::{FRAC_PI_4, PI, SQRT_2};
+use std::sync::Arc;
+use tuning::Tuning;
+use types::{Signal, StereoSignal, StereoSignalProcessorFunction};
+use wav::WavData;
+
+pub const MAX_GRAINS: usize = 64;
+/// How far back, in seconds, grains can reach into the live input.
+const LIVE_BUFFER_TIME: f64 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GrainWindow {
+    Hann,
+    Triangle,
+    /// Flat with short cosine ramps over the outer quarters, keeping more of the source.
+    Tukey,
+    Rectangle,
+}
+
+impl GrainWindow {
+    /// Gain at `t`, the position within the grain in [0.0, 1.0].
+    fn gain(self, t: f64) -> Signal {
+        match self {
+            GrainWindow::Hann => 0.5 - 0.5 * (2.0 * PI * t).cos(),
+            GrainWindow::Triangle => 1.0 - (2.0 * t - 1.0).abs(),
+            GrainWindow::Tukey => {
+                let edge = t.min(1.0 - t) * 4.0;
+                if edge >= 1.0 {
+                    1.0
+                } else {
+                    0.5 - 0.5 * (PI * edge).cos()
+                }
+            }
+            GrainWindow::Rectangle => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Grain {
+    active: bool,
+    // Frame in the sample, or delay into the live buffer, at the start of the grain.
+    start: f64,
+    step: f64,
+    // Samples played, fractional so grains can start between samples.
+    age: f64,
+    length: f64,
+    pan: StereoSignal,
+}
+
+/// Granular voice. Grains are read from a loaded sample, or from live input passed to
+/// `process`, and spawned at sample accurate intervals.
+pub struct Granular {
+    sample_rate: f64,
+    sample: Option<Arc<WavData>>,
+    sample_frames: Vec<Signal>,
+    live: DelayLine,
+    grains: [Grain; MAX_GRAINS],
+    random: Random,
+    running: bool,
+    samples_to_next_grain: f64,
+
+    grain_size: f64,
+    density: f64,
+    position: f64,
+    jitter: f64,
+    pitch: f64,
+    // Ratio of the frequency of the played key to that of key 0.
+    key_ratio: f64,
+    tuning: Arc<Tuning>,
+    spread: Signal,
+    window: GrainWindow,
+    mix: Signal,
+}
+
+impl Granular {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            sample: None,
+            sample_frames: Vec::new(),
+            live: DelayLine::new((LIVE_BUFFER_TIME * sample_rate).ceil() as usize),
+            grains: [Grain::default(); MAX_GRAINS],
+            random: Random::default(),
+            running: true,
+            samples_to_next_grain: 0.0,
+            grain_size: 0.08,
+            density: 20.0,
+            position: 0.5,
+            jitter: 0.05,
+            pitch: 0.0,
+            key_ratio: 1.0,
+            tuning: Arc::new(Tuning::default()),
+            spread: 0.5,
+            window: GrainWindow::Hann,
+            mix: 1.0,
+        }
+    }
+
+    /// Reads grains from `sample`, mixed to mono, or from the live input with `None`.
+    pub fn set_sample(&mut self, sample: Option<Arc<WavData>>) {
+        self.sample_frames = sample.as_ref().map_or(Vec::new(), |s| s.to_mono());
+        self.sample = sample;
+        for grain in self.grains.iter_mut() {
+            grain.active = false;
+        }
+    }
+
+    /// Grain length in seconds.
+    pub fn set_grain_size(&mut self, seconds: f64) {
+        self.grain_size = seconds.max(0.0);
+    }
+
+    /// Grains started per second.
+    pub fn set_density(&mut self, grains_per_second: f64) {
+        self.density = grains_per_second.max(0.0);
+    }
+
+    /// Where grains start, in [0.0, 1.0]. For a sample this is the share of its length, for
+    /// live input how far back in the buffer, 0.0 being the most recent input.
+    pub fn set_position(&mut self, position: f64) {
+        self.position = position.clamp(0.0, 1.0);
+    }
+
+    /// Random variation of the start position, as a share of the whole range.
+    pub fn set_jitter(&mut self, jitter: f64) {
+        self.jitter = jitter.clamp(0.0, 1.0);
+    }
+
+    /// Transposition of the grains in semitones.
+    pub fn set_pitch(&mut self, semitones: f64) {
+        self.pitch = semitones;
+    }
+
+    /// Tuning `gate` transposes keys in. Keys it leaves unmapped stop the grains.
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+    }
+
+    /// How far grains are randomly panned from the centre, in [0.0, 1.0].
+    pub fn set_spread(&mut self, spread: Signal) {
+        self.spread = spread.clamp(0.0, 1.0);
+    }
+
+    pub fn set_window(&mut self, window: GrainWindow) {
+        self.window = window;
+    }
+
+    /// Balance between the live input at 0.0 and only the grains at 1.0, used by `process`.
+    pub fn set_mix(&mut self, mix: Signal) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+
+    pub fn set_seed(&mut self, seed: u32) {
+        self.random = Random::new(seed);
+    }
+
+    /// Starts spawning grains, the first one on the next sample.
+    pub fn start(&mut self) {
+        if !self.running {
+            self.running = true;
+            self.samples_to_next_grain = 0.0;
+        }
+    }
+
+    /// Stops spawning grains. The ones already playing finish.
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    /// Drives the voice from the key handed to a processor function. Keys transpose relative
+    /// to key 0.
+    pub fn gate(&mut self, key: Option<i32>) {
+        match key.and_then(|key| self.tuning.interval(0.0, f64::from(key))) {
+            Some(ratio) => {
+                self.key_ratio = ratio;
+                self.start();
+            }
+            None => self.stop(),
+        }
+    }
+
+    pub fn active_grains(&self) -> usize {
+        self.grains.iter().filter(|grain| grain.active).count()
+    }
+
+    /// Spawns a grain now, independent of the density. `offset` is how far, as a fraction of
+    /// a sample, the grain should already have played.
+    pub fn trigger_grain(&mut self, offset: f64) {
+        let length = self.grain_size * self.sample_rate;
+        if length < 1.0 {
+            return;
+        }
+
+        let source_rate = self
+            .sample
+            .as_ref()
+            .map_or(self.sample_rate, |s| f64::from(s.sample_rate));
+        let step = 2f64.powf(self.pitch / 12.0) * self.key_ratio * source_rate / self.sample_rate;
+
+        let position = (self.position + self.jitter * self.random.next_bipolar()).clamp(0.0, 1.0);
+        let start = if self.sample.is_some() {
+            position * self.sample_frames.len() as f64
+        } else {
+            // Far enough back that the grain does not overtake the input when pitched up.
+            let headroom = (step - 1.0).max(0.0) * length + 1.0;
+            (position * self.live.max_delay()).max(headroom)
+        };
+
+        let angle = (self.spread * self.random.next_bipolar() + 1.0) * FRAC_PI_4;
+        let pan = (angle.cos() * SQRT_2, angle.sin() * SQRT_2);
+
+        if let Some(grain) = self.grains.iter_mut().find(|grain| !grain.active) {
+            *grain = Grain {
+                active: true,
+                start,
+                step,
+                age: offset,
+                length,
+                pan,
+            };
+        }
+    }
+
+    /// Plays grains from the loaded sample.
+    pub fn next_sample(&mut self) -> StereoSignal {
+        self.schedule();
+        self.render()
+    }
+
+    /// Records `input` for live grains and returns it mixed with the grains.
+    pub fn process(&mut self, (left, right): StereoSignal) -> StereoSignal {
+        self.schedule();
+        let (wet_left, wet_right) = self.render();
+        self.live.write((left + right) * 0.5);
+
+        let dry = 1.0 - self.mix;
+        (
+            left * dry + wet_left * self.mix,
+            right * dry + wet_right * self.mix,
+        )
+    }
+
+    /// Wraps the voice in a processor function for
+    /// `EngineController::set_stereo_processor_function`.
+    pub fn into_processor_function(mut self) -> StereoSignalProcessorFunction {
+        Box::new(move |key| {
+            self.gate(key);
+            self.next_sample()
+        })
+    }
+
+    fn schedule(&mut self) {
+        if !self.running || self.density <= 0.0 {
+            return;
+        }
+
+        let interval = self.sample_rate / self.density;
+        while self.samples_to_next_grain <= 0.0 {
+            self.trigger_grain(-self.samples_to_next_grain);
+            self.samples_to_next_grain += interval;
+        }
+        self.samples_to_next_grain -= 1.0;
+    }
+
+    fn render(&mut self) -> StereoSignal {
+        // Overlapping grains add up, so scale by the expected overlap.
+        let overlap = self.density * self.grain_size;
+        let gain = 1.0 / overlap.sqrt().max(1.0);
+        let mut output = (0.0, 0.0);
+
+        for grain in self.grains.iter_mut().filter(|grain| grain.active) {
+            let value = match self.sample {
+                Some(_) => read_frames(&self.sample_frames, grain.start + grain.age * grain.step),
+                None => self.live.read(grain.start + grain.age * (1.0 - grain.step)),
+            };
+            let value = value * self.window.gain(grain.age / grain.length) * gain;
+            output.0 += value * grain.pan.0;
+            output.1 += value * grain.pan.1;
+
+            grain.age += 1.0;
+            if grain.age >= grain.length {
+                grain.active = false;
+            }
+        }
+
+        output
+    }
+}
+
+fn read_frames(frames: &[Signal], position: f64) -> Signal {
+    let index = position.floor();
+    let fraction = position - index;
+    let index = index as usize;
+    match (frames.get(index), frames.get(index + 1)) {
+        (Some(&current), Some(&next)) => current + (next - current) * fraction,
+        (Some(&current), None) => current * (1.0 - fraction),
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 1000.0;
+
+    fn granular() -> Granular {
+        let mut granular = Granular::new(SAMPLE_RATE);
+        granular.set_sample(Some(Arc::new(WavData {
+            sample_rate: SAMPLE_RATE as u32,
+            channels: vec![vec![1.0; 1000]],
+        })));
+        granular.set_window(GrainWindow::Rectangle);
+        granular.set_spread(0.0);
+        granular.set_jitter(0.0);
+        granular
+    }
+
+    #[test]
+    fn grains_start_on_exact_samples() {
+        let mut granular = granular();
+        granular.set_density(100.0);
+        granular.set_grain_size(0.001);
+
+        for i in 0..1000 {
+            let (left, right) = granular.next_sample();
+            let expected = if i % 10 == 0 { 1.0 } else { 0.0 };
+            assert!((left - expected).abs() < 1e-12, "sample {}", i);
+            assert!((right - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn stopping_lets_grains_finish() {
+        let mut granular = granular();
+        granular.set_density(10.0);
+        granular.set_grain_size(0.05);
+
+        granular.next_sample();
+        granular.stop();
+        assert_eq!(granular.active_grains(), 1);
+        for _ in 0..49 {
+            granular.next_sample();
+        }
+        assert_eq!(granular.active_grains(), 0);
+    }
+
+    #[test]
+    fn live_grains_replay_the_input() {
+        let mut granular = Granular::new(SAMPLE_RATE);
+        granular.set_window(GrainWindow::Rectangle);
+        granular.set_spread(0.0);
+        granular.set_jitter(0.0);
+        granular.set_position(0.0);
+        granular.set_density(0.0);
+
+        for _ in 0..100 {
+            granular.process((0.5, 0.5));
+        }
+        granular.trigger_grain(0.0);
+        let (left, _) = granular.process((0.0, 0.0));
+        assert!((left - 0.5).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,650 @@
+use std::fmt;
+use std::mem;
+use std::sync::mpsc::{Receiver, Sender};
+use transport::ProcessContext;
+use types::{ContextProcessorFunction, Signal, StereoSignal};
+
+/// Samples a graph processes at a time. Keys reach the nodes one block late.
+pub const BLOCK_SIZE: usize = 64;
+
+pub type NodeId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortKind {
+    /// One value per sample.
+    Audio,
+    /// One value per block.
+    Control,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortSpec {
+    pub name: &'static str,
+    pub kind: PortKind,
+    /// Value of an input with nothing connected.
+    pub default: Signal,
+}
+
+impl PortSpec {
+    pub const fn audio(name: &'static str, default: Signal) -> Self {
+        Self {
+            name,
+            kind: PortKind::Audio,
+            default,
+        }
+    }
+
+    pub const fn control(name: &'static str, default: Signal) -> Self {
+        Self {
+            name,
+            kind: PortKind::Control,
+            default,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParameterSpec {
+    pub name: &'static str,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+}
+
+/// What every node sees of the block being processed.
+pub struct BlockContext<'a> {
+    pub sample_rate: f64,
+    /// Key at each sample of the block, as handed to processor functions.
+    pub keys: &'a [Option<i32>],
+    /// Transport at the start of the block.
+    pub transport: ProcessContext,
+}
+
+/// A module of a `Graph`. Nodes run on the audio thread, so `process` should not allocate
+/// or block.
+pub trait Node: Send {
+    fn inputs(&self) -> &[PortSpec];
+
+    fn outputs(&self) -> &[PortSpec];
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &[]
+    }
+
+    /// Values are in the range of the parameter's spec.
+    fn set_parameter(&mut self, _index: usize, _value: f64) {}
+
+    /// Fills `outputs` from `inputs`, one buffer per port. Audio buffers hold `BLOCK_SIZE`
+    /// values and control buffers one.
+    fn process(
+        &mut self,
+        context: &BlockContext,
+        inputs: &[Vec<Signal>],
+        outputs: &mut [Vec<Signal>],
+    );
+}
+
+/// A cable from an output port of one node to an input port of another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Connection {
+    pub from: NodeId,
+    pub output: usize,
+    pub to: NodeId,
+    pub input: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GraphError {
+    NoSuchNode(NodeId),
+    NoSuchPort(Connection),
+    /// Audio and control ports cannot be connected to each other.
+    KindMismatch(Connection),
+    AlreadyConnected(Connection),
+    /// The output node needs an audio output.
+    NotAudio(NodeId),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphError::NoSuchNode(id) => write!(f, "No node {}", id),
+            GraphError::NoSuchPort(c) => write!(f, "No port for {:?}", c),
+            GraphError::KindMismatch(c) => write!(f, "Port kinds differ for {:?}", c),
+            GraphError::AlreadyConnected(c) => write!(f, "Already connected: {:?}", c),
+            GraphError::NotAudio(id) => write!(f, "Node {} has no audio output", id),
+        }
+    }
+}
+
+/// Changes sent to a graph playing on the audio thread.
+pub enum GraphUpdate {
+    /// Crossfades to a new graph over one block.
+    Replace(Graph),
+    SetParameter(NodeId, usize, f64),
+}
+
+struct Slot {
+    node: Box<dyn Node>,
+    inputs: Vec<Vec<Signal>>,
+    outputs: Vec<Vec<Signal>>,
+}
+
+fn port_buffers(ports: &[PortSpec]) -> Vec<Vec<Signal>> {
+    ports
+        .iter()
+        .map(|port| match port.kind {
+            PortKind::Audio => vec![0.0; BLOCK_SIZE],
+            PortKind::Control => vec![0.0],
+        })
+        .collect()
+}
+
+/// Nodes and the connections between them, processed a block at a time in dependency
+/// order. Connections that close a loop carry the signal of the previous block.
+///
+/// Build and edit graphs outside the audio thread, then hand them over with
+/// `into_processor_function` and `GraphUpdate::Replace`.
+pub struct Graph {
+    sample_rate: f64,
+    // Removed nodes leave a hole so ids stay valid.
+    nodes: Vec<Option<Slot>>,
+    connections: Vec<Connection>,
+    feedback: Vec<bool>,
+    order: Vec<NodeId>,
+    output: Option<NodeId>,
+}
+
+impl Graph {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            nodes: Vec::new(),
+            connections: Vec::new(),
+            feedback: Vec::new(),
+            order: Vec::new(),
+            output: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub fn add_node(&mut self, node: Box<dyn Node>) -> NodeId {
+        let slot = Slot {
+            inputs: port_buffers(node.inputs()),
+            outputs: port_buffers(node.outputs()),
+            node,
+        };
+        self.nodes.push(Some(slot));
+        self.sort();
+        self.nodes.len() - 1
+    }
+
+    /// Removes a node with its connections.
+    pub fn remove_node(&mut self, id: NodeId) -> Option<Box<dyn Node>> {
+        let slot = self.nodes.get_mut(id)?.take()?;
+        self.connections.retain(|c| c.from != id && c.to != id);
+        if self.output == Some(id) {
+            self.output = None;
+        }
+        self.sort();
+        Some(slot.node)
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&dyn Node> {
+        let slot = self.nodes.get(id)?.as_ref()?;
+        Some(&*slot.node)
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> Option<&mut (dyn Node + 'static)> {
+        let slot = self.nodes.get_mut(id)?.as_mut()?;
+        Some(&mut *slot.node)
+    }
+
+    /// Ids of the nodes in the graph, lowest first.
+    pub fn node_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .map(|(id, _)| id)
+    }
+
+    /// Connects an output to an input of the same kind. Several cables into one input are
+    /// summed.
+    pub fn connect(&mut self, connection: Connection) -> Result<(), GraphError> {
+        let from = self.node(connection.from);
+        let from = from.ok_or(GraphError::NoSuchNode(connection.from))?;
+        let to = self.node(connection.to);
+        let to = to.ok_or(GraphError::NoSuchNode(connection.to))?;
+        let output = from.outputs().get(connection.output);
+        let input = to.inputs().get(connection.input);
+        match (output, input) {
+            (Some(output), Some(input)) if output.kind != input.kind => {
+                return Err(GraphError::KindMismatch(connection))
+            }
+            (Some(_), Some(_)) => (),
+            _ => return Err(GraphError::NoSuchPort(connection)),
+        }
+        if self.connections.contains(&connection) {
+            return Err(GraphError::AlreadyConnected(connection));
+        }
+
+        self.connections.push(connection);
+        self.sort();
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, connection: Connection) {
+        self.connections.retain(|&c| c != connection);
+        self.sort();
+    }
+
+    pub fn connections(&self) -> &[Connection] {
+        &self.connections
+    }
+
+    /// Whether a connection closes a loop, and so is delayed by a block.
+    pub fn is_feedback(&self, connection: Connection) -> bool {
+        self.connections
+            .iter()
+            .position(|&c| c == connection)
+            .is_some_and(|index| self.feedback[index])
+    }
+
+    /// Plays the first two outputs of a node as left and right, or its only output on both.
+    pub fn set_output(&mut self, id: Option<NodeId>) -> Result<(), GraphError> {
+        if let Some(id) = id {
+            let node = self.node(id).ok_or(GraphError::NoSuchNode(id))?;
+            let audio = node.outputs().first().map(|port| port.kind);
+            if audio != Some(PortKind::Audio) {
+                return Err(GraphError::NotAudio(id));
+            }
+        }
+        self.output = id;
+        Ok(())
+    }
+
+    pub fn output(&self) -> Option<NodeId> {
+        self.output
+    }
+
+    /// Runs every node once and writes the output node's signal to `output`.
+    pub fn process_block(&mut self, context: &BlockContext, output: &mut [StereoSignal]) {
+        for index in 0..self.order.len() {
+            let id = self.order[index];
+            let mut inputs = match self.nodes[id].as_mut() {
+                Some(slot) => mem::take(&mut slot.inputs),
+                None => continue,
+            };
+            self.gather_inputs(id, &mut inputs);
+
+            let slot = self.nodes[id].as_mut().unwrap();
+            slot.node.process(context, &inputs, &mut slot.outputs);
+            slot.inputs = inputs;
+        }
+
+        let outputs = self
+            .output
+            .and_then(|id| self.nodes[id].as_ref())
+            .map(|slot| &slot.outputs);
+        match outputs {
+            Some(outputs) => {
+                let left = &outputs[0];
+                let right = outputs
+                    .get(1)
+                    .filter(|buffer| buffer.len() == BLOCK_SIZE)
+                    .unwrap_or(left);
+                for (index, frame) in output.iter_mut().enumerate() {
+                    *frame = (left[index], right[index]);
+                }
+            }
+            None => {
+                for frame in output.iter_mut() {
+                    *frame = (0.0, 0.0);
+                }
+            }
+        }
+    }
+
+    /// Wraps the graph in a processor function for
+    /// `EngineController::set_context_processor_function`, which takes further changes
+    /// from `updates`. Graphs that are replaced come back through `retired`, so they are
+    /// dropped off the audio thread.
+    pub fn into_processor_function(
+        self,
+        updates: Receiver<GraphUpdate>,
+        retired: Sender<Graph>,
+    ) -> ContextProcessorFunction {
+        let mut player = GraphPlayer {
+            graph: self,
+            previous: None,
+            updates,
+            retired,
+            keys: [None; BLOCK_SIZE],
+            output: [(0.0, 0.0); BLOCK_SIZE],
+            previous_output: [(0.0, 0.0); BLOCK_SIZE],
+            position: 0,
+        };
+        Box::new(move |key, context| player.next_sample(key, context))
+    }
+
+    fn gather_inputs(&self, id: NodeId, inputs: &mut [Vec<Signal>]) {
+        let ports = self.nodes[id].as_ref().unwrap().node.inputs();
+        for (buffer, port) in inputs.iter_mut().zip(ports) {
+            for value in buffer.iter_mut() {
+                *value = port.default;
+            }
+        }
+
+        let incoming = || self.connections.iter().filter(move |c| c.to == id);
+        for connection in incoming() {
+            for value in inputs[connection.input].iter_mut() {
+                *value = 0.0;
+            }
+        }
+        for connection in incoming() {
+            // Sources later in the order still hold the previous block.
+            let source = &self.nodes[connection.from].as_ref().unwrap().outputs;
+            let source = &source[connection.output];
+            for (value, signal) in inputs[connection.input].iter_mut().zip(source.iter()) {
+                *value += signal;
+            }
+        }
+    }
+
+    /// Orders the nodes so each comes after the nodes feeding it. A connection that closes a
+    /// loop of the connections made before it is feedback, and is left out of the ordering.
+    fn sort(&mut self) {
+        let count = self.nodes.len();
+        let mut forward = Vec::with_capacity(self.connections.len());
+        self.feedback = self
+            .connections
+            .iter()
+            .map(|&connection| {
+                let closes_loop = reaches(&forward, connection.to, connection.from, count);
+                if !closes_loop {
+                    forward.push(connection);
+                }
+                closes_loop
+            })
+            .collect();
+
+        let mut incoming = vec![0; count];
+        for connection in forward.iter() {
+            incoming[connection.to] += 1;
+        }
+        let mut ready: Vec<NodeId> = (0..count)
+            .rev()
+            .filter(|&id| self.nodes[id].is_some() && incoming[id] == 0)
+            .collect();
+        self.order.clear();
+        while let Some(id) = ready.pop() {
+            self.order.push(id);
+            for connection in forward.iter().filter(|c| c.from == id) {
+                incoming[connection.to] -= 1;
+                if incoming[connection.to] == 0 {
+                    ready.push(connection.to);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `to` can be reached from `from` through `connections`.
+fn reaches(connections: &[Connection], from: NodeId, to: NodeId, count: usize) -> bool {
+    let mut visited = vec![false; count];
+    let mut stack = vec![from];
+    while let Some(id) = stack.pop() {
+        if id == to {
+            return true;
+        }
+        if !mem::replace(&mut visited[id], true) {
+            stack.extend(connections.iter().filter(|c| c.from == id).map(|c| c.to));
+        }
+    }
+    false
+}
+
+/// Turns the per sample calls of the engine into blocks for the graph.
+struct GraphPlayer {
+    graph: Graph,
+    // Graph being faded out after a replace.
+    previous: Option<Graph>,
+    updates: Receiver<GraphUpdate>,
+    retired: Sender<Graph>,
+    keys: [Option<i32>; BLOCK_SIZE],
+    output: [StereoSignal; BLOCK_SIZE],
+    previous_output: [StereoSignal; BLOCK_SIZE],
+    position: usize,
+}
+
+impl GraphPlayer {
+    fn next_sample(&mut self, key: Option<i32>, context: &ProcessContext) -> StereoSignal {
+        if self.position == BLOCK_SIZE {
+            self.apply_updates();
+            self.process_block(context);
+            self.position = 0;
+        }
+        self.keys[self.position] = key;
+        let output = self.output[self.position];
+        self.position += 1;
+        output
+    }
+
+    fn apply_updates(&mut self) {
+        for update in self.updates.try_iter() {
+            match update {
+                GraphUpdate::Replace(graph) => {
+                    let replaced = mem::replace(&mut self.graph, graph);
+                    // Of several replaces arriving together, only the first graph was heard,
+                    // so the fade starts from it and the ones in between are skipped.
+                    if self.previous.is_some() {
+                        self.retire(replaced);
+                    } else {
+                        self.previous = Some(replaced);
+                    }
+                }
+                GraphUpdate::SetParameter(id, index, value) => {
+                    if let Some(node) = self.graph.node_mut(id) {
+                        node.set_parameter(index, value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn process_block(&mut self, transport: &ProcessContext) {
+        let context = BlockContext {
+            sample_rate: self.graph.sample_rate,
+            keys: &self.keys,
+            transport: *transport,
+        };
+        self.graph.process_block(&context, &mut self.output);
+
+        if let Some(mut previous) = self.previous.take() {
+            previous.process_block(&context, &mut self.previous_output);
+            let frames = self.output.iter_mut().zip(self.previous_output.iter());
+            for (index, (new, old)) in frames.enumerate() {
+                let fade = (index + 1) as Signal / BLOCK_SIZE as Signal;
+                new.0 = new.0 * fade + old.0 * (1.0 - fade);
+                new.1 = new.1 * fade + old.1 * (1.0 - fade);
+            }
+            self.retire(previous);
+        }
+    }
+
+    fn retire(&self, graph: Graph) {
+        // Only dropped here if the control thread has stopped listening.
+        let _ = self.retired.send(graph);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    /// Outputs its parameter, plus its input.
+    struct Constant(Signal);
+
+    const CONSTANT_PORTS: [PortSpec; 1] = [PortSpec::audio("value", 0.0)];
+
+    impl Node for Constant {
+        fn inputs(&self) -> &[PortSpec] {
+            &CONSTANT_PORTS
+        }
+
+        fn outputs(&self) -> &[PortSpec] {
+            &CONSTANT_PORTS
+        }
+
+        fn set_parameter(&mut self, _index: usize, value: f64) {
+            self.0 = value;
+        }
+
+        fn process(
+            &mut self,
+            _: &BlockContext,
+            inputs: &[Vec<Signal>],
+            outputs: &mut [Vec<Signal>],
+        ) {
+            for (output, input) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+                *output = self.0 + input;
+            }
+        }
+    }
+
+    struct Level;
+
+    impl Node for Level {
+        fn inputs(&self) -> &[PortSpec] {
+            &[]
+        }
+
+        fn outputs(&self) -> &[PortSpec] {
+            const PORTS: [PortSpec; 1] = [PortSpec::control("level", 0.0)];
+            &PORTS
+        }
+
+        fn process(&mut self, _: &BlockContext, _: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+            outputs[0][0] = 1.0;
+        }
+    }
+
+    fn connection(from: NodeId, to: NodeId) -> Connection {
+        Connection {
+            from,
+            output: 0,
+            to,
+            input: 0,
+        }
+    }
+
+    fn constant(value: Signal) -> Graph {
+        let mut graph = Graph::new(SAMPLE_RATE);
+        let node = graph.add_node(Box::new(Constant(value)));
+        graph.set_output(Some(node)).unwrap();
+        graph
+    }
+
+    fn run(graph: &mut Graph) -> StereoSignal {
+        let keys = [None; BLOCK_SIZE];
+        let context = BlockContext {
+            sample_rate: SAMPLE_RATE,
+            keys: &keys,
+            transport: ProcessContext::default(),
+        };
+        let mut output = [(0.0, 0.0); BLOCK_SIZE];
+        graph.process_block(&context, &mut output);
+        output[0]
+    }
+
+    #[test]
+    fn chains_run_in_one_block_and_feedback_takes_a_block() {
+        let mut graph = Graph::new(SAMPLE_RATE);
+        // Added out of order, so only sorting gets the chain right.
+        let last = graph.add_node(Box::new(Constant(100.0)));
+        let first = graph.add_node(Box::new(Constant(1.0)));
+        graph.connect(connection(first, last)).unwrap();
+        graph.set_output(Some(last)).unwrap();
+        assert_eq!(run(&mut graph), (101.0, 101.0));
+
+        graph.connect(connection(last, first)).unwrap();
+        assert!(graph.is_feedback(connection(last, first)));
+        assert!(!graph.is_feedback(connection(first, last)));
+        // The loop adds 101 every block.
+        assert_eq!(run(&mut graph), (202.0, 202.0));
+        assert_eq!(run(&mut graph), (303.0, 303.0));
+    }
+
+    #[test]
+    fn rejects_bad_connections() {
+        let mut graph = Graph::new(SAMPLE_RATE);
+        let audio = graph.add_node(Box::new(Constant(0.0)));
+        let control = graph.add_node(Box::new(Level));
+        let c = connection(control, audio);
+        assert_eq!(graph.connect(c), Err(GraphError::KindMismatch(c)));
+        let c = connection(audio, control);
+        assert_eq!(graph.connect(c), Err(GraphError::NoSuchPort(c)));
+        assert_eq!(
+            graph.set_output(Some(control)),
+            Err(GraphError::NotAudio(control))
+        );
+
+        graph.remove_node(audio);
+        assert_eq!(graph.node_ids().collect::<Vec<_>>(), vec![control]);
+        let c = connection(audio, audio);
+        assert_eq!(graph.connect(c), Err(GraphError::NoSuchNode(audio)));
+    }
+
+    #[test]
+    fn replacing_crossfades_over_a_block() {
+        let (sender, receiver) = channel();
+        let (retired_tx, retired) = channel();
+        let mut function = constant(1.0).into_processor_function(receiver, retired_tx);
+
+        let context = ProcessContext::default();
+        let mut run =
+            |samples| -> Vec<Signal> { (0..samples).map(|_| function(None, &context).0).collect() };
+        // The first block is silent while the graph fills it.
+        assert_eq!(run(BLOCK_SIZE)[0], 0.0);
+        assert_eq!(run(BLOCK_SIZE)[0], 1.0);
+
+        sender.send(GraphUpdate::SetParameter(0, 0, 2.0)).unwrap();
+        assert_eq!(run(BLOCK_SIZE)[0], 2.0);
+
+        sender.send(GraphUpdate::Replace(constant(0.0))).unwrap();
+        let fade = run(BLOCK_SIZE);
+        assert!(fade.windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(fade[0] > 1.9 && fade[BLOCK_SIZE - 1] == 0.0);
+        assert_eq!(run(BLOCK_SIZE)[0], 0.0);
+        assert_eq!(retired.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn replacing_twice_in_a_row_stays_continuous() {
+        let (sender, receiver) = channel();
+        let (retired_tx, retired) = channel();
+        let mut function = constant(1.0).into_processor_function(receiver, retired_tx);
+
+        let context = ProcessContext::default();
+        let mut run =
+            |samples| -> Vec<Signal> { (0..samples).map(|_| function(None, &context).0).collect() };
+        run(2 * BLOCK_SIZE);
+
+        // The second replace lands before the first has been heard.
+        sender.send(GraphUpdate::Replace(constant(5.0))).unwrap();
+        sender.send(GraphUpdate::Replace(constant(0.0))).unwrap();
+        let output = run(2 * BLOCK_SIZE);
+        assert!(output[0] > 0.95 && output[0] <= 1.0);
+        assert!(output
+            .windows(2)
+            .all(|pair| pair[1] <= pair[0] && pair[0] - pair[1] < 0.05));
+        assert_eq!(output[BLOCK_SIZE - 1], 0.0);
+        assert_eq!(retired.try_iter().count(), 2);
+    }
+}
@@ -2,29 +2,67 @@ use std::collections::VecDeque;
 
 use types::KeyAction;
 
+/// Which of the held keys sounds when several are down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotePriority {
+    /// The most recently pressed key. Releasing it falls back to the one pressed before.
+    Last,
+    Lowest,
+    Highest,
+}
+
+/// Tracks the held keys in the order they were pressed.
 pub struct KeysState {
     state: VecDeque<i32>,
+    priority: NotePriority,
 }
 
 impl KeysState {
     pub fn new() -> Self {
+        Self::with_priority(NotePriority::Last)
+    }
+
+    pub fn with_priority(priority: NotePriority) -> Self {
         Self {
             state: VecDeque::new(),
+            priority,
         }
     }
 
+    pub fn priority(&self) -> NotePriority {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: NotePriority) {
+        self.priority = priority;
+    }
+
+    /// Held keys, most recently pressed first.
+    pub fn held_keys(&self) -> impl Iterator<Item = &i32> {
+        self.state.iter()
+    }
+
+    /// Updates the held keys and returns the one that should sound, if any.
     pub fn key_down(&mut self, key_action: KeyAction) -> Option<i32> {
         match key_action {
             KeyAction::Press(value) => {
                 self.remove_key(value);
                 self.state.push_front(value);
-                self.state.front().cloned()
             }
             KeyAction::Release(value) => {
                 self.remove_key(value);
-                self.state.front().cloned()
             }
         }
+        self.current_key()
+    }
+
+    /// The key that should sound according to the note priority.
+    pub fn current_key(&self) -> Option<i32> {
+        match self.priority {
+            NotePriority::Last => self.state.front().cloned(),
+            NotePriority::Lowest => self.state.iter().min().cloned(),
+            NotePriority::Highest => self.state.iter().max().cloned(),
+        }
     }
 
     fn remove_key(&mut self, key: i32) {
@@ -34,3 +72,61 @@ impl KeysState {
         }
     }
 }
+
+impl Default for KeysState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(priority: NotePriority, actions: &[KeyAction]) -> Vec<Option<i32>> {
+        let mut keys = KeysState::with_priority(priority);
+        actions
+            .iter()
+            .map(|&action| keys.key_down(action))
+            .collect()
+    }
+
+    const ACTIONS: [KeyAction; 5] = [
+        KeyAction::Press(5),
+        KeyAction::Press(2),
+        KeyAction::Press(9),
+        KeyAction::Release(9),
+        KeyAction::Release(2),
+    ];
+
+    #[test]
+    fn last_priority_falls_back_to_previous_key() {
+        assert_eq!(
+            play(NotePriority::Last, &ACTIONS),
+            vec![Some(5), Some(2), Some(9), Some(2), Some(5)]
+        );
+    }
+
+    #[test]
+    fn lowest_priority_keeps_lowest_key() {
+        assert_eq!(
+            play(NotePriority::Lowest, &ACTIONS),
+            vec![Some(5), Some(2), Some(2), Some(2), Some(5)]
+        );
+    }
+
+    #[test]
+    fn highest_priority_keeps_highest_key() {
+        assert_eq!(
+            play(NotePriority::Highest, &ACTIONS),
+            vec![Some(5), Some(5), Some(9), Some(5), Some(5)]
+        );
+    }
+
+    #[test]
+    fn releasing_every_key_gives_none() {
+        let mut keys = KeysState::new();
+        keys.key_down(KeyAction::Press(3));
+        assert_eq!(keys.key_down(KeyAction::Release(3)), None);
+    }
+}
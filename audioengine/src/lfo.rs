@@ -0,0 +1,222 @@
+use random::Random;
+use std::f64::consts::PI;
+use transport::ProcessContext;
+use types::{Phase, Signal};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// Holds a new random value for every cycle.
+    SampleAndHold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfoRate {
+    /// Free running, in cycles per second.
+    Hertz(f64),
+    /// Locked to the tempo, as the length of one cycle in beats.
+    Beats(f64),
+}
+
+/// Low frequency oscillator producing a bipolar control signal in [-1.0, 1.0]. Aliasing is
+/// not a concern at control rates, so the shapes are computed naively.
+pub struct Lfo {
+    sample_rate: f64,
+    shape: LfoShape,
+    rate: LfoRate,
+    tempo: f64,
+    start_phase: Phase,
+    phase: Phase,
+    fade_in: f64,
+    fade_position: f64,
+    held_value: Signal,
+    random: Random,
+}
+
+impl Lfo {
+    pub fn new(shape: LfoShape, sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            shape,
+            rate: LfoRate::Hertz(1.0),
+            tempo: 120.0,
+            start_phase: 0.0,
+            phase: 0.0,
+            fade_in: 0.0,
+            fade_position: 1.0,
+            held_value: 0.0,
+            random: Random::default(),
+        }
+    }
+
+    pub fn set_shape(&mut self, shape: LfoShape) {
+        self.shape = shape;
+    }
+
+    pub fn set_rate(&mut self, rate: LfoRate) {
+        self.rate = rate;
+    }
+
+    /// Tempo in beats per minute, used by `LfoRate::Beats`.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.tempo = bpm.max(1.0);
+    }
+
+    /// Keeps the tempo at that of the transport, for LFOs run by context processors.
+    pub fn follow_tempo(&mut self, context: &ProcessContext) {
+        if context.tempo != self.tempo {
+            self.set_tempo(context.tempo);
+        }
+    }
+
+    /// Phase, in cycles, the LFO starts from on `retrigger`.
+    pub fn set_start_phase(&mut self, phase: Phase) {
+        self.start_phase = phase - phase.floor();
+    }
+
+    /// Time in seconds for the depth to ramp up from zero after `retrigger`.
+    pub fn set_fade_in(&mut self, seconds: f64) {
+        self.fade_in = seconds.max(0.0);
+    }
+
+    pub fn set_seed(&mut self, seed: u32) {
+        self.random = Random::new(seed);
+    }
+
+    /// Restarts the cycle from the start phase and the fade-in from zero, typically on a new
+    /// note.
+    pub fn retrigger(&mut self) {
+        self.phase = self.start_phase;
+        self.fade_position = 0.0;
+        self.held_value = self.random.next_bipolar();
+    }
+
+    pub fn frequency(&self) -> f64 {
+        match self.rate {
+            LfoRate::Hertz(hertz) => hertz.max(0.0),
+            LfoRate::Beats(beats) => self.tempo / 60.0 / beats.max(1e-3),
+        }
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        let phase = self.phase;
+        let value = match self.shape {
+            LfoShape::Sine => (2.0 * PI * phase).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => self.held_value,
+        };
+
+        self.phase += self.frequency() / self.sample_rate;
+        if self.phase >= 1.0 {
+            self.phase -= self.phase.floor();
+            self.held_value = self.random.next_bipolar();
+        }
+
+        let fade = if self.fade_position < 1.0 {
+            self.fade_position += 1.0 / (self.fade_in * self.sample_rate).max(1.0);
+            self.fade_position.min(1.0)
+        } else {
+            1.0
+        };
+
+        value * fade
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::Transport;
+
+    // One cycle per second at this rate takes exactly 1000 samples.
+    const SAMPLE_RATE: f64 = 1000.0;
+
+    fn samples(lfo: &mut Lfo, count: usize) -> Vec<Signal> {
+        (0..count).map(|_| lfo.next_sample()).collect()
+    }
+
+    #[test]
+    fn shapes() {
+        let expected: [(LfoShape, [Signal; 4]); 4] = [
+            (LfoShape::Sine, [0.0, 1.0, 0.0, -1.0]),
+            (LfoShape::Triangle, [-1.0, 0.0, 1.0, 0.0]),
+            (LfoShape::Saw, [-1.0, -0.5, 0.0, 0.5]),
+            (LfoShape::Square, [1.0, 1.0, -1.0, -1.0]),
+        ];
+        for &(shape, quarters) in expected.iter() {
+            let mut lfo = Lfo::new(shape, SAMPLE_RATE);
+            let values = samples(&mut lfo, 1000);
+            for (quarter, &value) in quarters.iter().enumerate() {
+                assert!(
+                    (values[quarter * 250] - value).abs() < 1e-9,
+                    "{:?} at quarter {}",
+                    shape,
+                    quarter
+                );
+            }
+            assert!(values.iter().all(|v| v.abs() <= 1.0));
+        }
+    }
+
+    #[test]
+    fn sample_and_hold_changes_once_per_cycle() {
+        let mut lfo = Lfo::new(LfoShape::SampleAndHold, SAMPLE_RATE);
+        lfo.set_rate(LfoRate::Hertz(4.0));
+        lfo.retrigger();
+        let values = samples(&mut lfo, 1000);
+        let changes = values.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(changes, 3);
+        assert!(values.iter().all(|v| v.abs() <= 1.0));
+    }
+
+    #[test]
+    fn rate_and_start_phase() {
+        let mut lfo = Lfo::new(LfoShape::Saw, SAMPLE_RATE);
+        lfo.set_rate(LfoRate::Hertz(2.0));
+        lfo.set_start_phase(0.25);
+        lfo.retrigger();
+        let values = samples(&mut lfo, 500);
+        assert!((values[0] + 0.5).abs() < 1e-9);
+        assert!(values[125].abs() < 1e-9);
+        // Half a second is a full cycle at 2 Hz.
+        assert!((lfo.next_sample() - values[0]).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tempo_sync() {
+        let mut lfo = Lfo::new(LfoShape::Sine, SAMPLE_RATE);
+        lfo.set_rate(LfoRate::Beats(2.0));
+        lfo.set_tempo(120.0);
+        assert!((lfo.frequency() - 1.0).abs() < 1e-9);
+        lfo.set_tempo(90.0);
+        assert!((lfo.frequency() - 0.75).abs() < 1e-9);
+        lfo.set_rate(LfoRate::Beats(0.25));
+        assert!((lfo.frequency() - 6.0).abs() < 1e-9);
+
+        let mut transport = Transport::new(SAMPLE_RATE);
+        transport.set_tempo(60.0);
+        lfo.follow_tempo(&transport.next_sample().0);
+        assert!((lfo.frequency() - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fade_in_ramps_depth() {
+        let mut lfo = Lfo::new(LfoShape::Square, SAMPLE_RATE);
+        lfo.set_fade_in(0.1);
+        lfo.retrigger();
+        let values = samples(&mut lfo, 200);
+        assert!((values[49] - 0.5).abs() < 1e-9);
+        assert_eq!(values[150], 1.0);
+    }
+}
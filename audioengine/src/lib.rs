@@ -1,9 +1,41 @@
 extern crate cpal;
+extern crate hound;
+extern crate rustfft;
 
+pub mod additive;
+pub mod arpeggiator;
 pub mod audioengine;
+pub mod chorus;
+pub mod clock;
+pub mod delay;
+pub mod drums;
+pub mod envelope;
+pub mod filter;
+pub mod fm;
+pub mod granular;
+pub mod graph;
+pub mod keys_state;
+pub mod lfo;
+pub mod master;
+pub mod modulation;
+pub mod nodes;
+pub mod noise;
+pub mod oscillators;
+pub mod output;
+pub mod phaser;
+pub mod pluck;
+pub mod portamento;
+pub mod random;
+pub mod reverb;
+pub mod sampler;
+pub mod sequencer;
+pub mod smoothing;
+pub mod transport;
+pub mod tuning;
 pub mod types;
-
-mod keys_state;
+pub mod unison;
+pub mod wav;
+pub mod wavetable;
 
 pub use self::audioengine::*;
 pub use types::*;
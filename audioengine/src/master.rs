@@ -0,0 +1,261 @@
+use smoothing::SmoothedValue;
+use std::collections::VecDeque;
+use types::{Signal, StereoSignal};
+
+const VOLUME_SMOOTHING_TIME: f64 = 0.02;
+const DC_BLOCKER_CUTOFF: f64 = 10.0;
+const LIMITER_LOOKAHEAD_TIME: f64 = 0.005;
+const LIMITER_RELEASE_TIME: f64 = 0.1;
+/// Just below full scale, so the limited signal survives rounding in the output stage.
+const LIMITER_CEILING: Signal = 0.989;
+
+/// Levels reported to the UI once per output buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct MasterMeter {
+    /// Highest absolute output value of either channel in the buffer.
+    pub peak: Signal,
+    /// Lowest gain applied by the limiter in the buffer, 1.0 meaning untouched.
+    pub limiter_gain: Signal,
+    /// Whether the signal reaching the limiter went over full scale.
+    pub clipped: bool,
+}
+
+impl MasterMeter {
+    /// Combines two measurements into one covering both periods.
+    pub fn merge(self, other: MasterMeter) -> MasterMeter {
+        MasterMeter {
+            peak: self.peak.max(other.peak),
+            limiter_gain: self.limiter_gain.min(other.limiter_gain),
+            clipped: self.clipped || other.clipped,
+        }
+    }
+}
+
+impl Default for MasterMeter {
+    fn default() -> Self {
+        Self {
+            peak: 0.0,
+            limiter_gain: 1.0,
+            clipped: false,
+        }
+    }
+}
+
+/// First order high-pass removing any constant offset from the signal.
+pub struct DcBlocker {
+    coefficient: Signal,
+    previous_input: Signal,
+    previous_output: Signal,
+}
+
+impl DcBlocker {
+    pub fn new(cutoff: f64, sample_rate: f64) -> Self {
+        Self {
+            coefficient: 1.0 - 2.0 * std::f64::consts::PI * cutoff / sample_rate,
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: Signal) -> Signal {
+        let output = input - self.previous_input + self.coefficient * self.previous_output;
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+/// Stereo linked brickwall limiter that delays the signal by its look-ahead time, so the gain
+/// can start ramping down before a peak reaches the output. Both channels get the same gain,
+/// keeping the stereo image in place.
+pub struct Limiter {
+    ceiling: Signal,
+    lookahead: usize,
+    delay: VecDeque<StereoSignal>,
+    // Sliding window minimum of the gain each delayed sample needs, as (sample index, gain).
+    required_gains: VecDeque<(u64, Signal)>,
+    // The last `lookahead` held gains, averaged to get a smooth gain curve.
+    held_gains: VecDeque<Signal>,
+    held_gain_sum: Signal,
+    released_gain: Signal,
+    release_coefficient: Signal,
+    index: u64,
+}
+
+impl Limiter {
+    pub fn new(ceiling: Signal, lookahead_time: f64, release_time: f64, sample_rate: f64) -> Self {
+        let lookahead = ((lookahead_time * sample_rate) as usize).max(1);
+        Self {
+            ceiling,
+            lookahead,
+            delay: (0..lookahead).map(|_| (0.0, 0.0)).collect(),
+            required_gains: VecDeque::with_capacity(lookahead + 1),
+            held_gains: (0..lookahead).map(|_| 1.0).collect(),
+            held_gain_sum: lookahead as Signal,
+            released_gain: 1.0,
+            release_coefficient: (-1.0 / (release_time * sample_rate)).exp(),
+            index: 0,
+        }
+    }
+
+    /// Returns the delayed, limited signal and the gain applied to it.
+    pub fn process(&mut self, input: StereoSignal) -> (StereoSignal, Signal) {
+        let peak = input.0.abs().max(input.1.abs());
+        let required_gain = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+
+        while self
+            .required_gains
+            .back()
+            .is_some_and(|&(_, gain)| gain >= required_gain)
+        {
+            self.required_gains.pop_back();
+        }
+        self.required_gains.push_back((self.index, required_gain));
+        let oldest_index = self.index.saturating_sub(self.lookahead as u64);
+        while self
+            .required_gains
+            .front()
+            .is_some_and(|&(index, _)| index < oldest_index)
+        {
+            self.required_gains.pop_front();
+        }
+        self.index += 1;
+
+        let window_gain = self.required_gains.front().map_or(1.0, |&(_, gain)| gain);
+        self.released_gain = 1.0 - (1.0 - self.released_gain) * self.release_coefficient;
+        self.released_gain = self.released_gain.min(window_gain);
+
+        self.held_gain_sum += self.released_gain - self.held_gains.pop_front().unwrap_or(1.0);
+        self.held_gains.push_back(self.released_gain);
+
+        // Every held gain in the average covers the sample leaving the delay line, so the
+        // average can never exceed the gain that sample needs.
+        let gain = (self.held_gain_sum / self.lookahead as Signal).min(1.0);
+
+        self.delay.push_back(input);
+        let (left, right) = self.delay.pop_front().unwrap_or((0.0, 0.0));
+
+        ((left * gain, right * gain), gain)
+    }
+}
+
+/// The last stage before the output: master volume, DC blocking and limiting.
+pub struct MasterBus {
+    volume: SmoothedValue,
+    dc_blockers: [DcBlocker; 2],
+    limiter: Limiter,
+    meter: MasterMeter,
+}
+
+impl MasterBus {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            volume: SmoothedValue::new(1.0, VOLUME_SMOOTHING_TIME, sample_rate),
+            dc_blockers: [
+                DcBlocker::new(DC_BLOCKER_CUTOFF, sample_rate),
+                DcBlocker::new(DC_BLOCKER_CUTOFF, sample_rate),
+            ],
+            limiter: Limiter::new(
+                LIMITER_CEILING,
+                LIMITER_LOOKAHEAD_TIME,
+                LIMITER_RELEASE_TIME,
+                sample_rate,
+            ),
+            meter: MasterMeter::default(),
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: Signal) {
+        self.volume.set(volume.max(0.0));
+    }
+
+    pub fn process(&mut self, (left, right): StereoSignal) -> StereoSignal {
+        let volume = self.volume.next_value();
+        let signal = (
+            self.dc_blockers[0].process(left * volume),
+            self.dc_blockers[1].process(right * volume),
+        );
+        let (output, gain) = self.limiter.process(signal);
+
+        self.meter = self.meter.merge(MasterMeter {
+            peak: output.0.abs().max(output.1.abs()),
+            limiter_gain: gain,
+            clipped: signal.0.abs() > 1.0 || signal.1.abs() > 1.0,
+        });
+
+        output
+    }
+
+    /// Returns the levels measured since the last call and starts a new measurement.
+    pub fn take_meter(&mut self) -> MasterMeter {
+        std::mem::take(&mut self.meter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn limiter_keeps_peaks_below_ceiling() {
+        let mut limiter = Limiter::new(
+            LIMITER_CEILING,
+            LIMITER_LOOKAHEAD_TIME,
+            LIMITER_RELEASE_TIME,
+            SAMPLE_RATE,
+        );
+        for n in 0..48_000 {
+            let phase = n as f64 * 440.0 / SAMPLE_RATE;
+            let burst = if n % 5000 == 0 { 10.0 } else { 0.0 };
+            let signal = 4.0 * (2.0 * std::f64::consts::PI * phase).sin() + burst;
+            let ((left, right), gain) = limiter.process((signal, -signal));
+            assert!(left.abs() <= LIMITER_CEILING + 1e-9, "{} at {}", left, n);
+            assert!(right.abs() <= LIMITER_CEILING + 1e-9);
+            assert!(gain <= 1.0);
+        }
+    }
+
+    #[test]
+    fn limiter_delays_by_lookahead() {
+        let mut limiter = Limiter::new(
+            LIMITER_CEILING,
+            LIMITER_LOOKAHEAD_TIME,
+            LIMITER_RELEASE_TIME,
+            SAMPLE_RATE,
+        );
+        let lookahead = (LIMITER_LOOKAHEAD_TIME * SAMPLE_RATE) as usize;
+        let output: Vec<Signal> = (0..lookahead * 2)
+            .map(|n| {
+                let input = if n == 0 { 0.5 } else { 0.0 };
+                (limiter.process((input, input)).0).0
+            })
+            .collect();
+        for (n, &value) in output.iter().enumerate() {
+            let expected = if n == lookahead { 0.5 } else { 0.0 };
+            assert!((value - expected).abs() < 1e-12, "{} at {}", value, n);
+        }
+    }
+
+    #[test]
+    fn dc_blocker_removes_offset() {
+        let mut blocker = DcBlocker::new(DC_BLOCKER_CUTOFF, SAMPLE_RATE);
+        let mut output = 0.0;
+        for n in 0..48_000 {
+            let phase = n as f64 * 1000.0 / SAMPLE_RATE;
+            output = blocker.process(0.5 + 0.1 * (2.0 * std::f64::consts::PI * phase).sin());
+        }
+        assert!(output.abs() < 0.11);
+
+        let mut blocker = DcBlocker::new(DC_BLOCKER_CUTOFF, SAMPLE_RATE);
+        for _ in 0..48_000 {
+            output = blocker.process(1.0);
+        }
+        assert!(output.abs() < 1e-3);
+    }
+}
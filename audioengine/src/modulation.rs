@@ -0,0 +1,251 @@
+use types::Signal;
+
+/// Routes with a smaller depth than this are removed, as sliders rarely land on exactly zero.
+pub const ROUTE_DEAD_ZONE: f64 = 0.02;
+/// Routes a `ModMatrix` holds. Room for them is made up front, as routes are set on the
+/// audio thread, and further routes are ignored.
+pub const MAX_ROUTES: usize = 64;
+
+/// Index of a parameter registered with a `ModMatrix`.
+pub type ParameterId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ModSource {
+    /// Bipolar, in [-1.0, 1.0].
+    Lfo(usize),
+    /// Unipolar, in [0.0, 1.0].
+    Envelope(usize),
+    /// Unipolar, in [0.0, 1.0].
+    Velocity,
+    /// Octaves relative to key 0, so one octave up adds 1.0.
+    NoteNumber,
+    /// Unipolar, in [0.0, 1.0].
+    ModWheel,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModRoute {
+    pub source: ModSource,
+    pub destination: ParameterId,
+    /// Share of the parameter's range a source value of 1.0 moves it by. May be negative.
+    pub depth: f64,
+}
+
+/// Edits sent from the UI to the `ModMatrix` on the audio thread.
+#[derive(Clone, Copy, Debug)]
+pub enum ModRouteEvent {
+    /// Adds or updates the route between a source and a destination. A depth within
+    /// `ROUTE_DEAD_ZONE` of zero removes it.
+    Set(ModRoute),
+    Clear,
+}
+
+struct Parameter {
+    name: String,
+    min: f64,
+    max: f64,
+    base: f64,
+    value: f64,
+}
+
+/// Routes modulation sources to registered parameters. Each sample, update the sources with
+/// `set_source`, call `process` and read the modulated values with `value`.
+pub struct ModMatrix {
+    parameters: Vec<Parameter>,
+    routes: Vec<ModRoute>,
+    sources: Vec<(ModSource, Signal)>,
+}
+
+impl ModMatrix {
+    pub fn new() -> Self {
+        Self {
+            parameters: Vec::new(),
+            routes: Vec::with_capacity(MAX_ROUTES),
+            sources: Vec::new(),
+        }
+    }
+
+    /// Registers a parameter with the range modulation is clamped to and its unmodulated
+    /// value.
+    pub fn register_parameter(&mut self, name: &str, min: f64, max: f64, base: f64) -> ParameterId {
+        let base = base.clamp(min, max);
+        self.parameters.push(Parameter {
+            name: name.to_owned(),
+            min,
+            max,
+            base,
+            value: base,
+        });
+        self.parameters.len() - 1
+    }
+
+    pub fn parameter_name(&self, id: ParameterId) -> &str {
+        &self.parameters[id].name
+    }
+
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    /// Sets the unmodulated value of a parameter, e.g. from a slider.
+    pub fn set_base(&mut self, id: ParameterId, base: f64) {
+        let parameter = &mut self.parameters[id];
+        parameter.base = base.clamp(parameter.min, parameter.max);
+    }
+
+    pub fn routes(&self) -> &[ModRoute] {
+        &self.routes
+    }
+
+    /// Adds, updates or removes a route. A new route is ignored once there are `MAX_ROUTES`.
+    pub fn set_route(&mut self, route: ModRoute) {
+        let remove = route.depth.abs() < ROUTE_DEAD_ZONE;
+        let existing = self
+            .routes
+            .iter()
+            .position(|r| r.source == route.source && r.destination == route.destination);
+
+        match existing {
+            Some(index) if remove => {
+                self.routes.remove(index);
+            }
+            Some(index) => self.routes[index].depth = route.depth,
+            None if !remove && self.routes.len() < MAX_ROUTES => self.routes.push(route),
+            None => (),
+        }
+    }
+
+    pub fn apply_event(&mut self, event: ModRouteEvent) {
+        match event {
+            ModRouteEvent::Set(route) => self.set_route(route),
+            ModRouteEvent::Clear => self.routes.clear(),
+        }
+    }
+
+    pub fn set_source(&mut self, source: ModSource, value: Signal) {
+        match self.sources.iter_mut().find(|(s, _)| *s == source) {
+            Some(entry) => entry.1 = value,
+            None => self.sources.push((source, value)),
+        }
+    }
+
+    /// Convenience for `ModSource::NoteNumber`, taking the key handed to a processor function.
+    /// The source keeps its last value while no key is held, so releases are not modulated.
+    pub fn set_key(&mut self, key: Option<i32>) {
+        if let Some(key) = key {
+            self.set_source(ModSource::NoteNumber, f64::from(key) / 12.0);
+        }
+    }
+
+    fn source(&self, source: ModSource) -> Signal {
+        self.sources
+            .iter()
+            .find(|(s, _)| *s == source)
+            .map_or(0.0, |&(_, value)| value)
+    }
+
+    /// Recomputes all modulated parameter values.
+    pub fn process(&mut self) {
+        for parameter in self.parameters.iter_mut() {
+            parameter.value = parameter.base;
+        }
+
+        for route in self.routes.iter() {
+            let amount = self.source(route.source) * route.depth;
+            if let Some(parameter) = self.parameters.get_mut(route.destination) {
+                parameter.value += amount * (parameter.max - parameter.min);
+            }
+        }
+
+        for parameter in self.parameters.iter_mut() {
+            parameter.value = parameter.value.clamp(parameter.min, parameter.max);
+        }
+    }
+
+    pub fn value(&self, id: ParameterId) -> f64 {
+        self.parameters[id].value
+    }
+}
+
+impl Default for ModMatrix {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(source: ModSource, destination: ParameterId, depth: f64) -> ModRoute {
+        ModRoute {
+            source,
+            destination,
+            depth,
+        }
+    }
+
+    #[test]
+    fn routes_sum_and_clamp() {
+        let mut matrix = ModMatrix::new();
+        let cutoff = matrix.register_parameter("Cutoff", 0.0, 10.0, 5.0);
+        let level = matrix.register_parameter("Level", 0.0, 1.0, 0.5);
+        matrix.set_route(route(ModSource::Lfo(0), cutoff, 0.1));
+        matrix.set_route(route(ModSource::Envelope(0), cutoff, -0.2));
+        matrix.set_route(route(ModSource::Velocity, level, 1.0));
+
+        matrix.set_source(ModSource::Lfo(0), 1.0);
+        matrix.set_source(ModSource::Envelope(0), 0.5);
+        matrix.set_source(ModSource::Velocity, 1.0);
+        matrix.process();
+        assert!((matrix.value(cutoff) - 5.0).abs() < 1e-9);
+        assert_eq!(matrix.value(level), 1.0);
+
+        matrix.set_source(ModSource::Lfo(0), -1.0);
+        matrix.process();
+        assert!((matrix.value(cutoff) - 3.0).abs() < 1e-9);
+
+        matrix.set_base(cutoff, 1.0);
+        matrix.process();
+        assert_eq!(matrix.value(cutoff), 0.0);
+    }
+
+    #[test]
+    fn routes_update_and_remove() {
+        let mut matrix = ModMatrix::new();
+        let pitch = matrix.register_parameter("Pitch", -1.0, 1.0, 0.0);
+        matrix.set_route(route(ModSource::NoteNumber, pitch, 0.5));
+        matrix.set_route(route(ModSource::NoteNumber, pitch, 0.25));
+        assert_eq!(matrix.routes().len(), 1);
+        assert_eq!(matrix.routes()[0].depth, 0.25);
+
+        matrix.set_key(Some(12));
+        matrix.process();
+        assert!((matrix.value(pitch) - 0.5).abs() < 1e-9);
+
+        // Close enough to zero counts as removing the route.
+        matrix.set_route(route(ModSource::NoteNumber, pitch, 0.01));
+        assert!(matrix.routes().is_empty());
+        matrix.set_route(route(ModSource::ModWheel, pitch, -0.001));
+        assert!(matrix.routes().is_empty());
+
+        matrix.set_route(route(ModSource::ModWheel, pitch, 0.5));
+        matrix.apply_event(ModRouteEvent::Clear);
+        assert!(matrix.routes().is_empty());
+    }
+
+    #[test]
+    fn routes_stop_at_the_limit() {
+        let mut matrix = ModMatrix::new();
+        let capacity = matrix.routes.capacity();
+        for lfo in 0..2 * MAX_ROUTES {
+            matrix.set_route(route(ModSource::Lfo(lfo), 0, 0.5));
+        }
+        assert_eq!(matrix.routes().len(), MAX_ROUTES);
+        assert_eq!(matrix.routes.capacity(), capacity);
+
+        // Existing routes can still be changed.
+        matrix.set_route(route(ModSource::Lfo(0), 0, 0.25));
+        assert_eq!(matrix.routes()[0].depth, 0.25);
+    }
+}
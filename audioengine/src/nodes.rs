@@ -0,0 +1,747 @@
+use delay::{Delay, DelayTime};
+use envelope::Adsr;
+use filter::{FilterMode, StateVariableFilter};
+use graph::{BlockContext, Node, ParameterSpec, PortSpec};
+use lfo::{Lfo, LfoRate, LfoShape};
+use oscillators::{Oscillator, Waveform, MIDDLE_C};
+use std::sync::Arc;
+use tuning::Tuning;
+use types::Signal;
+
+const WAVEFORMS: [Waveform; 4] = [
+    Waveform::Sine,
+    Waveform::Saw,
+    Waveform::Square,
+    Waveform::Triangle,
+];
+const LFO_SHAPES: [LfoShape; 5] = [
+    LfoShape::Sine,
+    LfoShape::Triangle,
+    LfoShape::Saw,
+    LfoShape::Square,
+    LfoShape::SampleAndHold,
+];
+const FILTER_MODES: [FilterMode; 5] = [
+    FilterMode::LowPass,
+    FilterMode::BandPass,
+    FilterMode::HighPass,
+    FilterMode::Notch,
+    FilterMode::Peak,
+];
+const MAX_DELAY_TIME: f64 = 2.0;
+
+/// Picks from `choices` with a parameter running from 0 to the last index.
+fn choice<T: Copy>(choices: &[T], value: f64) -> T {
+    let index = value.round().max(0.0) as usize;
+    choices[index.min(choices.len() - 1)]
+}
+
+/// The built-in nodes, for creating them by name, e.g. from a patch file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Keyboard,
+    Oscillator,
+    Lfo,
+    Envelope,
+    Filter,
+    Gain,
+    Delay,
+    Output,
+}
+
+impl NodeKind {
+    pub const ALL: [NodeKind; 8] = [
+        NodeKind::Keyboard,
+        NodeKind::Oscillator,
+        NodeKind::Lfo,
+        NodeKind::Envelope,
+        NodeKind::Filter,
+        NodeKind::Gain,
+        NodeKind::Delay,
+        NodeKind::Output,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NodeKind::Keyboard => "Keyboard",
+            NodeKind::Oscillator => "Oscillator",
+            NodeKind::Lfo => "LFO",
+            NodeKind::Envelope => "Envelope",
+            NodeKind::Filter => "Filter",
+            NodeKind::Gain => "Gain",
+            NodeKind::Delay => "Delay",
+            NodeKind::Output => "Output",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        NodeKind::ALL
+            .iter()
+            .cloned()
+            .find(|kind| kind.name() == name)
+    }
+
+    pub fn inputs(self) -> &'static [PortSpec] {
+        match self {
+            NodeKind::Keyboard | NodeKind::Lfo => &[],
+            NodeKind::Oscillator => &OscillatorNode::INPUTS,
+            NodeKind::Envelope => &EnvelopeNode::INPUTS,
+            NodeKind::Filter => &FilterNode::INPUTS,
+            NodeKind::Gain => &GainNode::INPUTS,
+            NodeKind::Delay => &DelayNode::INPUTS,
+            NodeKind::Output => &OutputNode::INPUTS,
+        }
+    }
+
+    pub fn outputs(self) -> &'static [PortSpec] {
+        match self {
+            NodeKind::Keyboard => &KeyboardNode::OUTPUTS,
+            NodeKind::Oscillator => &OscillatorNode::OUTPUTS,
+            NodeKind::Lfo => &LfoNode::OUTPUTS,
+            NodeKind::Envelope => &EnvelopeNode::OUTPUTS,
+            NodeKind::Filter => &FilterNode::OUTPUTS,
+            NodeKind::Gain => &GainNode::OUTPUTS,
+            NodeKind::Delay => &DelayNode::OUTPUTS,
+            NodeKind::Output => &OutputNode::OUTPUTS,
+        }
+    }
+
+    pub fn parameters(self) -> &'static [ParameterSpec] {
+        match self {
+            NodeKind::Keyboard => &[],
+            NodeKind::Oscillator => &OscillatorNode::PARAMETERS,
+            NodeKind::Lfo => &LfoNode::PARAMETERS,
+            NodeKind::Envelope => &EnvelopeNode::PARAMETERS,
+            NodeKind::Filter => &FilterNode::PARAMETERS,
+            NodeKind::Gain => &GainNode::PARAMETERS,
+            NodeKind::Delay => &DelayNode::PARAMETERS,
+            NodeKind::Output => &OutputNode::PARAMETERS,
+        }
+    }
+
+    /// A node of this kind with its parameters at their defaults.
+    pub fn create(self, sample_rate: f64) -> Box<dyn Node> {
+        match self {
+            NodeKind::Keyboard => Box::new(KeyboardNode::new()),
+            NodeKind::Oscillator => Box::new(OscillatorNode::new(sample_rate)),
+            NodeKind::Lfo => Box::new(LfoNode::new(sample_rate)),
+            NodeKind::Envelope => Box::new(EnvelopeNode::new(sample_rate)),
+            NodeKind::Filter => Box::new(FilterNode::new(sample_rate)),
+            NodeKind::Gain => Box::new(GainNode::new()),
+            NodeKind::Delay => Box::new(DelayNode::new(sample_rate)),
+            NodeKind::Output => Box::new(OutputNode::new()),
+        }
+    }
+}
+
+/// Frequency and gate of the key played, the way `KeysState` picks it. The frequency holds
+/// after the key is released, so release tails keep their pitch.
+pub struct KeyboardNode {
+    tuning: Arc<Tuning>,
+    frequency: f64,
+}
+
+impl KeyboardNode {
+    const OUTPUTS: [PortSpec; 2] = [
+        PortSpec::audio("frequency", 0.0),
+        PortSpec::audio("gate", 0.0),
+    ];
+
+    pub fn new() -> Self {
+        Self {
+            tuning: Arc::new(Tuning::default()),
+            frequency: MIDDLE_C,
+        }
+    }
+
+    /// Keys the tuning leaves unmapped close the gate.
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+    }
+}
+
+impl Default for KeyboardNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node for KeyboardNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &[]
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn process(&mut self, context: &BlockContext, _: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        let (frequencies, gates) = outputs.split_at_mut(1);
+        let samples = frequencies[0].iter_mut().zip(gates[0].iter_mut());
+        for ((frequency, gate), key) in samples.zip(context.keys) {
+            let played = key.and_then(|key| self.tuning.frequency(f64::from(key)));
+            if let Some(played) = played {
+                self.frequency = played;
+            }
+            *frequency = self.frequency;
+            *gate = if played.is_some() { 1.0 } else { 0.0 };
+        }
+    }
+}
+
+/// Band-limited oscillator. The control input transposes it in octaves.
+pub struct OscillatorNode {
+    oscillator: Oscillator,
+    level: Signal,
+    detune: f64,
+}
+
+impl OscillatorNode {
+    const INPUTS: [PortSpec; 2] = [
+        PortSpec::audio("frequency", MIDDLE_C),
+        PortSpec::control("octaves", 0.0),
+    ];
+    const OUTPUTS: [PortSpec; 1] = [PortSpec::audio("out", 0.0)];
+    const PARAMETERS: [ParameterSpec; 3] = [
+        ParameterSpec {
+            name: "Wave",
+            min: 0.0,
+            max: 3.0,
+            default: 1.0,
+        },
+        ParameterSpec {
+            name: "Level",
+            min: 0.0,
+            max: 1.0,
+            default: 0.5,
+        },
+        ParameterSpec {
+            name: "Detune",
+            min: -24.0,
+            max: 24.0,
+            default: 0.0,
+        },
+    ];
+
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            oscillator: Oscillator::new(Waveform::Saw, sample_rate),
+            level: 0.5,
+            detune: 0.0,
+        }
+    }
+}
+
+impl Node for OscillatorNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &Self::INPUTS
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &Self::PARAMETERS
+    }
+
+    /// Wave picks sine, saw, square or triangle. Detune is in semitones.
+    fn set_parameter(&mut self, index: usize, value: f64) {
+        match index {
+            0 => self.oscillator.set_waveform(choice(&WAVEFORMS, value)),
+            1 => self.level = value,
+            2 => self.detune = value,
+            _ => (),
+        }
+    }
+
+    fn process(&mut self, _: &BlockContext, inputs: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        let ratio = 2f64.powf(inputs[1][0] + self.detune / 12.0);
+        for (output, &frequency) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+            self.oscillator.set_frequency(frequency * ratio);
+            *output = self.oscillator.next_sample() * self.level;
+        }
+    }
+}
+
+/// Low frequency oscillator as a control signal in [-depth, depth].
+pub struct LfoNode {
+    lfo: Lfo,
+    depth: Signal,
+}
+
+impl LfoNode {
+    const OUTPUTS: [PortSpec; 1] = [PortSpec::control("out", 0.0)];
+    const PARAMETERS: [ParameterSpec; 3] = [
+        ParameterSpec {
+            name: "Shape",
+            min: 0.0,
+            max: 4.0,
+            default: 0.0,
+        },
+        ParameterSpec {
+            name: "Rate",
+            min: 0.01,
+            max: 20.0,
+            default: 1.0,
+        },
+        ParameterSpec {
+            name: "Depth",
+            min: 0.0,
+            max: 1.0,
+            default: 1.0,
+        },
+    ];
+
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            lfo: Lfo::new(LfoShape::Sine, sample_rate),
+            depth: 1.0,
+        }
+    }
+}
+
+impl Node for LfoNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &[]
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &Self::PARAMETERS
+    }
+
+    /// Shape picks sine, triangle, saw, square or sample and hold. Rate is in Hz.
+    fn set_parameter(&mut self, index: usize, value: f64) {
+        match index {
+            0 => self.lfo.set_shape(choice(&LFO_SHAPES, value)),
+            1 => self.lfo.set_rate(LfoRate::Hertz(value)),
+            2 => self.depth = value,
+            _ => (),
+        }
+    }
+
+    fn process(&mut self, context: &BlockContext, _: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        let mut value = 0.0;
+        for _ in context.keys {
+            value = self.lfo.next_sample();
+        }
+        outputs[0][0] = value * self.depth;
+    }
+}
+
+/// ADSR envelope, started when the gate rises above 0.5 and released when it falls below.
+pub struct EnvelopeNode {
+    envelope: Adsr,
+    open: bool,
+}
+
+impl EnvelopeNode {
+    const INPUTS: [PortSpec; 1] = [PortSpec::audio("gate", 0.0)];
+    const OUTPUTS: [PortSpec; 1] = [PortSpec::audio("out", 0.0)];
+    const PARAMETERS: [ParameterSpec; 4] = [
+        ParameterSpec {
+            name: "Attack",
+            min: 0.001,
+            max: 2.0,
+            default: 0.01,
+        },
+        ParameterSpec {
+            name: "Decay",
+            min: 0.001,
+            max: 2.0,
+            default: 0.1,
+        },
+        ParameterSpec {
+            name: "Sustain",
+            min: 0.0,
+            max: 1.0,
+            default: 0.7,
+        },
+        ParameterSpec {
+            name: "Release",
+            min: 0.001,
+            max: 4.0,
+            default: 0.3,
+        },
+    ];
+
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            envelope: Adsr::new(sample_rate),
+            open: false,
+        }
+    }
+}
+
+impl Node for EnvelopeNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &Self::INPUTS
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &Self::PARAMETERS
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f64) {
+        match index {
+            0 => self.envelope.set_attack(value),
+            1 => self.envelope.set_decay(value),
+            2 => self.envelope.set_sustain(value),
+            3 => self.envelope.set_release(value),
+            _ => (),
+        }
+    }
+
+    fn process(&mut self, _: &BlockContext, inputs: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        for (output, &gate) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+            let open = gate > 0.5;
+            if open && !self.open {
+                self.envelope.note_on(1.0);
+            } else if !open && self.open {
+                self.envelope.note_off();
+            }
+            self.open = open;
+            *output = self.envelope.next_sample();
+        }
+    }
+}
+
+/// State-variable filter. The control input moves the cutoff in octaves.
+pub struct FilterNode {
+    filter: StateVariableFilter,
+    cutoff: f64,
+}
+
+impl FilterNode {
+    const INPUTS: [PortSpec; 2] = [PortSpec::audio("in", 0.0), PortSpec::control("cutoff", 0.0)];
+    const OUTPUTS: [PortSpec; 1] = [PortSpec::audio("out", 0.0)];
+    const PARAMETERS: [ParameterSpec; 3] = [
+        ParameterSpec {
+            name: "Mode",
+            min: 0.0,
+            max: 4.0,
+            default: 0.0,
+        },
+        ParameterSpec {
+            name: "Cutoff",
+            min: 20.0,
+            max: 20_000.0,
+            default: 2000.0,
+        },
+        ParameterSpec {
+            name: "Reso",
+            min: 0.0,
+            max: 1.0,
+            default: 0.0,
+        },
+    ];
+
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            filter: StateVariableFilter::new(FilterMode::LowPass, sample_rate),
+            cutoff: 2000.0,
+        }
+    }
+}
+
+impl Node for FilterNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &Self::INPUTS
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &Self::PARAMETERS
+    }
+
+    /// Mode picks low-pass, band-pass, high-pass, notch or peak.
+    fn set_parameter(&mut self, index: usize, value: f64) {
+        match index {
+            0 => self.filter.set_mode(choice(&FILTER_MODES, value)),
+            1 => self.cutoff = value,
+            2 => self.filter.set_resonance(value),
+            _ => (),
+        }
+    }
+
+    fn process(&mut self, _: &BlockContext, inputs: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        self.filter
+            .set_cutoff(self.cutoff * 2f64.powf(inputs[1][0]));
+        for (output, &input) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+            *output = self.filter.process(input);
+        }
+    }
+}
+
+/// Multiplies its input by the gain input and a level, e.g. to apply an envelope.
+pub struct GainNode {
+    level: Signal,
+}
+
+impl GainNode {
+    const INPUTS: [PortSpec; 2] = [PortSpec::audio("in", 0.0), PortSpec::audio("gain", 1.0)];
+    const OUTPUTS: [PortSpec; 1] = [PortSpec::audio("out", 0.0)];
+    const PARAMETERS: [ParameterSpec; 1] = [ParameterSpec {
+        name: "Level",
+        min: 0.0,
+        max: 2.0,
+        default: 1.0,
+    }];
+
+    pub fn new() -> Self {
+        Self { level: 1.0 }
+    }
+}
+
+impl Default for GainNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node for GainNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &Self::INPUTS
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &Self::PARAMETERS
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f64) {
+        if index == 0 {
+            self.level = value;
+        }
+    }
+
+    fn process(&mut self, _: &BlockContext, inputs: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        let samples = inputs[0].iter().zip(inputs[1].iter());
+        for (output, (&input, &gain)) in outputs[0].iter_mut().zip(samples) {
+            *output = input * gain * self.level;
+        }
+    }
+}
+
+/// Feedback delay.
+pub struct DelayNode {
+    delay: Delay,
+}
+
+impl DelayNode {
+    const INPUTS: [PortSpec; 1] = [PortSpec::audio("in", 0.0)];
+    const OUTPUTS: [PortSpec; 1] = [PortSpec::audio("out", 0.0)];
+    const PARAMETERS: [ParameterSpec; 3] = [
+        ParameterSpec {
+            name: "Time",
+            min: 0.01,
+            max: MAX_DELAY_TIME,
+            default: 0.3,
+        },
+        ParameterSpec {
+            name: "Feedback",
+            min: 0.0,
+            max: 0.95,
+            default: 0.4,
+        },
+        ParameterSpec {
+            name: "Mix",
+            min: 0.0,
+            max: 1.0,
+            default: 0.3,
+        },
+    ];
+
+    pub fn new(sample_rate: f64) -> Self {
+        let mut delay = Delay::new(MAX_DELAY_TIME, sample_rate);
+        for (index, parameter) in Self::PARAMETERS.iter().enumerate() {
+            Self::apply(&mut delay, index, parameter.default);
+        }
+        Self { delay }
+    }
+
+    fn apply(delay: &mut Delay, index: usize, value: f64) {
+        match index {
+            0 => delay.set_time(DelayTime::Seconds(value)),
+            1 => delay.set_feedback(value),
+            2 => delay.set_mix(value),
+            _ => (),
+        }
+    }
+}
+
+impl Node for DelayNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &Self::INPUTS
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &Self::PARAMETERS
+    }
+
+    /// Time is in seconds.
+    fn set_parameter(&mut self, index: usize, value: f64) {
+        Self::apply(&mut self.delay, index, value);
+    }
+
+    fn process(&mut self, _: &BlockContext, inputs: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        for (output, &input) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+            *output = self.delay.process(input);
+        }
+    }
+}
+
+/// Passes left and right through at a volume, to be set as the output of the graph. Patch
+/// mono signals into both inputs.
+pub struct OutputNode {
+    volume: Signal,
+}
+
+impl OutputNode {
+    const INPUTS: [PortSpec; 2] = [PortSpec::audio("left", 0.0), PortSpec::audio("right", 0.0)];
+    const OUTPUTS: [PortSpec; 2] = [PortSpec::audio("left", 0.0), PortSpec::audio("right", 0.0)];
+    const PARAMETERS: [ParameterSpec; 1] = [ParameterSpec {
+        name: "Volume",
+        min: 0.0,
+        max: 1.0,
+        default: 0.8,
+    }];
+
+    pub fn new() -> Self {
+        Self { volume: 0.8 }
+    }
+}
+
+impl Default for OutputNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node for OutputNode {
+    fn inputs(&self) -> &[PortSpec] {
+        &Self::INPUTS
+    }
+
+    fn outputs(&self) -> &[PortSpec] {
+        &Self::OUTPUTS
+    }
+
+    fn parameters(&self) -> &[ParameterSpec] {
+        &Self::PARAMETERS
+    }
+
+    fn set_parameter(&mut self, index: usize, value: f64) {
+        if index == 0 {
+            self.volume = value;
+        }
+    }
+
+    fn process(&mut self, _: &BlockContext, inputs: &[Vec<Signal>], outputs: &mut [Vec<Signal>]) {
+        for (output, input) in outputs.iter_mut().zip(inputs.iter()) {
+            for (output, &input) in output.iter_mut().zip(input.iter()) {
+                *output = input * self.volume;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::{Connection, Graph, BLOCK_SIZE};
+    use transport::ProcessContext;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn keyboard_oscillator_and_envelope_play_a_note() {
+        let mut graph = Graph::new(SAMPLE_RATE);
+        let kinds = [
+            NodeKind::Keyboard,
+            NodeKind::Oscillator,
+            NodeKind::Envelope,
+            NodeKind::Gain,
+            NodeKind::Output,
+        ];
+        let ids: Vec<_> = kinds
+            .iter()
+            .map(|kind| graph.add_node(kind.create(SAMPLE_RATE)))
+            .collect();
+        let cables = [
+            (0, 0, 1, 0),
+            (0, 1, 2, 0),
+            (1, 0, 3, 0),
+            (2, 0, 3, 1),
+            (3, 0, 4, 0),
+            (3, 0, 4, 1),
+        ];
+        for &(from, output, to, input) in cables.iter() {
+            let connection = Connection {
+                from: ids[from],
+                output,
+                to: ids[to],
+                input,
+            };
+            graph.connect(connection).unwrap();
+        }
+        graph.set_output(Some(ids[4])).unwrap();
+
+        let mut play = |key| {
+            let keys = [key; BLOCK_SIZE];
+            let context = BlockContext {
+                sample_rate: SAMPLE_RATE,
+                keys: &keys,
+                transport: ProcessContext::default(),
+            };
+            let mut output = [(0.0, 0.0); BLOCK_SIZE];
+            let mut peak: Signal = 0.0;
+            for _ in 0..200 {
+                graph.process_block(&context, &mut output);
+                peak = output
+                    .iter()
+                    .fold(peak, |peak, frame| peak.max(frame.0.abs()));
+            }
+            assert!(output.iter().all(|frame| frame.0 == frame.1));
+            peak
+        };
+        assert!(play(Some(0)) > 0.1);
+        // The release is over by the third call.
+        play(None);
+        play(None);
+        assert!(play(None) < 1e-6);
+    }
+
+    #[test]
+    fn node_kinds_round_trip_through_names() {
+        for &kind in NodeKind::ALL.iter() {
+            assert_eq!(NodeKind::from_name(kind.name()), Some(kind));
+            let node = kind.create(SAMPLE_RATE);
+            assert_eq!(node.inputs(), kind.inputs());
+            assert_eq!(node.outputs(), kind.outputs());
+            assert_eq!(node.parameters(), kind.parameters());
+            assert!(node
+                .parameters()
+                .iter()
+                .all(|p| p.min <= p.default && p.default <= p.max));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use random::Random;
+use types::{Signal, SignalProcessorFunction};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseColor {
+    /// Equal power at every frequency.
+    White,
+    /// Falls 3 dB per octave, equal power in every octave.
+    Pink,
+    /// Falls 6 dB per octave, like a random walk.
+    Brown,
+}
+
+/// Noise source, roughly in [-1.0, 1.0] for every color. Generators with the same seed
+/// produce the same sequence.
+pub struct Noise {
+    color: NoiseColor,
+    random: Random,
+    pink: [Signal; 7],
+    brown: Signal,
+}
+
+impl Noise {
+    pub fn new(color: NoiseColor) -> Self {
+        Self {
+            color,
+            random: Random::default(),
+            pink: [0.0; 7],
+            brown: 0.0,
+        }
+    }
+
+    pub fn set_color(&mut self, color: NoiseColor) {
+        self.color = color;
+    }
+
+    /// Restarts the sequence from `seed`.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.random = Random::new(seed);
+        self.pink = [0.0; 7];
+        self.brown = 0.0;
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        let white = self.random.next_bipolar();
+        match self.color {
+            NoiseColor::White => white,
+            NoiseColor::Pink => {
+                // Paul Kellet's filter bank, accurate to within 0.05 dB above 9 Hz at 44.1 kHz.
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + white * 0.055_517_9;
+                b[1] = 0.99332 * b[1] + white * 0.075_075_9;
+                b[2] = 0.96900 * b[2] + white * 0.153_852_0;
+                b[3] = 0.86650 * b[3] + white * 0.310_485_6;
+                b[4] = 0.55000 * b[4] + white * 0.532_952_2;
+                b[5] = -0.7616 * b[5] - white * 0.016_898_0;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115_926;
+                pink * 0.11
+            }
+            NoiseColor::Brown => {
+                // Leaky integrator, so the walk does not wander off.
+                self.brown = (self.brown + 0.02 * white) / 1.02;
+                self.brown * 3.5
+            }
+        }
+    }
+
+    /// Wraps the generator in a processor function that sounds while a key is held.
+    pub fn into_processor_function(mut self) -> SignalProcessorFunction {
+        Box::new(move |key| {
+            let sample = self.next_sample();
+            if key.is_some() {
+                sample
+            } else {
+                0.0
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(color: NoiseColor, seed: u32, count: usize) -> Vec<Signal> {
+        let mut noise = Noise::new(color);
+        noise.set_seed(seed);
+        (0..count).map(|_| noise.next_sample()).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_noise() {
+        for &color in [NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown].iter() {
+            assert_eq!(samples(color, 7, 1000), samples(color, 7, 1000));
+            assert_ne!(samples(color, 7, 1000), samples(color, 8, 1000));
+        }
+    }
+
+    #[test]
+    fn darker_colors_change_more_slowly() {
+        let mean_step = |color| {
+            let samples = samples(color, 1, 48_000);
+            let total: Signal = samples.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+            total / samples.len() as Signal
+        };
+        let white = mean_step(NoiseColor::White);
+        let pink = mean_step(NoiseColor::Pink);
+        let brown = mean_step(NoiseColor::Brown);
+        assert!(white > pink && pink > brown);
+
+        for &color in [NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown].iter() {
+            assert!(samples(color, 1, 48_000).iter().all(|s| s.abs() < 1.5));
+        }
+    }
+}
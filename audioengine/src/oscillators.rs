@@ -0,0 +1,337 @@
+use std::f64::consts::PI;
+use types::{Phase, Signal};
+
+/// Frequency of key 0, the A key on the computer keyboard.
+pub const MIDDLE_C: f64 = 261.625_565_300_598_6;
+
+/// Frequency of a key as handed to a processor function, in equal temperament. Fractional
+/// keys, e.g. from a glide, land in between. `tuning::Tuning` covers other tunings.
+pub fn key_to_frequency(key: f64) -> f64 {
+    MIDDLE_C * 2f64.powf(key / 12.0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+/// Phase accumulator oscillator with PolyBLEP corrections for steps in the waveform and
+/// PolyBLAMP corrections for kinks, which keeps aliasing low even for high notes.
+///
+/// Phase is measured in cycles, in [0.0, 1.0). Corrections are applied on both sides of a
+/// discontinuity, so the output lags the phase by one sample.
+pub struct Oscillator {
+    waveform: Waveform,
+    sample_rate: f64,
+    frequency: f64,
+    increment: Phase,
+    phase: Phase,
+    pulse_width: Phase,
+    // Output waiting for corrections from discontinuities in the next sample.
+    pending: Signal,
+    correction: Signal,
+    wrap_offset: Option<f64>,
+}
+
+impl Oscillator {
+    pub fn new(waveform: Waveform, sample_rate: f64) -> Self {
+        Self {
+            waveform,
+            sample_rate,
+            frequency: 0.0,
+            increment: 0.0,
+            phase: 0.0,
+            pulse_width: 0.5,
+            pending: 0.0,
+            correction: 0.0,
+            wrap_offset: None,
+        }
+    }
+
+    pub fn waveform(&self) -> Waveform {
+        self.waveform
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Frequencies are limited to the range [0.0, sample_rate / 2].
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency.clamp(0.0, self.sample_rate * 0.5);
+        self.increment = self.frequency / self.sample_rate;
+    }
+
+    /// Fraction of the cycle the square wave spends high, limited to [0.01, 0.99].
+    pub fn set_pulse_width(&mut self, pulse_width: Phase) {
+        self.pulse_width = pulse_width.clamp(0.01, 0.99);
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Jumps to `phase` immediately, e.g. on note start. Unlike hard sync the jump is not
+    /// band-limited.
+    pub fn reset_phase(&mut self, phase: Phase) {
+        self.phase = phase - phase.floor();
+        self.pending = self.naive(self.phase);
+    }
+
+    /// If the phase wrapped during the last sample, how far past the wrap the sample was
+    /// taken, as a fraction of a sample. Feed this to `next_sample_synced` of a slave
+    /// oscillator for hard sync.
+    pub fn wrap_offset(&self) -> Option<f64> {
+        self.wrap_offset
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        self.next_sample_synced(None)
+    }
+
+    /// Like `next_sample`, but restarts the cycle if `sync` holds the wrap offset of a master
+    /// oscillator.
+    pub fn next_sample_synced(&mut self, sync: Option<f64>) -> Signal {
+        self.correction = 0.0;
+        self.wrap_offset = None;
+
+        match sync {
+            Some(offset) if offset < 1.0 => {
+                self.advance(1.0 - offset, offset);
+                let before = self.naive(self.phase);
+                self.phase = 0.0;
+                let after = self.naive(self.phase);
+                self.add_step(after - before, offset);
+                self.advance(offset, 0.0);
+            }
+            _ => self.advance(1.0, 0.0),
+        }
+
+        let current = self.naive(self.phase) + self.correction;
+        std::mem::replace(&mut self.pending, current)
+    }
+
+    /// Moves the phase forward by `length` samples, ending `remaining` samples before the
+    /// current sample is taken, and corrects for every discontinuity passed on the way.
+    fn advance(&mut self, length: f64, remaining: f64) {
+        let start = self.phase;
+        let end = start + self.increment * length;
+        let increment = self.increment;
+
+        let offset_after = |threshold: Phase| -> Option<f64> {
+            if start < threshold && threshold <= end {
+                Some((end - threshold) / increment + remaining)
+            } else {
+                None
+            }
+        };
+
+        match self.waveform {
+            Waveform::Sine | Waveform::Saw => (),
+            Waveform::Square => {
+                let pulse_width = self.pulse_width;
+                for offset in offset_after(pulse_width)
+                    .into_iter()
+                    .chain(offset_after(pulse_width + 1.0))
+                {
+                    self.add_step(-2.0, offset);
+                }
+            }
+            Waveform::Triangle => {
+                for offset in offset_after(0.5).into_iter().chain(offset_after(1.5)) {
+                    self.add_ramp(-8.0 * increment, offset);
+                }
+            }
+        }
+
+        if let Some(offset) = offset_after(1.0) {
+            match self.waveform {
+                Waveform::Sine => (),
+                Waveform::Saw => self.add_step(-2.0, offset),
+                Waveform::Square => self.add_step(2.0, offset),
+                Waveform::Triangle => self.add_ramp(8.0 * increment, offset),
+            }
+            self.wrap_offset = Some(offset);
+        }
+
+        self.phase = end - end.floor();
+    }
+
+    /// A step of `height` happened `offset` samples before the current sample.
+    fn add_step(&mut self, height: Signal, offset: f64) {
+        let before = 1.0 - offset;
+        self.pending += height * 0.5 * offset * offset;
+        self.correction -= height * 0.5 * before * before;
+    }
+
+    /// The slope changed by `slope` per sample `offset` samples before the current sample.
+    fn add_ramp(&mut self, slope: Signal, offset: f64) {
+        let before = 1.0 - offset;
+        self.pending += slope * offset * offset * offset / 6.0;
+        self.correction += slope * before * before * before / 6.0;
+    }
+
+    fn naive(&self, phase: Phase) -> Signal {
+        match self.waveform {
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Square => {
+                if phase < self.pulse_width {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => {
+                if phase < 0.5 {
+                    4.0 * phase - 1.0
+                } else {
+                    3.0 - 4.0 * phase
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    const LENGTH: usize = 4096;
+    // A prime number of cycles in `LENGTH` samples, so that every harmonic falls exactly on a
+    // multiple of this bin while aliased partials land in between.
+    const CYCLES: usize = 191;
+
+    fn fundamental() -> f64 {
+        CYCLES as f64 * SAMPLE_RATE / LENGTH as f64
+    }
+
+    fn render(oscillator: &mut Oscillator) -> Vec<Signal> {
+        // Run one period first to get rid of the start-up transient.
+        for _ in 0..LENGTH {
+            oscillator.next_sample();
+        }
+        (0..LENGTH).map(|_| oscillator.next_sample()).collect()
+    }
+
+    /// Share of the energy that lies outside of the harmonic series of the fundamental.
+    fn aliasing_energy(signal: &[Signal]) -> f64 {
+        let n = signal.len();
+        let mut total = 0.0;
+        let mut aliased = 0.0;
+        for bin in 1..n / 2 {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, value) in signal.iter().enumerate() {
+                let angle = 2.0 * PI * (bin * i % n) as f64 / n as f64;
+                re += value * angle.cos();
+                im -= value * angle.sin();
+            }
+            let power = re * re + im * im;
+            total += power;
+            if bin % CYCLES != 0 {
+                aliased += power;
+            }
+        }
+        aliased / total
+    }
+
+    fn naive_saw() -> Vec<Signal> {
+        let increment = fundamental() / SAMPLE_RATE;
+        (0..LENGTH)
+            .map(|i| {
+                let phase = i as f64 * increment;
+                2.0 * (phase - phase.floor()) - 1.0
+            })
+            .collect()
+    }
+
+    /// Sum of the saw's harmonics up to Nyquist, which has no aliasing at all.
+    fn additive_saw() -> Vec<Signal> {
+        let harmonics = (SAMPLE_RATE / 2.0 / fundamental()) as usize;
+        (0..LENGTH)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE;
+                (1..=harmonics)
+                    .map(|k| {
+                        let k = k as f64;
+                        -2.0 / PI * (2.0 * PI * k * fundamental() * t).sin() / k
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn oscillator(waveform: Waveform) -> Oscillator {
+        let mut oscillator = Oscillator::new(waveform, SAMPLE_RATE);
+        oscillator.set_frequency(fundamental());
+        oscillator
+    }
+
+    #[test]
+    fn reference_has_no_aliasing() {
+        assert!(aliasing_energy(&additive_saw()) < 1e-12);
+    }
+
+    #[test]
+    fn saw_aliases_far_less_than_naive_saw() {
+        let naive = aliasing_energy(&naive_saw());
+        let blep = aliasing_energy(&render(&mut oscillator(Waveform::Saw)));
+        assert!(blep < naive / 10.0, "naive {} blep {}", naive, blep);
+        assert!(blep < 5e-3, "blep {}", blep);
+    }
+
+    #[test]
+    fn square_aliasing_is_low() {
+        let mut square = oscillator(Waveform::Square);
+        square.set_pulse_width(0.3);
+        let aliasing = aliasing_energy(&render(&mut square));
+        assert!(aliasing < 5e-3, "aliasing {}", aliasing);
+    }
+
+    #[test]
+    fn triangle_aliasing_is_low() {
+        let aliasing = aliasing_energy(&render(&mut oscillator(Waveform::Triangle)));
+        assert!(aliasing < 1e-4, "aliasing {}", aliasing);
+    }
+
+    #[test]
+    fn sine_is_pure() {
+        let aliasing = aliasing_energy(&render(&mut oscillator(Waveform::Sine)));
+        assert!(aliasing < 1e-12, "aliasing {}", aliasing);
+    }
+
+    #[test]
+    fn hard_sync_follows_master_frequency() {
+        let mut master = oscillator(Waveform::Sine);
+        let mut slave = Oscillator::new(Waveform::Saw, SAMPLE_RATE);
+        slave.set_frequency(fundamental() * 2.7);
+
+        let mut synced = Vec::with_capacity(LENGTH);
+        for _ in 0..2 * LENGTH {
+            master.next_sample();
+            synced.push(slave.next_sample_synced(master.wrap_offset()));
+        }
+
+        let aliasing = aliasing_energy(&synced[LENGTH..]);
+        assert!(aliasing < 1e-2, "aliasing {}", aliasing);
+    }
+
+    #[test]
+    fn reset_phase_restarts_cycle() {
+        let mut saw = oscillator(Waveform::Saw);
+        for _ in 0..10 {
+            saw.next_sample();
+        }
+        saw.reset_phase(0.25);
+        assert_eq!(saw.phase(), 0.25);
+        assert_eq!(saw.next_sample(), -0.5);
+    }
+}
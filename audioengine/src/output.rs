@@ -0,0 +1,238 @@
+use random::Random;
+use types::{Signal, StereoSignal};
+
+/// How signals outside of [-1.0, 1.0] are brought back into range before conversion.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipMode {
+    /// Clamp to full scale.
+    Hard,
+    /// Pass the signal through untouched below `SOFT_CLIP_KNEE`, then bend it smoothly towards
+    /// full scale.
+    Soft,
+}
+
+/// Noise added before quantizing to an integer format. Ignored for float output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    None,
+    /// Triangular noise with a peak amplitude of one least significant bit.
+    Tpdf,
+}
+
+const SOFT_CLIP_KNEE: Signal = 0.8;
+
+/// A sample type the output stage can write to a device buffer.
+pub trait OutputSample: Copy {
+    /// Whether the format is quantized, and thus benefits from dithering.
+    const INTEGER: bool;
+
+    /// Converts a clipped signal in [-1.0, 1.0]. `dither` is noise measured in least
+    /// significant bits.
+    fn from_signal(value: Signal, dither: Signal) -> Self;
+}
+
+impl OutputSample for i16 {
+    const INTEGER: bool = true;
+
+    fn from_signal(value: Signal, dither: Signal) -> Self {
+        let scaled = (value * 32768.0 + dither).round();
+        scaled.clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16
+    }
+}
+
+impl OutputSample for u16 {
+    const INTEGER: bool = true;
+
+    fn from_signal(value: Signal, dither: Signal) -> Self {
+        // The value 0 corresponds to 32768.
+        (i32::from(i16::from_signal(value, dither)) + 32768) as u16
+    }
+}
+
+impl OutputSample for f32 {
+    const INTEGER: bool = false;
+
+    fn from_signal(value: Signal, _dither: Signal) -> Self {
+        value as f32
+    }
+}
+
+pub struct OutputStage {
+    clip_mode: ClipMode,
+    dither: Dither,
+    random: Random,
+}
+
+impl OutputStage {
+    pub fn new(clip_mode: ClipMode, dither: Dither) -> Self {
+        Self {
+            clip_mode,
+            dither,
+            random: Random::default(),
+        }
+    }
+
+    pub fn clip(&self, value: Signal) -> Signal {
+        match self.clip_mode {
+            ClipMode::Hard => value.clamp(-1.0, 1.0),
+            ClipMode::Soft => {
+                let magnitude = value.abs();
+                if magnitude <= SOFT_CLIP_KNEE {
+                    value
+                } else {
+                    let range = 1.0 - SOFT_CLIP_KNEE;
+                    let bent =
+                        SOFT_CLIP_KNEE + range * ((magnitude - SOFT_CLIP_KNEE) / range).tanh();
+                    bent * value.signum()
+                }
+            }
+        }
+    }
+
+    pub fn convert<T: OutputSample>(&mut self, value: Signal) -> T {
+        let dither = match self.dither {
+            Dither::Tpdf if T::INTEGER => {
+                self.random.next_unipolar() + self.random.next_unipolar() - 1.0
+            }
+            _ => 0.0,
+        };
+        T::from_signal(self.clip(value), dither)
+    }
+
+    /// Writes `value` to every channel of an interleaved frame.
+    pub fn write_frame<T: OutputSample>(&mut self, frame: &mut [T], value: Signal) {
+        let sample = self.convert(value);
+        for out in frame.iter_mut() {
+            *out = sample;
+        }
+    }
+
+    /// Writes a stereo signal to an interleaved frame. Left and right go to the first two
+    /// channels and any further channels get their mix. A mono frame gets the mix as well.
+    pub fn write_stereo_frame<T: OutputSample>(
+        &mut self,
+        frame: &mut [T],
+        (left, right): StereoSignal,
+    ) {
+        match frame {
+            [] => (),
+            [mono] => *mono = self.convert((left + right) * 0.5),
+            [first, second, rest @ ..] => {
+                *first = self.convert(left);
+                *second = self.convert(right);
+                self.write_frame(rest, (left + right) * 0.5);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(clip_mode: ClipMode) -> OutputStage {
+        OutputStage::new(clip_mode, Dither::None)
+    }
+
+    #[test]
+    fn i16_maps_silence_to_zero() {
+        assert_eq!(stage(ClipMode::Hard).convert::<i16>(0.0), 0);
+    }
+
+    #[test]
+    fn i16_reaches_both_extremes() {
+        let mut stage = stage(ClipMode::Hard);
+        assert_eq!(stage.convert::<i16>(1.0), i16::MAX);
+        assert_eq!(stage.convert::<i16>(-1.0), i16::MIN);
+        assert_eq!(stage.convert::<i16>(-0.5), -16384);
+    }
+
+    #[test]
+    fn i16_overs_do_not_wrap() {
+        let mut stage = stage(ClipMode::Hard);
+        assert_eq!(stage.convert::<i16>(3.0), i16::MAX);
+        assert_eq!(stage.convert::<i16>(-3.0), i16::MIN);
+    }
+
+    #[test]
+    fn u16_maps_silence_to_midpoint() {
+        assert_eq!(stage(ClipMode::Hard).convert::<u16>(0.0), 32768);
+    }
+
+    #[test]
+    fn u16_reaches_both_extremes() {
+        let mut stage = stage(ClipMode::Hard);
+        assert_eq!(stage.convert::<u16>(1.0), u16::MAX);
+        assert_eq!(stage.convert::<u16>(-1.0), 0);
+        assert_eq!(stage.convert::<u16>(5.0), u16::MAX);
+        assert_eq!(stage.convert::<u16>(-5.0), 0);
+    }
+
+    #[test]
+    fn f32_passes_values_in_range() {
+        let mut stage = stage(ClipMode::Hard);
+        assert_eq!(stage.convert::<f32>(0.0), 0.0);
+        assert_eq!(stage.convert::<f32>(-0.25), -0.25);
+        assert_eq!(stage.convert::<f32>(1.5), 1.0);
+        assert_eq!(stage.convert::<f32>(-1.5), -1.0);
+    }
+
+    #[test]
+    fn soft_clip_is_transparent_below_knee_and_bounded_above() {
+        let stage = stage(ClipMode::Soft);
+        assert_eq!(stage.clip(0.5), 0.5);
+        assert_eq!(stage.clip(-0.5), -0.5);
+
+        let mut previous = stage.clip(SOFT_CLIP_KNEE);
+        for i in 1..100 {
+            let clipped = stage.clip(SOFT_CLIP_KNEE + f64::from(i) * 0.05);
+            assert!(clipped >= previous && clipped <= 1.0);
+            previous = clipped;
+        }
+        assert!(stage.clip(0.9) < 0.9);
+        assert!(stage.clip(-10.0) >= -1.0);
+    }
+
+    #[test]
+    fn tpdf_dither_stays_within_one_lsb() {
+        let mut stage = OutputStage::new(ClipMode::Hard, Dither::Tpdf);
+        let mut sum = 0.0;
+        for _ in 0..10_000 {
+            let sample = stage.convert::<i16>(0.0);
+            assert!((-1..=1).contains(&sample));
+            sum += f64::from(sample);
+        }
+        assert!((sum / 10_000.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn dither_is_not_applied_to_float_output() {
+        let mut stage = OutputStage::new(ClipMode::Hard, Dither::Tpdf);
+        assert_eq!(stage.convert::<f32>(0.0), 0.0);
+    }
+
+    #[test]
+    fn write_frame_fills_every_channel() {
+        let mut stage = stage(ClipMode::Hard);
+        let mut frame = [0i16; 2];
+        stage.write_frame(&mut frame, 0.5);
+        assert_eq!(frame, [16384, 16384]);
+    }
+
+    #[test]
+    fn write_stereo_frame_maps_channels() {
+        let mut stage = stage(ClipMode::Hard);
+
+        let mut mono = [0i16; 1];
+        stage.write_stereo_frame(&mut mono, (0.5, 0.0));
+        assert_eq!(mono, [8192]);
+
+        let mut stereo = [0i16; 2];
+        stage.write_stereo_frame(&mut stereo, (0.5, -0.5));
+        assert_eq!(stereo, [16384, -16384]);
+
+        let mut surround = [0i16; 4];
+        stage.write_stereo_frame(&mut surround, (0.5, 0.0));
+        assert_eq!(surround, [16384, 0, 8192, 8192]);
+    }
+}
@@ -0,0 +1,169 @@
+use filter::FirstOrderAllPass;
+use lfo::{Lfo, LfoRate, LfoShape};
+use transport::ProcessContext;
+use types::{Signal, StereoSignal};
+
+pub const MAX_PHASER_STAGES: usize = 12;
+const MAX_FEEDBACK: Signal = 0.95;
+
+/// Chain of all-passes with swept cutoffs. Mixed with the dry signal, every two stages add a
+/// notch moving through the spectrum.
+pub struct Phaser {
+    sample_rate: f64,
+    stages: [Vec<FirstOrderAllPass>; 2],
+    lfos: [Lfo; 2],
+    stage_count: usize,
+    min_cutoff: f64,
+    max_cutoff: f64,
+    feedback: Signal,
+    mix: Signal,
+    last_outputs: [Signal; 2],
+}
+
+impl Phaser {
+    pub fn new(sample_rate: f64) -> Self {
+        let chain = || {
+            (0..MAX_PHASER_STAGES)
+                .map(|_| FirstOrderAllPass::new(sample_rate))
+                .collect()
+        };
+        let mut phaser = Self {
+            sample_rate,
+            stages: [chain(), chain()],
+            lfos: [
+                Lfo::new(LfoShape::Triangle, sample_rate),
+                Lfo::new(LfoShape::Triangle, sample_rate),
+            ],
+            stage_count: 4,
+            min_cutoff: 200.0,
+            max_cutoff: 4000.0,
+            feedback: 0.3,
+            mix: 0.5,
+            last_outputs: [0.0; 2],
+        };
+        phaser.set_rate(LfoRate::Hertz(0.5));
+        phaser.set_stereo_phase(0.25);
+        phaser
+    }
+
+    /// Number of all-pass stages, from 1 to `MAX_PHASER_STAGES`. Even counts give the
+    /// classic sound.
+    pub fn set_stages(&mut self, count: usize) {
+        self.stage_count = count.clamp(1, MAX_PHASER_STAGES);
+    }
+
+    pub fn set_rate(&mut self, rate: LfoRate) {
+        for lfo in self.lfos.iter_mut() {
+            lfo.set_rate(rate);
+        }
+    }
+
+    /// Tempo in beats per minute, used by `LfoRate::Beats`.
+    pub fn set_tempo(&mut self, bpm: f64) {
+        for lfo in self.lfos.iter_mut() {
+            lfo.set_tempo(bpm);
+        }
+    }
+
+    /// Keeps the tempo at that of the transport, for use in a context post processor.
+    pub fn follow_tempo(&mut self, context: &ProcessContext) {
+        for lfo in self.lfos.iter_mut() {
+            lfo.follow_tempo(context);
+        }
+    }
+
+    /// Lowest and highest cutoff of the sweep, in Hz.
+    pub fn set_range(&mut self, min_cutoff: f64, max_cutoff: f64) {
+        self.min_cutoff = min_cutoff.max(1.0);
+        self.max_cutoff = max_cutoff.max(self.min_cutoff);
+    }
+
+    /// Share of the output fed back into the chain, in [-0.95, 0.95], sharpening the
+    /// notches.
+    pub fn set_feedback(&mut self, feedback: Signal) {
+        self.feedback = feedback.clamp(-MAX_FEEDBACK, MAX_FEEDBACK);
+    }
+
+    /// Offset, in cycles, of the right channel's sweep from the left. Restarts both sweeps.
+    pub fn set_stereo_phase(&mut self, phase: f64) {
+        for (index, lfo) in self.lfos.iter_mut().enumerate() {
+            lfo.set_start_phase(phase * index as f64);
+            lfo.retrigger();
+        }
+    }
+
+    /// Balance between the dry input at 0.0 and only the all-pass chain at 1.0. The notches
+    /// are deepest at 0.5.
+    pub fn set_mix(&mut self, mix: Signal) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+
+    pub fn clear(&mut self) {
+        for stage in self.stages.iter_mut().flat_map(|chain| chain.iter_mut()) {
+            stage.reset();
+        }
+        self.last_outputs = [0.0; 2];
+    }
+
+    pub fn process(&mut self, (left, right): StereoSignal) -> StereoSignal {
+        (
+            self.process_channel(0, left),
+            self.process_channel(1, right),
+        )
+    }
+
+    fn process_channel(&mut self, channel: usize, input: Signal) -> Signal {
+        let sweep = (self.lfos[channel].next_sample() + 1.0) * 0.5;
+        let cutoff = self.min_cutoff * (self.max_cutoff / self.min_cutoff).powf(sweep);
+        let coefficient = FirstOrderAllPass::coefficient(cutoff, self.sample_rate);
+
+        let mut signal = input + self.last_outputs[channel] * self.feedback;
+        for stage in self.stages[channel].iter_mut().take(self.stage_count) {
+            stage.set_coefficient(coefficient);
+            signal = stage.process(signal);
+        }
+        self.last_outputs[channel] = signal;
+
+        input * (1.0 - self.mix) + signal * self.mix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn stable_at_extreme_feedback() {
+        for &feedback in [-0.95, 0.95].iter() {
+            let mut phaser = Phaser::new(SAMPLE_RATE);
+            phaser.set_stages(MAX_PHASER_STAGES);
+            phaser.set_feedback(feedback);
+            phaser.set_rate(LfoRate::Hertz(5.0));
+            phaser.set_mix(1.0);
+            let mut random = Random::new(7);
+            for n in 0..96_000 {
+                let input = if n < 48_000 {
+                    random.next_bipolar()
+                } else {
+                    0.0
+                };
+                let (left, right) = phaser.process((input, input));
+                assert!(left.is_finite() && left.abs() < 25.0, "{} at {}", left, n);
+                assert!(right.is_finite() && right.abs() < 25.0);
+            }
+        }
+    }
+
+    #[test]
+    fn dry_mix_passes_input_through() {
+        let mut phaser = Phaser::new(SAMPLE_RATE);
+        phaser.set_mix(0.0);
+        for n in 0..1000 {
+            let input = (n as f64 * 0.01).sin();
+            assert_eq!(phaser.process((input, -input)), (input, -input));
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use delay::DelayLine;
+use filter::{FilterMode, StateVariableFilter};
+use noise::{Noise, NoiseColor};
+use std::sync::Arc;
+use tuning::Tuning;
+use types::{Signal, SignalProcessorFunction};
+
+const MIN_FREQUENCY: f64 = 20.0;
+/// Centre frequencies of the body resonances, loosely those of an acoustic guitar.
+const BODY_MODES: [f64; 3] = [100.0, 210.0, 400.0];
+const BODY_RESONANCE: f64 = 0.9;
+/// Brings the resonant band-passes back to about unity gain at their peaks.
+const BODY_GAIN: Signal = 0.2;
+/// Below this level a released string counts as silent.
+const SILENCE: Signal = 1e-4;
+
+/// Extended Karplus-Strong string. A burst of noise, shaped by the pick position, circulates
+/// through a delay line one period long with a low-pass in the loop, and the result can be
+/// coloured by a few body resonances.
+pub struct PluckedString {
+    sample_rate: f64,
+    line: DelayLine,
+    noise: Noise,
+    excitation: Vec<Signal>,
+    excitation_index: usize,
+    body: Vec<StateVariableFilter>,
+
+    frequency: f64,
+    decay: f64,
+    release: f64,
+    damping: Signal,
+    pick_position: f64,
+    body_amount: Signal,
+
+    loop_delay: f64,
+    loop_gain: Signal,
+    released: bool,
+    last_delayed: Signal,
+    level: Signal,
+    tuning: Arc<Tuning>,
+    current_key: Option<i32>,
+}
+
+impl PluckedString {
+    pub fn new(sample_rate: f64) -> Self {
+        let longest_period = (sample_rate / MIN_FREQUENCY).ceil() as usize;
+        let body = BODY_MODES
+            .iter()
+            .map(|&frequency| {
+                let mut filter = StateVariableFilter::new(FilterMode::BandPass, sample_rate);
+                filter.set_cutoff(frequency);
+                filter.set_resonance(BODY_RESONANCE);
+                filter
+            })
+            .collect();
+
+        let mut string = Self {
+            sample_rate,
+            line: DelayLine::new(longest_period + 1),
+            noise: Noise::new(NoiseColor::White),
+            excitation: Vec::with_capacity(longest_period + 1),
+            excitation_index: 0,
+            body,
+            frequency: 220.0,
+            decay: 3.0,
+            release: 0.1,
+            damping: 0.5,
+            pick_position: 0.2,
+            body_amount: 0.0,
+            loop_delay: 1.0,
+            loop_gain: 0.0,
+            released: false,
+            last_delayed: 0.0,
+            level: 0.0,
+            tuning: Arc::new(Tuning::default()),
+            current_key: None,
+        };
+        string.update_loop();
+        string
+    }
+
+    /// Time in seconds for a held note to fall by 60 dB.
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.decay = seconds.max(0.001);
+        self.update_loop();
+    }
+
+    /// Time in seconds for a released note to fall by 60 dB.
+    pub fn set_release(&mut self, seconds: f64) {
+        self.release = seconds.max(0.001);
+        self.update_loop();
+    }
+
+    /// In [0.0, 1.0]. Higher values make the high harmonics die out faster, from bright
+    /// steel at 0.0 to dull nylon at 1.0.
+    pub fn set_damping(&mut self, damping: Signal) {
+        self.damping = damping.clamp(0.0, 1.0);
+        self.update_loop();
+    }
+
+    /// Where the string is plucked, as a share of its length in (0.0, 0.5]. Harmonics with a
+    /// node at that point are missing, so plucking near the middle sounds hollow.
+    pub fn set_pick_position(&mut self, position: f64) {
+        self.pick_position = position.clamp(0.01, 0.5);
+    }
+
+    /// Mix of the body resonances in [0.0, 1.0].
+    pub fn set_body(&mut self, amount: Signal) {
+        self.body_amount = amount.clamp(0.0, 1.0);
+    }
+
+    /// Seeds the noise of the pluck, making notes repeatable.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.noise.set_seed(seed);
+    }
+
+    /// Tuning `gate` plays keys in. Keys it leaves unmapped damp the string.
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+    }
+
+    /// Plucks the string. `velocity` is in [0.0, 1.0].
+    pub fn note_on(&mut self, frequency: f64, velocity: Signal) {
+        self.frequency = frequency.clamp(MIN_FREQUENCY, self.sample_rate / 3.0);
+        self.released = false;
+        self.update_loop();
+
+        let period = (self.sample_rate / self.frequency).round() as usize;
+        let pick = ((period as f64 * self.pick_position).round() as usize).max(1);
+        self.excitation.clear();
+        for _ in 0..period {
+            self.excitation.push(self.noise.next_sample() * velocity);
+        }
+        // A comb filter removes the harmonics with a node at the pick position. Running
+        // from the end backwards keeps the earlier samples unfiltered until they are used.
+        for index in (pick..period).rev() {
+            self.excitation[index] -= self.excitation[index - pick];
+        }
+        self.excitation_index = 0;
+    }
+
+    /// Damps the string over the release time.
+    pub fn note_off(&mut self) {
+        self.released = true;
+        self.update_loop();
+    }
+
+    /// Drives the string from the key handed to a processor function. A different key counts
+    /// as a new note.
+    pub fn gate(&mut self, key: Option<i32>) {
+        if key == self.current_key {
+            return;
+        }
+        match key.and_then(|key| self.tuning.frequency(f64::from(key))) {
+            Some(frequency) => self.note_on(frequency, 1.0),
+            None => self.note_off(),
+        }
+        self.current_key = key;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.excitation_index < self.excitation.len() || self.level > SILENCE
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        let excitation = match self.excitation.get(self.excitation_index) {
+            Some(&sample) => {
+                self.excitation_index += 1;
+                sample
+            }
+            None => 0.0,
+        };
+
+        // Two point low-pass, delaying the loop by up to half a sample, which `update_loop`
+        // takes off the delay line.
+        let blend = self.damping * 0.5;
+        let delayed = self.line.read(self.loop_delay);
+        let filtered = delayed * (1.0 - blend) + self.last_delayed * blend;
+        self.last_delayed = delayed;
+
+        let output = excitation + filtered * self.loop_gain;
+        self.line.write(output);
+        self.level = output.abs().max(self.level * 0.999);
+
+        if self.body_amount > 0.0 {
+            let body: Signal = self.body.iter_mut().map(|f| f.process(output)).sum();
+            output + body * BODY_GAIN * self.body_amount
+        } else {
+            output
+        }
+    }
+
+    /// Wraps the string in a processor function for `EngineController::set_processor_function`.
+    pub fn into_processor_function(mut self) -> SignalProcessorFunction {
+        Box::new(move |key| {
+            self.gate(key);
+            self.next_sample()
+        })
+    }
+
+    fn update_loop(&mut self) {
+        let period = self.sample_rate / self.frequency;
+        self.loop_delay = (period - self.damping * 0.5).max(1.0);
+        let time = if self.released {
+            self.release
+        } else {
+            self.decay
+        };
+        // The signal passes the loop `frequency` times per second.
+        self.loop_gain = 0.001f64.powf(1.0 / (time * self.frequency));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn undamped_string_repeats_every_period() {
+        let mut string = PluckedString::new(SAMPLE_RATE);
+        string.set_damping(0.0);
+        string.note_on(480.0, 1.0);
+
+        let output: Vec<Signal> = (0..1000).map(|_| string.next_sample()).collect();
+        let gain = string.loop_gain;
+        assert!(output[..100].iter().any(|s| s.abs() > 0.1));
+        for index in 100..1000 {
+            assert!((output[index] - output[index - 100] * gain).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn released_string_falls_silent() {
+        let mut string = PluckedString::new(SAMPLE_RATE);
+        string.set_body(1.0);
+        string.gate(Some(60));
+        for _ in 0..4800 {
+            string.next_sample();
+        }
+        assert!(string.is_active());
+
+        string.gate(None);
+        for _ in 0..48_000 {
+            string.next_sample();
+        }
+        assert!(!string.is_active());
+    }
+}
@@ -0,0 +1,158 @@
+/// How long a glide between two notes takes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GlideMode {
+    /// Every glide takes this many seconds, whatever the interval.
+    ConstantTime(f64),
+    /// Glides move at this many seconds per octave, so wider intervals take longer.
+    ConstantRate(f64),
+}
+
+/// Slews the pitch between notes. Feed it the key handed to a processor function every
+/// sample and it returns the pitch to play, in keys, fractional while gliding.
+pub struct Glide {
+    sample_rate: f64,
+    mode: GlideMode,
+    legato_only: bool,
+    pitch: Option<f64>,
+    target: f64,
+    // Keys per sample towards the target.
+    step: f64,
+    current_key: Option<i32>,
+}
+
+impl Glide {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            mode: GlideMode::ConstantTime(0.1),
+            legato_only: false,
+            pitch: None,
+            target: 0.0,
+            step: 0.0,
+            current_key: None,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: GlideMode) {
+        self.mode = mode;
+    }
+
+    /// When set, only notes played while another is held glide. Detached notes start right
+    /// at their pitch.
+    pub fn set_legato_only(&mut self, legato_only: bool) {
+        self.legato_only = legato_only;
+    }
+
+    /// Jumps to `key` without gliding, or forgets the last pitch with `None` so the next note
+    /// starts at its own pitch.
+    pub fn reset(&mut self, key: Option<i32>) {
+        self.pitch = key.map(f64::from);
+        self.target = self.pitch.unwrap_or(0.0);
+        self.step = 0.0;
+        self.current_key = key;
+    }
+
+    pub fn is_gliding(&self) -> bool {
+        self.pitch.is_some_and(|pitch| pitch != self.target)
+    }
+
+    /// Returns the pitch for this sample. After a release the last pitch is held, so release
+    /// tails keep sounding at the right pitch. `None` until the first note.
+    pub fn next_pitch(&mut self, key: Option<i32>) -> Option<f64> {
+        if key != self.current_key {
+            if let Some(key) = key {
+                let legato = self.current_key.is_some();
+                self.start(f64::from(key), legato);
+            }
+            self.current_key = key;
+        }
+
+        if let Some(pitch) = self.pitch.as_mut() {
+            let distance = self.target - *pitch;
+            if distance.abs() <= self.step {
+                *pitch = self.target;
+            } else {
+                *pitch += self.step * distance.signum();
+            }
+        }
+        self.pitch
+    }
+
+    fn start(&mut self, target: f64, legato: bool) {
+        self.target = target;
+        let pitch = match self.pitch {
+            Some(pitch) if legato || !self.legato_only => pitch,
+            _ => {
+                self.pitch = Some(target);
+                self.step = 0.0;
+                return;
+            }
+        };
+
+        let samples = match self.mode {
+            GlideMode::ConstantTime(seconds) => seconds * self.sample_rate,
+            GlideMode::ConstantRate(seconds_per_octave) => {
+                seconds_per_octave * self.sample_rate * (target - pitch).abs() / 12.0
+            }
+        };
+        self.step = if samples >= 1.0 {
+            (target - pitch).abs() / samples
+        } else {
+            f64::INFINITY
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 1000.0;
+
+    fn run(glide: &mut Glide, key: Option<i32>, samples: usize) -> Option<f64> {
+        (0..samples).fold(None, |_, _| glide.next_pitch(key))
+    }
+
+    #[test]
+    fn first_note_starts_at_its_pitch() {
+        let mut glide = Glide::new(SAMPLE_RATE);
+        assert_eq!(glide.next_pitch(Some(7)), Some(7.0));
+    }
+
+    #[test]
+    fn constant_time_glides_take_the_same_time() {
+        for &interval in [2, 12].iter() {
+            let mut glide = Glide::new(SAMPLE_RATE);
+            glide.set_mode(GlideMode::ConstantTime(0.1));
+            run(&mut glide, Some(0), 1);
+
+            let halfway = run(&mut glide, Some(interval), 50).unwrap();
+            assert!((halfway - f64::from(interval) / 2.0).abs() < 1e-9);
+            let end = run(&mut glide, Some(interval), 50).unwrap();
+            assert!((end - f64::from(interval)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn constant_rate_glides_take_longer_for_wider_intervals() {
+        let mut glide = Glide::new(SAMPLE_RATE);
+        glide.set_mode(GlideMode::ConstantRate(0.12));
+        run(&mut glide, Some(0), 1);
+
+        // 0.12 seconds per octave is one key per 10 samples.
+        let pitch = run(&mut glide, Some(24), 100).unwrap();
+        assert!((pitch - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn legato_only_skips_glide_for_detached_notes() {
+        let mut glide = Glide::new(SAMPLE_RATE);
+        glide.set_legato_only(true);
+        run(&mut glide, Some(0), 10);
+        run(&mut glide, None, 10);
+        assert_eq!(glide.next_pitch(Some(12)), Some(12.0));
+
+        let pitch = glide.next_pitch(Some(0)).unwrap();
+        assert!(pitch > 11.0 && pitch < 12.0);
+    }
+}
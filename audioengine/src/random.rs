@@ -0,0 +1,74 @@
+use types::Signal;
+
+/// Small, fast and deterministic pseudo random number generator (xorshift32). Not suitable for
+/// anything but audio.
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    /// Generators created with the same seed produce the same sequence.
+    pub fn new(seed: u32) -> Self {
+        // xorshift gets stuck on zero.
+        Self {
+            state: if seed == 0 { 0x9E37_79B9 } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Uniform value in [0.0, 1.0).
+    pub fn next_unipolar(&mut self) -> Signal {
+        f64::from(self.next_u32()) / 4_294_967_296.0
+    }
+
+    /// Uniform value in [-1.0, 1.0).
+    pub fn next_bipolar(&mut self) -> Signal {
+        self.next_unipolar() * 2.0 - 1.0
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Random::new(42);
+        let mut b = Random::new(42);
+        let mut c = Random::new(43);
+        let a: Vec<u32> = (0..100).map(|_| a.next_u32()).collect();
+        let b: Vec<u32> = (0..100).map(|_| b.next_u32()).collect();
+        let c: Vec<u32> = (0..100).map(|_| c.next_u32()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn values_stay_in_range() {
+        let mut random = Random::default();
+        let mut sum = 0.0;
+        for _ in 0..10_000 {
+            let unipolar = random.next_unipolar();
+            let bipolar = random.next_bipolar();
+            assert!((0.0..1.0).contains(&unipolar));
+            assert!((-1.0..1.0).contains(&bipolar));
+            sum += bipolar;
+        }
+        assert!((sum / 10_000.0).abs() < 0.05);
+    }
+}
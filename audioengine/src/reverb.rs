@@ -0,0 +1,297 @@
+use delay::DelayLine;
+use smoothing::SmoothedValue;
+use types::{Signal, StereoSignal};
+
+/// Comb and all-pass lengths of the original Freeverb, in samples at 44.1 kHz.
+const COMB_LENGTHS: [f64; 8] = [
+    1116.0, 1188.0, 1277.0, 1356.0, 1422.0, 1491.0, 1557.0, 1617.0,
+];
+const ALLPASS_LENGTHS: [f64; 4] = [556.0, 441.0, 341.0, 225.0];
+const TUNING_SAMPLE_RATE: f64 = 44_100.0;
+/// Extra length of the right channel's delays, decorrelating it from the left.
+const STEREO_SPREAD: f64 = 23.0;
+const ALLPASS_FEEDBACK: Signal = 0.5;
+/// Scaling that keeps the sum of the combs at about the level of the input.
+const INPUT_GAIN: Signal = 0.015;
+const WET_GAIN: Signal = 3.0;
+/// Delay lengths are scaled by `size + MIN_SCALE`, so a size of 0.5 gives Freeverb's tuning.
+const MIN_SCALE: f64 = 0.5;
+const MAX_SCALE: f64 = 1.5;
+const MAX_PRE_DELAY: f64 = 0.5;
+const SIZE_SMOOTHING_TIME: f64 = 0.1;
+
+/// Feedback comb with a one pole low-pass in the loop, so high frequencies die out first.
+struct Comb {
+    line: DelayLine,
+    length: f64,
+    feedback: Signal,
+    damping: Signal,
+    filter_state: Signal,
+}
+
+impl Comb {
+    fn new(length: f64) -> Self {
+        Self {
+            line: DelayLine::new((length * MAX_SCALE).ceil() as usize),
+            length,
+            feedback: 0.0,
+            damping: 0.0,
+            filter_state: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: Signal, scale: f64) -> Signal {
+        let output = self.line.read(self.length * scale);
+        self.filter_state = output * (1.0 - self.damping) + self.filter_state * self.damping;
+        self.line.write(input + self.filter_state * self.feedback);
+        output
+    }
+}
+
+/// Schroeder all-pass diffusing the echoes of the combs.
+struct AllPass {
+    line: DelayLine,
+    length: f64,
+}
+
+impl AllPass {
+    fn new(length: f64) -> Self {
+        Self {
+            line: DelayLine::new((length * MAX_SCALE).ceil() as usize),
+            length,
+        }
+    }
+
+    fn process(&mut self, input: Signal, scale: f64) -> Signal {
+        let delayed = self.line.read(self.length * scale);
+        self.line.write(input + delayed * ALLPASS_FEEDBACK);
+        delayed - input
+    }
+}
+
+/// The combs and all-passes of one channel.
+struct Tank {
+    combs: Vec<Comb>,
+    allpasses: Vec<AllPass>,
+}
+
+impl Tank {
+    fn new(sample_rate: f64, spread: f64) -> Self {
+        let ratio = sample_rate / TUNING_SAMPLE_RATE;
+        Self {
+            combs: COMB_LENGTHS
+                .iter()
+                .map(|length| Comb::new((length + spread) * ratio))
+                .collect(),
+            allpasses: ALLPASS_LENGTHS
+                .iter()
+                .map(|length| AllPass::new((length + spread) * ratio))
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: Signal, scale: f64) -> Signal {
+        let mut output = self
+            .combs
+            .iter_mut()
+            .map(|comb| comb.process(input, scale))
+            .sum();
+        for allpass in self.allpasses.iter_mut() {
+            output = allpass.process(output, scale);
+        }
+        output
+    }
+
+    fn clear(&mut self) {
+        for comb in self.combs.iter_mut() {
+            comb.line.clear();
+            comb.filter_state = 0.0;
+        }
+        for allpass in self.allpasses.iter_mut() {
+            allpass.line.clear();
+        }
+    }
+}
+
+/// Freeverb style stereo reverb: eight parallel damped combs followed by four all-passes per
+/// channel. Meant to run as a post processor on the engine output.
+pub struct Reverb {
+    sample_rate: f64,
+    scale: SmoothedValue,
+    decay: f64,
+    width: Signal,
+    mix: Signal,
+    pre_delay_line: DelayLine,
+    pre_delay: f64,
+    tanks: [Tank; 2],
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut reverb = Self {
+            sample_rate,
+            scale: SmoothedValue::new(1.0, SIZE_SMOOTHING_TIME, sample_rate),
+            decay: 2.0,
+            width: 1.0,
+            mix: 0.25,
+            pre_delay_line: DelayLine::new((MAX_PRE_DELAY * sample_rate).ceil() as usize),
+            pre_delay: 1.0,
+            tanks: [
+                Tank::new(sample_rate, 0.0),
+                Tank::new(sample_rate, STEREO_SPREAD),
+            ],
+        };
+        reverb.set_damping(0.5);
+        reverb.update_feedback();
+        reverb
+    }
+
+    /// Room size in [0.0, 1.0], scaling the delay lengths. Changes glide, bending the pitch
+    /// of the tail a little.
+    pub fn set_size(&mut self, size: f64) {
+        self.scale.set(size.clamp(0.0, 1.0) + MIN_SCALE);
+        self.update_feedback();
+    }
+
+    /// Time in seconds for the tail to fall by 60 dB.
+    pub fn set_decay(&mut self, seconds: f64) {
+        self.decay = seconds.max(0.05);
+        self.update_feedback();
+    }
+
+    /// High frequency damping in [0.0, 1.0]. Higher values give a darker tail.
+    pub fn set_damping(&mut self, damping: Signal) {
+        let damping = damping.clamp(0.0, 1.0) * 0.4;
+        for tank in self.tanks.iter_mut() {
+            for comb in tank.combs.iter_mut() {
+                comb.damping = damping;
+            }
+        }
+    }
+
+    /// Time in seconds before the reverb starts, up to half a second.
+    pub fn set_pre_delay(&mut self, seconds: f64) {
+        self.pre_delay = (seconds * self.sample_rate).max(1.0);
+    }
+
+    /// Stereo width in [0.0, 1.0], from a mono tail to fully decorrelated channels.
+    pub fn set_width(&mut self, width: Signal) {
+        self.width = width.clamp(0.0, 1.0);
+    }
+
+    /// Balance between the dry input at 0.0 and only the reverb at 1.0.
+    pub fn set_mix(&mut self, mix: Signal) {
+        self.mix = mix.clamp(0.0, 1.0);
+    }
+
+    /// Silences the tail.
+    pub fn clear(&mut self) {
+        self.pre_delay_line.clear();
+        for tank in self.tanks.iter_mut() {
+            tank.clear();
+        }
+    }
+
+    pub fn process(&mut self, (left, right): StereoSignal) -> StereoSignal {
+        let scale = self.scale.next_value();
+
+        let input = self.pre_delay_line.read(self.pre_delay);
+        self.pre_delay_line.write((left + right) * INPUT_GAIN);
+
+        let wet_left = self.tanks[0].process(input, scale);
+        let wet_right = self.tanks[1].process(input, scale);
+
+        let wet = self.mix * WET_GAIN;
+        let direct = wet * (0.5 + self.width * 0.5);
+        let cross = wet * (0.5 - self.width * 0.5);
+        let dry = 1.0 - self.mix;
+
+        (
+            left * dry + wet_left * direct + wet_right * cross,
+            right * dry + wet_right * direct + wet_left * cross,
+        )
+    }
+
+    // Feedback giving each comb the decay time at the target size.
+    fn update_feedback(&mut self) {
+        let scale = self.scale.target();
+        let decay_samples = self.decay * self.sample_rate;
+        for tank in self.tanks.iter_mut() {
+            for comb in tank.combs.iter_mut() {
+                comb.feedback = 0.001f64.powf(comb.length * scale / decay_samples);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    fn rms(samples: &[StereoSignal]) -> Signal {
+        let sum: Signal = samples.iter().map(|&(l, r)| l * l + r * r).sum();
+        (sum / samples.len() as Signal).sqrt()
+    }
+
+    #[test]
+    fn decay_time_sets_the_tail_length() {
+        for &decay in [0.5, 1.0].iter() {
+            let mut reverb = Reverb::new(SAMPLE_RATE);
+            reverb.set_damping(0.0);
+            reverb.set_decay(decay);
+            reverb.set_mix(1.0);
+            let output: Vec<StereoSignal> = (0..(SAMPLE_RATE * (decay + 0.4)) as usize)
+                .map(|n| reverb.process(if n == 0 { (1.0, 1.0) } else { (0.0, 0.0) }))
+                .collect();
+
+            let window = (0.1 * SAMPLE_RATE) as usize;
+            let start = (0.2 * SAMPLE_RATE) as usize;
+            let end = start + (decay * SAMPLE_RATE) as usize;
+            let drop = 20.0
+                * (rms(&output[end..end + window]) / rms(&output[start..start + window])).log10();
+            assert!(
+                drop < -50.0 && drop > -70.0,
+                "{} dB after {} s",
+                drop,
+                decay
+            );
+        }
+    }
+
+    #[test]
+    fn dry_mix_passes_input_through() {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        reverb.set_mix(0.0);
+        let mut random = Random::new(3);
+        for _ in 0..10_000 {
+            let input = (random.next_bipolar(), random.next_bipolar());
+            assert_eq!(reverb.process(input), input);
+        }
+    }
+
+    #[test]
+    fn stable_at_largest_size() {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        reverb.set_size(1.0);
+        reverb.set_decay(10.0);
+        reverb.set_damping(0.0);
+        reverb.set_width(1.0);
+        reverb.set_mix(1.0);
+        let mut random = Random::new(5);
+        let mut peak: Signal = 0.0;
+        for n in 0..(SAMPLE_RATE * 4.0) as usize {
+            let input = if n < 48_000 {
+                random.next_bipolar()
+            } else {
+                0.0
+            };
+            let (left, right) = reverb.process((input, input));
+            assert!(left.is_finite() && right.is_finite());
+            peak = peak.max(left.abs()).max(right.abs());
+        }
+        assert!(peak < 10.0, "peak {}", peak);
+    }
+}
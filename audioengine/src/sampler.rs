@@ -0,0 +1,434 @@
+use envelope::Adsr;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use tuning::Tuning;
+use types::{Signal, StereoSignal, StereoSignalProcessorFunction};
+use wav::WavData;
+
+/// Zero crossings on either side of the windowed sinc kernel.
+const SINC_TAPS: isize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    /// Four point Hermite. Cheap, with some aliasing when pitching far up.
+    Cubic,
+    /// Windowed sinc, band-limited to the output rate when pitching up.
+    Sinc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Playback {
+    /// Plays until the end of the sample or the end of the release.
+    Sustain,
+    /// Plays to the end of the sample, ignoring note off.
+    OneShot,
+    /// Repeats the frames from `start` to `end` for as long as the note sounds, blending the
+    /// last `crossfade` frames of the loop with the frames before `start` to hide the seam.
+    /// Loops with `end` not after `start` play through like `Sustain`. `clamp_loop` keeps
+    /// loop points inside the sample.
+    Loop {
+        start: usize,
+        end: usize,
+        crossfade: usize,
+    },
+}
+
+/// A sample and the keys and velocities it answers to.
+#[derive(Clone)]
+pub struct SampleZone {
+    pub sample: Arc<WavData>,
+    /// Key at which the sample plays at its original pitch.
+    pub root_key: i32,
+    pub low_key: i32,
+    pub high_key: i32,
+    /// Velocities in [0.0, 1.0], inclusive.
+    pub low_velocity: Signal,
+    pub high_velocity: Signal,
+    pub playback: Playback,
+    pub gain: Signal,
+}
+
+impl SampleZone {
+    /// A zone covering every key and velocity, playing `sample` until the note is released.
+    pub fn new(sample: Arc<WavData>, root_key: i32) -> Self {
+        Self {
+            sample,
+            root_key,
+            low_key: i32::MIN,
+            high_key: i32::MAX,
+            low_velocity: 0.0,
+            high_velocity: 1.0,
+            playback: Playback::Sustain,
+            gain: 1.0,
+        }
+    }
+
+    pub fn contains(&self, key: i32, velocity: Signal) -> bool {
+        (self.low_key..=self.high_key).contains(&key)
+            && velocity >= self.low_velocity
+            && velocity <= self.high_velocity
+    }
+}
+
+/// Monophonic sample player. Notes pick the first zone containing their key and velocity
+/// and repitch it relative to the zone's root key.
+pub struct Sampler {
+    sample_rate: f64,
+    zones: Vec<SampleZone>,
+    interpolation: Interpolation,
+    envelope: Adsr,
+    zone: Option<usize>,
+    position: f64,
+    step: f64,
+    tuning: Arc<Tuning>,
+    current_key: Option<i32>,
+}
+
+impl Sampler {
+    pub fn new(sample_rate: f64) -> Self {
+        let mut envelope = Adsr::new(sample_rate);
+        envelope.set_attack(0.002);
+        envelope.set_decay(0.0);
+        envelope.set_sustain(1.0);
+        envelope.set_release(0.05);
+
+        Self {
+            sample_rate,
+            zones: Vec::new(),
+            interpolation: Interpolation::Cubic,
+            envelope,
+            zone: None,
+            position: 0.0,
+            step: 0.0,
+            tuning: Arc::new(Tuning::default()),
+            current_key: None,
+        }
+    }
+
+    /// Zones are searched in the order they were added.
+    pub fn add_zone(&mut self, zone: SampleZone) {
+        self.zones.push(zone);
+    }
+
+    /// Removes every zone and stops playback.
+    pub fn clear_zones(&mut self) {
+        self.zones.clear();
+        self.zone = None;
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    /// The amplitude envelope. Set its velocity sensitivity for velocity to scale the level.
+    pub fn envelope_mut(&mut self) -> &mut Adsr {
+        &mut self.envelope
+    }
+
+    /// Tuning notes are repitched in, relative to the root key of their zone. Keys it leaves
+    /// unmapped do not play.
+    pub fn set_tuning(&mut self, tuning: Arc<Tuning>) {
+        self.tuning = tuning;
+    }
+
+    /// Starts the first zone matching `key` and `velocity`, if any. `velocity` is in
+    /// [0.0, 1.0].
+    pub fn note_on(&mut self, key: i32, velocity: Signal) {
+        let zone = self.zones.iter().position(|z| z.contains(key, velocity));
+        let ratio = zone.and_then(|index| {
+            let root_key = f64::from(self.zones[index].root_key);
+            self.tuning.interval(root_key, f64::from(key))
+        });
+        match (zone, ratio) {
+            (Some(index), Some(ratio)) => {
+                let source_rate = f64::from(self.zones[index].sample.sample_rate);
+                self.step = ratio * source_rate / self.sample_rate;
+                self.position = 0.0;
+                self.envelope.note_on(velocity);
+                self.zone = zone;
+            }
+            _ => self.zone = None,
+        }
+    }
+
+    pub fn note_off(&mut self) {
+        let one_shot = self
+            .zone
+            .is_some_and(|index| self.zones[index].playback == Playback::OneShot);
+        if !one_shot {
+            self.envelope.note_off();
+        }
+    }
+
+    /// Drives the sampler from the key handed to a processor function. A different key
+    /// counts as a new note.
+    pub fn gate(&mut self, key: Option<i32>) {
+        if key == self.current_key {
+            return;
+        }
+        match key {
+            Some(key) => self.note_on(key, 1.0),
+            None => self.note_off(),
+        }
+        self.current_key = key;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.zone.is_some()
+    }
+
+    pub fn next_sample(&mut self) -> StereoSignal {
+        let index = match self.zone {
+            Some(index) => index,
+            None => return (0.0, 0.0),
+        };
+        let zone = &self.zones[index];
+        let sample = &zone.sample;
+        let right_channel = if sample.channels.len() > 1 { 1 } else { 0 };
+
+        let read = |channel: usize, position: f64| {
+            let frames = &sample.channels[channel];
+            match self.interpolation {
+                Interpolation::Cubic => read_cubic(frames, position),
+                Interpolation::Sinc => read_sinc(frames, position, self.step),
+            }
+        };
+        let read_frame = |position: f64| (read(0, position), read(right_channel, position));
+
+        let (left, right) = match zone.playback {
+            Playback::Loop {
+                start,
+                end,
+                crossfade,
+            } if end > start
+                && crossfade > 0
+                && self.position >= end.saturating_sub(crossfade) as f64 =>
+            {
+                let fade_start = end.saturating_sub(crossfade);
+                let fade = (self.position - fade_start as f64) / (end - fade_start) as f64;
+                let (out_left, out_right) = read_frame(self.position);
+                let (in_left, in_right) = read_frame(self.position - (end - start) as f64);
+                (
+                    out_left * (1.0 - fade) + in_left * fade,
+                    out_right * (1.0 - fade) + in_right * fade,
+                )
+            }
+            _ => read_frame(self.position),
+        };
+
+        let level = self.envelope.next_sample() * zone.gain;
+
+        self.position += self.step;
+        let finished = match zone.playback {
+            Playback::Loop { start, end, .. } if end > start => {
+                if self.position >= end as f64 {
+                    self.position -= (end - start) as f64;
+                }
+                false
+            }
+            _ => self.position >= sample.len() as f64,
+        };
+        if finished || !self.envelope.is_active() {
+            self.zone = None;
+        }
+
+        (left * level, right * level)
+    }
+
+    /// Wraps the sampler in a processor function for
+    /// `EngineController::set_stereo_processor_function`.
+    pub fn into_processor_function(mut self) -> StereoSignalProcessorFunction {
+        Box::new(move |key| {
+            self.gate(key);
+            self.next_sample()
+        })
+    }
+}
+
+/// Keeps loop points inside the sample and leaves room for the crossfade before the start.
+pub fn clamp_loop(sample: &WavData, start: usize, end: usize, crossfade: usize) -> Playback {
+    let end = end.clamp(1, sample.len().max(1));
+    let start = start.min(end - 1);
+    Playback::Loop {
+        start,
+        end,
+        crossfade: crossfade.min(start).min(end - start),
+    }
+}
+
+fn frame(frames: &[Signal], index: isize) -> Signal {
+    if index < 0 {
+        0.0
+    } else {
+        frames.get(index as usize).cloned().unwrap_or(0.0)
+    }
+}
+
+fn read_cubic(frames: &[Signal], position: f64) -> Signal {
+    let whole = position.floor();
+    let fraction = position - whole;
+    let index = whole as isize;
+
+    let previous = frame(frames, index - 1);
+    let current = frame(frames, index);
+    let next = frame(frames, index + 1);
+    let after = frame(frames, index + 2);
+
+    let c1 = 0.5 * (next - previous);
+    let c2 = previous - 2.5 * current + 2.0 * next - 0.5 * after;
+    let c3 = 0.5 * (after - previous) + 1.5 * (current - next);
+    ((c3 * fraction + c2) * fraction + c1) * fraction + current
+}
+
+fn read_sinc(frames: &[Signal], position: f64, step: f64) -> Signal {
+    // Lower the cutoff when pitching up, so frequencies pushed above Nyquist are removed
+    // instead of folding back.
+    let cutoff = (1.0 / step).min(1.0);
+    let whole = position.floor();
+    let fraction = position - whole;
+    let index = whole as isize;
+
+    let mut sum = 0.0;
+    for offset in (1 - SINC_TAPS)..=SINC_TAPS {
+        let x = offset as f64 - fraction;
+        let sinc = if x == 0.0 {
+            cutoff
+        } else {
+            (PI * x * cutoff).sin() / (PI * x)
+        };
+        let window = 0.5 + 0.5 * (PI * x / SINC_TAPS as f64).cos();
+        sum += frame(frames, index + offset) * sinc * window;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    fn sample(frames: Vec<Signal>) -> Arc<WavData> {
+        Arc::new(WavData {
+            sample_rate: SAMPLE_RATE as u32,
+            channels: vec![frames],
+        })
+    }
+
+    fn sampler(zone: SampleZone) -> Sampler {
+        let mut sampler = Sampler::new(SAMPLE_RATE);
+        sampler.envelope_mut().set_attack(0.0);
+        sampler.add_zone(zone);
+        sampler
+    }
+
+    #[test]
+    fn root_key_plays_frames_unchanged() {
+        let frames: Vec<Signal> = (0..64).map(|i| (f64::from(i) * 0.3).sin()).collect();
+        for &interpolation in [Interpolation::Cubic, Interpolation::Sinc].iter() {
+            let mut sampler = sampler(SampleZone::new(sample(frames.clone()), 0));
+            sampler.set_interpolation(interpolation);
+            sampler.note_on(0, 1.0);
+            for &expected in frames.iter() {
+                assert!((sampler.next_sample().0 - expected).abs() < 1e-9);
+            }
+            assert!(!sampler.is_active());
+        }
+    }
+
+    #[test]
+    fn octave_up_plays_every_other_frame() {
+        let frames: Vec<Signal> = (0..64).map(f64::from).collect();
+        let mut sampler = sampler(SampleZone::new(sample(frames), 0));
+        sampler.note_on(12, 1.0);
+        for i in 0..30 {
+            assert!((sampler.next_sample().0 - f64::from(i * 2)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn loop_keeps_playing_and_one_shot_ignores_note_off() {
+        let frames: Vec<Signal> = (0..100).map(|i| (f64::from(i) * 0.1).sin()).collect();
+        let data = sample(frames);
+
+        let mut zone = SampleZone::new(data.clone(), 0);
+        zone.playback = clamp_loop(&data, 20, 80, 10);
+        let mut looping = sampler(zone);
+        looping.note_on(0, 1.0);
+        for _ in 0..1000 {
+            looping.next_sample();
+        }
+        assert!(looping.is_active());
+
+        let mut zone = SampleZone::new(data, 0);
+        zone.playback = Playback::OneShot;
+        let mut one_shot = sampler(zone);
+        one_shot.note_on(0, 1.0);
+        one_shot.note_off();
+        for _ in 0..99 {
+            one_shot.next_sample();
+        }
+        assert!(one_shot.is_active());
+        one_shot.next_sample();
+        assert!(!one_shot.is_active());
+    }
+
+    #[test]
+    fn unclamped_loop_points_do_not_panic() {
+        let frames: Vec<Signal> = (0..100).map(f64::from).collect();
+        let data = sample(frames);
+        let loops = [
+            (20, 80, 0),
+            (20, 80, 200),
+            (0, 5, 10),
+            (80, 20, 10),
+            (50, 500, 10),
+        ];
+        for &(start, end, crossfade) in loops.iter() {
+            let mut zone = SampleZone::new(data.clone(), 0);
+            zone.playback = Playback::Loop {
+                start,
+                end,
+                crossfade,
+            };
+            let mut sampler = sampler(zone);
+            sampler.note_on(0, 1.0);
+            for _ in 0..1000 {
+                let (left, right) = sampler.next_sample();
+                assert!(left.is_finite() && right.is_finite());
+            }
+        }
+
+        // Without a crossfade the loop jumps straight back to the start.
+        let mut zone = SampleZone::new(data, 0);
+        zone.playback = Playback::Loop {
+            start: 20,
+            end: 80,
+            crossfade: 0,
+        };
+        let mut sampler = sampler(zone);
+        sampler.note_on(0, 1.0);
+        let output: Vec<Signal> = (0..90).map(|_| sampler.next_sample().0).collect();
+        assert_eq!(output[79], 79.0);
+        assert_eq!(output[80], 20.0);
+    }
+
+    #[test]
+    fn zones_are_picked_by_key_and_velocity() {
+        let mut soft = SampleZone::new(sample(vec![0.25; 8]), 0);
+        soft.high_velocity = 0.5;
+        let mut loud = SampleZone::new(sample(vec![1.0; 8]), 0);
+        loud.low_velocity = 0.5;
+        loud.high_key = 3;
+
+        let mut sampler = sampler(soft);
+        sampler.add_zone(loud);
+
+        sampler.note_on(0, 0.2);
+        assert!((sampler.next_sample().0 - 0.25).abs() < 1e-9);
+        sampler.note_on(0, 0.9);
+        assert!((sampler.next_sample().0 - 1.0).abs() < 1e-9);
+        sampler.note_on(5, 0.9);
+        assert!(!sampler.is_active());
+    }
+}
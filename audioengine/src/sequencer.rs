@@ -0,0 +1,478 @@
+use clock::{NoteDivision, StepGrid};
+use random::Random;
+use std::mem;
+use transport::ProcessContext;
+use types::Signal;
+
+pub const MAX_STEPS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Step {
+    /// Steps that are not active are rests.
+    pub active: bool,
+    pub key: i32,
+    /// Share of the step the note is held, in [0.0, 1.0].
+    pub gate: f64,
+    /// In [0.0, 1.0].
+    pub velocity: Signal,
+    /// Holds the note of the previous step through this one instead of playing a new note.
+    pub tie: bool,
+    /// Chance of the step playing, in [0.0, 1.0].
+    pub probability: f64,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            active: false,
+            key: 0,
+            gate: 0.5,
+            velocity: 1.0,
+            tie: false,
+            probability: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    steps: Vec<Step>,
+}
+
+impl Pattern {
+    /// A pattern of `length` rests, from 1 to `MAX_STEPS`.
+    pub fn new(length: usize) -> Self {
+        Self {
+            steps: vec![Step::default(); length.clamp(1, MAX_STEPS)],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Adds rests or drops steps at the end to reach `length`, from 1 to `MAX_STEPS`.
+    pub fn set_length(&mut self, length: usize) {
+        self.steps
+            .resize(length.clamp(1, MAX_STEPS), Step::default());
+    }
+
+    pub fn step(&self, index: usize) -> &Step {
+        &self.steps[index]
+    }
+
+    pub fn step_mut(&mut self, index: usize) -> &mut Step {
+        &mut self.steps[index]
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+/// Changes sent to the sequencer on the audio thread through
+/// `EngineController::sequencer_command`. Patterns are built before they are sent, so the
+/// audio thread never allocates for them.
+#[derive(Clone, Debug)]
+pub enum SequencerCommand {
+    SetSwing(f64),
+    SetDivision(NoteDivision),
+    /// Replaces all patterns. An empty list is ignored.
+    SetPatterns(Vec<Pattern>),
+    /// Replaces the pattern at an index. Indices past the last pattern are ignored.
+    SetPattern(usize, Pattern),
+    /// Changes a step of the pattern at an index, if both exist.
+    SetStep(usize, usize, Step),
+    SetChain(Vec<usize>),
+}
+
+/// Where the sequencer is, as reported by `EngineController::sequencer_playhead`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Playhead {
+    pub playing: bool,
+    pub pattern: usize,
+    pub step: usize,
+}
+
+/// Step sequencer playing a chain of patterns while the transport plays, with steps timed
+/// to the sample from its position.
+pub struct Sequencer {
+    grid: StepGrid,
+    patterns: Vec<Pattern>,
+    chain: Vec<usize>,
+    division: NoteDivision,
+    swing: f64,
+    random: Random,
+    playing: bool,
+
+    chain_position: usize,
+    step: usize,
+    // Pattern and step last played, for the playhead.
+    sounding: (usize, usize),
+    release_beat: f64,
+    key: Option<i32>,
+    velocity: Signal,
+    retrigger: bool,
+}
+
+impl Sequencer {
+    /// Starts out with one empty pattern of sixteenth notes.
+    pub fn new() -> Self {
+        Self {
+            grid: StepGrid::new(),
+            patterns: vec![Pattern::default()],
+            chain: vec![0],
+            division: NoteDivision::Straight(16),
+            swing: 0.0,
+            random: Random::default(),
+            playing: false,
+            chain_position: 0,
+            step: 0,
+            sounding: (0, 0),
+            release_beat: 0.0,
+            key: None,
+            velocity: 0.0,
+            retrigger: false,
+        }
+    }
+
+    /// Delay of every second step, as a share of a step in [0.0, 0.5]. A third of a step
+    /// gives a triplet feel.
+    pub fn set_swing(&mut self, swing: f64) {
+        self.swing = swing.clamp(0.0, 0.5);
+    }
+
+    /// Length of a step. Takes effect from the next step.
+    pub fn set_division(&mut self, division: NoteDivision) {
+        self.division = division;
+    }
+
+    pub fn set_seed(&mut self, seed: u32) {
+        self.random = Random::new(seed);
+    }
+
+    /// Adds empty patterns up to `index` if needed, so not for the audio thread.
+    pub fn pattern_mut(&mut self, index: usize) -> &mut Pattern {
+        if index >= self.patterns.len() {
+            self.patterns.resize(index + 1, Pattern::default());
+        }
+        &mut self.patterns[index]
+    }
+
+    /// Order the patterns are played in, looping at the end. Indices without a pattern, and
+    /// an empty chain, play pattern 0.
+    pub fn set_chain(&mut self, chain: Vec<usize>) {
+        self.chain = chain;
+        self.chain_position = 0;
+    }
+
+    /// Applies a change, returning what it replaced, or the change itself when it was
+    /// ignored, so the caller can drop it off the audio thread.
+    pub fn apply(&mut self, command: SequencerCommand) -> Option<SequencerCommand> {
+        match command {
+            SequencerCommand::SetSwing(swing) => self.set_swing(swing),
+            SequencerCommand::SetDivision(division) => self.set_division(division),
+            SequencerCommand::SetPatterns(mut patterns) => {
+                if !patterns.is_empty() {
+                    mem::swap(&mut self.patterns, &mut patterns);
+                }
+                return Some(SequencerCommand::SetPatterns(patterns));
+            }
+            SequencerCommand::SetPattern(index, mut pattern) => {
+                if let Some(current) = self.patterns.get_mut(index) {
+                    mem::swap(current, &mut pattern);
+                }
+                return Some(SequencerCommand::SetPattern(index, pattern));
+            }
+            SequencerCommand::SetStep(pattern, index, step) => {
+                if let Some(pattern) = self.patterns.get_mut(pattern) {
+                    if index < pattern.len() {
+                        *pattern.step_mut(index) = step;
+                    }
+                }
+            }
+            SequencerCommand::SetChain(mut chain) => {
+                mem::swap(&mut self.chain, &mut chain);
+                self.chain_position = 0;
+                return Some(SequencerCommand::SetChain(chain));
+            }
+        }
+        None
+    }
+
+    pub fn playhead(&self) -> Playhead {
+        Playhead {
+            playing: self.playing,
+            pattern: self.sounding.0,
+            step: self.sounding.1,
+        }
+    }
+
+    /// Velocity of the sounding note.
+    pub fn velocity(&self) -> Signal {
+        self.velocity
+    }
+
+    /// Advances to the sample of `context` and returns the key that should sound. Starting
+    /// the transport, or moving its position back, rewinds to the start of the chain. A key
+    /// played again right after itself is released for one sample, so voices see a new note.
+    pub fn next_sample(&mut self, context: &ProcessContext) -> Option<i32> {
+        self.playing = context.playing;
+        if !context.playing {
+            self.grid.reset();
+            self.key = None;
+            return None;
+        }
+
+        let beat = context.position;
+        let step_beats = self.division.beats();
+        if self.grid.follow(beat, step_beats, self.swing) {
+            self.rewind();
+        }
+        while let Some(step_start) = self.grid.next_step(step_beats, self.swing) {
+            self.play_step(step_start, step_beats);
+        }
+        if self.key.is_some() && beat >= self.release_beat {
+            self.key = None;
+        }
+
+        if self.retrigger {
+            self.retrigger = false;
+            None
+        } else {
+            self.key
+        }
+    }
+
+    fn current_pattern(&self) -> usize {
+        let index = self.chain.get(self.chain_position).cloned().unwrap_or(0);
+        if index < self.patterns.len() {
+            index
+        } else {
+            0
+        }
+    }
+
+    fn rewind(&mut self) {
+        self.chain_position = 0;
+        self.step = 0;
+        self.key = None;
+    }
+
+    /// The step to play next. Patterns can be shortened while playing.
+    fn next_step(&self) -> (usize, usize) {
+        let pattern = self.current_pattern();
+        (pattern, self.step.min(self.patterns[pattern].len() - 1))
+    }
+
+    fn play_step(&mut self, step_start: f64, step_beats: f64) {
+        let (pattern_index, index) = self.next_step();
+        let step = *self.patterns[pattern_index].step(index);
+        self.sounding = (pattern_index, index);
+
+        let sounding = if step.tie && self.key.is_some() {
+            true
+        } else if step.active && self.random.next_unipolar() < step.probability {
+            self.retrigger = self.key == Some(step.key);
+            self.key = Some(step.key);
+            self.velocity = step.velocity;
+            true
+        } else {
+            self.key = None;
+            false
+        };
+
+        self.step = index + 1;
+        if self.step >= self.patterns[pattern_index].len() {
+            self.step = 0;
+            self.chain_position = (self.chain_position + 1) % self.chain.len().max(1);
+        }
+
+        if sounding {
+            // A tie on the next step holds the note through it, whatever the gate.
+            let (pattern, index) = self.next_step();
+            self.release_beat = if self.patterns[pattern].step(index).tie {
+                f64::INFINITY
+            } else {
+                step_start + step_beats * step.gate
+            };
+        }
+    }
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::Transport;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    // Sixteenth notes at 120 BPM.
+    const STEP_SAMPLES: usize = 6000;
+
+    fn sequencer(steps: &[(usize, Step)]) -> Sequencer {
+        let mut sequencer = Sequencer::new();
+        for &(index, step) in steps {
+            *sequencer.pattern_mut(0).step_mut(index) = step;
+        }
+        sequencer
+    }
+
+    fn playing() -> Transport {
+        let mut transport = Transport::new(SAMPLE_RATE);
+        transport.play();
+        transport
+    }
+
+    fn note(key: i32) -> Step {
+        Step {
+            active: true,
+            key,
+            ..Step::default()
+        }
+    }
+
+    fn keys(
+        sequencer: &mut Sequencer,
+        transport: &mut Transport,
+        samples: usize,
+    ) -> Vec<Option<i32>> {
+        (0..samples)
+            .map(|_| sequencer.next_sample(&transport.next_sample().0))
+            .collect()
+    }
+
+    #[test]
+    fn steps_start_and_end_on_exact_samples() {
+        let mut sequencer = sequencer(&[(0, note(3)), (1, note(5))]);
+        let keys = keys(&mut sequencer, &mut playing(), 3 * STEP_SAMPLES);
+
+        assert_eq!(keys[0], Some(3));
+        assert_eq!(keys[STEP_SAMPLES / 2 - 1], Some(3));
+        assert_eq!(keys[STEP_SAMPLES / 2], None);
+        assert_eq!(keys[STEP_SAMPLES - 1], None);
+        assert_eq!(keys[STEP_SAMPLES], Some(5));
+        assert_eq!(keys[2 * STEP_SAMPLES], None);
+    }
+
+    #[test]
+    fn swing_delays_every_second_step() {
+        let mut sequencer = sequencer(&[(1, note(5)), (2, note(7))]);
+        sequencer.set_swing(0.25);
+        let keys = keys(&mut sequencer, &mut playing(), 3 * STEP_SAMPLES);
+
+        let swung = STEP_SAMPLES + STEP_SAMPLES / 4;
+        assert_eq!(keys[swung - 1], None);
+        assert_eq!(keys[swung], Some(5));
+        assert_eq!(keys[2 * STEP_SAMPLES], Some(7));
+    }
+
+    #[test]
+    fn ties_hold_and_zero_probability_rests() {
+        let tie = Step {
+            tie: true,
+            gate: 1.0,
+            ..Step::default()
+        };
+        let never = Step {
+            probability: 0.0,
+            ..note(9)
+        };
+        // The tie holds the note past the gate of the step before it.
+        let mut sequencer = sequencer(&[(0, note(3)), (1, tie), (2, never)]);
+        let keys = keys(&mut sequencer, &mut playing(), 3 * STEP_SAMPLES);
+
+        assert!(keys[..2 * STEP_SAMPLES].iter().all(|&key| key == Some(3)));
+        assert!(keys[2 * STEP_SAMPLES..].iter().all(|&key| key.is_none()));
+    }
+
+    #[test]
+    fn chain_moves_through_patterns() {
+        let mut sequencer = Sequencer::new();
+        let patterns = [1, 2]
+            .iter()
+            .map(|&key| {
+                let mut pattern = Pattern::new(1);
+                *pattern.step_mut(0) = Step {
+                    gate: 1.0,
+                    ..note(key)
+                };
+                pattern
+            })
+            .collect();
+        sequencer.apply(SequencerCommand::SetPatterns(patterns));
+        sequencer.apply(SequencerCommand::SetChain(vec![0, 1, 1]));
+
+        let mut transport = playing();
+        let keys = keys(&mut sequencer, &mut transport, 4 * STEP_SAMPLES);
+        let played: Vec<_> = (0..4).map(|step| keys[step * STEP_SAMPLES + 1]).collect();
+        assert_eq!(played, vec![Some(1), Some(2), Some(2), Some(1)]);
+        // The repeated pattern releases its key for one sample so the note is restarted.
+        assert_eq!(keys[2 * STEP_SAMPLES], None);
+    }
+
+    #[test]
+    fn follows_the_transport() {
+        let mut sequencer = sequencer(&[(0, note(1)), (1, note(2))]);
+        let mut transport = Transport::new(SAMPLE_RATE);
+        assert!(keys(&mut sequencer, &mut transport, STEP_SAMPLES)
+            .iter()
+            .all(|key| key.is_none()));
+
+        transport.play();
+        let keys_played = keys(&mut sequencer, &mut transport, STEP_SAMPLES + 1);
+        assert_eq!(keys_played[0], Some(1));
+        assert_eq!(keys_played[STEP_SAMPLES], Some(2));
+        assert!(sequencer.playhead().playing);
+
+        // Playing again after a stop starts from the first step.
+        transport.stop();
+        transport.play();
+        assert_eq!(keys(&mut sequencer, &mut transport, 1)[0], Some(1));
+    }
+
+    #[test]
+    fn replaced_patterns_are_handed_back() {
+        let mut sequencer = Sequencer::new();
+        let old = sequencer.apply(SequencerCommand::SetPattern(0, Pattern::new(4)));
+        match old {
+            Some(SequencerCommand::SetPattern(0, pattern)) => assert_eq!(pattern.len(), 16),
+            other => panic!("{:?}", other),
+        }
+        // Patterns that do not exist are not added on the audio thread.
+        let ignored = sequencer.apply(SequencerCommand::SetPattern(3, Pattern::new(2)));
+        match ignored {
+            Some(SequencerCommand::SetPattern(3, pattern)) => assert_eq!(pattern.len(), 2),
+            other => panic!("{:?}", other),
+        }
+        assert!(sequencer
+            .apply(SequencerCommand::SetStep(3, 0, note(1)))
+            .is_none());
+        assert_eq!(sequencer.patterns.len(), 1);
+    }
+
+    #[test]
+    fn velocity_follows_the_step() {
+        let soft = Step {
+            velocity: 0.25,
+            ..note(1)
+        };
+        let mut sequencer = sequencer(&[(0, soft), (1, note(2))]);
+        let mut transport = playing();
+        keys(&mut sequencer, &mut transport, 1);
+        assert_eq!(sequencer.velocity(), 0.25);
+        keys(&mut sequencer, &mut transport, STEP_SAMPLES);
+        assert_eq!(sequencer.velocity(), 1.0);
+    }
+}
@@ -0,0 +1,70 @@
+use types::Signal;
+
+/// A parameter that glides exponentially towards its target instead of jumping, avoiding
+/// zipper noise when values change between samples.
+pub struct SmoothedValue {
+    current: Signal,
+    target: Signal,
+    coefficient: Signal,
+}
+
+impl SmoothedValue {
+    /// `time` is roughly how long, in seconds, it takes to cover two thirds of a change.
+    pub fn new(value: Signal, time: f64, sample_rate: f64) -> Self {
+        Self {
+            current: value,
+            target: value,
+            coefficient: (-1.0 / (time * sample_rate).max(1.0)).exp(),
+        }
+    }
+
+    pub fn set(&mut self, target: Signal) {
+        self.target = target;
+    }
+
+    /// Jumps straight to `value`, skipping the glide.
+    pub fn reset(&mut self, value: Signal) {
+        self.current = value;
+        self.target = value;
+    }
+
+    pub fn target(&self) -> Signal {
+        self.target
+    }
+
+    pub fn next_value(&mut self) -> Signal {
+        self.current = self.target + (self.current - self.target) * self.coefficient;
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn covers_two_thirds_in_ramp_time() {
+        let mut value = SmoothedValue::new(0.0, 0.01, SAMPLE_RATE);
+        value.set(1.0);
+        let mut current = 0.0;
+        for _ in 0..480 {
+            current = value.next_value();
+        }
+        assert!((current - (1.0 - (-1.0f64).exp())).abs() < 0.01);
+
+        for _ in 0..4800 {
+            current = value.next_value();
+        }
+        assert!((current - 1.0).abs() < 1e-3);
+        assert_eq!(value.target(), 1.0);
+    }
+
+    #[test]
+    fn reset_skips_the_glide() {
+        let mut value = SmoothedValue::new(0.0, 0.01, SAMPLE_RATE);
+        value.reset(0.5);
+        assert_eq!(value.next_value(), 0.5);
+    }
+}
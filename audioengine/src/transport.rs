@@ -0,0 +1,342 @@
+use clock::TempoClock;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+use types::{Phase, Signal};
+
+/// Taps further apart than this start a new tempo.
+const MAX_TAP_INTERVAL: Duration = Duration::from_secs(2);
+/// Number of intervals averaged by `TapTempo`.
+const TAP_HISTORY: usize = 4;
+/// Range tapped tempos are kept in, in beats per minute.
+pub const MIN_TAP_TEMPO: f64 = 40.0;
+pub const MAX_TAP_TEMPO: f64 = 240.0;
+const CLICK_FREQUENCY: f64 = 1000.0;
+const ACCENT_FREQUENCY: f64 = 1500.0;
+const CLICK_TIME: f64 = 0.03;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeSignature {
+    pub beats_per_bar: u32,
+    /// Note value of a beat, 4 for quarter notes.
+    pub beat_unit: u32,
+}
+
+impl TimeSignature {
+    pub fn new(beats_per_bar: u32, beat_unit: u32) -> Self {
+        Self {
+            beats_per_bar: beats_per_bar.max(1),
+            beat_unit: beat_unit.max(1),
+        }
+    }
+
+    /// Length of one beat of the signature in quarter notes.
+    pub fn beat_length(&self) -> f64 {
+        4.0 / f64::from(self.beat_unit)
+    }
+
+    /// Length of a bar in quarter notes.
+    pub fn bar_length(&self) -> f64 {
+        self.beat_length() * f64::from(self.beats_per_bar)
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self::new(4, 4)
+    }
+}
+
+/// Musical time at one sample, handed to context processors by the engine.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProcessContext {
+    pub sample_rate: f64,
+    /// Quarter notes per minute.
+    pub tempo: f64,
+    pub time_signature: TimeSignature,
+    pub playing: bool,
+    /// Quarter notes since the start.
+    pub position: f64,
+    /// Bar since the start, counting from 0.
+    pub bar: u64,
+    /// Beat of the signature within the bar, counting from 0.0.
+    pub beat: f64,
+    /// Quarter notes since the engine started, counting on while the transport is stopped,
+    /// for parts like the arpeggiator that run without it.
+    pub clock: f64,
+    /// Velocity of the key handed over with the context, in [0.0, 1.0]. Sequenced keys play
+    /// at the velocity of their step, others at 1.0.
+    pub velocity: Signal,
+    /// Pitch of the key handed over, in keys, fractional while the engine glides between
+    /// notes. It holds the last pitch after a release, for release tails, and is `None`
+    /// before the first note.
+    pub pitch: Option<f64>,
+}
+
+/// Changes sent to the transport on the audio thread through
+/// `EngineController::transport_command`.
+#[derive(Clone, Copy, Debug)]
+pub enum TransportCommand {
+    Play,
+    /// Stops and rewinds to the start.
+    Stop,
+    SetTempo(f64),
+    SetTimeSignature(TimeSignature),
+    SetMetronome(bool),
+    SetMetronomeLevel(Signal),
+}
+
+/// Short sine blip, higher pitched on accented beats.
+pub struct Metronome {
+    sample_rate: f64,
+    phase: Phase,
+    frequency: f64,
+    level: Signal,
+    envelope: Signal,
+    decay: Signal,
+}
+
+impl Metronome {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            phase: 0.0,
+            frequency: CLICK_FREQUENCY,
+            level: 0.5,
+            envelope: 0.0,
+            decay: 0.001f64.powf(1.0 / (CLICK_TIME * sample_rate)),
+        }
+    }
+
+    pub fn set_level(&mut self, level: Signal) {
+        self.level = level.clamp(0.0, 1.0);
+    }
+
+    pub fn click(&mut self, accent: bool) {
+        self.phase = 0.0;
+        self.envelope = 1.0;
+        self.frequency = if accent {
+            ACCENT_FREQUENCY
+        } else {
+            CLICK_FREQUENCY
+        };
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        let output = (2.0 * PI * self.phase).sin() * self.envelope * self.level;
+        self.phase += self.frequency / self.sample_rate;
+        self.phase -= self.phase.floor();
+        self.envelope *= self.decay;
+        output
+    }
+}
+
+/// Common clock of the engine, with tempo, time signature and play state.
+pub struct Transport {
+    sample_rate: f64,
+    clock: TempoClock,
+    free_clock: TempoClock,
+    time_signature: TimeSignature,
+    playing: bool,
+    metronome: Metronome,
+    metronome_enabled: bool,
+    // Beat of the signature that was clicked last, counting from the start.
+    last_beat: Option<u64>,
+}
+
+impl Transport {
+    /// Starts out stopped at 120 BPM in 4/4, with the metronome off.
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            clock: TempoClock::new(sample_rate),
+            free_clock: TempoClock::new(sample_rate),
+            time_signature: TimeSignature::default(),
+            playing: false,
+            metronome: Metronome::new(sample_rate),
+            metronome_enabled: false,
+            last_beat: None,
+        }
+    }
+
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    /// Stops and rewinds to the start.
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.clock.reset();
+        self.last_beat = None;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.clock.set_tempo(bpm);
+        self.free_clock.set_tempo(bpm);
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.clock.tempo()
+    }
+
+    pub fn set_time_signature(&mut self, time_signature: TimeSignature) {
+        self.time_signature = time_signature;
+    }
+
+    pub fn set_metronome(&mut self, enabled: bool) {
+        self.metronome_enabled = enabled;
+    }
+
+    pub fn metronome_mut(&mut self) -> &mut Metronome {
+        &mut self.metronome
+    }
+
+    pub fn apply(&mut self, command: TransportCommand) {
+        match command {
+            TransportCommand::Play => self.play(),
+            TransportCommand::Stop => self.stop(),
+            TransportCommand::SetTempo(bpm) => self.set_tempo(bpm),
+            TransportCommand::SetTimeSignature(signature) => self.set_time_signature(signature),
+            TransportCommand::SetMetronome(enabled) => self.set_metronome(enabled),
+            TransportCommand::SetMetronomeLevel(level) => self.metronome.set_level(level),
+        }
+    }
+
+    /// Advances one sample, returning the musical time of the sample and the metronome
+    /// click.
+    pub fn next_sample(&mut self) -> (ProcessContext, Signal) {
+        let position = if self.playing {
+            self.clock.next_sample()
+        } else {
+            self.clock.position()
+        };
+
+        let beat_length = self.time_signature.beat_length();
+        let beats_per_bar = u64::from(self.time_signature.beats_per_bar);
+        let beats = (position / beat_length).floor() as u64;
+        let beat_in_bar = beats % beats_per_bar;
+
+        if self.playing && self.last_beat != Some(beats) {
+            self.last_beat = Some(beats);
+            if self.metronome_enabled {
+                self.metronome.click(beat_in_bar == 0);
+            }
+        }
+
+        let context = ProcessContext {
+            sample_rate: self.sample_rate,
+            tempo: self.clock.tempo(),
+            time_signature: self.time_signature,
+            playing: self.playing,
+            position,
+            bar: beats / beats_per_bar,
+            beat: position / beat_length - (beats - beat_in_bar) as f64,
+            clock: self.free_clock.next_sample(),
+            velocity: 1.0,
+            pitch: None,
+        };
+        (context, self.metronome.next_sample())
+    }
+}
+
+/// Works out a tempo from the time between taps on a key.
+#[derive(Default)]
+pub struct TapTempo {
+    taps: VecDeque<Instant>,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a tap at `now` and returns the tempo of the recent taps, once there are two,
+    /// kept between `MIN_TAP_TEMPO` and `MAX_TAP_TEMPO`.
+    pub fn tap(&mut self, now: Instant) -> Option<f64> {
+        let stale = self
+            .taps
+            .back()
+            .is_some_and(|&last| now.duration_since(last) > MAX_TAP_INTERVAL);
+        if stale {
+            self.taps.clear();
+        }
+
+        self.taps.push_back(now);
+        while self.taps.len() > TAP_HISTORY + 1 {
+            self.taps.pop_front();
+        }
+
+        let first = *self.taps.front()?;
+        let intervals = self.taps.len() - 1;
+        if intervals == 0 {
+            return None;
+        }
+        let seconds = now.duration_since(first).as_secs_f64() / intervals as f64;
+        Some((60.0 / seconds).clamp(MIN_TAP_TEMPO, MAX_TAP_TEMPO))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    #[test]
+    fn position_counts_bars_and_beats() {
+        let mut transport = Transport::new(SAMPLE_RATE);
+        transport.set_time_signature(TimeSignature::new(3, 4));
+        transport.play();
+
+        // Five and a half beats at 120 BPM.
+        for _ in 0..132_000 {
+            transport.next_sample();
+        }
+        let (context, _) = transport.next_sample();
+        assert!(context.playing);
+        assert_eq!(context.bar, 1);
+        assert!((context.beat - 2.5).abs() < 1e-9);
+        assert!((context.position - 5.5).abs() < 1e-9);
+
+        // Stopping rewinds the position, but the clock counts on.
+        transport.stop();
+        let (context, _) = transport.next_sample();
+        assert_eq!(context.position, 0.0);
+        assert!(context.clock > 5.5);
+    }
+
+    #[test]
+    fn metronome_clicks_on_every_beat() {
+        let mut transport = Transport::new(SAMPLE_RATE);
+        transport.set_metronome(true);
+        transport.play();
+
+        // Clicks start at zero, so look at the sample after each beat.
+        let clicks: Vec<Signal> = (0..96_000).map(|_| transport.next_sample().1).collect();
+        for beat in 0..4 {
+            assert!(clicks[beat * 24_000 + 1] > 0.0);
+            assert!(clicks[beat * 24_000 + 20_000].abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn tap_tempo_averages_recent_taps() {
+        let start = Instant::now();
+        let mut tap_tempo = TapTempo::new();
+        assert_eq!(tap_tempo.tap(start), None);
+        let bpm = tap_tempo.tap(start + Duration::from_millis(500)).unwrap();
+        assert!((bpm - 120.0).abs() < 1e-9);
+
+        // A long pause starts over.
+        let start = start + Duration::from_secs(10);
+        assert_eq!(tap_tempo.tap(start), None);
+
+        // Fast taps are kept in range.
+        let bpm = tap_tempo.tap(start + Duration::from_millis(100)).unwrap();
+        assert_eq!(bpm, MAX_TAP_TEMPO);
+    }
+}
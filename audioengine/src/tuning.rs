@@ -0,0 +1,477 @@
+use oscillators::MIDDLE_C;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Key 0 is MIDI note 60, which Scala keyboard maps use.
+const MIDI_KEY_OFFSET: i32 = 60;
+const CONCERT_A: f64 = 440.0;
+/// Largest keyboard map read from a `.kbm` file, well beyond the 128 MIDI keys.
+const MAX_MAP_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub enum TuningError {
+    Io(io::Error),
+    /// A line of a Scala file could not be read. Lines count from 1.
+    Parse {
+        line: usize,
+        reason: &'static str,
+    },
+}
+
+impl From<io::Error> for TuningError {
+    fn from(error: io::Error) -> Self {
+        TuningError::Io(error)
+    }
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TuningError::Io(error) => write!(f, "Failed to read tuning: {}", error),
+            TuningError::Parse { line, reason } => write!(f, "Line {}: {}", line, reason),
+        }
+    }
+}
+
+/// Pitches of one period of a scale as ratios to its first note. The unison is implied and
+/// the last pitch is the period, usually the octave.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    ratios: Vec<f64>,
+}
+
+impl Scale {
+    /// Returns `None` for an empty list or a ratio that is not above zero.
+    pub fn from_ratios(description: &str, ratios: &[f64]) -> Option<Self> {
+        if ratios.is_empty()
+            || ratios
+                .iter()
+                .any(|&ratio| ratio <= 0.0 || !ratio.is_finite())
+        {
+            return None;
+        }
+        Some(Self {
+            description: description.to_string(),
+            ratios: ratios.to_vec(),
+        })
+    }
+
+    /// Octave split into `divisions` equal steps.
+    pub fn equal_divisions(divisions: u32) -> Self {
+        let divisions = divisions.max(1);
+        let ratios: Vec<f64> = (1..=divisions)
+            .map(|step| 2f64.powf(f64::from(step) / f64::from(divisions)))
+            .collect();
+        Self {
+            description: format!("{} equal divisions of the octave", divisions),
+            ratios,
+        }
+    }
+
+    /// Twelve note five-limit just intonation.
+    pub fn just_intonation() -> Self {
+        let fractions = [
+            (16, 15),
+            (9, 8),
+            (6, 5),
+            (5, 4),
+            (4, 3),
+            (45, 32),
+            (3, 2),
+            (8, 5),
+            (5, 3),
+            (9, 5),
+            (15, 8),
+            (2, 1),
+        ];
+        Self {
+            description: "Five-limit just intonation".to_string(),
+            ratios: fractions
+                .iter()
+                .map(|&(num, den)| f64::from(num) / f64::from(den))
+                .collect(),
+        }
+    }
+
+    /// Reads the text of a Scala `.scl` file.
+    pub fn from_scl(text: &str) -> Result<Self, TuningError> {
+        let mut lines = scala_lines(text);
+        let (_, description) = lines.next().ok_or(TuningError::Parse {
+            line: 1,
+            reason: "missing description",
+        })?;
+        let (line, count) = lines.next().ok_or(TuningError::Parse {
+            line: 1,
+            reason: "missing note count",
+        })?;
+        let count: usize = first_word(count).parse().map_err(|_| TuningError::Parse {
+            line,
+            reason: "note count is not a number",
+        })?;
+        if count == 0 {
+            return Err(TuningError::Parse {
+                line,
+                reason: "scale has no notes",
+            });
+        }
+
+        let mut ratios = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (line, pitch) = lines.next().ok_or(TuningError::Parse {
+                line,
+                reason: "fewer pitches than the note count",
+            })?;
+            let ratio = parse_pitch(first_word(pitch)).ok_or(TuningError::Parse {
+                line,
+                reason: "pitch is not a ratio or cents above 0",
+            })?;
+            ratios.push(ratio);
+        }
+        Ok(Self {
+            description: description.trim().to_string(),
+            ratios,
+        })
+    }
+
+    pub fn load_scl<P: AsRef<Path>>(path: P) -> Result<Self, TuningError> {
+        Self::from_scl(&fs::read_to_string(path)?)
+    }
+
+    /// Number of notes in a period.
+    pub fn len(&self) -> usize {
+        self.ratios.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ratios.is_empty()
+    }
+
+    /// Ratio to the first note of a degree, which can lie in any period.
+    pub fn ratio(&self, degree: i32) -> f64 {
+        let notes = self.ratios.len() as i32;
+        let period = self.ratios[self.ratios.len() - 1];
+        let index = degree.rem_euclid(notes);
+        let within = if index == 0 {
+            1.0
+        } else {
+            self.ratios[index as usize - 1]
+        };
+        period.powi(degree.div_euclid(notes)) * within
+    }
+}
+
+/// Which keys play which scale degrees, and the frequency everything is tuned from. Keys are
+/// numbered as handed to processor functions, so key 0 is middle C, MIDI note 60.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardMap {
+    /// Lowest and highest keys that sound.
+    pub first_key: i32,
+    pub last_key: i32,
+    /// Key playing the first degree of the scale.
+    pub middle_key: i32,
+    pub reference_key: i32,
+    pub reference_frequency: f64,
+    /// Degree the mapping repeats at. 0 uses the period of the scale.
+    pub octave_degree: i32,
+    /// Degree of each key from `middle_key` on, repeating. `None` keys are silent. An empty
+    /// mapping plays consecutive degrees on consecutive keys.
+    pub mapping: Vec<Option<i32>>,
+}
+
+impl KeyboardMap {
+    /// Consecutive degrees on consecutive keys from key 0, with `reference_key` sounding at
+    /// `reference_frequency`.
+    pub fn linear(reference_key: i32, reference_frequency: f64) -> Self {
+        Self {
+            first_key: i32::MIN,
+            last_key: i32::MAX,
+            middle_key: 0,
+            reference_key,
+            reference_frequency,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+
+    /// Reads the text of a Scala `.kbm` file.
+    pub fn from_kbm(text: &str) -> Result<Self, TuningError> {
+        let mut lines = scala_lines(text);
+        let mut header = [(1, ""); 7];
+        let mut last_line = 1;
+        for word in header.iter_mut() {
+            let (line, value) = lines.next().ok_or(TuningError::Parse {
+                line: last_line,
+                reason: "missing keyboard map header",
+            })?;
+            *word = (line, first_word(value));
+            last_line = line;
+        }
+
+        let (size_line, size) = header[0];
+        let size = size
+            .parse()
+            .ok()
+            .filter(|&size| size <= MAX_MAP_SIZE)
+            .ok_or(TuningError::Parse {
+                line: size_line,
+                reason: "map size is not a count up to 1024",
+            })?;
+        let mut fields = [0f64; 6];
+        for (field, &(line, word)) in fields.iter_mut().zip(header[1..].iter()) {
+            *field = word.parse().map_err(|_| TuningError::Parse {
+                line,
+                reason: "header value is not a number",
+            })?;
+        }
+        let [first, last, middle, reference, frequency, octave] = fields;
+        if frequency <= 0.0 || !frequency.is_finite() {
+            return Err(TuningError::Parse {
+                line: header[5].0,
+                reason: "reference frequency out of range",
+            });
+        }
+
+        let mut mapping = vec![None; size];
+        for (entry, (line, value)) in mapping.iter_mut().zip(lines) {
+            let value = first_word(value);
+            if value != "x" {
+                let degree = value.parse().map_err(|_| TuningError::Parse {
+                    line,
+                    reason: "mapping is not a degree or x",
+                })?;
+                *entry = Some(degree);
+            }
+        }
+
+        Ok(Self {
+            first_key: first as i32 - MIDI_KEY_OFFSET,
+            last_key: last as i32 - MIDI_KEY_OFFSET,
+            middle_key: middle as i32 - MIDI_KEY_OFFSET,
+            reference_key: reference as i32 - MIDI_KEY_OFFSET,
+            reference_frequency: frequency,
+            octave_degree: octave as i32,
+            mapping,
+        })
+    }
+
+    pub fn load_kbm<P: AsRef<Path>>(path: P) -> Result<Self, TuningError> {
+        Self::from_kbm(&fs::read_to_string(path)?)
+    }
+
+    /// Scale degree played by `key`, if it sounds. The range of keys only applies when
+    /// `check_range` is set.
+    fn degree(&self, key: i32, scale_len: usize, check_range: bool) -> Option<i32> {
+        if check_range && (key < self.first_key || key > self.last_key) {
+            return None;
+        }
+        let offset = key - self.middle_key;
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+        let size = self.mapping.len() as i32;
+        let octave_degree = if self.octave_degree == 0 {
+            scale_len as i32
+        } else {
+            self.octave_degree
+        };
+        let degree = self.mapping[offset.rem_euclid(size) as usize]?;
+        Some(degree + offset.div_euclid(size) * octave_degree)
+    }
+}
+
+impl Default for KeyboardMap {
+    /// A above middle C at 440 Hz.
+    fn default() -> Self {
+        Self::linear(9, CONCERT_A)
+    }
+}
+
+/// Maps keys to frequencies through a scale and a keyboard map, the way Scala tunings do.
+/// Voices share one through an `Arc` and default to twelve tone equal temperament.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    scale: Scale,
+    map: KeyboardMap,
+    // Ratio of the degree of the reference key, which the reference frequency divides by.
+    reference_ratio: f64,
+}
+
+impl Tuning {
+    pub fn new(scale: Scale, map: KeyboardMap) -> Self {
+        // A silent reference key has no degree, so it is taken to play the one it would
+        // with an empty mapping.
+        let reference_degree = map
+            .degree(map.reference_key, scale.len(), false)
+            .unwrap_or(map.reference_key - map.middle_key);
+        let reference_ratio = scale.ratio(reference_degree);
+        Self {
+            scale,
+            map,
+            reference_ratio,
+        }
+    }
+
+    /// Twelve tone equal temperament with A above middle C at `a4`.
+    pub fn equal_temperament(a4: f64) -> Self {
+        Self::new(Scale::equal_divisions(12), KeyboardMap::linear(9, a4))
+    }
+
+    /// `divisions` equal steps to the octave, one per key, with key 0 at middle C.
+    pub fn equal_divisions(divisions: u32) -> Self {
+        Self::new(
+            Scale::equal_divisions(divisions),
+            KeyboardMap::linear(0, MIDDLE_C),
+        )
+    }
+
+    /// Five-limit just intonation on C, with key 0 at middle C.
+    pub fn just_intonation() -> Self {
+        Self::new(Scale::just_intonation(), KeyboardMap::linear(0, MIDDLE_C))
+    }
+
+    pub fn scale(&self) -> &Scale {
+        &self.scale
+    }
+
+    pub fn keyboard_map(&self) -> &KeyboardMap {
+        &self.map
+    }
+
+    /// Frequency of a key, or `None` if the key is not mapped. Fractional keys, e.g. from a
+    /// glide, land in between their neighbours.
+    pub fn frequency(&self, key: f64) -> Option<f64> {
+        let below = key.floor();
+        let low = self.key_frequency(below as i32)?;
+        let fraction = key - below;
+        if fraction == 0.0 {
+            return Some(low);
+        }
+        match self.key_frequency(below as i32 + 1) {
+            Some(high) => Some(low * (high / low).powf(fraction)),
+            None => Some(low),
+        }
+    }
+
+    /// Ratio of the frequency of key `to` to that of key `from`, if both are mapped.
+    pub fn interval(&self, from: f64, to: f64) -> Option<f64> {
+        Some(self.frequency(to)? / self.frequency(from)?)
+    }
+
+    fn key_frequency(&self, key: i32) -> Option<f64> {
+        let degree = self.map.degree(key, self.scale.len(), true)?;
+        Some(self.map.reference_frequency * self.scale.ratio(degree) / self.reference_ratio)
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::new(Scale::equal_divisions(12), KeyboardMap::default())
+    }
+}
+
+/// Lines of a Scala file with their numbers, leaving out comments.
+fn scala_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.starts_with('!'))
+}
+
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+/// A pitch with a period is in cents, anything else is a ratio such as `3/2` or `2`.
+fn parse_pitch(pitch: &str) -> Option<f64> {
+    let ratio = if pitch.contains('.') {
+        2f64.powf(pitch.parse::<f64>().ok()? / 1200.0)
+    } else {
+        let mut parts = pitch.splitn(2, '/');
+        let numerator: f64 = parts.next()?.parse::<u64>().ok()? as f64;
+        let denominator = match parts.next() {
+            Some(denominator) => denominator.parse::<u64>().ok()? as f64,
+            None => 1.0,
+        };
+        numerator / denominator
+    };
+    if ratio > 0.0 && ratio.is_finite() {
+        Some(ratio)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oscillators::key_to_frequency;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9 * b
+    }
+
+    #[test]
+    fn default_tuning_matches_equal_temperament() {
+        let tuning = Tuning::default();
+        for key in -24..24 {
+            let key = f64::from(key) + 0.25;
+            assert!(close(tuning.frequency(key).unwrap(), key_to_frequency(key)));
+        }
+        let tuning = Tuning::equal_temperament(432.0);
+        assert!(close(tuning.frequency(9.0).unwrap(), 432.0));
+        assert!(close(tuning.frequency(21.0).unwrap(), 864.0));
+    }
+
+    #[test]
+    fn equal_divisions_and_just_intonation() {
+        let tuning = Tuning::equal_divisions(19);
+        assert!(close(tuning.frequency(19.0).unwrap(), 2.0 * MIDDLE_C));
+        assert!(close(tuning.frequency(-19.0).unwrap(), 0.5 * MIDDLE_C));
+
+        let tuning = Tuning::just_intonation();
+        assert!(close(tuning.frequency(7.0).unwrap(), 1.5 * MIDDLE_C));
+        assert!(close(tuning.frequency(16.0).unwrap(), 2.5 * MIDDLE_C));
+    }
+
+    #[test]
+    fn reads_scala_files() {
+        let scl = "! pelog.scl\n!\nSlendro-ish\n 5\n!\n 240.0 cents\n 480.\n 702.0\n 8/5\n 2\n";
+        let scale = Scale::from_scl(scl).unwrap();
+        assert_eq!(scale.description, "Slendro-ish");
+        assert_eq!(scale.len(), 5);
+        assert!(close(scale.ratio(3), 2f64.powf(702.0 / 1200.0)));
+        assert!(close(scale.ratio(4), 1.6));
+        assert!(close(scale.ratio(-1), 0.8));
+
+        // Octaves on every second key from middle C, the keys in between silent.
+        let kbm = "! map\n2\n0\n127\n60\n70\n440.0\n5\n0\nx\n";
+        let map = KeyboardMap::from_kbm(kbm).unwrap();
+        assert_eq!(map.mapping, vec![Some(0), None]);
+        let tuning = Tuning::new(scale, map);
+        assert_eq!(tuning.frequency(1.0), None);
+        assert!(close(tuning.frequency(10.0).unwrap(), 440.0));
+        assert!(close(tuning.frequency(2.0).unwrap(), 440.0 / 16.0));
+        assert_eq!(tuning.frequency(68.0), None);
+
+        match Scale::from_scl("Broken\n2\n3/2\nfoo\n") {
+            Err(TuningError::Parse { line: 4, .. }) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_keyboard_maps() {
+        for size in ["1e12", "1e30", "-1", "1025", "2.5"].iter() {
+            let kbm = format!("{}\n0\n127\n60\n69\n440.0\n12\n", size);
+            match KeyboardMap::from_kbm(&kbm) {
+                Err(TuningError::Parse { line: 1, .. }) => (),
+                other => panic!("{}: {:?}", size, other),
+            }
+        }
+        let kbm = "1024\n0\n127\n60\n69\n440.0\n12\n";
+        assert_eq!(KeyboardMap::from_kbm(kbm).unwrap().mapping.len(), 1024);
+    }
+}
@@ -1,7 +1,19 @@
+use transport::ProcessContext;
+
 pub type Phase = f64;
 pub type Signal = f64;
+/// Left and right channel.
+pub type StereoSignal = (Signal, Signal);
 
-pub type SignalProcessorFunction = Box<FnMut(Option<i32>) -> Signal + Send>;
+pub type SignalProcessorFunction = Box<dyn FnMut(Option<i32>) -> Signal + Send>;
+pub type StereoSignalProcessorFunction = Box<dyn FnMut(Option<i32>) -> StereoSignal + Send>;
+/// Effects applied to the processor output before the master bus.
+pub type PostProcessorFunction = Box<dyn FnMut(StereoSignal) -> StereoSignal + Send>;
+/// Processor that is also handed the musical time of each sample.
+pub type ContextProcessorFunction =
+    Box<dyn FnMut(Option<i32>, &ProcessContext) -> StereoSignal + Send>;
+pub type ContextPostProcessorFunction =
+    Box<dyn FnMut(StereoSignal, &ProcessContext) -> StereoSignal + Send>;
 
 #[derive(Clone, Copy)]
 pub enum KeyAction {
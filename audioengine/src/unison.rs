@@ -0,0 +1,205 @@
+use oscillators::{Oscillator, Waveform};
+use random::Random;
+use std::f64::consts::{FRAC_PI_4, SQRT_2};
+use types::{Signal, StereoSignal};
+
+pub const MAX_UNISON_VOICES: usize = 16;
+
+/// Stack of detuned copies of an oscillator, spread across the stereo field. Detune and pan
+/// positions are symmetrical around the centre, and the level is compensated so adding
+/// voices does not make the stack louder.
+pub struct Unison {
+    oscillators: Vec<Oscillator>,
+    voice_count: usize,
+    frequency: f64,
+    detune: f64,
+    spread: Signal,
+    random_phase: bool,
+    random: Random,
+    // Left and right gain of each voice.
+    pans: Vec<StereoSignal>,
+}
+
+impl Unison {
+    pub fn new(waveform: Waveform, sample_rate: f64) -> Self {
+        let mut unison = Self {
+            oscillators: (0..MAX_UNISON_VOICES)
+                .map(|_| Oscillator::new(waveform, sample_rate))
+                .collect(),
+            voice_count: 1,
+            frequency: 0.0,
+            detune: 0.0,
+            spread: 1.0,
+            random_phase: true,
+            random: Random::default(),
+            pans: vec![(0.0, 0.0); MAX_UNISON_VOICES],
+        };
+        unison.update_pans();
+        unison
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        for oscillator in self.oscillators.iter_mut() {
+            oscillator.set_waveform(waveform);
+        }
+    }
+
+    /// Number of voices, from 1 to `MAX_UNISON_VOICES`.
+    pub fn set_voices(&mut self, count: usize) {
+        self.voice_count = count.clamp(1, MAX_UNISON_VOICES);
+        self.update_frequencies();
+        self.update_pans();
+    }
+
+    pub fn voices(&self) -> usize {
+        self.voice_count
+    }
+
+    /// Detune of the outermost voices in cents, one up and one down. The others are spaced
+    /// evenly in between.
+    pub fn set_detune(&mut self, cents: f64) {
+        self.detune = cents.max(0.0);
+        self.update_frequencies();
+    }
+
+    /// Stereo spread in [0.0, 1.0], 0.0 placing every voice in the centre.
+    pub fn set_spread(&mut self, spread: Signal) {
+        self.spread = spread.clamp(0.0, 1.0);
+        self.update_pans();
+    }
+
+    /// Whether `note_on` starts every voice at a random phase. Without it the voices start in
+    /// phase, which gives a sharper attack.
+    pub fn set_random_phase(&mut self, random_phase: bool) {
+        self.random_phase = random_phase;
+    }
+
+    pub fn set_seed(&mut self, seed: u32) {
+        self.random = Random::new(seed);
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.update_frequencies();
+    }
+
+    pub fn set_pulse_width(&mut self, pulse_width: f64) {
+        for oscillator in self.oscillators.iter_mut() {
+            oscillator.set_pulse_width(pulse_width);
+        }
+    }
+
+    /// Restarts the voices, at random phases if enabled.
+    pub fn note_on(&mut self) {
+        for oscillator in self.oscillators.iter_mut() {
+            let phase = if self.random_phase {
+                self.random.next_unipolar()
+            } else {
+                0.0
+            };
+            oscillator.reset_phase(phase);
+        }
+    }
+
+    pub fn next_sample(&mut self) -> StereoSignal {
+        let mut output = (0.0, 0.0);
+        for (oscillator, &(left, right)) in self
+            .oscillators
+            .iter_mut()
+            .zip(self.pans.iter())
+            .take(self.voice_count)
+        {
+            let sample = oscillator.next_sample();
+            output.0 += sample * left;
+            output.1 += sample * right;
+        }
+        output
+    }
+
+    /// Position of a voice in [-1.0, 1.0], used for both detune and panning.
+    fn position(&self, voice: usize) -> f64 {
+        if self.voice_count > 1 {
+            2.0 * voice as f64 / (self.voice_count - 1) as f64 - 1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn update_frequencies(&mut self) {
+        for voice in 0..self.voice_count {
+            let cents = self.position(voice) * self.detune;
+            let frequency = self.frequency * 2f64.powf(cents / 1200.0);
+            self.oscillators[voice].set_frequency(frequency);
+        }
+    }
+
+    fn update_pans(&mut self) {
+        // Detuned voices drift in and out of phase, so they add up by power rather than by
+        // amplitude.
+        let gain = 1.0 / (self.voice_count as Signal).sqrt();
+        // Constant power panning, scaled so a centred voice has unity gain on both sides.
+        let scale = gain * SQRT_2;
+        for voice in 0..self.voice_count {
+            let angle = (self.position(voice) * self.spread + 1.0) * FRAC_PI_4;
+            self.pans[voice] = (angle.cos() * scale, angle.sin() * scale);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    fn rms(unison: &mut Unison) -> (Signal, Signal) {
+        let length = 48_000;
+        let (left, right) = (0..length).fold((0.0, 0.0), |(l, r), _| {
+            let (left, right) = unison.next_sample();
+            (l + left * left, r + right * right)
+        });
+        (
+            (left / length as Signal).sqrt(),
+            (right / length as Signal).sqrt(),
+        )
+    }
+
+    #[test]
+    fn single_voice_matches_oscillator() {
+        let mut unison = Unison::new(Waveform::Saw, SAMPLE_RATE);
+        unison.set_random_phase(false);
+        unison.set_detune(50.0);
+        unison.set_frequency(440.0);
+        unison.note_on();
+
+        let mut oscillator = Oscillator::new(Waveform::Saw, SAMPLE_RATE);
+        oscillator.set_frequency(440.0);
+        oscillator.reset_phase(0.0);
+
+        for _ in 0..1000 {
+            let expected = oscillator.next_sample();
+            let (left, right) = unison.next_sample();
+            assert!((left - expected).abs() < 1e-12);
+            assert!((right - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn level_is_compensated_for_voice_count() {
+        let mut single = Unison::new(Waveform::Saw, SAMPLE_RATE);
+        single.set_frequency(220.0);
+        single.note_on();
+        let (reference, _) = rms(&mut single);
+
+        let mut stack = Unison::new(Waveform::Saw, SAMPLE_RATE);
+        stack.set_voices(16);
+        stack.set_detune(30.0);
+        stack.set_spread(0.0);
+        stack.set_frequency(220.0);
+        stack.note_on();
+        let (left, right) = rms(&mut stack);
+
+        assert!((left / reference - 1.0).abs() < 0.25);
+        assert!((left - right).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,64 @@
+use hound;
+use std::path::Path;
+use types::Signal;
+
+/// Audio read from a WAV file, converted to signals in [-1.0, 1.0].
+pub struct WavData {
+    pub sample_rate: u32,
+    /// One buffer per channel.
+    pub channels: Vec<Vec<Signal>>,
+}
+
+impl WavData {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, hound::Error> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+
+        let samples: Vec<Signal> = match spec.sample_format {
+            hound::SampleFormat::Int => {
+                let scale = f64::from(1u32 << (spec.bits_per_sample - 1));
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| f64::from(s) / scale))
+                    .collect::<Result<_, _>>()?
+            }
+            hound::SampleFormat::Float => reader
+                .samples::<f32>()
+                .map(|s| s.map(f64::from))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let channel_count = usize::from(spec.channels.max(1));
+        let channels = (0..channel_count)
+            .map(|channel| {
+                samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channel_count)
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            sample_rate: spec.sample_rate,
+            channels,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Average of all channels.
+    pub fn to_mono(&self) -> Vec<Signal> {
+        let gain = 1.0 / self.channels.len().max(1) as Signal;
+        (0..self.len())
+            .map(|i| self.channels.iter().map(|c| c[i]).sum::<Signal>() * gain)
+            .collect()
+    }
+}
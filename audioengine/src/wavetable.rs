@@ -0,0 +1,368 @@
+use hound;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use smoothing::SmoothedValue;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use types::{Phase, Signal};
+use wav::WavData;
+
+/// Number of samples in one cycle of every table, after import.
+pub const TABLE_SIZE: usize = 2048;
+/// Band-limited copies per frame, each holding half the harmonics of the one before. Level `n`
+/// keeps harmonics up to `(TABLE_SIZE / 2) >> n`, so the top level is a pure fundamental.
+const MIP_LEVELS: usize = 11;
+const POSITION_SMOOTHING_TIME: f64 = 0.01;
+
+#[derive(Debug)]
+pub enum WavetableError {
+    Wav(hound::Error),
+    /// The source did not contain a single complete frame.
+    Empty,
+}
+
+impl From<hound::Error> for WavetableError {
+    fn from(error: hound::Error) -> Self {
+        WavetableError::Wav(error)
+    }
+}
+
+impl fmt::Display for WavetableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WavetableError::Wav(error) => write!(f, "Failed to read wavetable: {}", error),
+            WavetableError::Empty => write!(f, "Wavetable contains no complete frames"),
+        }
+    }
+}
+
+/// A set of single-cycle frames, each stored as band-limited tables for every octave.
+///
+/// Building a table runs FFTs and allocates, so do it outside the audio thread and share the
+/// result between oscillators through an `Arc`.
+pub struct Wavetable {
+    // frames[frame][level] holds `TABLE_SIZE + 1` samples, the last repeating the first.
+    frames: Vec<Vec<Vec<Signal>>>,
+}
+
+impl Wavetable {
+    /// Builds a table from single cycles of any length. Cycles longer than `TABLE_SIZE` lose
+    /// the harmonics that do not fit.
+    pub fn from_cycles(cycles: &[Vec<Signal>]) -> Result<Self, WavetableError> {
+        let mut planner = FftPlanner::new();
+        let spectra = cycles
+            .iter()
+            .filter(|cycle| !cycle.is_empty())
+            .map(|cycle| {
+                let fft = planner.plan_fft_forward(cycle.len());
+                let mut buffer: Vec<Complex<f64>> =
+                    cycle.iter().map(|&v| Complex::new(v, 0.0)).collect();
+                fft.process(&mut buffer);
+
+                let scale = TABLE_SIZE as f64 / cycle.len() as f64;
+                let harmonics = (cycle.len() / 2).min(TABLE_SIZE / 2);
+                let mut spectrum = vec![Complex::new(0.0, 0.0); TABLE_SIZE / 2 + 1];
+                for harmonic in 1..=harmonics {
+                    // An even-length cycle's Nyquist bin has no phase and is left out.
+                    if cycle.len() % 2 == 0 && harmonic == cycle.len() / 2 {
+                        continue;
+                    }
+                    spectrum[harmonic] = buffer[harmonic] * scale;
+                }
+                spectrum
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_spectra(&mut planner, spectra)
+    }
+
+    /// Builds one frame per list of harmonic amplitudes, where the first entry is the
+    /// fundamental. All partials are sines starting at phase zero. The table is normalized so
+    /// the loudest frame peaks at 1.0.
+    pub fn from_harmonics(frames: &[Vec<Signal>]) -> Result<Self, WavetableError> {
+        let spectra = frames
+            .iter()
+            .map(|amplitudes| {
+                let mut spectrum = vec![Complex::new(0.0, 0.0); TABLE_SIZE / 2 + 1];
+                for (bin, &amplitude) in spectrum[1..TABLE_SIZE / 2].iter_mut().zip(amplitudes) {
+                    *bin = Complex::new(0.0, -amplitude * TABLE_SIZE as f64 * 0.5);
+                }
+                spectrum
+            })
+            .collect();
+
+        let mut table = Self::from_spectra(&mut FftPlanner::new(), spectra)?;
+
+        let peak = table
+            .frames
+            .iter()
+            .flat_map(|levels| levels[0].iter())
+            .fold(0.0, |peak: Signal, v| peak.max(v.abs()));
+        if peak > 0.0 {
+            for sample in table.frames.iter_mut().flatten().flatten() {
+                *sample /= peak;
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Loads a WAV file of consecutive single cycles, `frame_size` samples each. Multichannel
+    /// files are mixed down to mono.
+    pub fn from_wav<P: AsRef<Path>>(path: P, frame_size: usize) -> Result<Self, WavetableError> {
+        let samples = WavData::load(path)?.to_mono();
+        let cycles: Vec<Vec<Signal>> = samples
+            .chunks(frame_size.max(1))
+            .filter(|chunk| chunk.len() == frame_size)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+
+        Self::from_cycles(&cycles)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn from_spectra(
+        planner: &mut FftPlanner<f64>,
+        spectra: Vec<Vec<Complex<f64>>>,
+    ) -> Result<Self, WavetableError> {
+        if spectra.is_empty() {
+            return Err(WavetableError::Empty);
+        }
+
+        let inverse = planner.plan_fft_inverse(TABLE_SIZE);
+
+        let frames = spectra
+            .iter()
+            .map(|spectrum| {
+                (0..MIP_LEVELS)
+                    .map(|level| {
+                        let harmonics = (TABLE_SIZE / 2) >> level;
+                        let mut buffer = vec![Complex::new(0.0, 0.0); TABLE_SIZE];
+                        for harmonic in 1..=harmonics.min(TABLE_SIZE / 2 - 1) {
+                            buffer[harmonic] = spectrum[harmonic];
+                            buffer[TABLE_SIZE - harmonic] = spectrum[harmonic].conj();
+                        }
+                        inverse.process(&mut buffer);
+
+                        let mut table: Vec<Signal> =
+                            buffer.iter().map(|c| c.re / TABLE_SIZE as f64).collect();
+                        table.push(table[0]);
+                        table
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self { frames })
+    }
+
+    fn read(&self, frame: usize, level: usize, phase: Phase) -> Signal {
+        let table = &self.frames[frame][level];
+        let position = phase * TABLE_SIZE as f64;
+        let index = position as usize;
+        let fraction = position - index as f64;
+        table[index] + (table[index + 1] - table[index]) * fraction
+    }
+}
+
+/// Reads a `Wavetable`, picking the octave table that keeps every harmonic below Nyquist and
+/// crossfading between neighbouring frames under the position parameter.
+pub struct WavetableOscillator {
+    table: Arc<Wavetable>,
+    sample_rate: f64,
+    phase: Phase,
+    increment: Phase,
+    level: usize,
+    position: SmoothedValue,
+}
+
+impl WavetableOscillator {
+    pub fn new(table: Arc<Wavetable>, sample_rate: f64) -> Self {
+        Self {
+            table,
+            sample_rate,
+            phase: 0.0,
+            increment: 0.0,
+            level: 0,
+            position: SmoothedValue::new(0.0, POSITION_SMOOTHING_TIME, sample_rate),
+        }
+    }
+
+    /// Swaps in another table, keeping phase and position.
+    pub fn set_table(&mut self, table: Arc<Wavetable>) {
+        self.table = table;
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.increment = (frequency / self.sample_rate).clamp(0.0, 0.5);
+
+        // Level n keeps harmonics up to TABLE_SIZE / 2 >> n, which stay at or below Nyquist
+        // once 2^n reaches increment * TABLE_SIZE.
+        let highest = self.increment * TABLE_SIZE as f64;
+        self.level = if highest <= 1.0 {
+            0
+        } else {
+            (highest.log2().ceil() as usize).min(MIP_LEVELS - 1)
+        };
+    }
+
+    /// Morph position in [0.0, 1.0], from the first to the last frame.
+    pub fn set_position(&mut self, position: f64) {
+        self.position.set(position.clamp(0.0, 1.0));
+    }
+
+    pub fn reset_phase(&mut self, phase: Phase) {
+        self.phase = phase - phase.floor();
+    }
+
+    pub fn next_sample(&mut self) -> Signal {
+        let last_frame = self.table.frame_count() - 1;
+        let position = self.position.next_value() * last_frame as f64;
+        let frame = (position as usize).min(last_frame);
+        let blend = position - frame as f64;
+
+        let mut value = self.table.read(frame, self.level, self.phase);
+        if blend > 0.0 && frame < last_frame {
+            let next = self.table.read(frame + 1, self.level, self.phase);
+            value += (next - value) * blend;
+        }
+
+        self.phase += self.increment;
+        self.phase -= self.phase.floor();
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+
+    fn saw() -> Arc<Wavetable> {
+        let amplitudes: Vec<Signal> = (1..TABLE_SIZE / 2).map(|n| 1.0 / n as f64).collect();
+        Arc::new(Wavetable::from_harmonics(&[amplitudes]).unwrap())
+    }
+
+    /// Highest harmonic with any energy in one level of the first frame.
+    fn highest_harmonic(table: &Wavetable, level: usize) -> usize {
+        let mut buffer: Vec<Complex<f64>> = table.frames[0][level][..TABLE_SIZE]
+            .iter()
+            .map(|&v| Complex::new(v, 0.0))
+            .collect();
+        FftPlanner::new()
+            .plan_fft_forward(TABLE_SIZE)
+            .process(&mut buffer);
+        (1..=TABLE_SIZE / 2)
+            .rev()
+            .find(|&harmonic| buffer[harmonic].norm() / TABLE_SIZE as f64 > 1e-9)
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn every_level_keeps_the_fundamental() {
+        let table = saw();
+        for level in 0..MIP_LEVELS {
+            let peak = table.frames[0][level]
+                .iter()
+                .fold(0.0, |peak: Signal, v| peak.max(v.abs()));
+            assert!(peak > 0.1, "level {} is silent", level);
+            assert!(highest_harmonic(&table, level) >= 1);
+        }
+        assert_eq!(highest_harmonic(&table, MIP_LEVELS - 1), 1);
+    }
+
+    #[test]
+    fn partials_stay_below_nyquist() {
+        let table = saw();
+        let mut oscillator = WavetableOscillator::new(table.clone(), SAMPLE_RATE);
+        let mut frequency = 20.0;
+        while frequency < SAMPLE_RATE / 2.0 {
+            oscillator.set_frequency(frequency);
+            let highest = highest_harmonic(&table, oscillator.level);
+            assert!(highest >= 1);
+            assert!(
+                highest as f64 * frequency <= SAMPLE_RATE / 2.0 + 1e-6,
+                "{} Hz plays harmonic {}",
+                frequency,
+                highest
+            );
+            frequency *= 1.1;
+        }
+    }
+
+    #[test]
+    fn odd_length_cycles_keep_their_top_harmonic() {
+        // Three samples hold the fundamental and nothing else.
+        let cycle = vec![0.0, 0.75f64.sqrt(), -(0.75f64.sqrt())];
+        let table = Wavetable::from_cycles(&[cycle]).unwrap();
+        assert_eq!(highest_harmonic(&table, 0), 1);
+        assert!((table.read(0, 0, 0.25) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn loads_frames_from_wav() {
+        let path = std::env::temp_dir().join("audioengine_wavetable_test.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48_000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let frame_size = 256;
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        // A sine frame, a silent frame and half a frame that is dropped.
+        for i in 0..frame_size {
+            let phase = i as f64 / frame_size as f64;
+            writer
+                .write_sample((phase * 2.0 * std::f64::consts::PI).sin() as f32)
+                .unwrap();
+        }
+        for _ in 0..frame_size + frame_size / 2 {
+            writer.write_sample(0.0f32).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let table = Wavetable::from_wav(&path, frame_size);
+        let _ = std::fs::remove_file(&path);
+        let table = table.unwrap();
+
+        assert_eq!(table.frame_count(), 2);
+        assert!((table.read(0, 0, 0.25) - 1.0).abs() < 1e-6);
+        assert!(table.read(1, 0, 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn position_morphs_between_frames() {
+        let table = Arc::new(Wavetable::from_harmonics(&[vec![1.0], vec![0.0, 1.0]]).unwrap());
+        let mut oscillator = WavetableOscillator::new(table.clone(), SAMPLE_RATE);
+        oscillator.set_frequency(100.0);
+        oscillator.set_position(0.5);
+        // Let the position settle before comparing.
+        for _ in 0..SAMPLE_RATE as usize {
+            oscillator.next_sample();
+        }
+
+        for _ in 0..1000 {
+            let phase = oscillator.phase;
+            let level = oscillator.level;
+            let expected = (table.read(0, level, phase) + table.read(1, level, phase)) * 0.5;
+            assert!((oscillator.next_sample() - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn high_notes_are_not_silent() {
+        let mut oscillator = WavetableOscillator::new(saw(), SAMPLE_RATE);
+        oscillator.set_frequency(SAMPLE_RATE * 0.3);
+        let peak = (0..1000)
+            .map(|_| oscillator.next_sample().abs())
+            .fold(0.0, Signal::max);
+        assert!(peak > 0.1);
+    }
+}
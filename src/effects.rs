@@ -0,0 +1,87 @@
+use audioengine::chorus::{Chorus, Flanger};
+use audioengine::lfo::LfoRate;
+use audioengine::phaser::Phaser;
+use audioengine::reverb::Reverb;
+use audioengine::transport::ProcessContext;
+use audioengine::types::StereoSignal;
+use types::{SliderEvent, SliderEventType};
+
+/// The effects run as the engine's post processor, in signal order.
+pub struct EffectChain {
+    phaser: Phaser,
+    flanger: Flanger,
+    chorus: Chorus,
+    reverb: Reverb,
+}
+
+impl EffectChain {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            phaser: Phaser::new(sample_rate),
+            flanger: Flanger::new(sample_rate),
+            chorus: Chorus::new(sample_rate),
+            reverb: Reverb::new(sample_rate),
+        }
+    }
+
+    pub fn apply_slider(&mut self, (event_type, value): SliderEvent) {
+        match event_type {
+            SliderEventType::PhaserRate => self.phaser.set_rate(LfoRate::Hertz(value)),
+            SliderEventType::PhaserStages => self.phaser.set_stages(value.round() as usize),
+            SliderEventType::PhaserFeedback => self.phaser.set_feedback(value),
+            SliderEventType::PhaserMix => self.phaser.set_mix(value),
+            SliderEventType::FlangerRate => self.flanger.set_rate(LfoRate::Hertz(value)),
+            SliderEventType::FlangerFeedback => self.flanger.set_feedback(value),
+            SliderEventType::FlangerMix => self.flanger.set_mix(value),
+            SliderEventType::ChorusRate => self.chorus.set_rate(LfoRate::Hertz(value)),
+            // The slider is in milliseconds.
+            SliderEventType::ChorusDepth => self.chorus.set_depth(value / 1000.0),
+            SliderEventType::ChorusMix => self.chorus.set_mix(value),
+            SliderEventType::ReverbSize => self.reverb.set_size(value),
+            SliderEventType::ReverbDecay => self.reverb.set_decay(value),
+            SliderEventType::ReverbDamping => self.reverb.set_damping(value),
+            SliderEventType::ReverbPreDelay => self.reverb.set_pre_delay(value),
+            SliderEventType::ReverbWidth => self.reverb.set_width(value),
+            SliderEventType::ReverbMix => self.reverb.set_mix(value),
+            _ => (),
+        }
+    }
+
+    /// Keeps the rates synced to the tempo in time with the transport.
+    pub fn follow_tempo(&mut self, context: &ProcessContext) {
+        self.phaser.follow_tempo(context);
+        self.flanger.follow_tempo(context);
+        self.chorus.follow_tempo(context);
+    }
+
+    pub fn process(&mut self, signal: StereoSignal) -> StereoSignal {
+        let signal = self.phaser.process(signal);
+        let signal = self.flanger.process(signal);
+        let signal = self.chorus.process(signal);
+        self.reverb.process(signal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_chain_passes_input_through() {
+        let mut chain = EffectChain::new(48_000.0);
+        for &mix in [
+            SliderEventType::PhaserMix,
+            SliderEventType::FlangerMix,
+            SliderEventType::ChorusMix,
+            SliderEventType::ReverbMix,
+        ]
+        .iter()
+        {
+            chain.apply_slider((mix, 0.0));
+        }
+        for n in 0..1000 {
+            let input = ((n as f64 * 0.01).sin(), (n as f64 * 0.02).cos());
+            assert_eq!(chain.process(input), input);
+        }
+    }
+}
@@ -1,16 +1,34 @@
 #[macro_use]
 extern crate conrod;
 extern crate conrod_derive;
+extern crate ron;
+#[macro_use]
+extern crate serde;
 
 extern crate audioengine;
 
+mod effects;
 mod event_loop;
+mod patch;
 mod types;
 mod ui;
 
 #[allow(unused_imports)]
 use audioengine::types::KeyAction;
 
+use audioengine::drums::DrumMachine;
+use audioengine::envelope::Adsr;
+use audioengine::lfo::{Lfo, LfoShape};
+use audioengine::modulation::{ModMatrix, ModRouteEvent, ModSource};
+use audioengine::sequencer::Pattern;
+use audioengine::transport::ProcessContext;
+use audioengine::types::StereoSignal;
+use audioengine::EngineError;
+use effects::EffectChain;
+use std::env;
+use std::sync::mpsc::channel;
+use types::{Slider, SliderEvent, SliderEventType};
+
 #[allow(unused_imports)]
 use ui::Ui;
 
@@ -19,7 +37,7 @@ use std::f64::consts::PI;
 
 #[allow(unused_variables)]
 fn main() -> Result<(), Error> {
-    let audioengine = audioengine::EngineController::start();
+    let audioengine = audioengine::EngineController::start()?;
 
     let sample_rate = audioengine.sample_rate;
     let time_per_sample = 1.0 / sample_rate;
@@ -28,12 +46,62 @@ fn main() -> Result<(), Error> {
 
     let mut current_key = None;
 
+    let sliders = [
+        Slider::new(0.001, 2.0, 0.01, SliderEventType::Attack, "Attack"),
+        Slider::new(0.001, 2.0, 0.1, SliderEventType::Decay, "Decay"),
+        Slider::new(0.0, 1.0, 0.7, SliderEventType::Sustain, "Sustain"),
+        Slider::new(0.001, 4.0, 0.3, SliderEventType::Release, "Release"),
+        Slider::new(0.05, 5.0, 0.5, SliderEventType::PhaserRate, "Ph Rate"),
+        Slider::new(1.0, 12.0, 4.0, SliderEventType::PhaserStages, "Stages"),
+        Slider::new(-0.95, 0.95, 0.3, SliderEventType::PhaserFeedback, "Ph Fdbk"),
+        Slider::new(0.0, 1.0, 0.0, SliderEventType::PhaserMix, "Phaser"),
+        Slider::new(0.05, 5.0, 0.2, SliderEventType::FlangerRate, "Fl Rate"),
+        Slider::new(
+            -0.95,
+            0.95,
+            0.5,
+            SliderEventType::FlangerFeedback,
+            "Fl Fdbk",
+        ),
+        Slider::new(0.0, 1.0, 0.0, SliderEventType::FlangerMix, "Flanger"),
+        Slider::new(0.05, 5.0, 0.8, SliderEventType::ChorusRate, "Ch Rate"),
+        Slider::new(0.0, 10.0, 3.0, SliderEventType::ChorusDepth, "Ch Depth"),
+        Slider::new(0.0, 1.0, 0.0, SliderEventType::ChorusMix, "Chorus"),
+        Slider::new(0.0, 1.0, 0.5, SliderEventType::ReverbSize, "Size"),
+        Slider::new(0.1, 10.0, 2.0, SliderEventType::ReverbDecay, "Rv Decay"),
+        Slider::new(0.0, 1.0, 0.5, SliderEventType::ReverbDamping, "Damp"),
+        Slider::new(0.0, 0.2, 0.02, SliderEventType::ReverbPreDelay, "Pre"),
+        Slider::new(0.0, 1.0, 1.0, SliderEventType::ReverbWidth, "Width"),
+        Slider::new(0.0, 1.0, 0.0, SliderEventType::ReverbMix, "Reverb"),
+    ];
+    let (slider_tx, slider_rx) = channel::<SliderEvent>();
+    let (effect_slider_tx, effect_slider_rx) = channel::<SliderEvent>();
+
+    let (route_tx, route_rx) = channel::<ModRouteEvent>();
+    let mut mod_matrix = ModMatrix::new();
+    let level = mod_matrix.register_parameter("Level", 0.0, 1.0, 1.0);
+    let mut lfo = Lfo::new(LfoShape::Sine, sample_rate);
+    let mod_sources = vec![
+        (ModSource::Lfo(0), "LFO".to_string()),
+        (ModSource::Envelope(0), "Env".to_string()),
+        (ModSource::Velocity, "Vel".to_string()),
+        (ModSource::NoteNumber, "Note".to_string()),
+    ];
+    let mod_destinations = vec![(level, mod_matrix.parameter_name(level).to_string())];
+
+    let mut envelope = Adsr::new(sample_rate);
+    let mut effect_chain = EffectChain::new(sample_rate);
+    for slider in sliders.iter() {
+        apply_envelope_slider(&mut envelope, (slider.event_type, slider.default));
+        effect_chain.apply_slider((slider.event_type, slider.default));
+    }
+
     /*
     The `move` keyword here means that values defined in the current scope are moved into whats essentially is a closure.
     The closure will be called thousands of times each second.
     You typically would want to define variables in this scope and move them inside the closure as allocation is costly.
     */
-    let synth = move |action: Option<i32>| {
+    let synth = move |action: Option<i32>, context: &ProcessContext| {
         time += time_per_sample;
         if action != current_key {
             current_key = action;
@@ -41,28 +109,84 @@ fn main() -> Result<(), Error> {
             println!("{:?}", action);
         }
 
+        for event in slider_rx.try_iter() {
+            apply_envelope_slider(&mut envelope, event);
+        }
+        for event in route_rx.try_iter() {
+            mod_matrix.apply_event(event);
+        }
+        envelope.gate_with_velocity(action, context.velocity);
+        let amplitude = envelope.next_sample();
+        lfo.follow_tempo(context);
+        mod_matrix.set_source(ModSource::Lfo(0), lfo.next_sample());
+        mod_matrix.set_source(ModSource::Envelope(0), amplitude);
+        mod_matrix.set_source(ModSource::Velocity, context.velocity);
+        mod_matrix.set_key(action);
+        mod_matrix.process();
+
         /*
         TODO: Your implementation of a synthesizer should be here.
-        Start with returning an oscillating wave determined by the `time`-variable
+        Start with returning an oscillating wave determined by the `time`-variable.
+        `context.pitch` is the key to play, gliding between notes when glide is turned up.
         */
-        0.0
+        let oscillator = 0.0;
+
+        let signal = oscillator * amplitude * mod_matrix.value(level);
+        (signal, signal)
+    };
+
+    let effects = move |signal: StereoSignal, context: &ProcessContext| {
+        for event in effect_slider_rx.try_iter() {
+            effect_chain.apply_slider(event);
+        }
+        effect_chain.follow_tempo(context);
+        effect_chain.process(signal)
     };
 
-    audioengine.set_processor_function(Box::new(synth));
+    audioengine.set_context_processor_function(Box::new(synth));
+    audioengine.set_context_post_processor_function(Box::new(effects));
 
     let mut window = Ui::new(
         "Synthesizer",
         [1280.0, 800.0],
         audioengine,
-        None,
-        None,
+        Some(&sliders),
+        Some(slider_tx),
         None,
     );
 
+    window.add_slider_sender(effect_slider_tx);
+    window.set_mod_matrix(mod_sources, mod_destinations, route_tx);
+    window.set_sequencer(Pattern::default(), 0, 12);
+    window.set_arpeggiator();
+    window.set_drum_machine(DrumMachine::with_kit(sample_rate));
+    // A patch file given on the command line is edited and played in place of the synth.
+    if let Some(path) = env::args().nth(1) {
+        window.set_patch_editor(path);
+    }
+
     window.show();
 
     Ok(())
 }
 
+fn apply_envelope_slider(envelope: &mut Adsr, (event_type, value): SliderEvent) {
+    match event_type {
+        SliderEventType::Attack => envelope.set_attack(value),
+        SliderEventType::Decay => envelope.set_decay(value),
+        SliderEventType::Sustain => envelope.set_sustain(value),
+        SliderEventType::Release => envelope.set_release(value),
+        _ => (),
+    }
+}
+
 #[derive(Debug)]
-enum Error {}
+enum Error {
+    Engine(EngineError),
+}
+
+impl From<EngineError> for Error {
+    fn from(error: EngineError) -> Self {
+        Error::Engine(error)
+    }
+}
@@ -0,0 +1,305 @@
+use audioengine::graph::{Connection, Graph, GraphError};
+use audioengine::nodes::NodeKind;
+use ron;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A module of a patch, one of the built-in `NodeKind`s by name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Module {
+    pub kind: String,
+    /// Top left corner in the patch editor, relative to the middle of its canvas.
+    pub position: [f64; 2],
+    pub parameters: Vec<f64>,
+}
+
+/// A cable from an output jack of one module to an input jack of another, by index.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cable {
+    pub from: usize,
+    pub output: usize,
+    pub to: usize,
+    pub input: usize,
+}
+
+impl Cable {
+    pub fn connection(&self) -> Connection {
+        Connection {
+            from: self.from,
+            output: self.output,
+            to: self.to,
+            input: self.input,
+        }
+    }
+}
+
+/// Modules and cables as saved in a patch file. The first `Output` module is what the
+/// engine plays.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Patch {
+    pub modules: Vec<Module>,
+    pub cables: Vec<Cable>,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(io::Error),
+    Parse(ron::de::Error),
+    Serialize(ron::ser::Error),
+    UnknownModule(String),
+    Graph(GraphError),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Io(error) => write!(f, "Patch file: {}", error),
+            PatchError::Parse(error) => write!(f, "Failed to read patch: {}", error),
+            PatchError::Serialize(error) => write!(f, "Failed to write patch: {}", error),
+            PatchError::UnknownModule(kind) => write!(f, "Unknown module {}", kind),
+            PatchError::Graph(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<io::Error> for PatchError {
+    fn from(error: io::Error) -> Self {
+        PatchError::Io(error)
+    }
+}
+
+impl From<GraphError> for PatchError {
+    fn from(error: GraphError) -> Self {
+        PatchError::Graph(error)
+    }
+}
+
+impl Patch {
+    /// A keyboard playing a saw through an envelope.
+    pub fn basic() -> Self {
+        let mut patch = Patch::default();
+        let modules = [
+            (NodeKind::Keyboard, [-560.0, 90.0]),
+            (NodeKind::Oscillator, [-380.0, 110.0]),
+            (NodeKind::Envelope, [-380.0, -10.0]),
+            (NodeKind::Gain, [-180.0, 60.0]),
+            (NodeKind::Output, [0.0, 60.0]),
+        ];
+        for &(kind, position) in modules.iter() {
+            patch.add_module(kind, position);
+        }
+        let cables = [
+            (0, 0, 1, 0),
+            (0, 1, 2, 0),
+            (1, 0, 3, 0),
+            (2, 0, 3, 1),
+            (3, 0, 4, 0),
+            (3, 0, 4, 1),
+        ];
+        for &(from, output, to, input) in cables.iter() {
+            patch.cables.push(Cable {
+                from,
+                output,
+                to,
+                input,
+            });
+        }
+        patch
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PatchError> {
+        let text = fs::read_to_string(path)?;
+        ron::de::from_str(&text).map_err(PatchError::Parse)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PatchError> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(PatchError::Serialize)?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Loads the patch at `path` for editing, returning it with the path to save it to. A
+    /// missing file starts from the basic patch. A file that fails to load or build is left
+    /// alone: the basic patch is returned along with the error, to be saved to a new file
+    /// beside it.
+    pub fn open<P: AsRef<Path>>(path: P, sample_rate: f64) -> (Self, PathBuf, Option<PatchError>) {
+        let path = path.as_ref();
+        let loaded = Patch::load(path).and_then(|patch| {
+            patch.build(sample_rate)?;
+            Ok(patch)
+        });
+        match loaded {
+            Ok(patch) => (patch, path.to_path_buf(), None),
+            Err(PatchError::Io(ref error)) if error.kind() == io::ErrorKind::NotFound => {
+                (Patch::basic(), path.to_path_buf(), None)
+            }
+            Err(error) => (Patch::basic(), unused_path(path), Some(error)),
+        }
+    }
+
+    /// Adds a module with its parameters at their defaults.
+    pub fn add_module(&mut self, kind: NodeKind, position: [f64; 2]) {
+        self.modules.push(Module {
+            kind: kind.name().to_string(),
+            position,
+            parameters: kind.parameters().iter().map(|p| p.default).collect(),
+        });
+    }
+
+    /// Removes a module with its cables.
+    pub fn remove_module(&mut self, index: usize) {
+        self.modules.remove(index);
+        self.cables.retain(|c| c.from != index && c.to != index);
+        for cable in self.cables.iter_mut() {
+            if cable.from > index {
+                cable.from -= 1;
+            }
+            if cable.to > index {
+                cable.to -= 1;
+            }
+        }
+    }
+
+    /// Builds the graph of the patch. Node ids match module indices.
+    pub fn build(&self, sample_rate: f64) -> Result<Graph, PatchError> {
+        let mut graph = Graph::new(sample_rate);
+        let mut output = None;
+        for module in self.modules.iter() {
+            let kind = NodeKind::from_name(&module.kind)
+                .ok_or_else(|| PatchError::UnknownModule(module.kind.clone()))?;
+            let mut node = kind.create(sample_rate);
+            for (index, &value) in module.parameters.iter().enumerate() {
+                node.set_parameter(index, value);
+            }
+            let id = graph.add_node(node);
+            if kind == NodeKind::Output && output.is_none() {
+                output = Some(id);
+            }
+        }
+        for cable in self.cables.iter() {
+            graph.connect(cable.connection())?;
+        }
+        graph.set_output(output)?;
+        Ok(graph)
+    }
+}
+
+/// A path beside `path` that no file uses, as `name.new.ron`, `name.new2.ron` and so on.
+fn unused_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map_or("patch".into(), |stem| stem.to_string_lossy());
+    (1..)
+        .map(|count| {
+            let suffix = if count == 1 {
+                String::new()
+            } else {
+                count.to_string()
+            };
+            path.with_file_name(format!("{}.new{}.ron", stem, suffix))
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn cable(from: usize, output: usize, to: usize, input: usize) -> Cable {
+        Cable {
+            from,
+            output,
+            to,
+            input,
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let patch = Patch::basic();
+        let path = env::temp_dir().join("patch_round_trip.ron");
+        patch.save(&path).unwrap();
+        let loaded = Patch::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.cables, patch.cables);
+        assert_eq!(loaded.modules.len(), patch.modules.len());
+        for (loaded, module) in loaded.modules.iter().zip(patch.modules.iter()) {
+            assert_eq!(loaded.kind, module.kind);
+            assert_eq!(loaded.position, module.position);
+            assert_eq!(loaded.parameters, module.parameters);
+        }
+    }
+
+    #[test]
+    fn files_that_fail_to_load_are_not_saved_over() {
+        let directory = env::temp_dir();
+        let broken = directory.join("patch_broken.ron");
+        let unknown = directory.join("patch_unknown.ron");
+        fs::write(&broken, "not a patch").unwrap();
+        let mut patch = Patch::basic();
+        patch.modules[0].kind = "Theremin".to_string();
+        patch.save(&unknown).unwrap();
+
+        let (opened, path, error) = Patch::open(&broken, 48_000.0);
+        match error {
+            Some(PatchError::Parse(_)) => (),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(path, directory.join("patch_broken.new.ron"));
+        assert_eq!(opened.modules.len(), Patch::basic().modules.len());
+
+        let (_, path, error) = Patch::open(&unknown, 48_000.0);
+        match error {
+            Some(PatchError::UnknownModule(_)) => (),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(path, directory.join("patch_unknown.new.ron"));
+
+        // A fallback already saved beside the file is not saved over either.
+        fs::write(directory.join("patch_broken.new.ron"), "").unwrap();
+        let (_, path, _) = Patch::open(&broken, 48_000.0);
+        assert_eq!(path, directory.join("patch_broken.new2.ron"));
+
+        assert_eq!(fs::read_to_string(&broken).unwrap(), "not a patch");
+        for file in [broken, unknown, directory.join("patch_broken.new.ron")].iter() {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn removing_a_module_reindexes_cables() {
+        let mut patch = Patch::basic();
+        // The envelope, which feeds the gain.
+        patch.remove_module(2);
+        assert_eq!(patch.modules.len(), 4);
+        assert_eq!(
+            patch.cables,
+            vec![
+                cable(0, 0, 1, 0),
+                cable(1, 0, 2, 0),
+                cable(2, 0, 3, 0),
+                cable(2, 0, 3, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_modules_are_rejected() {
+        let mut patch = Patch::basic();
+        patch.modules.push(Module {
+            kind: "Theremin".to_string(),
+            position: [0.0, 0.0],
+            parameters: Vec::new(),
+        });
+        match patch.build(48_000.0) {
+            Err(PatchError::UnknownModule(kind)) => assert_eq!(kind, "Theremin"),
+            _ => panic!("expected an unknown module error"),
+        }
+    }
+}
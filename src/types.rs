@@ -27,6 +27,22 @@ pub enum SliderEventType {
     Decay,
     Sustain,
     Release,
+    PhaserRate,
+    PhaserStages,
+    PhaserFeedback,
+    PhaserMix,
+    FlangerRate,
+    FlangerFeedback,
+    FlangerMix,
+    ChorusRate,
+    ChorusDepth,
+    ChorusMix,
+    ReverbSize,
+    ReverbDecay,
+    ReverbDamping,
+    ReverbPreDelay,
+    ReverbWidth,
+    ReverbMix,
 }
 
 #[allow(dead_code)]
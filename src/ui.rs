@@ -1,9 +1,19 @@
 extern crate conrod;
 
+use audioengine::arpeggiator::{ArpMode, ArpeggiatorCommand};
+use audioengine::clock::NoteDivision;
+use audioengine::drums::{DrumCommand, DrumMachine, DRUM_STEPS};
+use audioengine::graph::{Graph, GraphUpdate, PortKind};
+use audioengine::modulation::{ModRoute, ModRouteEvent, ModSource, ParameterId, ROUTE_DEAD_ZONE};
+use audioengine::nodes::NodeKind;
+use audioengine::portamento::GlideMode;
+use audioengine::sequencer::{Pattern, SequencerCommand, MAX_STEPS};
+use audioengine::transport::TransportCommand;
 use audioengine::EngineController;
 use event_loop;
-use std::path::Path;
-use std::sync::mpsc::Sender;
+use patch::{Cable, Module, Patch};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
 use types::{Slider, SliderEvent};
 
 use std::collections::VecDeque;
@@ -12,6 +22,43 @@ use conrod::color;
 use std::sync::mpsc::Receiver;
 
 const SIGNAL_PLOT_HEIGHT: f64 = 300.0;
+/// Sliders wrap into rows above each other, leaving room for the master section.
+const SLIDERS_PER_ROW: usize = 12;
+const SLIDER_ROW_HEIGHT: f64 = 200.0;
+const MASTER_METER_HEIGHT: f64 = 150.0;
+const MASTER_METER_RANGE_DB: f64 = 60.0;
+const MOD_MATRIX_CELL_WIDTH: f64 = 80.0;
+const MOD_MATRIX_CELL_HEIGHT: f64 = 24.0;
+const MOD_MATRIX_MARGIN: f64 = 40.0;
+const SEQUENCER_CELL_WIDTH: f64 = 18.0;
+const SEQUENCER_CELL_HEIGHT: f64 = 12.0;
+const SEQUENCER_MARGIN: f64 = 40.0;
+/// Width of the sequencer grid, whose cells narrow for patterns longer than 16 steps.
+const SEQUENCER_GRID_WIDTH: f64 = 16.0 * SEQUENCER_CELL_WIDTH;
+const SEQUENCER_PATTERNS: usize = 4;
+/// Height the window grows by for the mod matrix, sequencer, arpeggiator and drums, shown
+/// side by side below the signal plot.
+const PANEL_BAND_HEIGHT: f64 = 320.0;
+const PATCH_CANVAS_HEIGHT: f64 = 320.0;
+const PATCH_MODULE_WIDTH: f64 = 140.0;
+const PATCH_TITLE_HEIGHT: f64 = 20.0;
+const PATCH_ROW_HEIGHT: f64 = 18.0;
+const PATCH_JACK_RADIUS: f64 = 5.0;
+const ARPEGGIATOR_MODES: [(ArpMode, &str); 5] = [
+    (ArpMode::Up, "Up"),
+    (ArpMode::Down, "Down"),
+    (ArpMode::UpDown, "Up/Down"),
+    (ArpMode::Random, "Random"),
+    (ArpMode::AsPlayed, "Played"),
+];
+const ARPEGGIATOR_RATES: [(NoteDivision, &str); 6] = [
+    (NoteDivision::Straight(4), "1/4"),
+    (NoteDivision::Straight(8), "1/8"),
+    (NoteDivision::Triplet(8), "1/8T"),
+    (NoteDivision::Straight(16), "1/16"),
+    (NoteDivision::Triplet(16), "1/16T"),
+    (NoteDivision::Straight(32), "1/32"),
+];
 
 widget_ids! {
     struct Ids {
@@ -23,9 +70,197 @@ widget_ids! {
 
         sliders[],
         slider_texts[],
+
+        master_volume,
+        master_volume_text,
+        master_meter_background,
+        master_meter_level,
+        master_meter_clip,
+
+        glide,
+        glide_text,
+        glide_legato,
+
+        mod_matrix_title,
+        mod_matrix,
+        mod_matrix_row_labels[],
+        mod_matrix_column_labels[],
+
+        sequencer_title,
+        sequencer_play,
+        sequencer_tempo,
+        sequencer_swing,
+        sequencer_metronome,
+        sequencer_pattern,
+        sequencer_length,
+        sequencer_chain,
+        sequencer_step_select,
+        sequencer_grid,
+        sequencer_step_label,
+        sequencer_velocity,
+        sequencer_gate,
+        sequencer_probability,
+        sequencer_tie,
+
+        arpeggiator_title,
+        arpeggiator_enabled,
+        arpeggiator_latch,
+        arpeggiator_mode,
+        arpeggiator_rate,
+        arpeggiator_octaves,
+        arpeggiator_gate,
+        arpeggiator_tempo,
+
+        drums_title,
+        drums_swing,
+        drums_grid,
+        drums_mutes[],
+        drums_solos[],
+        drums_names[],
+
+        patch_canvas,
+        patch_title,
+        patch_add,
+        patch_save,
+        patch_status,
+        patch_pending_cable,
+        patch_cables[],
+        patch_modules[],
+        patch_module_titles[],
+        patch_module_labels[],
+        patch_module_removes[],
+        patch_jacks[],
+        patch_jack_labels[],
+        patch_parameters[],
     }
 }
 
+/// Grid of route depths, one row per modulation source and one column per destination.
+struct ModMatrixPanel {
+    sources: Vec<(ModSource, String)>,
+    destinations: Vec<(ParameterId, String)>,
+    depths: Vec<Vec<f64>>,
+    route_tx: Sender<ModRouteEvent>,
+}
+
+/// Step grid for one of the sequencer patterns, one row per key and one column per step,
+/// with the selected step's settings below it.
+struct SequencerPanel {
+    patterns: Vec<Pattern>,
+    /// Pattern shown in the grid.
+    pattern: usize,
+    /// Step edited below the grid.
+    step: usize,
+    /// Chain of pattern numbers, counting from 1, as typed.
+    chain: String,
+    /// Key of the bottom row.
+    base_key: i32,
+    rows: usize,
+    playing: bool,
+    tempo: f64,
+    swing: f64,
+    metronome: bool,
+}
+
+/// Arpeggiator settings, indices pointing into `ARPEGGIATOR_MODES` and `ARPEGGIATOR_RATES`.
+struct ArpeggiatorPanel {
+    enabled: bool,
+    latch: bool,
+    mode: usize,
+    rate: usize,
+    octaves: u32,
+    gate: f64,
+    /// Tempo of the transport, shared with the sequencer panel.
+    tempo: f64,
+}
+
+/// Step grid of the drum machine, one row per lane. Clicking a step cycles it through off,
+/// on and accented.
+struct DrumPanel {
+    names: Vec<String>,
+    steps: Vec<[bool; DRUM_STEPS]>,
+    accents: Vec<[bool; DRUM_STEPS]>,
+    mutes: Vec<bool>,
+    solos: Vec<bool>,
+    swing: f64,
+}
+
+/// Modules of a patch drawn as boxes with jacks, edited with the mouse.
+struct PatchEditorPanel {
+    patch: Patch,
+    path: PathBuf,
+    sample_rate: f64,
+    updates: Sender<GraphUpdate>,
+    /// Graphs replaced on the audio thread, dropped here.
+    retired: Receiver<Graph>,
+    /// Whether each cable closes a loop, from the last built graph.
+    feedback: Vec<bool>,
+    /// Output jack a cable is being dragged from, as module and output index.
+    dragging: Option<(usize, usize)>,
+    status: String,
+}
+
+impl PatchEditorPanel {
+    /// Applies a change to the modules or cables and swaps the rebuilt graph in on the
+    /// audio thread. A change that does not build is undone.
+    fn edit<F: FnOnce(&mut Patch)>(&mut self, change: F) {
+        self.retired.try_iter().for_each(drop);
+        let previous = self.patch.clone();
+        change(&mut self.patch);
+        match self.patch.build(self.sample_rate) {
+            Ok(graph) => {
+                self.feedback = self
+                    .patch
+                    .cables
+                    .iter()
+                    .map(|cable| graph.is_feedback(cable.connection()))
+                    .collect();
+                self.status.clear();
+                let _ = self.updates.send(GraphUpdate::Replace(graph));
+            }
+            Err(error) => {
+                self.status = error.to_string();
+                self.patch = previous;
+            }
+        }
+    }
+
+    /// Sets a parameter of a module without rebuilding the graph.
+    fn set_parameter(&mut self, module: usize, parameter: usize, value: f64) {
+        let kind = patch_module_kind(&self.patch.modules[module]);
+        let values = &mut self.patch.modules[module].parameters;
+        if values.len() <= parameter {
+            let missing = &kind.parameters()[values.len()..=parameter];
+            values.extend(missing.iter().map(|p| p.default));
+        }
+        values[parameter] = value;
+        let _ = self
+            .updates
+            .send(GraphUpdate::SetParameter(module, parameter, value));
+    }
+}
+
+/// Kind of a module. Modules that do not build never make it into the editor.
+fn patch_module_kind(module: &Module) -> NodeKind {
+    NodeKind::from_name(&module.kind).expect("patch modules are checked when built")
+}
+
+/// A row for each pair of jacks and for each parameter, below the title.
+fn patch_module_height(kind: NodeKind) -> f64 {
+    let jacks = kind.inputs().len().max(kind.outputs().len());
+    let rows = jacks + kind.parameters().len();
+    PATCH_TITLE_HEIGHT + rows as f64 * PATCH_ROW_HEIGHT + 6.0
+}
+
+/// Middle of a jack relative to the patch canvas, inputs on the left edge of the module
+/// and outputs on the right.
+fn patch_jack_position(module: &Module, index: usize, output: bool) -> [f64; 2] {
+    let [x, y] = module.position;
+    let x = if output { x + PATCH_MODULE_WIDTH } else { x };
+    let y = y - PATCH_TITLE_HEIGHT - (index as f64 + 0.5) * PATCH_ROW_HEIGHT;
+    [x, y]
+}
+
 pub struct Ui<'a> {
     dimensions: [f64; 2],
     events_loop: conrod::glium::glutin::EventsLoop,
@@ -37,9 +272,14 @@ pub struct Ui<'a> {
     sliders: &'a [Slider],
     renderer: conrod::backend::glium::Renderer,
     audioengine: EngineController,
-    slider_tx: Option<Sender<SliderEvent>>,
+    slider_txs: Vec<Sender<SliderEvent>>,
     graphdata_rx: Option<Receiver<Vec<f64>>>,
     signal_buffer: VecDeque<f64>,
+    mod_matrix: Option<ModMatrixPanel>,
+    sequencer: Option<SequencerPanel>,
+    arpeggiator: Option<ArpeggiatorPanel>,
+    drums: Option<DrumPanel>,
+    patch_editor: Option<PatchEditorPanel>,
 }
 
 impl<'a> Ui<'a> {
@@ -89,13 +329,193 @@ impl<'a> Ui<'a> {
             sliders,
             renderer,
             audioengine,
-            slider_tx,
+            slider_txs: slider_tx.into_iter().collect(),
             graphdata_rx,
             signal_buffer,
+            mod_matrix: None,
+            sequencer: None,
+            arpeggiator: None,
+            drums: None,
+            patch_editor: None,
         }
     }
 
+    /// Also sends slider changes to `slider_tx`, for processors that need their own receiver.
+    pub fn add_slider_sender(&mut self, slider_tx: Sender<SliderEvent>) {
+        self.slider_txs.push(slider_tx);
+    }
+
+    /// Shows a grid for editing modulation routes. Every change is sent as a
+    /// `ModRouteEvent` through `route_tx`, to be applied to a `ModMatrix` by the processor.
+    pub fn set_mod_matrix(
+        &mut self,
+        sources: Vec<(ModSource, String)>,
+        destinations: Vec<(ParameterId, String)>,
+        route_tx: Sender<ModRouteEvent>,
+    ) {
+        self.ids
+            .mod_matrix_row_labels
+            .resize(sources.len(), &mut self.ui.widget_id_generator());
+        self.ids
+            .mod_matrix_column_labels
+            .resize(destinations.len(), &mut self.ui.widget_id_generator());
+
+        self.add_panel_band();
+        let depths = sources
+            .iter()
+            .map(|_| vec![0.0; destinations.len()])
+            .collect();
+
+        self.mod_matrix = Some(ModMatrixPanel {
+            sources,
+            destinations,
+            depths,
+            route_tx,
+        });
+    }
+
+    /// Shows a grid for editing the sequencer patterns, starting with `pattern` followed by
+    /// empty ones, with `rows` keys from `base_key` up, and the transport controls. Edits go
+    /// straight to the audio thread.
+    pub fn set_sequencer(&mut self, pattern: Pattern, base_key: i32, rows: usize) {
+        self.add_panel_band();
+        let tempo = 120.0;
+        let mut patterns = vec![pattern];
+        patterns.resize(SEQUENCER_PATTERNS, Pattern::default());
+        self.audioengine
+            .sequencer_command(SequencerCommand::SetPatterns(patterns.clone()));
+        self.audioengine
+            .sequencer_command(SequencerCommand::SetChain(vec![0]));
+        self.audioengine
+            .transport_command(TransportCommand::SetTempo(tempo));
+
+        self.sequencer = Some(SequencerPanel {
+            patterns,
+            pattern: 0,
+            step: 0,
+            chain: "1".to_string(),
+            base_key,
+            rows: rows.max(1),
+            playing: false,
+            tempo,
+            swing: 0.0,
+            metronome: false,
+        });
+    }
+
+    /// Shows the arpeggiator controls. The arpeggiator steps along the transport, whose
+    /// tempo can be set from here as well as from the sequencer.
+    pub fn set_arpeggiator(&mut self) {
+        self.add_panel_band();
+        self.arpeggiator = Some(ArpeggiatorPanel {
+            enabled: false,
+            latch: false,
+            mode: 0,
+            rate: 3,
+            octaves: 1,
+            gate: 0.5,
+            tempo: self.sequencer.as_ref().map_or(120.0, |panel| panel.tempo),
+        });
+    }
+
+    /// Shows a step grid for `machine`, with mute and solo for each lane, and plays it along
+    /// with the transport.
+    pub fn set_drum_machine(&mut self, machine: DrumMachine) {
+        self.add_panel_band();
+        let lanes = machine.lanes().len();
+        self.ids
+            .drums_mutes
+            .resize(lanes, &mut self.ui.widget_id_generator());
+        self.ids
+            .drums_solos
+            .resize(lanes, &mut self.ui.widget_id_generator());
+        self.ids
+            .drums_names
+            .resize(lanes, &mut self.ui.widget_id_generator());
+
+        let swing = 0.0;
+        self.drums = Some(DrumPanel {
+            names: machine.lanes().iter().map(|l| l.name.clone()).collect(),
+            steps: machine.lanes().iter().map(|l| l.steps).collect(),
+            accents: machine.lanes().iter().map(|l| l.accents).collect(),
+            mutes: machine.lanes().iter().map(|l| l.mute).collect(),
+            solos: machine.lanes().iter().map(|l| l.solo).collect(),
+            swing,
+        });
+        self.audioengine.set_drum_machine(machine);
+        self.audioengine.drum_command(DrumCommand::SetSwing(swing));
+    }
+
+    /// Shows an editor for the patch file at `path`, playing its graph in place of the
+    /// processor function. A missing file starts from a basic patch, and Save writes it. A
+    /// file that fails to load is kept, and Save writes the basic patch beside it instead.
+    pub fn set_patch_editor<P: AsRef<Path>>(&mut self, path: P) {
+        if self.patch_editor.is_none() {
+            self.grow_window(PATCH_CANVAS_HEIGHT);
+        }
+        let sample_rate = self.audioengine.sample_rate;
+        let is_new = !path.as_ref().exists();
+        let (patch, path, error) = Patch::open(path, sample_rate);
+        let status = match error {
+            Some(error) => format!("{}, saving to {}", error, path.display()),
+            None if is_new => "New patch".to_string(),
+            None => String::new(),
+        };
+
+        let (updates, update_rx) = channel();
+        let (retired_tx, retired) = channel();
+        let graph = Graph::new(sample_rate);
+        self.audioengine
+            .set_context_processor_function(graph.into_processor_function(update_rx, retired_tx));
+
+        let mut panel = PatchEditorPanel {
+            patch: Patch::default(),
+            path,
+            sample_rate,
+            updates,
+            retired,
+            feedback: Vec::new(),
+            dragging: None,
+            status: String::new(),
+        };
+        panel.edit(|current| *current = patch);
+        if !status.is_empty() {
+            panel.status = status;
+        }
+        self.patch_editor = Some(panel);
+    }
+
+    fn has_panel_band(&self) -> bool {
+        self.mod_matrix.is_some()
+            || self.sequencer.is_some()
+            || self.arpeggiator.is_some()
+            || self.drums.is_some()
+    }
+
+    /// Makes room for the first panel shown below the signal plot.
+    fn add_panel_band(&mut self) {
+        if !self.has_panel_band() {
+            self.grow_window(PANEL_BAND_HEIGHT);
+        }
+    }
+
+    /// Makes the window taller, keeping the sliders at the bottom clear of a new panel.
+    fn grow_window(&mut self, height: f64) {
+        self.dimensions[1] += height;
+        let [width, height] = self.dimensions;
+        self.display
+            .gl_window()
+            .set_inner_size((width, height).into());
+    }
+
     pub fn show(&mut self) {
+        // The patch editor goes below the other panels.
+        let patch_canvas_top = if self.has_panel_band() {
+            SIGNAL_PLOT_HEIGHT + PANEL_BAND_HEIGHT
+        } else {
+            SIGNAL_PLOT_HEIGHT
+        };
+
         let Ui {
             ref mut events_loop,
             event_loop,
@@ -106,13 +526,21 @@ impl<'a> Ui<'a> {
             renderer,
             image_map,
             audioengine,
-            slider_tx,
+            slider_txs,
             graphdata_rx,
             ref mut signal_buffer,
+            mod_matrix,
+            sequencer,
+            arpeggiator,
+            drums,
+            patch_editor,
             ..
         } = self;
 
-        let mut _gain = 1.0;
+        let mut master_volume = 1.0;
+        let mut glide_time = 0.0;
+        let mut legato_glide = false;
+        let mut clip_indicator_until = std::time::Instant::now();
 
         let [width, _height] = self.dimensions;
 
@@ -158,6 +586,16 @@ impl<'a> Ui<'a> {
                                 Some(KeyboardInput::KeyInput(kee)) => {
                                     audioengine.key_action(KeyAction::Press(kee))
                                 }
+                                Some(KeyboardInput::SettingsInput(SettingsKey::TapTempo)) => {
+                                    if let Some(tempo) = audioengine.tap_tempo() {
+                                        if let Some(panel) = sequencer.as_mut() {
+                                            panel.tempo = tempo;
+                                        }
+                                        if let Some(panel) = arpeggiator.as_mut() {
+                                            panel.tempo = tempo;
+                                        }
+                                    }
+                                }
                                 _ => (),
                             }
                         }
@@ -193,6 +631,13 @@ impl<'a> Ui<'a> {
                 }
             }
 
+            let master_meter = audioengine.master_meter();
+            let playhead = audioengine.sequencer_playhead();
+            if master_meter.clipped {
+                clip_indicator_until =
+                    std::time::Instant::now() + std::time::Duration::from_secs(1);
+            }
+
             // Draw the widgets
             {
                 use conrod::{
@@ -217,7 +662,7 @@ impl<'a> Ui<'a> {
                             .small_font(ui)
                             .set($slider_id, ui)
                         {
-                            if let Some(tx) = slider_tx {
+                            for tx in slider_txs.iter() {
                                 tx.send(($slider_type, value)).unwrap();
                             }
                             slider_values[$index] = value;
@@ -262,13 +707,16 @@ impl<'a> Ui<'a> {
                 for (idx, slider) in sliders.iter().enumerate() {
                     let slider_id = ids.sliders[idx];
                     let slider_text_id = ids.slider_texts[idx];
-                    let x = (idx as f64) * 80.0 + 40.0;
+                    let column = idx % SLIDERS_PER_ROW;
+                    let row = idx / SLIDERS_PER_ROW;
+                    let x = (column as f64) * 80.0 + 40.0;
+                    let y = (row as f64) * SLIDER_ROW_HEIGHT + 40.0;
                     create_slider!(
                         slider_id,
                         red,
                         conrod::color::rgb(0.75, 0.3, 0.3),
                         slider.event_type,
-                        (40.0, x),
+                        (y, x),
                         idx,
                         (slider.min, slider.max)
                     );
@@ -279,6 +727,959 @@ impl<'a> Ui<'a> {
                         slider.label.as_str()
                     );
                 }
+
+                // Master volume and output meter
+                let master_volume_label = format!("{:.*}", 2, master_volume);
+                for value in widget::Slider::new(master_volume, 0.0, 1.0)
+                    .w_h(40.0, MASTER_METER_HEIGHT)
+                    .bottom_right_with_margins_on(ids.background, 40.0, 80.0)
+                    .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                    .border_color(color::DARK_GRAY)
+                    .border(1.0)
+                    .label(&master_volume_label)
+                    .label_color(color::BLACK)
+                    .small_font(ui)
+                    .set(ids.master_volume, ui)
+                {
+                    audioengine.set_master_volume(value);
+                    master_volume = value;
+                }
+                let master_volume_id = ids.master_volume;
+                let master_volume_text_id = ids.master_volume_text;
+                create_slider_text!(
+                    master_volume_text_id,
+                    master_volume_id,
+                    color::LIGHT_BLUE,
+                    "Master"
+                );
+
+                // Glide of the engine, left of the master volume
+                let glide_label = format!("{:.*}", 2, glide_time);
+                for value in widget::Slider::new(glide_time, 0.0, 1.0)
+                    .w_h(40.0, MASTER_METER_HEIGHT - 30.0)
+                    .bottom_right_with_margins_on(ids.background, 40.0, 150.0)
+                    .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                    .border_color(color::DARK_GRAY)
+                    .border(1.0)
+                    .label(&glide_label)
+                    .label_color(color::BLACK)
+                    .small_font(ui)
+                    .set(ids.glide, ui)
+                {
+                    audioengine.set_glide(GlideMode::ConstantTime(value), legato_glide);
+                    glide_time = value;
+                }
+                for legato in widget::Toggle::new(legato_glide)
+                    .w_h(40.0, 20.0)
+                    .up_from(ids.glide, 10.0)
+                    .color(color::DARK_GREEN)
+                    .label("Leg")
+                    .label_color(color::WHITE)
+                    .small_font(ui)
+                    .set(ids.glide_legato, ui)
+                {
+                    audioengine.set_glide(GlideMode::ConstantTime(glide_time), legato);
+                    legato_glide = legato;
+                }
+                let glide_id = ids.glide;
+                let glide_text_id = ids.glide_text;
+                create_slider_text!(glide_text_id, glide_id, color::LIGHT_BLUE, "Glide");
+
+                widget::Rectangle::fill([16.0, MASTER_METER_HEIGHT])
+                    .right_from(ids.master_volume, 10.0)
+                    .color(color::BLACK)
+                    .set(ids.master_meter_background, ui);
+
+                let peak_db = 20.0 * master_meter.peak.max(1e-6).log10();
+                let level = ((peak_db + MASTER_METER_RANGE_DB) / MASTER_METER_RANGE_DB)
+                    .max(0.0)
+                    .min(1.0);
+                let level_color = if master_meter.limiter_gain < 1.0 {
+                    color::YELLOW
+                } else {
+                    color::GREEN
+                };
+                widget::Rectangle::fill([16.0, (level * MASTER_METER_HEIGHT).max(1.0)])
+                    .mid_bottom_of(ids.master_meter_background)
+                    .color(level_color)
+                    .set(ids.master_meter_level, ui);
+
+                let clip_color = if std::time::Instant::now() < clip_indicator_until {
+                    color::RED
+                } else {
+                    color::DARK_RED
+                };
+                widget::Rectangle::fill([16.0, 8.0])
+                    .up_from(ids.master_meter_background, 4.0)
+                    .color(clip_color)
+                    .set(ids.master_meter_clip, ui);
+
+                // Modulation matrix
+                if let Some(panel) = mod_matrix.as_mut() {
+                    let rows = panel.sources.len();
+                    let columns = panel.destinations.len();
+                    let matrix_width = columns as f64 * MOD_MATRIX_CELL_WIDTH;
+                    let matrix_height = rows as f64 * MOD_MATRIX_CELL_HEIGHT;
+
+                    widget::Text::new("Modulation")
+                        .top_right_with_margins_on(
+                            ids.background,
+                            SIGNAL_PLOT_HEIGHT + 20.0,
+                            MOD_MATRIX_MARGIN,
+                        )
+                        .font_size(16)
+                        .color(color::WHITE)
+                        .set(ids.mod_matrix_title, ui);
+
+                    let mut elements = widget::Matrix::new(columns, rows)
+                        .w_h(matrix_width, matrix_height)
+                        .down_from(ids.mod_matrix_title, 30.0)
+                        .align_right_of(ids.mod_matrix_title)
+                        .set(ids.mod_matrix, ui);
+
+                    while let Some(element) = elements.next(ui) {
+                        let (row, column) = (element.row, element.col);
+                        let depth = panel.depths[row][column];
+                        let label = format!("{:.*}", 2, depth);
+                        let slider_color = if depth == 0.0 {
+                            color::DARK_GRAY
+                        } else {
+                            conrod::color::rgb(0.75, 0.3, 0.3)
+                        };
+                        let slider = widget::Slider::new(depth, -1.0, 1.0)
+                            .color(slider_color)
+                            .border_color(color::DARK_CHARCOAL)
+                            .border(1.0)
+                            .label(&label)
+                            .label_color(color::WHITE)
+                            .label_font_size(10);
+
+                        if let Some(value) = element.set(slider, ui) {
+                            // Snap to zero where the matrix removes the route.
+                            let value = if value.abs() < ROUTE_DEAD_ZONE {
+                                0.0
+                            } else {
+                                value
+                            };
+                            panel.depths[row][column] = value;
+                            let route = ModRoute {
+                                source: panel.sources[row].0,
+                                destination: panel.destinations[column].0,
+                                depth: value,
+                            };
+                            let _ = panel.route_tx.send(ModRouteEvent::Set(route));
+                        }
+                    }
+
+                    for (row, (_, name)) in panel.sources.iter().enumerate() {
+                        let y = matrix_height / 2.0 - (row as f64 + 0.5) * MOD_MATRIX_CELL_HEIGHT;
+                        widget::Text::new(name)
+                            .x_y_relative_to(ids.mod_matrix, -matrix_width / 2.0 - 50.0, y)
+                            .font_size(12)
+                            .color(color::WHITE)
+                            .set(ids.mod_matrix_row_labels[row], ui);
+                    }
+
+                    for (column, (_, name)) in panel.destinations.iter().enumerate() {
+                        let x = -matrix_width / 2.0 + (column as f64 + 0.5) * MOD_MATRIX_CELL_WIDTH;
+                        widget::Text::new(name)
+                            .x_y_relative_to(ids.mod_matrix, x, matrix_height / 2.0 + 14.0)
+                            .font_size(12)
+                            .color(color::WHITE)
+                            .set(ids.mod_matrix_column_labels[column], ui);
+                    }
+                }
+
+                // Step sequencer
+                if let Some(panel) = sequencer.as_mut() {
+                    let columns = panel.patterns[panel.pattern].len();
+                    let grid_height = panel.rows as f64 * SEQUENCER_CELL_HEIGHT;
+
+                    widget::Text::new("Sequencer")
+                        .top_left_with_margins_on(
+                            ids.background,
+                            SIGNAL_PLOT_HEIGHT + 20.0,
+                            SEQUENCER_MARGIN,
+                        )
+                        .font_size(16)
+                        .color(color::WHITE)
+                        .set(ids.sequencer_title, ui);
+
+                    let play_label = if panel.playing { "Stop" } else { "Play" };
+                    for playing in widget::Toggle::new(panel.playing)
+                        .w_h(60.0, 24.0)
+                        .right_from(ids.sequencer_title, 20.0)
+                        .color(color::DARK_GREEN)
+                        .label(play_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.sequencer_play, ui)
+                    {
+                        audioengine.transport_command(if playing {
+                            TransportCommand::Play
+                        } else {
+                            TransportCommand::Stop
+                        });
+                        panel.playing = playing;
+                    }
+
+                    let tempo_label = format!("{:.0} BPM", panel.tempo);
+                    for tempo in widget::Slider::new(panel.tempo, 40.0, 240.0)
+                        .w_h(120.0, 24.0)
+                        .right_from(ids.sequencer_play, 10.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&tempo_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.sequencer_tempo, ui)
+                    {
+                        audioengine.transport_command(TransportCommand::SetTempo(tempo));
+                        panel.tempo = tempo;
+                        if let Some(arpeggiator) = arpeggiator.as_mut() {
+                            arpeggiator.tempo = tempo;
+                        }
+                    }
+
+                    let swing_label = format!("Swing {:.*}", 2, panel.swing);
+                    for swing in widget::Slider::new(panel.swing, 0.0, 0.5)
+                        .w_h(120.0, 24.0)
+                        .right_from(ids.sequencer_tempo, 10.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&swing_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.sequencer_swing, ui)
+                    {
+                        audioengine.sequencer_command(SequencerCommand::SetSwing(swing));
+                        panel.swing = swing;
+                    }
+
+                    for metronome in widget::Toggle::new(panel.metronome)
+                        .w_h(60.0, 24.0)
+                        .right_from(ids.sequencer_swing, 10.0)
+                        .color(color::DARK_GREEN)
+                        .label("Click")
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.sequencer_metronome, ui)
+                    {
+                        audioengine.transport_command(TransportCommand::SetMetronome(metronome));
+                        panel.metronome = metronome;
+                    }
+
+                    // Pattern, length and chain, below the transport
+                    let names: Vec<String> = (1..=SEQUENCER_PATTERNS)
+                        .map(|number| format!("Pattern {}", number))
+                        .collect();
+                    for pattern in widget::DropDownList::new(&names, Some(panel.pattern))
+                        .w_h(90.0, 24.0)
+                        .down_from(ids.sequencer_title, 12.0)
+                        .align_left_of(ids.sequencer_title)
+                        .small_font(ui)
+                        .set(ids.sequencer_pattern, ui)
+                    {
+                        panel.pattern = pattern;
+                        panel.step = panel.step.min(panel.patterns[pattern].len() - 1);
+                    }
+
+                    let length_label = format!("{} steps", columns);
+                    for length in widget::Slider::new(columns as f64, 1.0, MAX_STEPS as f64)
+                        .w_h(100.0, 24.0)
+                        .right_from(ids.sequencer_pattern, 10.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&length_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.sequencer_length, ui)
+                    {
+                        let length = length.round() as usize;
+                        if length != columns {
+                            let pattern = &mut panel.patterns[panel.pattern];
+                            pattern.set_length(length);
+                            panel.step = panel.step.min(length - 1);
+                            audioengine.sequencer_command(SequencerCommand::SetPattern(
+                                panel.pattern,
+                                pattern.clone(),
+                            ));
+                        }
+                    }
+
+                    // Typed as pattern numbers, and sent on Enter
+                    for event in widget::TextBox::new(&panel.chain)
+                        .w_h(100.0, 24.0)
+                        .right_from(ids.sequencer_length, 10.0)
+                        .font_size(12)
+                        .set(ids.sequencer_chain, ui)
+                    {
+                        match event {
+                            widget::text_box::Event::Update(text) => panel.chain = text,
+                            widget::text_box::Event::Enter => {
+                                let chain: Vec<usize> = panel
+                                    .chain
+                                    .split_whitespace()
+                                    .filter_map(|number| number.parse::<usize>().ok())
+                                    .filter(|&number| number >= 1 && number <= SEQUENCER_PATTERNS)
+                                    .map(|number| number - 1)
+                                    .collect();
+                                if !chain.is_empty() {
+                                    panel.chain = chain
+                                        .iter()
+                                        .map(|index| (index + 1).to_string())
+                                        .collect::<Vec<_>>()
+                                        .join(" ");
+                                    audioengine
+                                        .sequencer_command(SequencerCommand::SetChain(chain));
+                                }
+                            }
+                        }
+                    }
+
+                    // A row above the grid selects the step edited below it
+                    let columns = panel.patterns[panel.pattern].len();
+                    let mut elements = widget::Matrix::new(columns, 1)
+                        .w_h(SEQUENCER_GRID_WIDTH, 8.0)
+                        .down_from(ids.sequencer_pattern, 12.0)
+                        .align_left_of(ids.sequencer_title)
+                        .set(ids.sequencer_step_select, ui);
+
+                    while let Some(element) = elements.next(ui) {
+                        let column = element.col;
+                        let select_color = if column == panel.step {
+                            color::WHITE
+                        } else {
+                            color::DARK_GRAY
+                        };
+                        let button = widget::Button::new()
+                            .color(select_color)
+                            .border_color(color::DARK_CHARCOAL)
+                            .border(1.0);
+                        for _click in element.set(button, ui) {
+                            panel.step = column;
+                        }
+                    }
+
+                    let selected = panel.pattern;
+                    let mut elements = widget::Matrix::new(columns, panel.rows)
+                        .w_h(SEQUENCER_GRID_WIDTH, grid_height)
+                        .down_from(ids.sequencer_step_select, 4.0)
+                        .align_left_of(ids.sequencer_title)
+                        .set(ids.sequencer_grid, ui);
+
+                    while let Some(element) = elements.next(ui) {
+                        let (row, column) = (element.row, element.col);
+                        // The top row holds the highest key.
+                        let key = panel.base_key + (panel.rows - 1 - row) as i32;
+                        let pattern = &mut panel.patterns[selected];
+                        let step = *pattern.step(column);
+                        let on = step.active && step.key == key;
+                        let at_playhead = playhead.playing
+                            && playhead.pattern == selected
+                            && playhead.step == column;
+                        let cell_color = match (on, at_playhead) {
+                            // Tied steps are darker, holding the note before them.
+                            (true, _) if step.tie => conrod::color::rgb(0.55, 0.25, 0.25),
+                            (true, _) => conrod::color::rgb(0.75, 0.3, 0.3),
+                            (false, true) => color::GRAY,
+                            // Every fourth column is lighter, marking the beats.
+                            (false, false) if column % 4 == 0 => color::CHARCOAL,
+                            (false, false) => color::DARK_GRAY,
+                        };
+                        let toggle = widget::Toggle::new(on)
+                            .color(cell_color)
+                            .border_color(color::DARK_CHARCOAL)
+                            .border(1.0);
+
+                        for on in element.set(toggle, ui) {
+                            let step = pattern.step_mut(column);
+                            step.active = on;
+                            step.key = key;
+                            audioengine.sequencer_command(SequencerCommand::SetStep(
+                                selected, column, *step,
+                            ));
+                            panel.step = column;
+                        }
+                    }
+
+                    // Settings of the selected step
+                    let step_label = format!("Step {}", panel.step + 1);
+                    widget::Text::new(&step_label)
+                        .down_from(ids.sequencer_grid, 12.0)
+                        .align_left_of(ids.sequencer_title)
+                        .font_size(12)
+                        .color(color::WHITE)
+                        .set(ids.sequencer_step_label, ui);
+
+                    let mut step = *panel.patterns[selected].step(panel.step);
+                    let mut changed = false;
+
+                    let velocity_label = format!("Vel {:.*}", 2, step.velocity);
+                    for velocity in widget::Slider::new(step.velocity, 0.0, 1.0)
+                        .w_h(70.0, 20.0)
+                        .right_from(ids.sequencer_step_label, 10.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&velocity_label)
+                        .label_color(color::WHITE)
+                        .label_font_size(10)
+                        .set(ids.sequencer_velocity, ui)
+                    {
+                        step.velocity = velocity;
+                        changed = true;
+                    }
+
+                    let gate_label = format!("Gate {:.*}", 2, step.gate);
+                    for gate in widget::Slider::new(step.gate, 0.05, 1.0)
+                        .w_h(70.0, 20.0)
+                        .right_from(ids.sequencer_velocity, 6.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&gate_label)
+                        .label_color(color::WHITE)
+                        .label_font_size(10)
+                        .set(ids.sequencer_gate, ui)
+                    {
+                        step.gate = gate;
+                        changed = true;
+                    }
+
+                    let probability_label = format!("Prob {:.*}", 2, step.probability);
+                    for probability in widget::Slider::new(step.probability, 0.0, 1.0)
+                        .w_h(70.0, 20.0)
+                        .right_from(ids.sequencer_gate, 6.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&probability_label)
+                        .label_color(color::WHITE)
+                        .label_font_size(10)
+                        .set(ids.sequencer_probability, ui)
+                    {
+                        step.probability = probability;
+                        changed = true;
+                    }
+
+                    for tie in widget::Toggle::new(step.tie)
+                        .w_h(40.0, 20.0)
+                        .right_from(ids.sequencer_probability, 6.0)
+                        .color(color::DARK_GREEN)
+                        .label("Tie")
+                        .label_color(color::WHITE)
+                        .label_font_size(10)
+                        .set(ids.sequencer_tie, ui)
+                    {
+                        step.tie = tie;
+                        changed = true;
+                    }
+
+                    if changed {
+                        *panel.patterns[selected].step_mut(panel.step) = step;
+                        audioengine.sequencer_command(SequencerCommand::SetStep(
+                            selected, panel.step, step,
+                        ));
+                    }
+                }
+
+                // Arpeggiator, right of the sequencer grid if it is shown
+                if let Some(panel) = arpeggiator.as_mut() {
+                    let title = widget::Text::new("Arpeggiator")
+                        .font_size(16)
+                        .color(color::WHITE);
+                    let title = if sequencer.is_some() {
+                        title
+                            .right_from(ids.sequencer_grid, 40.0)
+                            .align_top_of(ids.sequencer_grid)
+                    } else {
+                        title.top_left_with_margins_on(
+                            ids.background,
+                            SIGNAL_PLOT_HEIGHT + 20.0,
+                            SEQUENCER_MARGIN,
+                        )
+                    };
+                    title.set(ids.arpeggiator_title, ui);
+
+                    for enabled in widget::Toggle::new(panel.enabled)
+                        .w_h(60.0, 24.0)
+                        .right_from(ids.arpeggiator_title, 20.0)
+                        .color(color::DARK_GREEN)
+                        .label("On")
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.arpeggiator_enabled, ui)
+                    {
+                        audioengine.arpeggiator_command(ArpeggiatorCommand::SetEnabled(enabled));
+                        panel.enabled = enabled;
+                    }
+
+                    for latch in widget::Toggle::new(panel.latch)
+                        .w_h(60.0, 24.0)
+                        .right_from(ids.arpeggiator_enabled, 10.0)
+                        .color(color::DARK_GREEN)
+                        .label("Latch")
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.arpeggiator_latch, ui)
+                    {
+                        audioengine.arpeggiator_command(ArpeggiatorCommand::SetLatch(latch));
+                        panel.latch = latch;
+                    }
+
+                    let modes: Vec<&str> = ARPEGGIATOR_MODES.iter().map(|m| m.1).collect();
+                    for mode in widget::DropDownList::new(&modes, Some(panel.mode))
+                        .w_h(90.0, 24.0)
+                        .right_from(ids.arpeggiator_latch, 10.0)
+                        .small_font(ui)
+                        .set(ids.arpeggiator_mode, ui)
+                    {
+                        let command = ArpeggiatorCommand::SetMode(ARPEGGIATOR_MODES[mode].0);
+                        audioengine.arpeggiator_command(command);
+                        panel.mode = mode;
+                    }
+
+                    let rates: Vec<&str> = ARPEGGIATOR_RATES.iter().map(|r| r.1).collect();
+                    for rate in widget::DropDownList::new(&rates, Some(panel.rate))
+                        .w_h(70.0, 24.0)
+                        .right_from(ids.arpeggiator_mode, 10.0)
+                        .small_font(ui)
+                        .set(ids.arpeggiator_rate, ui)
+                    {
+                        let command = ArpeggiatorCommand::SetDivision(ARPEGGIATOR_RATES[rate].0);
+                        audioengine.arpeggiator_command(command);
+                        panel.rate = rate;
+                    }
+
+                    // Second row, so the panel fits beside the sequencer grid
+                    let octaves_label = format!("{} oct", panel.octaves);
+                    for octaves in widget::Slider::new(f64::from(panel.octaves), 1.0, 4.0)
+                        .w_h(80.0, 24.0)
+                        .down_from(ids.arpeggiator_title, 16.0)
+                        .align_left_of(ids.arpeggiator_title)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&octaves_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.arpeggiator_octaves, ui)
+                    {
+                        let octaves = octaves.round() as u32;
+                        if octaves != panel.octaves {
+                            audioengine
+                                .arpeggiator_command(ArpeggiatorCommand::SetOctaves(octaves));
+                            panel.octaves = octaves;
+                        }
+                    }
+
+                    let gate_label = format!("Gate {:.*}", 2, panel.gate);
+                    for gate in widget::Slider::new(panel.gate, 0.05, 1.0)
+                        .w_h(80.0, 24.0)
+                        .right_from(ids.arpeggiator_octaves, 10.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&gate_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.arpeggiator_gate, ui)
+                    {
+                        audioengine.arpeggiator_command(ArpeggiatorCommand::SetGate(gate));
+                        panel.gate = gate;
+                    }
+
+                    let tempo_label = format!("{:.0} BPM", panel.tempo);
+                    for tempo in widget::Slider::new(panel.tempo, 40.0, 240.0)
+                        .w_h(120.0, 24.0)
+                        .right_from(ids.arpeggiator_gate, 10.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&tempo_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.arpeggiator_tempo, ui)
+                    {
+                        audioengine.transport_command(TransportCommand::SetTempo(tempo));
+                        panel.tempo = tempo;
+                        if let Some(sequencer) = sequencer.as_mut() {
+                            sequencer.tempo = tempo;
+                        }
+                    }
+                }
+
+                // Drum machine, below the arpeggiator or right of the sequencer grid
+                if let Some(panel) = drums.as_mut() {
+                    let title = widget::Text::new("Drums").font_size(16).color(color::WHITE);
+                    let title = if arpeggiator.is_some() {
+                        title
+                            .down_from(ids.arpeggiator_octaves, 20.0)
+                            .align_left_of(ids.arpeggiator_title)
+                    } else if sequencer.is_some() {
+                        title
+                            .right_from(ids.sequencer_grid, 40.0)
+                            .align_top_of(ids.sequencer_grid)
+                    } else {
+                        title.top_left_with_margins_on(
+                            ids.background,
+                            SIGNAL_PLOT_HEIGHT + 20.0,
+                            SEQUENCER_MARGIN,
+                        )
+                    };
+                    title.set(ids.drums_title, ui);
+
+                    let swing_label = format!("Swing {:.*}", 2, panel.swing);
+                    for swing in widget::Slider::new(panel.swing, 0.0, 0.5)
+                        .w_h(120.0, 24.0)
+                        .right_from(ids.drums_title, 20.0)
+                        .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                        .label(&swing_label)
+                        .label_color(color::WHITE)
+                        .small_font(ui)
+                        .set(ids.drums_swing, ui)
+                    {
+                        audioengine.drum_command(DrumCommand::SetSwing(swing));
+                        panel.swing = swing;
+                    }
+
+                    let rows = panel.names.len();
+                    let grid_width = DRUM_STEPS as f64 * SEQUENCER_CELL_WIDTH;
+                    let grid_height = rows as f64 * SEQUENCER_CELL_HEIGHT;
+                    let mut elements = widget::Matrix::new(DRUM_STEPS, rows)
+                        .w_h(grid_width, grid_height)
+                        .down_from(ids.drums_title, 12.0)
+                        .align_left_of(ids.drums_title)
+                        .set(ids.drums_grid, ui);
+
+                    while let Some(element) = elements.next(ui) {
+                        let (lane, step) = (element.row, element.col);
+                        let cell_color = match (panel.steps[lane][step], panel.accents[lane][step])
+                        {
+                            (true, true) => conrod::color::rgb(0.95, 0.55, 0.45),
+                            (true, false) => conrod::color::rgb(0.75, 0.3, 0.3),
+                            // Every fourth column is lighter, marking the beats.
+                            (false, _) if step % 4 == 0 => color::CHARCOAL,
+                            (false, _) => color::DARK_GRAY,
+                        };
+                        let button = widget::Button::new()
+                            .color(cell_color)
+                            .border_color(color::DARK_CHARCOAL)
+                            .border(1.0);
+
+                        for _click in element.set(button, ui) {
+                            let (on, accent) =
+                                match (panel.steps[lane][step], panel.accents[lane][step]) {
+                                    (false, _) => (true, false),
+                                    (true, false) => (true, true),
+                                    (true, true) => (false, false),
+                                };
+                            panel.steps[lane][step] = on;
+                            panel.accents[lane][step] = accent;
+                            audioengine.drum_command(DrumCommand::SetStep(lane, step, on));
+                            audioengine.drum_command(DrumCommand::SetAccent(lane, step, accent));
+                        }
+                    }
+
+                    // Mute, solo and name of each lane, right of its row
+                    for lane in 0..rows {
+                        let y = grid_height / 2.0 - (lane as f64 + 0.5) * SEQUENCER_CELL_HEIGHT;
+                        for mute in widget::Toggle::new(panel.mutes[lane])
+                            .w_h(20.0, SEQUENCER_CELL_HEIGHT)
+                            .x_y_relative_to(ids.drums_grid, grid_width / 2.0 + 20.0, y)
+                            .color(color::DARK_YELLOW)
+                            .label("M")
+                            .label_color(color::WHITE)
+                            .label_font_size(9)
+                            .set(ids.drums_mutes[lane], ui)
+                        {
+                            audioengine.drum_command(DrumCommand::SetMute(lane, mute));
+                            panel.mutes[lane] = mute;
+                        }
+
+                        for solo in widget::Toggle::new(panel.solos[lane])
+                            .w_h(20.0, SEQUENCER_CELL_HEIGHT)
+                            .right_from(ids.drums_mutes[lane], 4.0)
+                            .color(color::DARK_GREEN)
+                            .label("S")
+                            .label_color(color::WHITE)
+                            .label_font_size(9)
+                            .set(ids.drums_solos[lane], ui)
+                        {
+                            audioengine.drum_command(DrumCommand::SetSolo(lane, solo));
+                            panel.solos[lane] = solo;
+                        }
+
+                        widget::Text::new(&panel.names[lane])
+                            .right_from(ids.drums_solos[lane], 6.0)
+                            .font_size(10)
+                            .color(color::WHITE)
+                            .set(ids.drums_names[lane], ui);
+                    }
+                }
+
+                // Patch editor across the window, below the other panels
+                if let Some(panel) = patch_editor.as_mut() {
+                    widget::Canvas::new()
+                        .w_h(width, PATCH_CANVAS_HEIGHT)
+                        .mid_top_with_margin_on(ids.background, patch_canvas_top)
+                        .color(color::CHARCOAL)
+                        .set(ids.patch_canvas, ui);
+
+                    widget::Text::new("Patch")
+                        .top_left_with_margins_on(ids.patch_canvas, 10.0, 10.0)
+                        .font_size(16)
+                        .color(color::WHITE)
+                        .set(ids.patch_title, ui);
+
+                    let names: Vec<&str> = NodeKind::ALL.iter().map(|kind| kind.name()).collect();
+                    for index in widget::DropDownList::new(&names, None)
+                        .w_h(110.0, 24.0)
+                        .right_from(ids.patch_title, 20.0)
+                        .label("Add module")
+                        .small_font(ui)
+                        .set(ids.patch_add, ui)
+                    {
+                        let kind = NodeKind::ALL[index];
+                        let position =
+                            [-PATCH_MODULE_WIDTH / 2.0, PATCH_CANVAS_HEIGHT / 2.0 - 50.0];
+                        panel.edit(|patch| patch.add_module(kind, position));
+                    }
+
+                    for _click in widget::Button::new()
+                        .w_h(60.0, 24.0)
+                        .right_from(ids.patch_add, 10.0)
+                        .label("Save")
+                        .small_font(ui)
+                        .set(ids.patch_save, ui)
+                    {
+                        panel.status = match panel.patch.save(&panel.path) {
+                            Ok(()) => format!("Saved {}", panel.path.display()),
+                            Err(error) => error.to_string(),
+                        };
+                    }
+
+                    widget::Text::new(&panel.status)
+                        .right_from(ids.patch_save, 10.0)
+                        .font_size(12)
+                        .color(color::LIGHT_GRAY)
+                        .set(ids.patch_status, ui);
+
+                    let kinds: Vec<NodeKind> =
+                        panel.patch.modules.iter().map(patch_module_kind).collect();
+                    let jack_count = kinds
+                        .iter()
+                        .map(|kind| kind.inputs().len() + kind.outputs().len())
+                        .sum();
+                    let parameter_count = kinds.iter().map(|kind| kind.parameters().len()).sum();
+                    {
+                        let mut generator = ui.widget_id_generator();
+                        ids.patch_modules.resize(kinds.len(), &mut generator);
+                        ids.patch_module_titles.resize(kinds.len(), &mut generator);
+                        ids.patch_module_labels.resize(kinds.len(), &mut generator);
+                        ids.patch_module_removes.resize(kinds.len(), &mut generator);
+                        ids.patch_jacks.resize(jack_count, &mut generator);
+                        ids.patch_jack_labels.resize(jack_count, &mut generator);
+                        ids.patch_parameters.resize(parameter_count, &mut generator);
+                        ids.patch_cables
+                            .resize(panel.patch.cables.len(), &mut generator);
+                    }
+
+                    let origin = ui.xy_of(ids.patch_canvas).unwrap_or([0.0, 0.0]);
+                    let mut jack = 0;
+                    let mut parameter = 0;
+                    let mut removed = None;
+                    let mut pressed_input = None;
+                    let mut input_jacks = Vec::new();
+                    for (index, &kind) in kinds.iter().enumerate() {
+                        let module = panel.patch.modules[index].clone();
+                        let [x, y] = module.position;
+                        let height = patch_module_height(kind);
+                        let module_id = ids.patch_modules[index];
+                        let title_id = ids.patch_module_titles[index];
+
+                        widget::Rectangle::fill_with(
+                            [PATCH_MODULE_WIDTH, height],
+                            color::DARK_CHARCOAL,
+                        )
+                        .x_y(
+                            origin[0] + x + PATCH_MODULE_WIDTH / 2.0,
+                            origin[1] + y - height / 2.0,
+                        )
+                        .parent(ids.patch_canvas)
+                        .set(module_id, ui);
+
+                        widget::Rectangle::fill_with(
+                            [PATCH_MODULE_WIDTH, PATCH_TITLE_HEIGHT],
+                            color::DARK_BLUE,
+                        )
+                        .mid_top_of(module_id)
+                        .set(title_id, ui);
+
+                        widget::Text::new(kind.name())
+                            .mid_left_with_margin_on(title_id, 6.0)
+                            .font_size(12)
+                            .color(color::WHITE)
+                            .graphics_for(title_id)
+                            .set(ids.patch_module_labels[index], ui);
+
+                        for _click in widget::Button::new()
+                            .w_h(16.0, 16.0)
+                            .mid_right_with_margin_on(title_id, 2.0)
+                            .color(color::DARK_RED)
+                            .label("x")
+                            .label_color(color::WHITE)
+                            .small_font(ui)
+                            .set(ids.patch_module_removes[index], ui)
+                        {
+                            removed = Some(index);
+                        }
+
+                        // Dragging the title moves the module
+                        for drag in ui.widget_input(title_id).drags().left() {
+                            let position = &mut panel.patch.modules[index].position;
+                            position[0] += drag.delta_xy[0];
+                            position[1] += drag.delta_xy[1];
+                        }
+
+                        let ports = kind
+                            .inputs()
+                            .iter()
+                            .enumerate()
+                            .map(|(number, port)| (number, port, false))
+                            .chain(
+                                kind.outputs()
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(number, port)| (number, port, true)),
+                            );
+                        for (number, port, output) in ports {
+                            let [jack_x, jack_y] = patch_jack_position(&module, number, output);
+                            let xy = [origin[0] + jack_x, origin[1] + jack_y];
+                            let jack_color = match port.kind {
+                                PortKind::Audio => color::LIGHT_ORANGE,
+                                PortKind::Control => color::LIGHT_BLUE,
+                            };
+                            widget::Circle::fill(PATCH_JACK_RADIUS)
+                                .x_y(xy[0], xy[1])
+                                .color(jack_color)
+                                .parent(module_id)
+                                .set(ids.patch_jacks[jack], ui);
+
+                            let label = widget::Text::new(port.name)
+                                .font_size(10)
+                                .color(color::WHITE);
+                            let label = if output {
+                                label.left_from(ids.patch_jacks[jack], 4.0)
+                            } else {
+                                label.right_from(ids.patch_jacks[jack], 4.0)
+                            };
+                            label.set(ids.patch_jack_labels[jack], ui);
+
+                            let pressed = ui
+                                .widget_input(ids.patch_jacks[jack])
+                                .presses()
+                                .mouse()
+                                .left()
+                                .next()
+                                .is_some();
+                            if output {
+                                if pressed {
+                                    panel.dragging = Some((index, number));
+                                }
+                            } else {
+                                if pressed {
+                                    pressed_input = Some((index, number));
+                                }
+                                input_jacks.push((index, number, xy));
+                            }
+                            jack += 1;
+                        }
+
+                        let rows = kind.inputs().len().max(kind.outputs().len());
+                        for (number, spec) in kind.parameters().iter().enumerate() {
+                            let value = module
+                                .parameters
+                                .get(number)
+                                .cloned()
+                                .unwrap_or(spec.default);
+                            let label = format!("{} {:.*}", spec.name, 2, value);
+                            let row = (rows + number) as f64 + 0.5;
+                            for value in widget::Slider::new(value, spec.min, spec.max)
+                                .w_h(PATCH_MODULE_WIDTH - 20.0, PATCH_ROW_HEIGHT - 4.0)
+                                .x_y(
+                                    origin[0] + x + PATCH_MODULE_WIDTH / 2.0,
+                                    origin[1] + y - PATCH_TITLE_HEIGHT - row * PATCH_ROW_HEIGHT,
+                                )
+                                .parent(module_id)
+                                .color(conrod::color::rgb(0.3, 0.3, 0.75))
+                                .label(&label)
+                                .label_color(color::WHITE)
+                                .label_font_size(10)
+                                .set(ids.patch_parameters[parameter], ui)
+                            {
+                                panel.set_parameter(index, number, value);
+                            }
+                            parameter += 1;
+                        }
+                    }
+
+                    // Cables over the modules, loops in another color
+                    for (number, cable) in panel.patch.cables.iter().enumerate() {
+                        let modules = &panel.patch.modules;
+                        let from = patch_jack_position(&modules[cable.from], cable.output, true);
+                        let to = patch_jack_position(&modules[cable.to], cable.input, false);
+                        let cable_color = if panel.feedback.get(number) == Some(&true) {
+                            color::LIGHT_RED
+                        } else {
+                            color::LIGHT_YELLOW
+                        };
+                        widget::Line::abs(
+                            [origin[0] + from[0], origin[1] + from[1]],
+                            [origin[0] + to[0], origin[1] + to[1]],
+                        )
+                        .thickness(2.0)
+                        .color(cable_color)
+                        .parent(ids.patch_canvas)
+                        .graphics_for(ids.patch_canvas)
+                        .set(ids.patch_cables[number], ui);
+                    }
+
+                    let mouse = ui.global_input().current.mouse.clone();
+                    if let Some((from, output)) = panel.dragging {
+                        let start = patch_jack_position(&panel.patch.modules[from], output, true);
+                        widget::Line::abs([origin[0] + start[0], origin[1] + start[1]], mouse.xy)
+                            .thickness(2.0)
+                            .color(color::WHITE)
+                            .parent(ids.patch_canvas)
+                            .graphics_for(ids.patch_canvas)
+                            .set(ids.patch_pending_cable, ui);
+                    }
+
+                    // Pressing an input jack picks up the last cable plugged into it
+                    if let Some(index) = removed {
+                        panel.dragging = None;
+                        panel.edit(|patch| patch.remove_module(index));
+                    } else if let Some((to, input)) = pressed_input {
+                        let cables = &panel.patch.cables;
+                        if let Some(number) =
+                            cables.iter().rposition(|c| c.to == to && c.input == input)
+                        {
+                            let cable = cables[number];
+                            panel.edit(|patch| {
+                                patch.cables.remove(number);
+                            });
+                            panel.dragging = Some((cable.from, cable.output));
+                        }
+                    }
+
+                    // Releasing the mouse plugs a dragged cable into the input jack under it
+                    if let Some((from, output)) = panel.dragging {
+                        if mouse.buttons.left().is_up() {
+                            panel.dragging = None;
+                            let target = input_jacks.iter().find(|&&(_, _, xy)| {
+                                let (dx, dy) = (xy[0] - mouse.xy[0], xy[1] - mouse.xy[1]);
+                                (dx * dx + dy * dy).sqrt() <= PATCH_JACK_RADIUS * 2.0
+                            });
+                            if let Some(&(to, input, _)) = target {
+                                panel.edit(|patch| {
+                                    patch.cables.push(Cable {
+                                        from,
+                                        output,
+                                        to,
+                                        input,
+                                    })
+                                });
+                            }
+                        }
+                    }
+                }
             }
             {
                 use conrod::glium::Surface;
@@ -291,6 +1692,11 @@ impl<'a> Ui<'a> {
                 }
             }
         }
+
+        // Fade out and stop the audio thread instead of letting the process die mid-buffer.
+        if let Err(error) = audioengine.stop() {
+            eprintln!("{}", error);
+        }
     }
 }
 
@@ -319,6 +1725,7 @@ fn glium_key_to_binding(glium_key: conrod::glium::glutin::VirtualKeyCode) -> Opt
         N => Some(SettingsInput(NextSignalFn)),
         Z => Some(SettingsInput(OctaveDown)),
         X => Some(SettingsInput(OctaveUp)),
+        Space => Some(SettingsInput(TapTempo)),
         _ => None,
     }
 }
@@ -332,6 +1739,7 @@ pub enum SettingsKey {
     NextSignalFn,
     OctaveUp,
     OctaveDown,
+    TapTempo,
 }
 
 #[allow(dead_code)]